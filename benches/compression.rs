@@ -0,0 +1,64 @@
+use bytes::BytesMut;
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use kvserver::{CommandResponse, Compression, FrameCoder, FrameOptions, KvPair, Value};
+use prost::Message;
+use rand::{Rng, distributions::Alphanumeric};
+
+// 典型的响应：hgetall 返回的字符串键值对、json 文档以及随机的二进制值
+fn payloads() -> Vec<(&'static str, CommandResponse)> {
+    let mut rng = rand::thread_rng();
+
+    let pairs = (0..500)
+        .map(|i| KvPair { key: format!("user:{}", i), value: Some(format!("name-{}-{}", i, i * 7).into()) })
+        .collect::<Vec<_>>();
+
+    let json = (0..200)
+        .map(|i| format!(r#"{{"id":{},"name":"user{}","tags":["a","b","c"],"active":true,"score":{}}}"#, i, i, i * 3))
+        .collect::<Vec<_>>()
+        .join(",");
+    let json = CommandResponse::from(Value::from(format!("[{}]", json)));
+
+    let text: String = (0..64 * 1024).map(|_| rng.sample(Alphanumeric) as char).collect();
+    let binary: Vec<u8> = (0..64 * 1024).map(|_| rng.gen()).collect();
+
+    vec![
+        ("pairs", pairs.into()),
+        ("json", json),
+        ("random_text", Value::from(text).into()),
+        ("random_binary", Value::from(binary).into()),
+    ]
+}
+
+fn compression(c: &mut Criterion) {
+    let codecs = [Compression::None, Compression::Gzip, Compression::Zstd, Compression::Lz4];
+
+    for (name, res) in payloads() {
+        let mut group = c.benchmark_group(format!("compression/{}", name));
+        group.throughput(Throughput::Bytes(res.encoded_len() as _));
+
+        for codec in codecs {
+            let options = FrameOptions { codecs: vec![codec], threshold: 0, level: None, ..Default::default() };
+            let mut buf = BytesMut::new();
+            res.encode_frame_with(&mut buf, &options).unwrap();
+            println!("{}/{}: {} -> {} bytes", name, codec.name(), res.encoded_len(), buf.len());
+
+            group.bench_with_input(BenchmarkId::new("encode", codec.name()), &options, |b, options| {
+                b.iter(|| {
+                    let mut buf = BytesMut::new();
+                    res.encode_frame_with(&mut buf, options).unwrap();
+                    black_box(buf);
+                })
+            });
+            group.bench_with_input(BenchmarkId::new("decode", codec.name()), &buf, |b, buf| {
+                b.iter(|| {
+                    let mut buf = buf.clone();
+                    black_box(CommandResponse::decode_frame(&mut buf).unwrap());
+                })
+            });
+        }
+        group.finish();
+    }
+}
+
+criterion_group!(benches, compression);
+criterion_main!(benches);
@@ -18,7 +18,7 @@ async fn start_server() -> kvserver::Result<()> {
 
 async fn connect() -> kvserver::Result<YamuxCtrl<TcpStream>> {
     let stream = TcpStream::connect("127.0.0.1:9900").await?;
-    let ctrl = YamuxCtrl::new_client(stream, None);
+    let ctrl = YamuxCtrl::new_client(stream, None).await?;
     Ok(ctrl)
 }
 
@@ -22,6 +22,11 @@ async fn main() -> kvserver::Result<()> {
                 println!("{:?}", res);
             }
         },
+        CommandType::Pipeline(cmds) => {
+            for res in stream.execute_pipeline(cmds).await? {
+                println!("{:?}", res);
+            }
+        },
     };
 
     Ok(())
@@ -1,4 +1,4 @@
-use clap::{Parser, Subcommand};
+use clap::{error::ErrorKind, Parser, Subcommand};
 
 use crate::CommandRequest;
 
@@ -21,6 +21,19 @@ pub enum SubCommand {
     SUBSCRIBE(Subscribe),
     UNSUBSCRIBE(Unsubscribe),
     PUBLISH(Publish),
+    JSONGET(JsonGet),
+    JSONSET(JsonSet),
+    JSONDEL(JsonDel),
+    JSONAPPEND(JsonAppend),
+    JSONINCR(JsonIncr),
+    CREATEINDEX(CreateIndex),
+    DROPINDEX(DropIndex),
+    QUERYINDEX(QueryIndex),
+    AGGREGATE(Aggregate),
+    HHISTORY(Hhistory),
+    ENABLEHISTORY(EnableHistory),
+    DISABLEHISTORY(DisableHistory),
+    PIPELINE(Pipeline),
 }
 
 #[derive(Subcommand, Debug, Clone)]
@@ -40,6 +53,9 @@ pub enum Value {
     },
     Binary {
         binary: Vec<u8>
+    },
+    Json {
+        json: String
     }
     // TEXT(String),
     // INTEGER(i64),
@@ -52,6 +68,10 @@ pub enum Value {
 pub struct Get {
     pub(crate) table: String,
     pub(crate) key: String,
+    #[arg(long, default_value_t = 0)]
+    pub(crate) as_of_version: u64,
+    #[arg(long, default_value_t = 0)]
+    pub(crate) as_of_time: u64,
 }
 
 #[derive(Parser, Debug)]
@@ -113,6 +133,112 @@ pub struct MDelete {
 #[derive(Parser, Debug)]
 pub struct GetAll {
     pub(crate) table: String,
+    #[arg(long, default_value = "")]
+    pub(crate) filter: String,
+}
+
+#[derive(Parser, Debug)]
+pub struct JsonGet {
+    pub(crate) table: String,
+    pub(crate) key: String,
+    #[arg(default_value = "$")]
+    pub(crate) path: String,
+}
+
+#[derive(Parser, Debug)]
+pub struct JsonSet {
+    pub(crate) table: String,
+    pub(crate) key: String,
+    pub(crate) path: String,
+    #[arg(value_parser = parse_value)]
+    pub(crate) value: Value,
+}
+
+#[derive(Parser, Debug)]
+pub struct JsonDel {
+    pub(crate) table: String,
+    pub(crate) key: String,
+    #[arg(default_value = "$")]
+    pub(crate) path: String,
+}
+
+#[derive(Parser, Debug)]
+pub struct JsonAppend {
+    pub(crate) table: String,
+    pub(crate) key: String,
+    pub(crate) path: String,
+    #[arg(value_parser = parse_value)]
+    pub(crate) values: Vec<Value>,
+}
+
+#[derive(Parser, Debug)]
+pub struct JsonIncr {
+    pub(crate) table: String,
+    pub(crate) key: String,
+    pub(crate) path: String,
+    #[arg(value_parser = parse_value)]
+    pub(crate) by: Value,
+}
+
+#[derive(Parser, Debug)]
+pub struct CreateIndex {
+    pub(crate) table: String,
+    pub(crate) index: String,
+    #[arg(default_value = "")]
+    pub(crate) path: String,
+}
+
+#[derive(Parser, Debug)]
+pub struct DropIndex {
+    pub(crate) table: String,
+    pub(crate) index: String,
+}
+
+#[derive(Parser, Debug)]
+pub struct QueryIndex {
+    pub(crate) table: String,
+    pub(crate) index: String,
+    #[arg(long, value_parser = parse_value)]
+    pub(crate) eq: Option<Value>,
+    #[arg(long, value_parser = parse_value)]
+    pub(crate) min: Option<Value>,
+    #[arg(long, value_parser = parse_value)]
+    pub(crate) max: Option<Value>,
+}
+
+#[derive(Parser, Debug)]
+pub struct Aggregate {
+    pub(crate) table: String,
+    #[arg(default_value = "")]
+    pub(crate) path: String,
+    #[arg(long, default_value = "")]
+    pub(crate) prefix: String,
+    #[arg(long, default_value = "")]
+    pub(crate) start: String,
+    #[arg(long, default_value = "")]
+    pub(crate) end: String,
+    #[arg(long, default_value = "")]
+    pub(crate) filter: String,
+}
+
+#[derive(Parser, Debug)]
+pub struct Hhistory {
+    pub(crate) table: String,
+    pub(crate) key: String,
+}
+
+#[derive(Parser, Debug)]
+pub struct EnableHistory {
+    pub(crate) table: String,
+    #[arg(long, default_value_t = 0)]
+    pub(crate) max_versions: u32,
+    #[arg(long, default_value_t = 0)]
+    pub(crate) retention_secs: u64,
+}
+
+#[derive(Parser, Debug)]
+pub struct DisableHistory {
+    pub(crate) table: String,
 }
 
 // #[derive(Parser, Debug)]
@@ -136,19 +262,49 @@ fn parse_value(s: &str) -> Result<Value, Box<dyn std::error::Error + Send + Sync
         "d" => Ok(Value::Double { double: val[1].parse::<f64>()? }),
         "f" => Ok(Value::Boolean { boolean: val[1].parse::<bool>()? }),
         "b" => Ok(Value::Binary { binary: val[1].as_bytes().to_vec() }),
+        "j" => Ok(Value::Json { json: val[1].to_string() }),
         _ => Ok(Value::Text { text: s.to_string() }),
     }
 } 
 
+// 从标准输入逐行读取命令（格式与命令行参数相同），在同一个 stream 上流水线发送
+// 空行和 # 开头的行被忽略，不支持 subscribe
+#[derive(Parser, Debug)]
+pub struct Pipeline {}
+
 pub enum CommandType {
     Unary(CommandRequest),
     Stream(CommandRequest),
+    Pipeline(Vec<CommandRequest>),
 }
 
 pub fn get_command() -> CommandType {
     let command = Command::parse();
-
     match command.subcommand {
+        SubCommand::PIPELINE(_) => {
+            let input = std::io::read_to_string(std::io::stdin()).unwrap_or_else(|e| clap::Error::from(e).exit());
+            CommandType::Pipeline(parse_pipeline(&input).unwrap_or_else(|e| e.exit()))
+        },
+        subcommand => command_type(subcommand),
+    }
+}
+
+pub fn parse_pipeline(input: &str) -> Result<Vec<CommandRequest>, clap::Error> {
+    let mut cmds = Vec::new();
+    for line in input.lines().map(str::trim).filter(|x| !x.is_empty() && !x.starts_with('#')) {
+        let words = shlex::split(line)
+            .ok_or_else(|| clap::Error::raw(ErrorKind::InvalidValue, format!("invalid command: {}\n", line)))?;
+        let command = Command::try_parse_from(std::iter::once("cli".to_string()).chain(words))?;
+        match command_type(command.subcommand) {
+            CommandType::Unary(cmd) => cmds.push(cmd),
+            _ => return Err(clap::Error::raw(ErrorKind::InvalidSubcommand, format!("unsupported command in pipeline: {}\n", line))),
+        }
+    }
+    Ok(cmds)
+}
+
+fn command_type(subcommand: SubCommand) -> CommandType {
+    match subcommand {
         SubCommand::GET(x) => CommandType::Unary(x.into()),
         SubCommand::SET(x) => CommandType::Unary(x.into()),
         SubCommand::EXISTS(x) => CommandType::Unary(x.into()),
@@ -159,6 +315,19 @@ pub fn get_command() -> CommandType {
         SubCommand::GETALL(x) => CommandType::Unary(x.into()),
         SubCommand::SUBSCRIBE(x) => CommandType::Stream(x.into()),
         SubCommand::UNSUBSCRIBE(x) => CommandType::Unary(x.into()),
-        SubCommand::PUBLISH(x) => CommandType::Unary(x.into()),        
+        SubCommand::PUBLISH(x) => CommandType::Unary(x.into()),
+        SubCommand::JSONGET(x) => CommandType::Unary(x.into()),
+        SubCommand::JSONSET(x) => CommandType::Unary(x.into()),
+        SubCommand::JSONDEL(x) => CommandType::Unary(x.into()),
+        SubCommand::JSONAPPEND(x) => CommandType::Unary(x.into()),
+        SubCommand::JSONINCR(x) => CommandType::Unary(x.into()),
+        SubCommand::CREATEINDEX(x) => CommandType::Unary(x.into()),
+        SubCommand::DROPINDEX(x) => CommandType::Unary(x.into()),
+        SubCommand::QUERYINDEX(x) => CommandType::Unary(x.into()),
+        SubCommand::AGGREGATE(x) => CommandType::Unary(x.into()),
+        SubCommand::HHISTORY(x) => CommandType::Unary(x.into()),
+        SubCommand::ENABLEHISTORY(x) => CommandType::Unary(x.into()),
+        SubCommand::DISABLEHISTORY(x) => CommandType::Unary(x.into()),
+        SubCommand::PIPELINE(_) => CommandType::Pipeline(vec![]),
     }
 }
\ No newline at end of file
@@ -12,7 +12,10 @@ impl From<Get> for CommandRequest {
             request_data: Some(RequestData::Hget(crate::Hget {
                 table: value.table,
                 key: value.key,
+                as_of_version: value.as_of_version,
+                as_of_time: value.as_of_time,
             })),
+            ..Default::default()
         }
     }
 }
@@ -24,6 +27,7 @@ impl From<Set> for CommandRequest {
                 table: value.table,
                 pair: Some((value.key, value.value.into()).into()),
             })),
+            ..Default::default()
         }
     }
 }
@@ -35,6 +39,7 @@ impl From<Exists> for CommandRequest {
                 table: value.table, 
                 key: value.key 
             })),
+            ..Default::default()
         }
     }
 }
@@ -46,6 +51,7 @@ impl From<Delete> for CommandRequest {
                 table: value.table, 
                 key: value.key 
             })),
+            ..Default::default()
         }
     }
 }
@@ -56,6 +62,7 @@ impl From<Subscribe> for CommandRequest {
             request_data: Some(RequestData::Subscribe(crate::Subscribe {
                 topic: value.topic,
             })),
+            ..Default::default()
         }
     }
 }
@@ -67,6 +74,7 @@ impl From<Unsubscribe> for CommandRequest {
                 topic: value.topic,
                 id: value.id
             })),
+            ..Default::default()
         }
     }
 }
@@ -78,6 +86,7 @@ impl From<Publish> for CommandRequest {
                 topic: value.topic,
                 data: value.data.into_iter().map(|x| x.into()).collect::<Vec<_>>()
             })),
+            ..Default::default()
         }
     }
 }
@@ -89,6 +98,7 @@ impl From<MGet> for CommandRequest {
                 table: value.table,
                 keys: value.keys,
             })),
+            ..Default::default()
         }
     }
 }
@@ -100,6 +110,7 @@ impl From<MExists> for CommandRequest {
                 table: value.table,
                 keys: value.keys,
             })),
+            ..Default::default()
         }
     }
 }
@@ -111,6 +122,7 @@ impl From<MDelete> for CommandRequest {
                 table: value.table,
                 keys: value.keys,
             })),
+            ..Default::default()
         }
     }
 }
@@ -120,11 +132,102 @@ impl From<GetAll> for CommandRequest {
         Self {
             request_data: Some(RequestData::Hgetall(crate::Hgetall {
                 table: value.table,
+                filter: value.filter,
             })),
+            ..Default::default()
         }
     }
 }
 
+impl From<JsonGet> for CommandRequest {
+    fn from(value: JsonGet) -> Self {
+        CommandRequest::new_json_get(value.table, value.key, value.path)
+    }
+}
+
+impl From<JsonSet> for CommandRequest {
+    fn from(value: JsonSet) -> Self {
+        CommandRequest::new_json_set(value.table, value.key, value.path, value.value.into())
+    }
+}
+
+impl From<JsonDel> for CommandRequest {
+    fn from(value: JsonDel) -> Self {
+        CommandRequest::new_json_del(value.table, value.key, value.path)
+    }
+}
+
+impl From<JsonAppend> for CommandRequest {
+    fn from(value: JsonAppend) -> Self {
+        let values = value.values.into_iter().map(|x| x.into()).collect::<Vec<_>>();
+        CommandRequest::new_json_append(value.table, value.key, value.path, values)
+    }
+}
+
+impl From<JsonIncr> for CommandRequest {
+    fn from(value: JsonIncr) -> Self {
+        CommandRequest::new_json_incr(value.table, value.key, value.path, value.by.into())
+    }
+}
+
+impl From<CreateIndex> for CommandRequest {
+    fn from(value: CreateIndex) -> Self {
+        CommandRequest::new_create_index(value.table, value.index, value.path)
+    }
+}
+
+impl From<DropIndex> for CommandRequest {
+    fn from(value: DropIndex) -> Self {
+        CommandRequest::new_drop_index(value.table, value.index)
+    }
+}
+
+impl From<QueryIndex> for CommandRequest {
+    fn from(value: QueryIndex) -> Self {
+        CommandRequest::new_query_index(
+            value.table,
+            value.index,
+            value.eq.map(|x| x.into()),
+            value.min.map(|x| x.into()),
+            value.max.map(|x| x.into()),
+        )
+    }
+}
+
+impl From<Aggregate> for CommandRequest {
+    fn from(value: Aggregate) -> Self {
+        Self {
+            request_data: Some(RequestData::Aggregate(crate::Aggregate {
+                table: value.table,
+                path: value.path,
+                prefix: value.prefix,
+                start: value.start,
+                end: value.end,
+                filter: value.filter,
+            })),
+            ..Default::default()
+        }
+    }
+}
+
+impl From<Hhistory> for CommandRequest {
+    fn from(value: Hhistory) -> Self {
+        CommandRequest::new_hhistory(value.table, value.key)
+    }
+}
+
+impl From<EnableHistory> for CommandRequest {
+    fn from(value: EnableHistory) -> Self {
+        CommandRequest::new_enable_history(value.table, value.max_versions, value.retention_secs)
+    }
+}
+
+impl From<DisableHistory> for CommandRequest {
+    fn from(value: DisableHistory) -> Self {
+        CommandRequest::new_disable_history(value.table)
+    }
+}
+
 impl From<self::command::Value> for crate::Value {
     fn from(value: self::command::Value) -> Self {
         match value {
@@ -133,6 +236,9 @@ impl From<self::command::Value> for crate::Value {
             self::command::Value::Double { double } => double.into(),
             self::command::Value::Boolean { boolean } => boolean.into(),
             self::command::Value::Binary { binary } => binary.into(),
+            self::command::Value::Json { json } => crate::Value {
+                value: Some(crate::value::Value::Json(json)),
+            },
         }
     }
 }
\ No newline at end of file
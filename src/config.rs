@@ -1,15 +1,191 @@
 use config::{Config, File};
 use serde::Deserialize;
 
+use crate::{Acl, AclRule, Authenticator, MAX_UPLOAD_PER_CONNECTION, MAX_UPLOADS_IN_PROGRESS, FrameOptions, KeepaliveOptions, Result, TlsServerAcceptor, TlsClientConnector, YamuxOptions};
+
 #[derive(Debug, Deserialize)]
 pub struct Settings {
     pub server: ServerSettings,
+    #[serde(default)]
+    pub client: ClientSettings,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct ServerSettings {
     pub port: u16,
-    pub store: StoreSettings
+    pub store: StoreSettings,
+    pub tls: Option<ServerTlsSettings>,
+    pub auth: Option<AuthSettings>,
+    pub acl: Option<Vec<AclRule>>,
+    #[serde(default)]
+    pub listeners: Vec<ListenerSettings>,
+    // 关闭时等待处理中请求的最长时间（秒）
+    #[serde(default = "default_shutdown_timeout")]
+    pub shutdown_timeout: u64,
+    // 支持的压缩算法、压缩阈值和等级
+    #[serde(default)]
+    pub compression: FrameOptions,
+    // 允许的最大帧长度（字节），默认 2 MiB
+    pub max_frame_size: Option<usize>,
+    // 每个连接以及所有连接上未完成的分块上传最多占用的内存（字节），默认 64 MiB 和 512 MiB
+    pub max_upload_per_connection: Option<u64>,
+    pub max_uploads_in_progress: Option<u64>,
+    // yamux 的接收窗口、缓存大小以及每个连接的 stream 数量和打开速度限制
+    #[serde(default)]
+    pub yamux: YamuxOptions,
+    // tcp keepalive、QUIC PING 的间隔，断开检测的超时以及连接的空闲超时（秒）
+    #[serde(default)]
+    pub keepalive: KeepaliveOptions,
+    // 兼容 Redis 协议的监听地址，不配置时不开启
+    pub resp: Option<ListenerSettings>,
+    // HTTP/JSON 网关的监听地址，不配置时不开启
+    pub http: Option<ListenerSettings>,
+    // gRPC 的监听地址，不配置时不开启
+    pub grpc: Option<ListenerSettings>,
+    // QUIC 的监听地址（udp），不配置时不开启；没有单独配置 tls 时使用 server.tls
+    pub quic: Option<ListenerSettings>,
+}
+
+fn default_shutdown_timeout() -> u64 {
+    30
+}
+
+// addr 为 ipv4、ipv6 地址或者 unix:路径，每个监听可以单独配置 tls
+#[derive(Debug, Clone, Deserialize)]
+pub struct ListenerSettings {
+    pub addr: String,
+    pub tls: Option<ServerTlsSettings>,
+}
+
+impl ServerSettings {
+    pub fn frame_options(&self) -> FrameOptions {
+        frame_options(&self.compression, self.max_frame_size)
+    }
+
+    pub fn upload_limits(&self) -> (u64, u64) {
+        (
+            self.max_upload_per_connection.unwrap_or(MAX_UPLOAD_PER_CONNECTION),
+            self.max_uploads_in_progress.unwrap_or(MAX_UPLOADS_IN_PROGRESS),
+        )
+    }
+
+    pub fn acl(&self) -> Option<Acl> {
+        self.acl.clone().map(Acl::new)
+    }
+
+    // 没有配置 listeners 时只监听 127.0.0.1:port，使用 server.tls
+    pub fn listeners(&self) -> Vec<ListenerSettings> {
+        if !self.listeners.is_empty() {
+            return self.listeners.clone();
+        }
+
+        vec![ListenerSettings {
+            addr: format!("127.0.0.1:{}", self.port),
+            tls: self.tls.clone(),
+        }]
+    }
+}
+
+// addr 为空时连接 127.0.0.1:port
+#[derive(Debug, Default, Deserialize)]
+pub struct ClientSettings {
+    pub addr: Option<String>,
+    pub tls: Option<ClientTlsSettings>,
+    pub auth: Option<ClientAuthSettings>,
+    // 按优先级排列的压缩算法、压缩阈值和等级
+    #[serde(default)]
+    pub compression: FrameOptions,
+    pub max_frame_size: Option<usize>,
+}
+
+impl ClientSettings {
+    pub fn frame_options(&self) -> FrameOptions {
+        frame_options(&self.compression, self.max_frame_size)
+    }
+}
+
+fn frame_options(compression: &FrameOptions, max_frame_size: Option<usize>) -> FrameOptions {
+    match max_frame_size {
+        Some(n) => compression.clone().with_max_frame_size(n),
+        None => compression.clone(),
+    }
+}
+
+// 密码为 argon2 的 PHC 字符串，token 为 sha256 的十六进制字符串
+#[derive(Debug, Default, Deserialize)]
+pub struct AuthSettings {
+    #[serde(default)]
+    pub users: Vec<UserSettings>,
+    #[serde(default)]
+    pub tokens: Vec<TokenSettings>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UserSettings {
+    pub name: String,
+    pub password: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TokenSettings {
+    pub name: String,
+    pub token: String,
+}
+
+// username 为空时 password 作为 token
+#[derive(Debug, Deserialize)]
+pub struct ClientAuthSettings {
+    #[serde(default)]
+    pub username: String,
+    pub password: String,
+}
+
+// 证书和私钥为 PEM 文件路径，配置 client_ca 时要求客户端证书（mTLS）
+#[derive(Debug, Clone, Deserialize)]
+pub struct ServerTlsSettings {
+    pub cert: String,
+    pub key: String,
+    pub client_ca: Option<String>,
+}
+
+// ca 为空时使用内置的根证书，cert 和 key 为 mTLS 时的客户端证书
+#[derive(Debug, Deserialize)]
+pub struct ClientTlsSettings {
+    pub domain: String,
+    pub ca: Option<String>,
+    pub cert: Option<String>,
+    pub key: Option<String>,
+}
+
+impl AuthSettings {
+    pub fn authenticator(&self) -> Authenticator {
+        let auth = self.users.iter().fold(Authenticator::new(), |auth, x| auth.user(&x.name, &x.password));
+        self.tokens.iter().fold(auth, |auth, x| auth.token(&x.name, &x.token))
+    }
+}
+
+impl ServerTlsSettings {
+    pub fn acceptor(&self) -> Result<TlsServerAcceptor> {
+        let cert = std::fs::read_to_string(&self.cert)?;
+        let key = std::fs::read_to_string(&self.key)?;
+        let client_ca = self.client_ca.as_ref().map(std::fs::read_to_string).transpose()?;
+        TlsServerAcceptor::new(&cert, &key, client_ca.as_deref())
+    }
+}
+
+impl ClientTlsSettings {
+    pub fn connector(&self) -> Result<TlsClientConnector> {
+        let ca = self.ca.as_ref().map(std::fs::read_to_string).transpose()?;
+        let identity = match (&self.cert, &self.key) {
+            (Some(cert), Some(key)) => Some((std::fs::read_to_string(cert)?, std::fs::read_to_string(key)?)),
+            _ => None,
+        };
+        TlsClientConnector::new(
+            &self.domain,
+            identity.as_ref().map(|(cert, key)| (cert.as_str(), key.as_str())),
+            ca.as_deref(),
+        )
+    }
 }
 
 #[derive(Debug, Deserialize)]
@@ -9,21 +9,38 @@ pub type Result<T> = std::result::Result<T, KvError>;
 pub enum KvError {
     #[error("not found table {0} or key {1}")]
     NotFound(String, String),
+    #[error("not found index {1} on table {0}")]
+    IndexNotFound(String, String),
+    #[error("history is not enabled on table {0}")]
+    HistoryDisabled(String),
     #[error("{0}")]
     Invalid(String),
     #[error("invalid command {0}")]
     InvalidCommand(String),
+    #[error("unauthorized: {0}")]
+    Unauthorized(String),
+    #[error("forbidden: {0}")]
+    Forbidden(String),
     #[error("{0}")]
     Internal(String),
 
     #[error("failed to convert value")]
     ConvertError,
+    #[error("invalid chunk: {0}")]
+    InvalidChunk(String),
+    #[error("checksum mismatch, expected {0}, got {1}")]
+    ChecksumMismatch(String, String),
 
     #[error(transparent)]
     SledError(#[from] sled::Error),
 
+    #[error("json error: {0}")]
+    JsonError(#[from] serde_json::Error),
+
     #[error("frame error")]
     FrameError,
+    #[error("frame size {0} exceeds limit {1}")]
+    FrameTooLarge(usize, usize),
     #[error("frame encode error")]
     FrameEncodeError(#[from] EncodeError),
     #[error("frame decode error")]
@@ -34,6 +51,19 @@ pub enum KvError {
     #[error("connection error")]
     ConnectionError(#[from] ConnectionError),
 
+    #[error("quic connection error: {0}")]
+    QuicConnectionError(#[from] quinn::ConnectionError),
+    #[error("quic connect error: {0}")]
+    QuicConnectError(#[from] quinn::ConnectError),
+
+    #[error("tls error: {0}")]
+    TlsError(#[from] tokio_rustls::rustls::Error),
+    #[error("failed to parse {0}")]
+    CertificateParseError(&'static str),
+
+    #[error("handshake failed: {0}")]
+    HandshakeError(String),
+
     #[error("unknown error")]
     Unknown
 }
\ No newline at end of file
@@ -10,49 +10,359 @@ pub use commandline::{get_command, CommandType};
 use storage::{SledDb, Storage};
 pub use crate::config::*;
 pub use error::*;
-pub use network::{ProstClientStream, ProstServerStream, YamuxCtrl};
+pub use network::{ProstClientStream, ProstServerStream, RespServerStream, YamuxCtrl, TlsServerAcceptor, TlsClientConnector, ClientStream, ServerStream, Listener, Compression, FrameCoder, FrameOptions, YamuxOptions, KeepaliveOptions, Gateway, GrpcService, QuicConnection, QuicCtrl, QuicListener, QuicStream};
+use network::{connect_stream, server_handshake, IdleTracker};
 pub use pb::*;
-pub use service::ServiceInner;
+pub use service::{Acl, AclRule, Authenticator, Permission, Service, ServiceInner, Session, ShutdownHandle, LOCKOUT_DURATION, LOCKOUT_FAILURES, MAX_AUTH_FAILURES, MAX_UPLOAD_PER_CONNECTION, MAX_UPLOADS_IN_PROGRESS, MIN_CHUNK_SIZE, hash_token, shutdown_signal};
 pub use storage::MemoryDb;
-use tokio::{net::{TcpListener, TcpStream}};
-use tokio_util::compat::FuturesAsyncReadCompatExt;
-use tracing::log::info;
+use std::{future::Future, time::Duration};
+
+use futures::{future::{self, try_join_all}, FutureExt};
+use tokio::{io::{AsyncRead, AsyncWrite}, net::TcpListener};
+use tokio_util::{compat::FuturesAsyncReadCompatExt, sync::CancellationToken};
+use tracing::log::{info, warn};
+
+// accept 出现暂时的错误（例如文件描述符用完）后等待的时间
+const ACCEPT_ERROR_BACKOFF: Duration = Duration::from_millis(100);
+
+// 没有配置 idle_timeout 时 tls 握手的超时
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
 
 lazy_static::lazy_static! {
     pub static ref CONFIG: ServerSettings = config().server;
+    pub static ref CLIENT_CONFIG: ClientSettings = config().client;
 }
 
 pub async fn start_server_with_config() -> Result<()> {
-    let addr = format!("127.0.0.1:{}", CONFIG.port);
     let name = CONFIG.store.name.as_ref();
     let path = CONFIG.store.path.as_deref();
     match (name, path) {
-        ("sleddb", Some(path)) => start_server(&addr, SledDb::new(path)).await,
-        _ => start_server(&addr, MemoryDb::new()).await
+        ("sleddb", Some(path)) => serve_listeners(service_with_config(SledDb::new(path))).await,
+        _ => serve_listeners(service_with_config(MemoryDb::new())).await
     }
 }
 
+// 所有 listener 共用同一个 Service，收到 SIGINT 或 SIGTERM 后优雅关闭
+async fn serve_listeners<Store: Storage>(service: Service<Store>) -> Result<()> {
+    let handle = service.shutdown_handle();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!("Received shutdown signal, stop accepting connections");
+        handle.shutdown();
+    });
+
+    let mut servers = vec![];
+    for x in CONFIG.listeners() {
+        let acceptor = x.tls.as_ref().map(|x| x.acceptor()).transpose()?;
+        let listener = Listener::bind(&x.addr).await?;
+        servers.push(serve_listener(listener, service.clone(), acceptor).boxed());
+    }
+    if let Some(x) = CONFIG.resp.as_ref() {
+        let acceptor = x.tls.as_ref().map(|x| x.acceptor()).transpose()?;
+        let listener = Listener::bind(&x.addr).await?;
+        servers.push(serve_resp_listener(listener, service.clone(), acceptor).boxed());
+    }
+    if let Some(x) = CONFIG.http.as_ref() {
+        let acceptor = x.tls.as_ref().map(|x| x.acceptor()).transpose()?;
+        let listener = Listener::bind(&x.addr).await?;
+        servers.push(serve_http_listener(listener, service.clone(), acceptor).boxed());
+    }
+    if let Some(x) = CONFIG.grpc.as_ref() {
+        let acceptor = x.tls.as_ref().map(|x| x.acceptor()).transpose()?;
+        let listener = Listener::bind(&x.addr).await?;
+        servers.push(serve_grpc_listener(listener, service.clone(), acceptor).boxed());
+    }
+    if let Some(x) = CONFIG.quic.as_ref() {
+        let tls = x.tls.as_ref().or(CONFIG.tls.as_ref()).ok_or_else(|| KvError::Invalid("quic requires tls settings".into()))?;
+        let listener = QuicListener::bind(&x.addr, tls.acceptor()?).await?;
+        servers.push(serve_quic_listener(listener, service.clone()).boxed());
+    }
+
+    try_join_all(servers).await?;
+    service.graceful_shutdown(Duration::from_secs(CONFIG.shutdown_timeout)).await?;
+    info!("Server shutdown");
+    Ok(())
+}
+
+fn service_with_config<Store: Storage>(store: Store) -> Service<Store> {
+    let mut inner = ServiceInner::new(store);
+    if let Some(auth) = CONFIG.auth.as_ref() {
+        inner = inner.with_auth(auth.authenticator());
+    }
+    if let Some(acl) = CONFIG.acl() {
+        inner = inner.with_acl(acl);
+    }
+    let (per_connection, total) = CONFIG.upload_limits();
+    inner.with_upload_limits(per_connection, total).with_frame_options(CONFIG.frame_options()).with_yamux_options(CONFIG.yamux.clone()).with_keepalive_options(CONFIG.keepalive.clone()).service()
+}
+
 pub async fn start_server<Store: Storage>(addr: &str, store: Store) -> Result<()> {
+    start_server_with_tls(addr, store, None).await
+}
+
+pub async fn start_server_with_tls<Store: Storage>(addr: &str, store: Store, acceptor: Option<TlsServerAcceptor>) -> Result<()> {
+    serve(addr, ServiceInner::new(store).service(), acceptor).await
+}
+
+pub async fn serve<Store: Storage>(addr: &str, service: Service<Store>, acceptor: Option<TlsServerAcceptor>) -> Result<()> {
+    serve_listener(Listener::bind(addr).await?, service, acceptor).await
+}
 
-    let listener = TcpListener::bind(&addr).await?;
-    info!("Listenning address: {:?}", &addr);
-    let service = ServiceInner::new(store).service();
+pub async fn serve_listener<Store: Storage>(listener: Listener, service: Service<Store>, acceptor: Option<TlsServerAcceptor>) -> Result<()> {
+    accept_loop(listener, service, acceptor, "kv", serve_connection).await
+}
+
+// 使用 Redis 协议（RESP2/RESP3）的 listener，与其他 listener 共用同一个 Service
+pub async fn serve_resp_listener<Store: Storage>(listener: Listener, service: Service<Store>, acceptor: Option<TlsServerAcceptor>) -> Result<()> {
+    accept_loop(listener, service, acceptor, "resp", serve_resp_connection).await
+}
+
+// HTTP/JSON 网关，每个请求单独认证
+pub async fn serve_http_listener<Store: Storage>(listener: Listener, service: Service<Store>, acceptor: Option<TlsServerAcceptor>) -> Result<()> {
+    accept_loop(listener, service, acceptor, "http", serve_http_connection).await
+}
+
+// gRPC（HTTP/2），每个调用单独认证
+pub async fn serve_grpc_listener<Store: Storage>(listener: Listener, service: Service<Store>, acceptor: Option<TlsServerAcceptor>) -> Result<()> {
+    accept_loop(listener, service, acceptor, "grpc", serve_grpc_connection).await
+}
+
+// QUIC 上的每个 stream 相当于 yamux 的一个 stream，使用相同的帧格式和处理逻辑
+pub async fn serve_quic_listener<Store: Storage>(listener: QuicListener, service: Service<Store>) -> Result<()> {
+    info!("Listenning address: {:?}, protocol: quic, tls: true", listener.local_addr()?);
+    listener.set_keepalive(service.keepalive_options())?;
+    let shutdown = service.shutdown_handle();
+    let listener = std::sync::Arc::new(listener);
 
     loop {
-        let (stream, _) = listener.accept().await?;
+        let connecting = tokio::select! {
+            res = listener.accept() => match res {
+                Some(connecting) => connecting,
+                None => return Ok(()),
+            },
+            _ = shutdown.wait() => {
+                listener.close();
+                return Ok(());
+            },
+        };
         let svc = service.clone();
+        let listener = listener.clone();
         tokio::spawn(async move {
-            YamuxCtrl::new_server(stream, None, move |stream| {
-                let mut stream = ProstServerStream::new(stream.compat(), svc.clone());
-                async move {
-                    stream.process().await.expect("failed to server stream execute");
-                    Ok(())
+            match listener.establish(connecting).await {
+                Ok((conn, identity)) => {
+                    let session = Session::new(conn.peer(), identity);
+                    serve_quic_connection(conn, svc, session).await
+                },
+                Err(e) => warn!("Rejected quic connection: {}", e),
+            }
+        });
+    }
+}
+
+async fn accept_loop<Store, F, Fut>(listener: Listener, service: Service<Store>, acceptor: Option<TlsServerAcceptor>, protocol: &str, handler: F) -> Result<()>
+where
+    Store: Storage,
+    F: Fn(ClientStream, Service<Store>, Session) -> Fut + Copy + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+
+    info!("Listenning address: {:?}, protocol: {}, tls: {}", listener.local_addr()?, protocol, acceptor.is_some());
+    let shutdown = service.shutdown_handle();
+
+    loop {
+        let res = tokio::select! {
+            res = listener.accept_with(service.keepalive_options()) => res,
+            _ = shutdown.wait() => return Ok(()),
+        };
+        let (stream, peer) = match res {
+            Ok(x) => x,
+            Err(e) if Listener::is_fatal_error(&e) => return Err(e),
+            // 暂时的错误稍等后继续 accept，不会让其他 listener 一起停止
+            Err(e) => {
+                warn!("Failed to accept {} connection: {}", protocol, e);
+                tokio::select! {
+                    _ = tokio::time::sleep(ACCEPT_ERROR_BACKOFF) => continue,
+                    _ = shutdown.wait() => return Ok(()),
                 }
-            });
+            },
+        };
+        let svc = service.clone();
+        let acceptor = acceptor.clone();
+        tokio::spawn(async move {
+            match acceptor {
+                Some(acceptor) => match tls_accept(&acceptor, stream, svc.keepalive_options()).await {
+                    Ok((stream, identity)) => {
+                        info!("Accepted tls connection from {}, identity: {:?}", peer, identity);
+                        handler(Box::new(stream), svc, Session::new(peer, identity)).await
+                    },
+                    Err(e) => warn!("Rejected tls handshake from {}: {}", peer, e),
+                },
+                None => handler(Box::new(stream), svc, Session::new(peer, None)).await,
+            }
+        });
+    }
+}
+
+async fn serve_resp_connection<Store: Storage>(stream: ClientStream, service: Service<Store>, session: Session) {
+    let peer = session.peer.clone();
+    if let Err(e) = RespServerStream::new(stream, service).with_session(session).process().await {
+        warn!("Failed to process RESP connection from {}: {}", peer, e);
+    }
+}
+
+async fn serve_http_connection<Store: Storage>(stream: ClientStream, service: Service<Store>, session: Session) {
+    let peer = session.peer.clone();
+    let router = Gateway::router(service, session);
+    if let Err(e) = hyper::server::conn::Http::new().serve_connection(stream, router).with_upgrades().await {
+        warn!("Failed to process HTTP connection from {}: {}", peer, e);
+    }
+}
+
+async fn serve_grpc_connection<Store: Storage>(stream: ClientStream, service: Service<Store>, session: Session) {
+    let peer = session.peer.clone();
+    let server = GrpcService::server(service, session);
+    if let Err(e) = hyper::server::conn::Http::new().http2_only(true).serve_connection(stream, server).await {
+        warn!("Failed to process gRPC connection from {}: {}", peer, e);
+    }
+}
+
+// 第一个 stream 用于协议握手
+async fn serve_quic_connection<Store: Storage>(conn: QuicConnection, service: Service<Store>, session: Session) {
+    let local = Handshake::new(service.frame_options(), service.auth_methods(&session));
+    let handshake = async {
+        match conn.accept_stream().await? {
+            Some(mut stream) => server_handshake(&mut stream, &local).await.map(Some),
+            None => Ok(None),
+        }
+    };
+    let handshake = match with_idle_timeout(service.keepalive_options(), handshake).await {
+        Ok(Some(handshake)) => Ok(handshake),
+        Ok(None) => return,
+        Err(e) => Err(e),
+    };
+    let options = match handshake {
+        Ok(handshake) => service.frame_options().negotiated(&handshake),
+        Err(e) => {
+            warn!("Rejected connection from {}: {}", session.peer, e);
+            return;
+        },
+    };
+
+    // 没有打开的 stream 超过 idle_timeout 时关闭连接，返回时通知所有 stream 连接已经结束
+    let closed = CancellationToken::new();
+    let _closed = closed.clone().drop_guard();
+    let tracker = IdleTracker::default();
+    let idle = wait_idle(&tracker, service.keepalive_options());
+    tokio::pin!(idle);
+    loop {
+        let res = tokio::select! {
+            res = conn.accept_stream() => res,
+            _ = &mut idle => {
+                info!("Closing idle quic connection from {}", session.peer);
+                conn.close("idle");
+                return;
+            },
+        };
+        let stream = match res {
+            Ok(Some(stream)) => stream,
+            Ok(None) => return,
+            Err(e) => {
+                warn!("Quic connection from {} closed: {}", session.peer, e);
+                return;
+            },
+        };
+        let mut stream = ProstServerStream::new(stream, service.clone())
+            .with_session(session.clone())
+            .with_options(options.clone())
+            .with_closed(closed.clone());
+        let peer = session.peer.clone();
+        let guard = tracker.enter();
+        tokio::spawn(async move {
+            let _guard = guard;
+            if let Err(e) = stream.process().await {
+                warn!("Failed to process stream from {}: {}", peer, e);
+            }
         });
     }
 }
 
+async fn serve_connection<S, Store>(mut stream: S, service: Service<Store>, session: Session)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    Store: Storage,
+{
+    let local = Handshake::new(service.frame_options(), service.auth_methods(&session));
+    let options = match with_idle_timeout(service.keepalive_options(), server_handshake(&mut stream, &local)).await {
+        Ok(handshake) => service.frame_options().negotiated(&handshake),
+        Err(e) => {
+            warn!("Rejected connection from {}: {}", session.peer, e);
+            return;
+        },
+    };
+
+    let yamux = service.yamux_options().clone();
+    let keepalive = service.keepalive_options().clone();
+    let tracker = IdleTracker::default();
+    let closed = CancellationToken::new();
+    let (idle_tracker, conn_closed, peer) = (tracker.clone(), closed.clone(), session.peer.clone());
+    let mut ctrl = YamuxCtrl::new_server_with_options(stream, &yamux, move |stream| {
+        let mut stream = ProstServerStream::new(stream.compat(), service.clone())
+            .with_session(session.clone())
+            .with_options(options.clone())
+            .with_closed(closed.clone());
+        let peer = session.peer.clone();
+        let guard = tracker.enter();
+        // 单个 stream 出错只关闭该 stream，返回 Ok 让连接上的其他 stream 继续工作
+        async move {
+            let _guard = guard;
+            if let Err(e) = stream.process().await {
+                warn!("Failed to process stream from {}: {}", peer, e);
+            }
+            Ok(())
+        }
+    });
+
+    // 没有打开的 stream 超过 idle_timeout 时关闭连接，连接结束后通知所有 stream 取消订阅
+    tokio::spawn(async move {
+        let yamux_closed = ctrl.closed();
+        tokio::select! {
+            _ = wait_idle(&idle_tracker, &keepalive) => {
+                info!("Closing idle connection from {}", peer);
+                if let Err(e) = ctrl.close().await {
+                    warn!("Failed to close connection from {}: {}", peer, e);
+                }
+            },
+            _ = yamux_closed.cancelled() => {},
+        }
+        conn_closed.cancel();
+    });
+}
+
+// 握手也受 idle_timeout 限制，避免连接后不发送数据的客户端一直占用连接
+async fn with_idle_timeout<T>(keepalive: &KeepaliveOptions, f: impl Future<Output = Result<T>>) -> Result<T> {
+    match keepalive.idle_timeout() {
+        Some(timeout) => tokio::time::timeout(timeout, f).await.map_err(|_| KvError::HandshakeError("timed out".into()))?,
+        None => f.await,
+    }
+}
+
+// tls 握手使用 idle_timeout，没有配置时也有默认的超时，避免不完成握手的客户端一直占用连接
+async fn tls_accept<S>(acceptor: &TlsServerAcceptor, stream: S, keepalive: &KeepaliveOptions) -> Result<(tokio_rustls::server::TlsStream<S>, Option<String>)>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send,
+{
+    let timeout = keepalive.idle_timeout().unwrap_or(TLS_HANDSHAKE_TIMEOUT);
+    tokio::time::timeout(timeout, acceptor.accept(stream)).await.map_err(|_| KvError::HandshakeError("tls handshake timed out".into()))?
+}
+
+// 没有配置 idle_timeout 时永远不会返回
+async fn wait_idle(tracker: &IdleTracker, keepalive: &KeepaliveOptions) {
+    match keepalive.idle_timeout() {
+        Some(timeout) => tracker.wait_idle(timeout).await,
+        None => future::pending().await,
+    }
+}
+
 
 
 pub async fn start_server_with_yamux(addr: &str) -> Result<()> {
@@ -62,24 +372,46 @@ pub async fn start_server_with_yamux(addr: &str) -> Result<()> {
     let service = ServiceInner::new(MemoryDb::new()).service();
 
     loop {
-        let (stream, _) = listener.accept().await?;
-        let svc = service.clone();
-        YamuxCtrl::new_server(stream, None, move |stream| {
-            let mut stream = ProstServerStream::new(stream.compat(), svc.clone());
-            async move {
-                stream.process().await.expect("failed to server stream execute");
-                Ok(())
-            }
-        });
+        let (stream, peer) = listener.accept().await?;
+        tokio::spawn(serve_connection(stream, service.clone(), Session::new(peer.to_string(), None)));
     }
 }
 
 
-pub async fn start_client() -> Result<YamuxCtrl<TcpStream>> {
+pub async fn start_client() -> Result<YamuxCtrl<ClientStream>> {
+
+    let addr = CLIENT_CONFIG.addr.clone().unwrap_or_else(|| format!("127.0.0.1:{}", CONFIG.port));
+    let connector = CLIENT_CONFIG.tls.as_ref().map(|x| x.connector()).transpose()?;
+
+    let mut ctrl = connect_with_options(&addr, connector.as_ref(), CLIENT_CONFIG.frame_options()).await?;
+    if let Some(auth) = CLIENT_CONFIG.auth.as_ref() {
+        authenticate(&mut ctrl, &auth.username, &auth.password).await?;
+    }
+    Ok(ctrl)
+}
+
+// 认证对整个连接生效，之后打开的 stream 都无需再次认证
+pub async fn authenticate(ctrl: &mut YamuxCtrl<ClientStream>, username: &str, password: &str) -> Result<()> {
+    let mut stream = ctrl.open_stream().await?;
+    let res = stream.execute_unary(&CommandRequest::new_auth(username, password)).await?;
+    if res.state_code != 200 {
+        return Err(KvError::Unauthorized(res.msg));
+    }
+    Ok(())
+}
+
+pub async fn connect(addr: &str, connector: Option<&TlsClientConnector>) -> Result<YamuxCtrl<ClientStream>> {
+    connect_with_options(addr, connector, FrameOptions::default()).await
+}
+
+pub async fn connect_with_options(addr: &str, connector: Option<&TlsClientConnector>, options: FrameOptions) -> Result<YamuxCtrl<ClientStream>> {
 
-    let addr = format!("127.0.0.1:{}", CONFIG.port);
+    let stream = connect_stream(addr).await?;
 
-    let stream = TcpStream::connect(&addr).await?;
+    let stream: ClientStream = match connector {
+        Some(connector) => Box::new(connector.connect(stream).await?),
+        None => stream,
+    };
 
-    Ok(YamuxCtrl::new_client(stream, None))
+    YamuxCtrl::new_client_with_options(stream, None, options).await
 }
\ No newline at end of file
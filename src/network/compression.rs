@@ -0,0 +1,217 @@
+use std::{io::{Read, Write}, str::FromStr};
+
+use flate2::{read::GzDecoder, write::GzEncoder};
+use serde::Deserialize;
+
+use crate::{pb::Handshake, KvError, Result};
+
+use super::frame::{LEN_MASK, MAX_FRAME};
+
+// 超过该长度（约一个 MTU）的帧才压缩
+const COMPRESSION_LIMIT: usize = 1436;
+
+// 帧头部最高两位表示压缩算法，gzip 与旧版本的压缩位保持一致
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+    Lz4,
+}
+
+impl Compression {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+            Self::Lz4 => "lz4",
+        }
+    }
+
+    pub(crate) fn bits(&self) -> u8 {
+        match self {
+            Self::None => 0b00,
+            Self::Gzip => 0b10,
+            Self::Zstd => 0b01,
+            Self::Lz4 => 0b11,
+        }
+    }
+
+    pub(crate) fn from_bits(bits: u8) -> Self {
+        match bits & 0b11 {
+            0b10 => Self::Gzip,
+            0b01 => Self::Zstd,
+            0b11 => Self::Lz4,
+            _ => Self::None,
+        }
+    }
+
+    // level 为 None 时使用各算法的默认等级，lz4 没有等级
+    pub fn compress(&self, data: &[u8], level: Option<i32>) -> Result<Vec<u8>> {
+        match self {
+            Self::None => Ok(data.to_vec()),
+            Self::Gzip => {
+                let level = level.map_or(flate2::Compression::default(), |x| flate2::Compression::new(x.clamp(0, 9) as _));
+                let mut encoder = GzEncoder::new(Vec::with_capacity(data.len()), level);
+                encoder.write_all(data)?;
+                Ok(encoder.finish()?)
+            },
+            Self::Zstd => {
+                let range = zstd::compression_level_range();
+                let level = level.unwrap_or(zstd::DEFAULT_COMPRESSION_LEVEL).clamp(*range.start(), *range.end());
+                Ok(zstd::bulk::compress(data, level)?)
+            },
+            Self::Lz4 => Ok(lz4_flex::compress_prepend_size(data)),
+        }
+    }
+
+    // 解压后的长度超过 limit 时返回错误，避免压缩炸弹
+    pub fn decompress(&self, data: &[u8], limit: usize) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(data.len() * 2);
+        match self {
+            Self::None => buf.extend_from_slice(data),
+            Self::Gzip => {
+                GzDecoder::new(data).take(limit as u64 + 1).read_to_end(&mut buf)?;
+            },
+            Self::Zstd => {
+                zstd::Decoder::new(data)?.take(limit as u64 + 1).read_to_end(&mut buf)?;
+            },
+            Self::Lz4 => {
+                let size = data.get(..4).ok_or(KvError::FrameError)?;
+                let size = u32::from_le_bytes(size.try_into().unwrap()) as usize;
+                if size > limit {
+                    return Err(KvError::FrameTooLarge(size, limit));
+                }
+                buf = lz4_flex::decompress_size_prepended(data).map_err(|_| KvError::FrameError)?;
+            },
+        }
+
+        if buf.len() > limit {
+            return Err(KvError::FrameTooLarge(buf.len(), limit));
+        }
+        Ok(buf)
+    }
+}
+
+impl FromStr for Compression {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "none" => Ok(Self::None),
+            "gzip" => Ok(Self::Gzip),
+            "zstd" => Ok(Self::Zstd),
+            "lz4" => Ok(Self::Lz4),
+            _ => Err(KvError::Invalid(format!("unknown compression {}", s))),
+        }
+    }
+}
+
+// 连接上收发帧时的配置，codecs 按优先级排列，握手后只保留协商出的一个
+// max_frame_size 在配置文件中单独设置，见 ServerSettings::frame_options
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct FrameOptions {
+    pub codecs: Vec<Compression>,
+    pub threshold: usize,
+    pub level: Option<i32>,
+    #[serde(skip)]
+    pub max_frame_size: usize,
+}
+
+impl Default for FrameOptions {
+    fn default() -> Self {
+        Self {
+            codecs: vec![Compression::Gzip],
+            threshold: COMPRESSION_LIMIT,
+            level: None,
+            max_frame_size: MAX_FRAME,
+        }
+    }
+}
+
+impl FrameOptions {
+    // 长度为 size 的帧使用的压缩算法
+    pub fn compression(&self, size: usize) -> Compression {
+        match self.codecs.first() {
+            Some(x) if size > self.threshold => *x,
+            _ => Compression::None,
+        }
+    }
+
+    pub fn with_max_frame_size(mut self, max_frame_size: usize) -> Self {
+        // 头部只有 30 位表示长度
+        self.max_frame_size = max_frame_size.clamp(1, LEN_MASK);
+        self
+    }
+
+    // 按握手的协商结果选择压缩算法和最大帧长度，阈值和等级仍使用本端的配置
+    pub fn negotiated(&self, handshake: &Handshake) -> Self {
+        let codec = handshake
+            .compressions
+            .iter()
+            .filter_map(|x| x.parse::<Compression>().ok())
+            .find(|x| self.codecs.contains(x));
+        let max_frame_size = match handshake.max_frame_size as usize {
+            0 => self.max_frame_size,
+            n => n.min(self.max_frame_size),
+        };
+        Self {
+            codecs: codec.into_iter().collect(),
+            max_frame_size,
+            ..self.clone()
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{pb::Handshake, KvError};
+
+    use super::{Compression, FrameOptions};
+
+    const CODECS: [Compression; 4] = [Compression::None, Compression::Gzip, Compression::Zstd, Compression::Lz4];
+
+    #[test]
+    fn compression_should_work() {
+        let data = b"hello world ".repeat(1000);
+        for codec in CODECS {
+            for level in [None, Some(1), Some(100)] {
+                let compressed = codec.compress(&data, level).unwrap();
+                if codec != Compression::None {
+                    assert!(compressed.len() < data.len());
+                }
+                assert_eq!(codec.decompress(&compressed, data.len()).unwrap(), data);
+            }
+            assert_eq!(Compression::from_bits(codec.bits()), codec);
+            assert_eq!(codec.name().parse::<Compression>().unwrap(), codec);
+        }
+    }
+
+    #[test]
+    fn decompress_over_limit_should_fail() {
+        let data = vec![0u8; 100000];
+        for codec in CODECS {
+            let compressed = codec.compress(&data, None).unwrap();
+            assert!(matches!(codec.decompress(&compressed, 1000), Err(KvError::FrameTooLarge(_, 1000))));
+        }
+    }
+
+    #[test]
+    fn negotiated_options_should_work() {
+        let options = FrameOptions { codecs: vec![Compression::Lz4, Compression::Gzip], ..Default::default() };
+        let handshake = Handshake { compressions: vec!["zstd".into(), "gzip".into()], max_frame_size: 4096, ..Default::default() };
+        let res = options.negotiated(&handshake);
+        assert_eq!(res.codecs, vec![Compression::Gzip]);
+        assert_eq!(res.max_frame_size, 4096);
+        assert_eq!(res.compression(100), Compression::None);
+        assert_eq!(res.compression(10000), Compression::Gzip);
+
+        let res = options.negotiated(&Handshake::default());
+        assert_eq!(res.compression(10000), Compression::None);
+    }
+}
@@ -1,94 +1,105 @@
-use std::io::{Write, Read};
-
 use bytes::{BytesMut, BufMut, Buf};
-use flate2::{write::GzEncoder, Compression, read::GzDecoder};
 use prost::Message;
-use tokio::io::{AsyncRead, AsyncReadExt};
 
-use crate::{Result, KvError, pb::{CommandRequest, CommandResponse}};
+use crate::{Result, KvError, pb::{CommandRequest, CommandResponse, Handshake}};
+
+use super::compression::{Compression, FrameOptions};
 
 
-const LEN_LEN: usize = 4;
-const MAX_FRAME: usize = 2 * 1024 * 1024 - 1;
-const COMPRESSION_LIMIT: usize = 1436;
-const COMPRESSION_BIT: usize = 1 << 31;
+pub const LEN_LEN: usize = 4;
+// 默认的最大帧长度，可以通过配置修改，连接上实际使用双方协商出的较小值
+pub const MAX_FRAME: usize = 2 * 1024 * 1024 - 1;
+// 头部最高两位为压缩算法，其余为正文长度
+const CODEC_SHIFT: usize = 30;
+pub const LEN_MASK: usize = (1 << CODEC_SHIFT) - 1;
 
 pub trait FrameCoder 
 where
     Self: Sized + Message + Default,
 {
     fn encode_frame(&self, buf: &mut BytesMut) -> Result<()> {
+        self.encode_frame_with(buf, &FrameOptions::default())
+    }
+
+    fn encode_frame_with(&self, buf: &mut BytesMut, options: &FrameOptions) -> Result<()> {
         let size = self.encoded_len();
-        if size > MAX_FRAME {
-            return Err(KvError::FrameError);
+        if size > options.max_frame_size {
+            return Err(KvError::FrameTooLarge(size, options.max_frame_size));
         }
 
-        buf.put_u32(size as _);
-        if size > COMPRESSION_LIMIT {
+        // buf 中可能已有尚未发送的帧，只能在末尾追加
+        let compression = options.compression(size);
+        if compression != Compression::None {
             let mut buf1 = Vec::with_capacity(size);
             self.encode(&mut buf1)?;
 
-            let payload = buf.split_off(LEN_LEN);
-            buf.clear();
-
-            let mut encoder = GzEncoder::new(payload.writer(), Compression::default());
-            encoder.write_all(&buf1[..])?;
-            let payload = encoder.finish()?.into_inner();
-
-            buf.put_u32((payload.len() | COMPRESSION_BIT) as _);
-            buf.unsplit(payload);
-        } else {
-            self.encode(buf)?;
+            let payload = compression.compress(&buf1, options.level)?;
+            // 压缩后没有变小时直接发送原始数据
+            if payload.len() < size {
+                buf.put_u32(encode_header(payload.len(), compression));
+                buf.put_slice(&payload);
+                return Ok(());
+            }
         }
 
+        buf.put_u32(size as _);
+        self.encode(buf)?;
         Ok(())
     }
 
     fn decode_frame(buf: &mut BytesMut) -> Result<Self> {
+        Self::decode_frame_with(buf, MAX_FRAME)
+    }
+
+    // 解压后的长度同样不能超过 max_frame_size
+    fn decode_frame_with(buf: &mut BytesMut, max_frame_size: usize) -> Result<Self> {
         let header = buf.get_u32();
         let (len, compression) = decode_header(header as _);
 
-        if compression {
-            let mut buf1 = Vec::with_capacity(len * 2);
-            let mut decoder = GzDecoder::new(&buf[..len]);
-            decoder.read_to_end(&mut buf1)?;
-
-            buf.advance(len);
-            
-            Ok(Self::decode(&buf1[..buf1.len()])?)
+        let res = if compression == Compression::None {
+            Self::decode(&buf[..len])?
         } else {
-            let res = Self::decode(&buf[..len])?;
-            buf.advance(len);
-            Ok(res)
-        }
+            let buf1 = compression.decompress(&buf[..len], max_frame_size)?;
+            Self::decode(&buf1[..])?
+        };
+        buf.advance(len);
+        Ok(res)
     }
 }
 
-fn decode_header(header: usize) -> (usize, bool) {
-    let is_compression = header | COMPRESSION_BIT == header;
-    let len = header & (COMPRESSION_BIT - 1);
-    (len, is_compression)
+fn encode_header(len: usize, compression: Compression) -> u32 {
+    ((compression.bits() as usize) << CODEC_SHIFT | len) as _
 }
 
-impl FrameCoder for CommandRequest {}
-impl FrameCoder for CommandResponse {}
+fn decode_header(header: usize) -> (usize, Compression) {
+    let compression = Compression::from_bits((header >> CODEC_SHIFT) as _);
+    (header & LEN_MASK, compression)
+}
 
-pub async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<()> 
-where
-    S: AsyncRead + Unpin + Send
-{
-    // 上游会使用 while let Ok(x) = s.next() 持续拉取，当 stream 中没有内容时返回错误，终止循环
-    let header = stream.read_u32().await.map_err(|_| KvError::FrameError)?;
-    let (len, _compression) = decode_header(header as _);
+// buf 中已有完整的帧时返回整帧长度（含头部），否则返回 None
+// 只根据头部判断长度是否超过限制，不会为超长的帧分配内存
+pub fn frame_len(buf: &[u8], max_frame_size: usize) -> Result<Option<usize>> {
+    if buf.len() < LEN_LEN {
+        return Ok(None);
+    }
 
-    buf.reserve(len + LEN_LEN);
-    buf.put_u32(header);
+    let header = u32::from_be_bytes(buf[..LEN_LEN].try_into().unwrap());
+    let (len, _) = decode_header(header as _);
+    if len > max_frame_size {
+        return Err(KvError::FrameTooLarge(len, max_frame_size));
+    }
 
-    unsafe { buf.advance_mut(len) }
-    stream.read_exact(&mut buf[LEN_LEN..]).await?;
-    Ok(())
+    if buf.len() < len + LEN_LEN {
+        Ok(None)
+    } else {
+        Ok(Some(len + LEN_LEN))
+    }
 }
 
+impl FrameCoder for CommandRequest {}
+impl FrameCoder for CommandResponse {}
+impl FrameCoder for Handshake {}
+
 
 #[cfg(test)]
 mod tests {
@@ -96,11 +107,24 @@ mod tests {
 
     use bytes::{BytesMut};
     use prost::Message;
-    use tokio::io::{AsyncRead, ReadBuf};
+    use tokio::io::{AsyncRead, AsyncReadExt, ReadBuf};
 
-    use crate::{pb::{CommandRequest, CommandResponse}, Value};
+    use crate::{pb::{CommandRequest, CommandResponse}, Value, Result, KvError};
 
-    use super::{FrameCoder, read_frame};
+    use super::{FrameCoder, frame_len, decode_header, MAX_FRAME};
+    use crate::network::compression::{Compression, FrameOptions};
+
+    async fn read_frame<S>(stream: &mut S, buf: &mut BytesMut) -> Result<()>
+    where
+        S: AsyncRead + Unpin + Send
+    {
+        while frame_len(buf, MAX_FRAME)?.is_none() {
+            if stream.read_buf(buf).await? == 0 {
+                return Err(KvError::FrameError);
+            }
+        }
+        Ok(())
+    }
 
 
     #[test]
@@ -139,6 +163,33 @@ mod tests {
         assert_eq!(res.unwrap(), cmd);
     }
 
+    #[test]
+    fn frame_codecs_should_work() {
+        let mut cmd = CommandResponse::ok();
+        cmd.values = vec!["hello world".into(); 1000];
+
+        for codec in [Compression::None, Compression::Gzip, Compression::Zstd, Compression::Lz4] {
+            let options = FrameOptions { codecs: vec![codec], threshold: 100, level: Some(1), ..Default::default() };
+            let mut buf = BytesMut::new();
+            cmd.encode_frame_with(&mut buf, &options).unwrap();
+            CommandResponse::ok().encode_frame_with(&mut buf, &options).unwrap();
+
+            // 头部记录压缩算法，低于阈值的帧不压缩
+            let (len, compression) = decode_header(u32::from_be_bytes(buf[..4].try_into().unwrap()) as _);
+            assert_eq!(compression, codec);
+            assert_eq!(CommandResponse::decode_frame(&mut buf).unwrap(), cmd);
+            let (_, compression) = decode_header(u32::from_be_bytes(buf[..4].try_into().unwrap()) as _);
+            assert_eq!(compression, Compression::None);
+            assert_eq!(CommandResponse::decode_frame(&mut buf).unwrap(), CommandResponse::ok());
+            assert!(len < cmd.encoded_len() || codec == Compression::None);
+        }
+    }
+
+    #[test]
+    fn legacy_gzip_header_should_work() {
+        assert_eq!(decode_header((1 << 31) | 100), (100, Compression::Gzip));
+    }
+
     #[derive(Debug)]
     struct DummyStream {
         stream: BytesMut,
@@ -150,7 +201,7 @@ mod tests {
             _cx: &mut Context<'_>,
             buf: &mut ReadBuf<'_>,
         ) -> Poll<std::io::Result<()>> {
-            let len = buf.capacity();
+            let len = buf.remaining().min(self.stream.len());
 
             let data = self.get_mut().stream.split_to(len);
             buf.put_slice(&data);
@@ -189,4 +240,38 @@ mod tests {
         assert!(res.is_ok());
         assert_eq!(res.unwrap(), cmd);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn frame_len_should_work() {
+        let cmd = CommandRequest::new_hget("t1", "k1");
+        let mut buf = BytesMut::new();
+        cmd.encode_frame(&mut buf).unwrap();
+        let len = buf.len();
+
+        assert_eq!(frame_len(&buf[..3], MAX_FRAME).unwrap(), None);
+        assert_eq!(frame_len(&buf[..len - 1], MAX_FRAME).unwrap(), None);
+        assert_eq!(frame_len(&buf, MAX_FRAME).unwrap(), Some(len));
+
+        cmd.encode_frame(&mut buf).unwrap();
+        assert_eq!(frame_len(&buf, MAX_FRAME).unwrap(), Some(len));
+
+        assert!(frame_len(&[0x7f, 0xff, 0xff, 0xff], MAX_FRAME).is_err());
+        assert!(matches!(frame_len(&buf, len - 5), Err(KvError::FrameTooLarge(_, _))));
+    }
+
+    #[test]
+    fn max_frame_size_should_work() {
+        let mut cmd = CommandResponse::ok();
+        cmd.values = vec![vec![0u8; 1000].into(); 10];
+        let options = FrameOptions { max_frame_size: 1000, ..Default::default() };
+
+        let mut buf = BytesMut::new();
+        assert!(matches!(cmd.encode_frame_with(&mut buf, &options), Err(KvError::FrameTooLarge(_, 1000))));
+        assert!(buf.is_empty());
+
+        // 压缩后的帧很小，但解压后超过限制
+        cmd.encode_frame(&mut buf).unwrap();
+        assert!(buf.len() < 1000);
+        assert!(matches!(CommandResponse::decode_frame_with(&mut buf, 1000), Err(KvError::FrameTooLarge(_, 1000))));
+    }
+}
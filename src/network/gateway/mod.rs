@@ -0,0 +1,320 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{extract::{Path, Query, State}, response::{IntoResponse, Response}, routing::{get, post}, Json, Router};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use futures::StreamExt;
+use http::{header::AUTHORIZATION, HeaderMap, StatusCode};
+use serde::Deserialize;
+use serde_json::{json, Value as JsonValue};
+
+use self::subscribe::subscribe;
+use crate::{hash_token, pb::{value, CommandRequest, CommandResponse, Value}, service::{Service, Session}, storage::Storage};
+
+mod subscribe;
+
+// HTTP/JSON 网关，把 REST 请求转换成 CommandRequest 交给同一个 Service
+// 每个请求使用独立的 Session，认证信息放在 Authorization 头中（Bearer token 或 Basic 用户名密码）
+pub struct Gateway<Store> {
+    service: Service<Store>,
+    session: Session,
+    // 连接上最近一次认证通过的 Authorization 头（sha256）和对应的用户，相同的头不再重复校验密码
+    verified: Arc<Mutex<Option<(String, String)>>>,
+}
+
+impl<Store> Clone for Gateway<Store> {
+    fn clone(&self) -> Self {
+        Self {
+            service: self.service.clone(),
+            session: self.session.clone(),
+            verified: self.verified.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct TableQuery {
+    #[serde(default)]
+    filter: String,
+}
+
+impl<Store: Storage> Gateway<Store> {
+    // session 为连接的上下文，请求的认证只对该请求有效
+    pub fn router(service: Service<Store>, session: Session) -> Router {
+        Router::new()
+            .route("/tables/:table", get(get_table::<Store>))
+            .route("/tables/:table/keys/:key", get(get_key::<Store>).put(put_key::<Store>).delete(delete_key::<Store>))
+            .route("/publish/:topic", post(publish::<Store>))
+            .route("/subscribe/:topic", get(subscribe::<Store>))
+            .with_state(Self { service, session, verified: Default::default() })
+    }
+
+    // 非 200 的响应转换成对应状态码的错误
+    async fn execute(&self, headers: &HeaderMap, cmd: CommandRequest) -> Result<Arc<CommandResponse>, ApiError> {
+        let session = self.authenticate(headers).await?;
+        check(self.call(cmd, &session).await)
+    }
+
+    // 为请求创建新的 Session，带有 Authorization 头时先认证
+    async fn authenticate(&self, headers: &HeaderMap) -> Result<Session, ApiError> {
+        let session = self.session.fork();
+        if let Some(auth) = headers.get(AUTHORIZATION) {
+            let Some(auth) = auth.to_str().ok() else {
+                return Err(ApiError::bad_request("invalid authorization header"));
+            };
+            let digest = hash_token(auth);
+            if let Some((_, user)) = self.verified.lock().unwrap().as_ref().filter(|x| x.0 == digest) {
+                session.set_user(user);
+                return Ok(session);
+            }
+
+            let Some((username, password)) = parse_authorization(auth) else {
+                return Err(ApiError::bad_request("invalid authorization header"));
+            };
+            check(self.call(CommandRequest::new_auth(username, password), &session).await)?;
+            if let Some(user) = session.user() {
+                *self.verified.lock().unwrap() = Some((digest, user));
+            }
+        }
+        Ok(session)
+    }
+
+    async fn call(&self, cmd: CommandRequest, session: &Session) -> Arc<CommandResponse> {
+        let res = self.service.execute_with_session(cmd, session).next().await;
+        res.unwrap_or_else(|| Arc::new(CommandResponse { state_code: 500, msg: "no response".into(), ..Default::default() }))
+    }
+}
+
+type JsonResult = Result<Json<JsonValue>, ApiError>;
+
+async fn get_key<Store: Storage>(State(gw): State<Gateway<Store>>, headers: HeaderMap, Path((table, key)): Path<(String, String)>) -> JsonResult {
+    let res = gw.execute(&headers, CommandRequest::new_hget(table, key)).await?;
+    Ok(Json(to_json(res.values.first())))
+}
+
+// 返回被覆盖的旧值，没有时为 null
+async fn put_key<Store: Storage>(
+    State(gw): State<Gateway<Store>>,
+    headers: HeaderMap,
+    Path((table, key)): Path<(String, String)>,
+    Json(body): Json<JsonValue>,
+) -> JsonResult {
+    let value = from_json(body).map_err(ApiError::bad_request)?;
+    let res = gw.execute(&headers, CommandRequest::new_hset(table, key, value)).await?;
+    Ok(Json(to_json(res.values.first())))
+}
+
+// 返回被删除的值，key 不存在时返回 404
+async fn delete_key<Store: Storage>(State(gw): State<Gateway<Store>>, headers: HeaderMap, Path((table, key)): Path<(String, String)>) -> JsonResult {
+    let not_found = format!("Not found for table: {}, key: {}", table, key);
+    let res = gw.execute(&headers, CommandRequest::new_hdelete(table, key)).await?;
+    match to_json(res.values.first()) {
+        JsonValue::Null => Err(ApiError(StatusCode::NOT_FOUND, not_found)),
+        value => Ok(Json(value)),
+    }
+}
+
+// 返回 key 到 value 的 JSON 对象，filter 参数与 getall 的过滤表达式相同
+async fn get_table<Store: Storage>(
+    State(gw): State<Gateway<Store>>,
+    headers: HeaderMap,
+    Path(table): Path<String>,
+    Query(query): Query<TableQuery>,
+) -> JsonResult {
+    let res = gw.execute(&headers, CommandRequest::new_hget_all_with_filter(table, query.filter)).await?;
+    let pairs = res.pairs.iter().map(|x| (x.key.clone(), to_json(x.value.as_ref())));
+    Ok(Json(pairs.collect::<serde_json::Map<_, _>>().into()))
+}
+
+// body 为数组时每个元素作为一个 value 发布
+async fn publish<Store: Storage>(State(gw): State<Gateway<Store>>, headers: HeaderMap, Path(topic): Path<String>, Json(body): Json<JsonValue>) -> JsonResult {
+    let values = match body {
+        JsonValue::Array(values) => values.into_iter().map(from_json).collect(),
+        body => from_json(body).map(|x| vec![x]),
+    };
+    let values = values.map_err(ApiError::bad_request)?;
+    let res = gw.execute(&headers, CommandRequest::publish(topic, values)).await?;
+    Ok(Json(json!({ "msg": res.msg })))
+}
+
+// 出错时返回 {"error": msg}
+struct ApiError(StatusCode, String);
+
+impl ApiError {
+    fn bad_request(msg: impl ToString) -> Self {
+        Self(StatusCode::BAD_REQUEST, msg.to_string())
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        (self.0, Json(json!({ "error": self.1 }))).into_response()
+    }
+}
+
+// state_code 作为 HTTP 状态码
+impl From<&CommandResponse> for ApiError {
+    fn from(res: &CommandResponse) -> Self {
+        let status = StatusCode::from_u16(res.state_code as _).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        Self(status, res.msg.clone())
+    }
+}
+
+fn check(res: Arc<CommandResponse>) -> Result<Arc<CommandResponse>, ApiError> {
+    match res.state_code {
+        200 => Ok(res),
+        _ => Err(ApiError::from(&*res)),
+    }
+}
+
+// Bearer 为 token，Basic 为 base64 编码的 用户名:密码
+pub(super) fn parse_authorization(auth: &str) -> Option<(String, String)> {
+    let (scheme, credentials) = auth.split_once(' ')?;
+    match scheme.to_ascii_lowercase().as_str() {
+        "bearer" => Some((String::new(), credentials.trim().to_string())),
+        "basic" => {
+            let decoded = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+            let (username, password) = decoded.split_once(':')?;
+            Some((username.to_string(), password.to_string()))
+        },
+        _ => None,
+    }
+}
+
+// 字符串、数字和布尔值使用对应的 JSON 类型，json 文档原样嵌入，binary 为 {"$binary": base64}
+pub fn to_json(value: Option<&Value>) -> JsonValue {
+    match value.and_then(|x| x.value.as_ref()) {
+        None => JsonValue::Null,
+        Some(value::Value::String(s)) => s.clone().into(),
+        Some(value::Value::Binary(data)) => json!({ "$binary": STANDARD.encode(data) }),
+        Some(value::Value::Integer(n)) => (*n).into(),
+        Some(value::Value::Float(x)) => (*x).into(),
+        Some(value::Value::Bool(b)) => (*b).into(),
+        Some(value::Value::Json(s)) => serde_json::from_str(s).unwrap_or_else(|_| s.clone().into()),
+    }
+}
+
+// 整数保存为 integer，其他数字为 float，对象和数组保存为 json 文档
+pub fn from_json(value: JsonValue) -> Result<Value, String> {
+    match value {
+        JsonValue::Null => Err("value can't be null".into()),
+        JsonValue::Bool(b) => Ok(b.into()),
+        JsonValue::Number(n) => match n.as_i64() {
+            Some(n) => Ok(n.into()),
+            None => Ok(n.as_f64().unwrap_or_default().into()),
+        },
+        JsonValue::String(s) => Ok(s.into()),
+        JsonValue::Object(x) if x.len() == 1 && x.contains_key("$binary") => match &x["$binary"] {
+            JsonValue::String(s) => STANDARD.decode(s).map(|x| x.into()).map_err(|e| format!("invalid base64: {}", e)),
+            _ => Err("$binary must be a base64 string".into()),
+        },
+        value => Ok(Value { value: Some(value::Value::Json(value.to_string())) }),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use axum::{body::Body, Router};
+    use http::{header::{AUTHORIZATION, CONTENT_TYPE}, Request, StatusCode};
+    use serde_json::{json, Value as JsonValue};
+    use tower::ServiceExt;
+
+    use crate::{pb::command_request::RequestData, Acl, Authenticator, CommandRequest, MemoryDb, Permission, ServiceInner, Session, Value};
+
+    use super::{from_json, to_json, Gateway};
+
+    async fn call(router: &Router, method: &str, uri: &str, body: Option<JsonValue>, auth: Option<&str>) -> (StatusCode, JsonValue) {
+        let mut req = Request::builder().method(method).uri(uri).header(CONTENT_TYPE, "application/json");
+        if let Some(auth) = auth {
+            req = req.header(AUTHORIZATION, auth);
+        }
+        let body = body.map_or_else(Body::empty, |x| Body::from(x.to_string()));
+        let res = router.clone().oneshot(req.body(body).unwrap()).await.unwrap();
+        let status = res.status();
+        let body = hyper::body::to_bytes(res.into_body()).await.unwrap();
+        (status, serde_json::from_slice(&body).unwrap_or(JsonValue::Null))
+    }
+
+    #[test]
+    fn json_conversion_should_work() {
+        let values: Vec<Value> = vec!["hello".into(), 42.into(), 1.5.into(), true.into(), vec![0u8, 255].into()];
+        for value in values {
+            assert_eq!(from_json(to_json(Some(&value))).unwrap(), value);
+        }
+        assert_eq!(to_json(Some(&vec![0u8, 255].into())), json!({ "$binary": "AP8=" }));
+
+        let doc = from_json(json!({ "a": [1, 2] })).unwrap();
+        assert_eq!(to_json(Some(&doc)), json!({ "a": [1, 2] }));
+        assert!(from_json(JsonValue::Null).is_err());
+        assert!(from_json(json!({ "$binary": "!!" })).is_err());
+    }
+
+    #[tokio::test]
+    async fn gateway_should_work() {
+        let router = Gateway::router(ServiceInner::new(MemoryDb::new()).service(), Session::new("test", None));
+
+        assert_eq!(call(&router, "PUT", "/tables/t1/keys/k1", Some(json!("v1")), None).await, (StatusCode::OK, JsonValue::Null));
+        assert_eq!(call(&router, "PUT", "/tables/t1/keys/k1", Some(json!({ "n": 1 })), None).await, (StatusCode::OK, json!("v1")));
+        assert_eq!(call(&router, "PUT", "/tables/t1/keys/k2", Some(json!(42)), None).await.0, StatusCode::OK);
+        assert_eq!(call(&router, "GET", "/tables/t1/keys/k1", None, None).await, (StatusCode::OK, json!({ "n": 1 })));
+        assert_eq!(call(&router, "GET", "/tables/t1", None, None).await, (StatusCode::OK, json!({ "k1": { "n": 1 }, "k2": 42 })));
+        assert_eq!(call(&router, "GET", "/tables/t1?filter=key%20%3D%3D%20%22k2%22", None, None).await, (StatusCode::OK, json!({ "k2": 42 })));
+
+        assert_eq!(call(&router, "DELETE", "/tables/t1/keys/k2", None, None).await, (StatusCode::OK, json!(42)));
+        let (status, body) = call(&router, "DELETE", "/tables/t1/keys/k2", None, None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body["error"].is_string());
+        assert_eq!(call(&router, "GET", "/tables/t1/keys/k2", None, None).await.0, StatusCode::NOT_FOUND);
+
+        assert_eq!(call(&router, "POST", "/publish/lobby", Some(json!(["hello", 1])), None).await.0, StatusCode::OK);
+        assert_eq!(call(&router, "PUT", "/tables/t1/keys/k3", Some(JsonValue::Null), None).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(call(&router, "GET", "/tables/t1?filter=%3D%3D", None, None).await.0, StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn gateway_auth_should_work() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .with_auth(Authenticator::new().token("ci", crate::hash_token("t0ken")))
+            .with_acl(Acl::default().rule("ci", &["t1"], &[], &[Permission::Read, Permission::Write]))
+            .service();
+        let router = Gateway::router(service, Session::new("test", None));
+
+        assert_eq!(call(&router, "GET", "/tables/t1/keys/k1", None, None).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&router, "GET", "/tables/t1/keys/k1", None, Some("Bearer wrong")).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(call(&router, "GET", "/tables/t1/keys/k1", None, Some("Digest x")).await.0, StatusCode::BAD_REQUEST);
+        assert_eq!(call(&router, "GET", "/tables/t1/keys/k1", None, Some("Bearer t0ken")).await.0, StatusCode::NOT_FOUND);
+        assert_eq!(call(&router, "GET", "/tables/t2/keys/k1", None, Some("Bearer t0ken")).await.0, StatusCode::FORBIDDEN);
+
+        // 认证只对当前请求有效
+        assert_eq!(call(&router, "GET", "/tables/t1/keys/k1", None, None).await.0, StatusCode::UNAUTHORIZED);
+    }
+
+    static AUTH_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+    fn count_auth(session: &Session, cmd: &CommandRequest) {
+        if session.peer == "gateway-cache" && matches!(cmd.request_data, Some(RequestData::Auth(_))) {
+            AUTH_COUNT.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[tokio::test]
+    async fn gateway_should_cache_verified_credentials() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .with_auth(Authenticator::new().token("ci", crate::hash_token("t0ken")))
+            .fn_session_received(count_auth)
+            .service();
+        let router = Gateway::router(service, Session::new("gateway-cache", None));
+
+        for _ in 0..3 {
+            assert_eq!(call(&router, "GET", "/tables/t1/keys/k1", None, Some("Bearer t0ken")).await.0, StatusCode::NOT_FOUND);
+        }
+        assert_eq!(AUTH_COUNT.load(Ordering::Relaxed), 1);
+
+        // 不同的凭据仍然需要认证
+        assert_eq!(call(&router, "GET", "/tables/t1/keys/k1", None, Some("Bearer wrong")).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(AUTH_COUNT.load(Ordering::Relaxed), 2);
+        assert_eq!(call(&router, "GET", "/tables/t1/keys/k1", None, None).await.0, StatusCode::UNAUTHORIZED);
+    }
+}
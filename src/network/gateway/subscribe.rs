@@ -0,0 +1,170 @@
+use std::convert::Infallible;
+
+use axum::{extract::{ws::{Message, WebSocket, WebSocketUpgrade}, Path, Query, State}, response::{sse::{Event, KeepAlive, Sse}, IntoResponse, Response}};
+use futures::{stream, Stream, StreamExt};
+use http::{header::AUTHORIZATION, HeaderMap, HeaderValue};
+use serde::Deserialize;
+use serde_json::Value as JsonValue;
+
+use crate::{network::subscription, storage::Storage};
+
+use super::{to_json, ApiError, Gateway};
+
+// 响应头中带回订阅 id
+const SUBSCRIPTION_ID: &str = "x-subscription-id";
+
+#[derive(Debug, Default, Deserialize)]
+pub(super) struct SubscribeQuery {
+    // 浏览器的 EventSource 和 WebSocket 不能设置请求头，token 可以放在参数中
+    access_token: Option<String>,
+}
+
+// 订阅 topic，请求带有 Upgrade 头时使用 WebSocket，否则使用 Server-Sent Events
+// 每个 value 作为一条消息，内容为 value 的 JSON 编码
+pub(super) async fn subscribe<Store: Storage>(
+    State(gw): State<Gateway<Store>>,
+    mut headers: HeaderMap,
+    Path(topic): Path<String>,
+    Query(query): Query<SubscribeQuery>,
+    ws: Option<WebSocketUpgrade>,
+) -> Result<Response, ApiError> {
+    if let Some(token) = query.access_token.filter(|_| !headers.contains_key(AUTHORIZATION)) {
+        let auth = HeaderValue::from_str(&format!("Bearer {}", token)).map_err(ApiError::bad_request)?;
+        headers.insert(AUTHORIZATION, auth);
+    }
+    let session = gw.authenticate(&headers).await?;
+
+    // 先完成订阅再升级连接，没有权限时返回对应的状态码
+    let (id, stream) = subscription::subscribe(&gw.service, &session, &topic).await.map_err(|res| ApiError::from(&*res))?;
+    let messages = stream.flat_map(|res| stream::iter(res.values.iter().map(|x| to_json(Some(x))).collect::<Vec<_>>()));
+
+    let mut res = match ws {
+        Some(ws) => ws.on_upgrade(move |socket| forward(socket, messages)).into_response(),
+        None => {
+            let events = messages.map(|x| Ok::<_, Infallible>(Event::default().data(x.to_string())));
+            Sse::new(events).keep_alive(KeepAlive::default()).into_response()
+        },
+    };
+    res.headers_mut().insert(SUBSCRIPTION_ID, id.into());
+    Ok(res)
+}
+
+// 客户端发送的消息被忽略，客户端关闭连接时结束；订阅结束时发送 Close
+async fn forward(mut socket: WebSocket, messages: impl Stream<Item = JsonValue> + Send) {
+    let mut messages = std::pin::pin!(messages);
+    loop {
+        tokio::select! {
+            msg = messages.next() => match msg {
+                Some(msg) => if socket.send(Message::Text(msg.to_string())).await.is_err() {
+                    break;
+                },
+                None => {
+                    let _ = socket.close().await;
+                    break;
+                },
+            },
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {},
+            },
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use axum::body::{Body, HttpBody};
+    use futures::{SinkExt, StreamExt};
+    use http::{Request, StatusCode};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+    use tower::ServiceExt;
+
+    use crate::{Acl, Authenticator, CommandRequest, MemoryDb, Permission, Service, ServiceInner, Session};
+
+    use super::{Gateway, SUBSCRIPTION_ID};
+
+    async fn publish(service: &Service, topic: &str, value: &str) {
+        service.execute(CommandRequest::publish(topic, vec![value.into()])).next().await.unwrap();
+    }
+
+    // 订阅已经被取消时再次取消返回 404
+    async fn assert_unsubscribed(service: &Service, topic: &str, id: u32) {
+        for _ in 0..100 {
+            let res = service.execute(CommandRequest::unsubscribe(topic, id)).next().await.unwrap();
+            if res.state_code == 404 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("subscription {} is still alive", id);
+    }
+
+    fn subscription_id<B>(res: &http::Response<B>) -> u32 {
+        res.headers()[SUBSCRIPTION_ID].to_str().unwrap().parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn sse_subscribe_should_work() {
+        let service: Service = ServiceInner::new(MemoryDb::new()).service();
+        let router = Gateway::router(service.clone(), Session::new("test", None));
+
+        let req = Request::get("/subscribe/lobby").body(Body::empty()).unwrap();
+        let res = router.oneshot(req).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(res.headers()["content-type"], "text/event-stream");
+        let id = subscription_id(&res);
+
+        let mut body = res.into_body();
+        publish(&service, "lobby", "hello").await;
+        let data = body.data().await.unwrap().unwrap();
+        assert_eq!(&data[..], b"data:\"hello\"\n\n");
+
+        // 客户端断开后取消订阅
+        drop(body);
+        assert_unsubscribed(&service, "lobby", id).await;
+    }
+
+    #[tokio::test]
+    async fn sse_subscribe_should_check_acl() {
+        let service: Service = ServiceInner::new(MemoryDb::new())
+            .with_auth(Authenticator::new().token("ci", crate::hash_token("t0ken")))
+            .with_acl(Acl::default().rule("ci", &[], &["lobby"], &[Permission::Subscribe]))
+            .service();
+        let router = Gateway::router(service, Session::new("test", None));
+
+        let cases = [
+            ("/subscribe/lobby", StatusCode::UNAUTHORIZED),
+            ("/subscribe/lobby?access_token=wrong", StatusCode::UNAUTHORIZED),
+            ("/subscribe/private?access_token=t0ken", StatusCode::FORBIDDEN),
+            ("/subscribe/lobby?access_token=t0ken", StatusCode::OK),
+        ];
+        for (uri, status) in cases {
+            let res = router.clone().oneshot(Request::get(uri).body(Body::empty()).unwrap()).await.unwrap();
+            assert_eq!(res.status(), status, "{}", uri);
+        }
+    }
+
+    #[tokio::test]
+    async fn websocket_subscribe_should_work() {
+        let service: Service = ServiceInner::new(MemoryDb::new()).service();
+        let router = Gateway::router(service.clone(), Session::new("test", None));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            hyper::server::conn::Http::new().serve_connection(stream, router).with_upgrades().await.unwrap();
+        });
+
+        let (mut ws, res) = tokio_tungstenite::connect_async(format!("ws://{}/subscribe/lobby", addr)).await.unwrap();
+        let id = subscription_id(&res);
+        publish(&service, "lobby", "hello").await;
+        assert_eq!(ws.next().await.unwrap().unwrap(), Message::Text("\"hello\"".into()));
+
+        ws.send(Message::Close(None)).await.unwrap();
+        assert_unsubscribed(&service, "lobby", id).await;
+    }
+}
@@ -0,0 +1,230 @@
+use std::{pin::Pin, sync::Arc};
+
+use futures::{stream, Stream, StreamExt};
+use tonic::{metadata::MetadataMap, Code, Request, Response, Status};
+
+use crate::{
+    pb::{command_request::RequestData, kv_service_server::{KvService, KvServiceServer}, CommandRequest, CommandResponse, Hdelete, Hexists, Hget, Hgetall, Hmdelete, Hmexists, Hmget, Hmset, Hset, Publish, Subscribe},
+    service::{Service, Session},
+    storage::Storage,
+};
+
+use super::{gateway::parse_authorization, subscription};
+
+type GrpcResult<T> = Result<Response<T>, Status>;
+
+// gRPC 接口，与 HTTP 网关一样每个调用使用独立的 Session，认证信息放在 authorization metadata 中
+pub struct GrpcService<Store> {
+    service: Service<Store>,
+    session: Session,
+}
+
+impl<Store: Storage> GrpcService<Store> {
+    // session 为连接的上下文（对端地址和 mTLS 身份）
+    pub fn server(service: Service<Store>, session: Session) -> KvServiceServer<Self> {
+        KvServiceServer::new(Self { service, session })
+    }
+
+    async fn authenticate(&self, metadata: &MetadataMap) -> Result<Session, Status> {
+        let session = self.session.fork();
+        if let Some(auth) = metadata.get("authorization") {
+            let Some((username, password)) = auth.to_str().ok().and_then(parse_authorization) else {
+                return Err(Status::invalid_argument("invalid authorization metadata"));
+            };
+            self.call(CommandRequest::new_auth(username, password), &session).await?;
+        }
+        Ok(session)
+    }
+
+    async fn call(&self, cmd: CommandRequest, session: &Session) -> Result<CommandResponse, Status> {
+        match self.service.execute_with_session(cmd, session).next().await {
+            Some(res) if res.state_code == 200 => Ok(Arc::unwrap_or_clone(res)),
+            Some(res) => Err(to_status(&res)),
+            None => Err(Status::internal("no response")),
+        }
+    }
+
+    async fn unary<T>(&self, req: Request<T>, f: fn(T) -> RequestData) -> GrpcResult<CommandResponse> {
+        let session = self.authenticate(req.metadata()).await?;
+        let cmd = CommandRequest { request_data: Some(f(req.into_inner())), ..Default::default() };
+        self.call(cmd, &session).await.map(Response::new)
+    }
+}
+
+#[tonic::async_trait]
+impl<Store: Storage> KvService for GrpcService<Store> {
+    async fn hget(&self, req: Request<Hget>) -> GrpcResult<CommandResponse> {
+        self.unary(req, RequestData::Hget).await
+    }
+
+    async fn hmget(&self, req: Request<Hmget>) -> GrpcResult<CommandResponse> {
+        self.unary(req, RequestData::Hmget).await
+    }
+
+    async fn hset(&self, req: Request<Hset>) -> GrpcResult<CommandResponse> {
+        self.unary(req, RequestData::Hset).await
+    }
+
+    async fn hmset(&self, req: Request<Hmset>) -> GrpcResult<CommandResponse> {
+        self.unary(req, RequestData::Hmset).await
+    }
+
+    async fn hexists(&self, req: Request<Hexists>) -> GrpcResult<CommandResponse> {
+        self.unary(req, RequestData::Hexists).await
+    }
+
+    async fn hmexists(&self, req: Request<Hmexists>) -> GrpcResult<CommandResponse> {
+        self.unary(req, RequestData::Hmexists).await
+    }
+
+    async fn hdelete(&self, req: Request<Hdelete>) -> GrpcResult<CommandResponse> {
+        self.unary(req, RequestData::Hdelete).await
+    }
+
+    async fn hmdelete(&self, req: Request<Hmdelete>) -> GrpcResult<CommandResponse> {
+        self.unary(req, RequestData::Hmdelete).await
+    }
+
+    async fn hgetall(&self, req: Request<Hgetall>) -> GrpcResult<CommandResponse> {
+        self.unary(req, RequestData::Hgetall).await
+    }
+
+    async fn publish(&self, req: Request<Publish>) -> GrpcResult<CommandResponse> {
+        self.unary(req, RequestData::Publish).await
+    }
+
+    // 认证使用 metadata，订阅使用 Subscribe，分块传输依赖原生协议的 stream
+    async fn execute(&self, req: Request<CommandRequest>) -> GrpcResult<CommandResponse> {
+        let session = self.authenticate(req.metadata()).await?;
+        let cmd = req.into_inner();
+        match cmd.request_data {
+            Some(RequestData::Auth(_)) | Some(RequestData::Subscribe(_)) | Some(RequestData::HsetChunk(_)) | Some(RequestData::HgetChunked(_)) => {
+                Err(Status::invalid_argument("command is not supported by Execute"))
+            },
+            None => Err(Status::invalid_argument("missing request data")),
+            _ => self.call(cmd, &session).await.map(Response::new),
+        }
+    }
+
+    type SubscribeStream = Pin<Box<dyn Stream<Item = Result<CommandResponse, Status>> + Send>>;
+
+    async fn subscribe(&self, req: Request<Subscribe>) -> GrpcResult<Self::SubscribeStream> {
+        let session = self.authenticate(req.metadata()).await?;
+        let (id, messages) = subscription::subscribe(&self.service, &session, &req.get_ref().topic).await.map_err(|res| to_status(&res))?;
+        let first = stream::once(async move { Ok(CommandResponse::from(id as i64)) });
+        let messages = messages.map(Arc::unwrap_or_clone).map(Ok);
+        Ok(Response::new(Box::pin(first.chain(messages))))
+    }
+}
+
+// state_code 转换成 gRPC 状态码，message 为响应的 msg
+fn to_status(res: &CommandResponse) -> Status {
+    let code = match res.state_code {
+        400 => Code::InvalidArgument,
+        401 => Code::Unauthenticated,
+        403 => Code::PermissionDenied,
+        404 => Code::NotFound,
+        413 => Code::ResourceExhausted,
+        _ => Code::Internal,
+    };
+    Status::new(code, &res.msg)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tonic::{transport::Channel, Code, Request};
+
+    use crate::{kv_service_client::KvServiceClient, Acl, Authenticator, CommandRequest, Hget, Hmset, Hset, KvPair, Listener, MemoryDb, Permission, Publish, Service, ServiceInner, Subscribe, Value};
+
+    async fn start(service: Service) -> KvServiceClient<Channel> {
+        let listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(crate::serve_grpc_listener(listener, service, None));
+        KvServiceClient::connect(format!("http://{}", addr)).await.unwrap()
+    }
+
+    fn hset(key: &str, value: Value) -> Hset {
+        Hset { table: "t1".into(), pair: Some(KvPair::from((key.to_string(), value))) }
+    }
+
+    fn hget(key: &str) -> Hget {
+        Hget { table: "t1".into(), key: key.into(), ..Default::default() }
+    }
+
+    fn with_token<T>(msg: T, token: &str) -> Request<T> {
+        let mut req = Request::new(msg);
+        req.metadata_mut().insert("authorization", format!("Bearer {}", token).parse().unwrap());
+        req
+    }
+
+    #[tokio::test]
+    async fn grpc_unary_should_work() {
+        let mut client = start(ServiceInner::new(MemoryDb::new()).service()).await;
+
+        client.hset(hset("k1", "v1".into())).await.unwrap();
+        let res = client.hget(hget("k1")).await.unwrap().into_inner();
+        assert_eq!(res.values, vec!["v1".into()]);
+
+        let res = client.execute(CommandRequest::new_hget_all("t1")).await.unwrap().into_inner();
+        assert_eq!(res.pairs, vec![KvPair::from(("k1".to_string(), "v1".into()))]);
+
+        let status = client.hget(hget("k2")).await.unwrap_err();
+        assert_eq!(status.code(), Code::NotFound);
+        let status = client.execute(CommandRequest::subscribe("lobby")).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+
+        // 缺少 pair 或者 value 的请求返回错误，连接仍然可用
+        let status = client.hset(Hset { table: "t1".into(), pair: None }).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        let pairs = vec![KvPair::from(("k2".to_string(), "v2".into())), KvPair { key: "k3".into(), value: None }];
+        let status = client.hmset(Hmset { table: "t1".into(), pairs }).await.unwrap_err();
+        assert_eq!(status.code(), Code::InvalidArgument);
+        assert_eq!(client.hget(hget("k2")).await.unwrap_err().code(), Code::NotFound);
+    }
+
+    #[tokio::test]
+    async fn grpc_auth_should_work() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .with_auth(Authenticator::new().token("ci", crate::hash_token("t0ken")))
+            .with_acl(Acl::default().rule("ci", &["t1"], &[], &[Permission::Read, Permission::Write]))
+            .service();
+        let mut client = start(service).await;
+
+        assert_eq!(client.hget(hget("k1")).await.unwrap_err().code(), Code::Unauthenticated);
+        assert_eq!(client.hget(with_token(hget("k1"), "wrong")).await.unwrap_err().code(), Code::Unauthenticated);
+        client.hset(with_token(hset("k1", 1.into()), "t0ken")).await.unwrap();
+        let res = client.hget(with_token(hget("k1"), "t0ken")).await.unwrap().into_inner();
+        assert_eq!(res.values, vec![1.into()]);
+
+        let other = Hget { table: "t2".into(), key: "k1".into(), ..Default::default() };
+        assert_eq!(client.hget(with_token(other, "t0ken")).await.unwrap_err().code(), Code::PermissionDenied);
+    }
+
+    #[tokio::test]
+    async fn grpc_subscribe_should_work() {
+        let service: Service = ServiceInner::new(MemoryDb::new()).service();
+        let mut client = start(service.clone()).await;
+
+        let mut stream = client.subscribe(Subscribe { topic: "lobby".into() }).await.unwrap().into_inner();
+        let id = i64::try_from(&stream.next().await.unwrap().unwrap().values[0]).unwrap() as u32;
+
+        client.publish(Publish { topic: "lobby".into(), data: vec!["hello".into()] }).await.unwrap();
+        let res = stream.next().await.unwrap().unwrap();
+        assert_eq!(res.values, vec!["hello".into()]);
+
+        // 取消调用后服务端取消订阅，再次取消返回 404
+        drop(stream);
+        for _ in 0..100 {
+            let res = service.execute(CommandRequest::unsubscribe("lobby", id)).next().await.unwrap();
+            if res.state_code == 404 {
+                return;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        panic!("subscription {} is still alive", id);
+    }
+}
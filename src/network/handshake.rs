@@ -0,0 +1,181 @@
+use std::time::Duration;
+
+use bytes::{BufMut, BytesMut};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::{pb::Handshake, KvError, Result};
+
+use super::{compression::FrameOptions, frame::FrameCoder};
+
+// 修改帧格式时增加 PROTOCOL_VERSION，能兼容的最低版本为 MIN_PROTOCOL_VERSION
+pub const PROTOCOL_VERSION: u32 = 1;
+pub const MIN_PROTOCOL_VERSION: u32 = 1;
+
+// 握手消息很小，限制长度避免恶意的客户端让服务端分配大块内存
+const MAX_HANDSHAKE_LEN: usize = 4096;
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+impl Handshake {
+    pub fn new(options: &FrameOptions, auth_methods: Vec<String>) -> Self {
+        Self {
+            version: PROTOCOL_VERSION,
+            compressions: options.codecs.iter().map(|x| x.name().to_string()).collect(),
+            max_frame_size: options.max_frame_size as _,
+            auth_methods,
+            error: String::new(),
+        }
+    }
+
+    // 服务端根据客户端发来的 Handshake 生成协商结果，压缩算法按客户端的顺序取双方都支持的
+    pub fn negotiate(&self, client: &Handshake) -> Handshake {
+        if client.version < MIN_PROTOCOL_VERSION || client.version > self.version {
+            return Self {
+                version: self.version,
+                error: format!(
+                    "unsupported protocol version {}, server supports {}..={}",
+                    client.version, MIN_PROTOCOL_VERSION, self.version
+                ),
+                ..Default::default()
+            };
+        }
+
+        let max_frame_size = match client.max_frame_size {
+            0 => self.max_frame_size,
+            n => n.min(self.max_frame_size),
+        };
+        Self {
+            version: client.version,
+            compressions: client.compressions.iter().filter(|x| self.compressions.contains(x)).cloned().collect(),
+            max_frame_size,
+            auth_methods: self.auth_methods.clone(),
+            error: String::new(),
+        }
+    }
+}
+
+// 客户端发送自己的 Handshake，返回服务端的协商结果
+pub async fn client_handshake<S>(stream: &mut S, local: &Handshake) -> Result<Handshake>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    write_handshake(stream, local).await?;
+    let res = read_handshake(stream).await?;
+    if !res.error.is_empty() {
+        return Err(KvError::HandshakeError(res.error));
+    }
+    if res.version < MIN_PROTOCOL_VERSION || res.version > PROTOCOL_VERSION {
+        return Err(KvError::HandshakeError(format!("unsupported protocol version {}", res.version)));
+    }
+    Ok(res)
+}
+
+// 服务端读取客户端的 Handshake 并回复协商结果，不兼容时回复原因后返回错误
+pub async fn server_handshake<S>(stream: &mut S, local: &Handshake) -> Result<Handshake>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let client = tokio::time::timeout(HANDSHAKE_TIMEOUT, read_handshake(stream))
+        .await
+        .map_err(|_| KvError::HandshakeError("timeout".into()))??;
+    let res = local.negotiate(&client);
+    write_handshake(stream, &res).await?;
+    if !res.error.is_empty() {
+        return Err(KvError::HandshakeError(res.error));
+    }
+    Ok(res)
+}
+
+async fn write_handshake<S: AsyncWrite + Unpin>(stream: &mut S, handshake: &Handshake) -> Result<()> {
+    let mut buf = BytesMut::new();
+    handshake.encode_frame(&mut buf)?;
+    stream.write_all(&buf).await?;
+    stream.flush().await?;
+    Ok(())
+}
+
+// 只读取握手帧本身，之后的数据留给 yamux
+async fn read_handshake<S: AsyncRead + Unpin>(stream: &mut S) -> Result<Handshake> {
+    let header = stream.read_u32().await?;
+    let len = header as usize;
+    if len > MAX_HANDSHAKE_LEN {
+        return Err(KvError::HandshakeError("invalid handshake frame".into()));
+    }
+
+    let mut buf = BytesMut::with_capacity(len + 4);
+    buf.put_u32(header);
+    buf.resize(len + 4, 0);
+    stream.read_exact(&mut buf[4..]).await?;
+    Handshake::decode_frame(&mut buf)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+    use tokio::io::{duplex, AsyncWriteExt};
+
+    use crate::{network::frame::FrameCoder, pb::Handshake, KvError};
+
+    use crate::network::compression::FrameOptions;
+
+    use super::{client_handshake, server_handshake, PROTOCOL_VERSION};
+
+    #[test]
+    fn negotiate_should_work() {
+        let server = Handshake::new(&FrameOptions::default(), vec!["password".into()]);
+        let client = Handshake {
+            compressions: vec!["zstd".into(), "gzip".into()],
+            max_frame_size: 1024,
+            ..Handshake::new(&FrameOptions::default(), vec![])
+        };
+
+        let res = server.negotiate(&client);
+        assert!(res.error.is_empty());
+        assert_eq!(res.version, PROTOCOL_VERSION);
+        assert_eq!(res.compressions, vec!["gzip".to_string()]);
+        assert_eq!(res.max_frame_size, 1024);
+        assert_eq!(res.auth_methods, vec!["password".to_string()]);
+
+        let client = Handshake { version: PROTOCOL_VERSION + 1, ..Handshake::new(&FrameOptions::default(), vec![]) };
+        assert!(!server.negotiate(&client).error.is_empty());
+    }
+
+    #[tokio::test]
+    async fn handshake_should_work() {
+        let (mut client, mut server) = duplex(4096);
+        let local = Handshake::new(&FrameOptions::default(), vec!["token".into()]);
+        let task = tokio::spawn(async move {
+            server_handshake(&mut server, &local).await
+        });
+
+        let res = client_handshake(&mut client, &Handshake::new(&FrameOptions::default(), vec![])).await.unwrap();
+        assert_eq!(res.auth_methods, vec!["token".to_string()]);
+        assert_eq!(task.await.unwrap().unwrap(), res);
+    }
+
+    #[tokio::test]
+    async fn incompatible_client_should_be_rejected() {
+        let (mut client, mut server) = duplex(4096);
+        let task = tokio::spawn(async move {
+            server_handshake(&mut server, &Handshake::new(&FrameOptions::default(), vec![])).await
+        });
+
+        let local = Handshake { version: 0, ..Handshake::new(&FrameOptions::default(), vec![]) };
+        let res = client_handshake(&mut client, &local).await;
+        assert!(matches!(res, Err(KvError::HandshakeError(_))));
+        assert!(matches!(task.await.unwrap(), Err(KvError::HandshakeError(_))));
+    }
+
+    #[tokio::test]
+    async fn invalid_handshake_frame_should_fail() {
+        // 旧版本客户端直接发送 yamux 数据，或者超长的握手帧
+        let (mut client, mut server) = duplex(4096);
+        let mut buf = BytesMut::new();
+        Handshake::new(&FrameOptions::default(), vec![]).encode_frame(&mut buf).unwrap();
+        buf[0] = 0x7f;
+        client.write_all(&buf).await.unwrap();
+
+        let res = server_handshake(&mut server, &Handshake::new(&FrameOptions::default(), vec![])).await;
+        assert!(matches!(res, Err(KvError::HandshakeError(_))));
+    }
+}
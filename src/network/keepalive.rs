@@ -0,0 +1,149 @@
+use std::{sync::Arc, time::Duration};
+
+use quinn::{IdleTimeout, TransportConfig};
+use serde::Deserialize;
+use socket2::{SockRef, TcpKeepalive};
+use tokio::{net::TcpStream, sync::watch};
+
+use crate::{KvError, Result};
+
+// 连接保活和空闲超时的配置，单位为秒，没有配置的项不开启
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct KeepaliveOptions {
+    // tcp keepalive 探测以及 QUIC PING 的间隔
+    pub interval: Option<u64>,
+    // 对端超过这么久没有响应时认为连接已断开并关闭
+    pub timeout: Option<u64>,
+    // 连接上没有打开的 stream 超过这么久时关闭连接
+    pub idle_timeout: Option<u64>,
+}
+
+impl KeepaliveOptions {
+    pub fn idle_timeout(&self) -> Option<Duration> {
+        self.idle_timeout.map(Duration::from_secs)
+    }
+
+    // 连接空闲 interval 后开始探测，连续 timeout 没有响应（包括发送的数据没有被确认）时内核关闭连接
+    pub fn apply(&self, stream: &TcpStream) -> Result<()> {
+        let socket = SockRef::from(stream);
+        if let Some(interval) = self.interval.map(Duration::from_secs) {
+            let keepalive = TcpKeepalive::new().with_time(interval);
+            #[cfg(target_os = "linux")]
+            let keepalive = match self.timeout {
+                Some(timeout) => keepalive.with_interval(interval).with_retries((timeout / interval.as_secs().max(1)).max(1) as u32),
+                None => keepalive.with_interval(interval),
+            };
+            socket.set_tcp_keepalive(&keepalive)?;
+        }
+        #[cfg(target_os = "linux")]
+        if let Some(timeout) = self.timeout {
+            socket.set_tcp_user_timeout(Some(Duration::from_secs(timeout)))?;
+        }
+        Ok(())
+    }
+
+    // QUIC 自带 PING 和空闲超时，没有配置时使用 quinn 的默认值
+    pub fn quic_transport(&self) -> Result<Arc<TransportConfig>> {
+        let mut transport = TransportConfig::default();
+        if let Some(interval) = self.interval {
+            transport.keep_alive_interval(Some(Duration::from_secs(interval)));
+        }
+        if let Some(timeout) = self.timeout {
+            let timeout = IdleTimeout::try_from(Duration::from_secs(timeout)).map_err(|e| KvError::Invalid(e.to_string()))?;
+            transport.max_idle_timeout(Some(timeout));
+        }
+        Ok(Arc::new(transport))
+    }
+}
+
+// 记录连接上打开的 stream 数，用于判断连接是否空闲
+#[derive(Debug, Clone)]
+pub struct IdleTracker(Arc<watch::Sender<usize>>);
+
+// stream 处理结束时 drop
+pub struct StreamGuard(Arc<watch::Sender<usize>>);
+
+impl Default for IdleTracker {
+    fn default() -> Self {
+        Self(Arc::new(watch::channel(0).0))
+    }
+}
+
+impl IdleTracker {
+    pub fn enter(&self) -> StreamGuard {
+        self.0.send_modify(|n| *n += 1);
+        StreamGuard(self.0.clone())
+    }
+
+    // 连接上连续 timeout 没有打开的 stream 时返回
+    pub async fn wait_idle(&self, timeout: Duration) {
+        let mut rx = self.0.subscribe();
+        loop {
+            if rx.wait_for(|n| *n == 0).await.is_err() {
+                return;
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(timeout) => return,
+                _ = rx.changed() => {},
+            }
+        }
+    }
+}
+
+impl Drop for StreamGuard {
+    fn drop(&mut self) {
+        self.0.send_modify(|n| *n -= 1);
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use socket2::SockRef;
+    use tokio::net::{TcpListener, TcpStream};
+
+    use super::{IdleTracker, KeepaliveOptions};
+
+    #[tokio::test]
+    async fn apply_should_set_tcp_keepalive() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).await.unwrap();
+        let options = KeepaliveOptions { interval: Some(10), timeout: Some(30), idle_timeout: None };
+        options.apply(&stream).unwrap();
+
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        #[cfg(target_os = "linux")]
+        {
+            assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(10));
+            assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(10));
+            assert_eq!(socket.keepalive_retries().unwrap(), 3);
+            assert_eq!(socket.tcp_user_timeout().unwrap(), Some(Duration::from_secs(30)));
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_tracker_should_wait_without_streams() {
+        let tracker = IdleTracker::default();
+        let guard = tracker.enter();
+        let idle = tokio::spawn({
+            let tracker = tracker.clone();
+            async move { tracker.wait_idle(Duration::from_millis(200)).await }
+        });
+
+        // 有打开的 stream 时不会超时
+        tokio::time::sleep(Duration::from_millis(400)).await;
+        assert!(!idle.is_finished());
+
+        // 新的 stream 重新开始计时
+        drop(guard);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        drop(tracker.enter());
+        tokio::time::sleep(Duration::from_millis(150)).await;
+        assert!(!idle.is_finished());
+        tokio::time::timeout(Duration::from_millis(200), idle).await.unwrap().unwrap();
+    }
+}
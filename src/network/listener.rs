@@ -0,0 +1,227 @@
+use tokio::net::{TcpListener, TcpStream};
+#[cfg(unix)]
+use tokio::net::{UnixListener, UnixStream};
+
+use tracing::log::warn;
+
+use crate::{KvError, Result};
+
+use super::{AsyncStream, ClientStream, KeepaliveOptions};
+
+// unix domain socket 的地址以该前缀开头，例如 unix:/tmp/kvserver.sock
+const UNIX_PREFIX: &str = "unix:";
+
+pub type ServerStream = Box<dyn AsyncStream>;
+
+pub enum Listener {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener, String),
+}
+
+impl Listener {
+    // addr 为 ipv4、ipv6 地址（如 0.0.0.0:9909、[::]:9909）或者 unix:路径
+    pub async fn bind(addr: &str) -> Result<Self> {
+        #[cfg(unix)]
+        if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+            // 清理上次退出时残留的 socket 文件，其他类型的文件不处理
+            use std::os::unix::fs::FileTypeExt;
+            if std::fs::metadata(path).is_ok_and(|x| x.file_type().is_socket()) {
+                std::fs::remove_file(path)?;
+            }
+            return Ok(Self::Unix(UnixListener::bind(path)?, path.to_string()));
+        }
+
+        Ok(Self::Tcp(TcpListener::bind(addr).await?))
+    }
+
+    // 返回连接以及对端地址
+    pub async fn accept(&self) -> Result<(ServerStream, String)> {
+        self.accept_with(&KeepaliveOptions::default()).await
+    }
+
+    // tcp 连接开启 keepalive，unix domain socket 不需要
+    pub async fn accept_with(&self, keepalive: &KeepaliveOptions) -> Result<(ServerStream, String)> {
+        match self {
+            Self::Tcp(listener) => {
+                let (stream, peer) = listener.accept().await?;
+                if let Err(e) = keepalive.apply(&stream) {
+                    warn!("Failed to set keepalive for {}: {}", peer, e);
+                }
+                Ok((Box::new(stream), peer.to_string()))
+            },
+            #[cfg(unix)]
+            Self::Unix(listener, path) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), format!("{}{}", UNIX_PREFIX, path)))
+            },
+        }
+    }
+
+    // accept 的错误大多只影响单个连接（ECONNABORTED）或者是暂时的资源不足（EMFILE、ENFILE），
+    // 只有监听的 socket 本身失效时才需要停止 listener
+    pub fn is_fatal_error(e: &KvError) -> bool {
+        let KvError::IOError(e) = e else {
+            return false;
+        };
+        #[cfg(unix)]
+        if e.raw_os_error() == Some(9) {
+            // EBADF
+            return true;
+        }
+        e.kind() == std::io::ErrorKind::InvalidInput
+    }
+
+    pub fn local_addr(&self) -> Result<String> {
+        match self {
+            Self::Tcp(listener) => Ok(listener.local_addr()?.to_string()),
+            #[cfg(unix)]
+            Self::Unix(_, path) => Ok(format!("{}{}", UNIX_PREFIX, path)),
+        }
+    }
+}
+
+// 关闭时删除 unix domain socket 文件
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Self::Unix(_, path) = self {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+// 连接 tcp 地址或者 unix:路径
+pub async fn connect_stream(addr: &str) -> Result<ClientStream> {
+    #[cfg(unix)]
+    if let Some(path) = addr.strip_prefix(UNIX_PREFIX) {
+        return Ok(Box::new(UnixStream::connect(path).await?));
+    }
+
+    Ok(Box::new(TcpStream::connect(addr).await?))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+    use tempfile::tempdir;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use crate::{connect, connect_with_options, serve_listener, CommandRequest, Compression, FrameOptions, MemoryDb, ServiceInner};
+
+    use super::{connect_stream, Listener};
+
+    async fn echo(addr: &str) -> String {
+        let listener = Listener::bind(addr).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = [0u8; 5];
+            stream.read_exact(&mut buf).await.unwrap();
+            stream.write_all(&buf).await.unwrap();
+        });
+
+        let mut stream = connect_stream(&addr).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+        addr
+    }
+
+    #[tokio::test]
+    async fn listener_should_work() {
+        assert!(echo("127.0.0.1:0").await.starts_with("127.0.0.1:"));
+        assert!(echo("[::1]:0").await.starts_with("[::1]:"));
+
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kvserver.sock");
+        let addr = format!("unix:{}", path.display());
+        assert_eq!(echo(&addr).await, addr);
+        // 已存在的 socket 文件会被替换
+        assert_eq!(echo(&addr).await, addr);
+    }
+
+    #[test]
+    fn only_listener_errors_should_be_fatal() {
+        let err = |kind| crate::KvError::IOError(std::io::Error::from(kind));
+        assert!(!Listener::is_fatal_error(&err(std::io::ErrorKind::ConnectionAborted)));
+        // EMFILE
+        assert!(!Listener::is_fatal_error(&std::io::Error::from_raw_os_error(24).into()));
+        assert!(Listener::is_fatal_error(&err(std::io::ErrorKind::InvalidInput)));
+    }
+
+    #[tokio::test]
+    async fn multiple_listeners_should_share_service() {
+        let dir = tempdir().unwrap();
+        let unix = format!("unix:{}", dir.path().join("kvserver.sock").display());
+        let service = ServiceInner::new(MemoryDb::new()).service();
+
+        let mut addrs = vec![];
+        for addr in ["127.0.0.1:0", "[::1]:0", unix.as_str()] {
+            let listener = Listener::bind(addr).await.unwrap();
+            addrs.push(listener.local_addr().unwrap());
+            tokio::spawn(serve_listener(listener, service.clone(), None));
+        }
+
+        let mut ctrl = connect(&addrs[0], None).await.unwrap();
+        let mut stream = ctrl.open_stream().await.unwrap();
+        stream.execute_unary(&CommandRequest::new_hset("t1", "k1", "v1".into())).await.unwrap();
+
+        for addr in addrs {
+            let mut ctrl = connect(&addr, None).await.unwrap();
+            let mut stream = ctrl.open_stream().await.unwrap();
+            let res = stream.execute_unary(&CommandRequest::new_hget("t1", "k1")).await.unwrap();
+            assert_eq!(res.values, vec!["v1".into()]);
+        }
+    }
+
+    #[tokio::test]
+    async fn graceful_shutdown_should_work() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("kvserver.sock");
+        let addr = format!("unix:{}", path.display());
+        let service = ServiceInner::new(MemoryDb::new()).service();
+
+        let listener = Listener::bind(&addr).await.unwrap();
+        let server = tokio::spawn(serve_listener(listener, service.clone(), None));
+
+        let mut ctrl = connect(&addr, None).await.unwrap();
+        let stream = ctrl.open_stream().await.unwrap();
+        let mut sub = stream.execute_streaming(&CommandRequest::subscribe("lobby")).await.unwrap();
+
+        service.graceful_shutdown(Duration::from_secs(1)).await.unwrap();
+
+        // 订阅者收到退出消息，listener 停止并删除 socket 文件
+        let res = sub.next().await.unwrap().unwrap();
+        assert!(res.exit);
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+        assert!(connect(&addr, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn compression_should_be_negotiated() {
+        let options = FrameOptions { codecs: vec![Compression::Zstd, Compression::Gzip], ..Default::default() };
+        let service = ServiceInner::new(MemoryDb::new()).with_frame_options(options).service();
+        let listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener, service, None));
+
+        let value: crate::Value = "hello world ".repeat(1000).into();
+        let cases = [(vec![Compression::Lz4, Compression::Zstd], vec!["zstd"]), (vec![Compression::Lz4], vec![])];
+        for (codecs, expected) in cases {
+            let options = FrameOptions { codecs, ..Default::default() };
+            let mut ctrl = connect_with_options(&addr, None, options).await.unwrap();
+            assert_eq!(ctrl.handshake().compressions, expected);
+
+            let mut stream = ctrl.open_stream().await.unwrap();
+            stream.execute_unary(&CommandRequest::new_hset("t1", "k1", value.clone())).await.unwrap();
+            let res = stream.execute_unary(&CommandRequest::new_hget("t1", "k1")).await.unwrap();
+            assert_eq!(res.values, vec![value.clone()]);
+        }
+    }
+}
@@ -1,23 +1,62 @@
-use futures::{StreamExt, SinkExt};
-use tokio::io::{AsyncWrite, AsyncRead};
+use std::{any::Any, panic::{catch_unwind, AssertUnwindSafe}, sync::Arc};
+
+use futures::{future, StreamExt, SinkExt};
+use tokio::{io::{AsyncWrite, AsyncRead}, sync::{mpsc, Semaphore}};
+use tokio_util::sync::CancellationToken;
 use tracing::log::warn;
 
-use crate::{pb::{CommandResponse, CommandRequest}, service::Service, KvError, storage::Storage};
+use crate::{pb::{command_request::RequestData, CommandResponse, CommandRequest, HsetChunk, Value}, service::{chunk_size, split, ChunkBuffer, Service, Session, MAX_CHUNKED_VALUE}, KvError, storage::Storage};
 use crate::Result;
 
-use self::stream::ProstStream;
+use self::{pipeline::Scheduler, stream::ProstStream};
+
+// 同一个 stream 上同时处理的请求数上限
+const MAX_IN_FLIGHT: usize = 256;
 
+mod compression;
 mod frame;
+mod gateway;
+mod grpc;
+mod handshake;
+mod keepalive;
+mod listener;
 mod multiplex;
+mod pipeline;
+mod quic;
+mod resp;
 mod stream;
 mod stream_result;
+mod subscription;
+mod tls;
 
-pub use multiplex::YamuxCtrl;
+pub use compression::{Compression, FrameOptions};
+pub use frame::FrameCoder;
+pub use gateway::Gateway;
+pub use grpc::GrpcService;
+pub use handshake::{client_handshake, server_handshake};
+pub use keepalive::{IdleTracker, KeepaliveOptions};
+pub use listener::{connect_stream, Listener, ServerStream};
+pub use multiplex::{YamuxCtrl, YamuxOptions};
+pub use quic::{QuicConnection, QuicCtrl, QuicListener, QuicStream};
+pub use resp::RespServerStream;
 pub use stream_result::StreamResult;
+pub use tls::{TlsServerAcceptor, TlsClientConnector};
+
+// 客户端可能是 tcp 或者 tls 连接，统一成一个类型交给 YamuxCtrl
+pub trait AsyncStream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<S: AsyncRead + AsyncWrite + Unpin + Send> AsyncStream for S {}
+
+pub type ClientStream = Box<dyn AsyncStream>;
 
 pub struct ProstServerStream<S, Store> {
     stream: ProstStream<S, CommandRequest, CommandResponse>,
-    service: Service<Store>
+    service: Service<Store>,
+    session: Session,
+    // 正在进行的分块上传：table、key 以及已收到的数据
+    upload: Option<(String, String, ChunkBuffer)>,
+    // stream 所在的连接结束时取消
+    closed: CancellationToken,
 }
 
 impl<S, Store> ProstServerStream<S, Store> 
@@ -31,25 +70,253 @@ where
         Self {
             stream,
             service,
+            session: Session::default(),
+            upload: None,
+            closed: CancellationToken::new(),
         }
     }
 
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = session;
+        self
+    }
+
+    // 使用握手协商出的压缩配置发送响应
+    pub fn with_options(mut self, options: FrameOptions) -> Self {
+        self.stream = self.stream.with_options(options);
+        self
+    }
+
+    // 连接断开时取消这个 stream 上的订阅
+    pub fn with_closed(mut self, closed: CancellationToken) -> Self {
+        self.closed = closed;
+        self
+    }
+
+    // 返回时取消这个 stream 上建立的所有订阅，避免客户端断开后订阅一直留在服务端
+    // 客户端发送完请求后可以只关闭写端，所以读取结束不代表客户端已经断开
     pub async fn process(&mut self) -> Result<()> {
-        while let Some(Ok(cmd)) = self.stream.next().await {
-            let mut stream = self.service.execute(cmd);
-            while let Some(cmd) = stream.next().await {
-                // println!("{:?}", cmd);
-                if let Err(_) = self.stream.send(&cmd).await {
-                    warn!("Failed to send command response")
-                }
+        let owned = subscription::Owned::default();
+        let res = self.serve(&owned).await;
+        owned.close(&self.service, &self.session);
+        res
+    }
+
+    async fn serve(&mut self, owned: &subscription::Owned) -> Result<()> {
+        let shutdown = self.service.shutdown_handle();
+        let mut scheduler = Scheduler::default();
+        let permits = Arc::new(Semaphore::new(MAX_IN_FLIGHT));
+        // 并发执行的请求通过 channel 把响应交给当前任务发送，tx 为 None 时不再读取新的请求
+        let (tx, mut rx) = mpsc::channel(MAX_IN_FLIGHT);
+        let mut tx = Some(tx);
+        // 连接断开或者响应无法发送时客户端已经不在了，取消订阅让订阅的任务结束
+        let mut dead = false;
+        loop {
+            let cmd = tokio::select! {
+                res = rx.recv() => match res {
+                    Some(res) => {
+                        if !dead && !self.send(res).await {
+                            dead = true;
+                            tx = None;
+                            owned.close(&self.service, &self.session);
+                        }
+                        continue;
+                    },
+                    // 所有请求都已处理完
+                    None => break,
+                },
+                // 处理中的请求达到上限时暂停读取
+                cmd = self.stream.next(), if tx.is_some() && permits.available_permits() > 0 => cmd,
+                // 服务关闭后不再读取新的请求，处理中的请求继续完成
+                _ = shutdown.wait(), if tx.is_some() => {
+                    tx = None;
+                    continue;
+                },
+                _ = self.closed.cancelled(), if !dead => {
+                    dead = true;
+                    tx = None;
+                    owned.close(&self.service, &self.session);
+                    continue;
+                },
+            };
+            let cmd = match cmd {
+                Some(Ok(cmd)) => cmd,
+                // 超长的帧已被跳过，返回 413 后继续处理后续请求
+                Some(Err(e @ KvError::FrameTooLarge(_, _))) => {
+                    warn!("Oversize frame from {}: {}", self.session.peer, e);
+                    self.stream.send(&e.into()).await?;
+                    continue;
+                },
+                // 协议错误时返回错误帧并关闭当前 stream，不影响连接上的其他 stream
+                Some(Err(e)) => {
+                    warn!("Invalid frame from {}: {}", self.session.peer, e);
+                    self.stream.send(&e.into()).await?;
+                    self.stream.close().await?;
+                    break;
+                },
+                None => {
+                    tx = None;
+                    continue;
+                },
+            };
+
+            // Auth 失败次数达到上限后不再读取新的请求，处理中的请求完成后关闭 stream
+            let request_id = cmd.request_id;
+            if matches!(cmd.request_data, Some(RequestData::Auth(_))) && self.session.auth_locked() {
+                warn!("Too many failed authentication attempts from {}", self.session.peer);
+                let e = KvError::Unauthorized("too many failed authentication attempts".into());
+                self.stream.send(&CommandResponse { request_id, ..e.into() }).await?;
+                tx = None;
+                continue;
+            }
+
+            // 分块上传的块在这里组装，收齐后作为一个 Hset 交给 service
+            let uploaded = matches!(cmd.request_data, Some(RequestData::HsetChunk(_)));
+            let mut cmd = match self.assemble(cmd) {
+                Ok(Some(cmd)) => cmd,
+                Ok(None) => {
+                    self.stream.send(&CommandResponse { request_id, ..CommandResponse::ok() }).await?;
+                    continue;
+                },
+                Err(e) => {
+                    self.upload = None;
+                    self.stream.send(&CommandResponse { request_id, ..e.into() }).await?;
+                    continue;
+                },
+            };
+
+            // 分块下载的块不能超过这个连接上协商出的最大帧长度
+            if let Some(RequestData::HgetChunked(x)) = cmd.request_data.as_mut() {
+                x.chunk_size = chunk_size(x.chunk_size as _, self.stream.options().max_frame_size) as _;
             }
+
+            let mut ticket = scheduler.schedule(&cmd);
+            let permit = permits.clone().try_acquire_owned().expect("permit is available");
+            let guard = shutdown.enter();
+            let tx = tx.clone().expect("stream is still reading");
+            let service = self.service.clone();
+            let session = self.session.clone();
+            // 订阅在注册后就不再阻塞同一个 topic 上的后续请求
+            let mut topic = match &cmd.request_data {
+                Some(RequestData::Subscribe(x)) => Some(x.topic.clone()),
+                _ => None,
+            };
+            let owned = owned.clone();
+            tokio::spawn(async move {
+                let (_permit, _guard) = (permit, guard);
+                ticket.ready().await;
+
+                // handler panic 时返回 500，连接继续处理后续请求
+                let mut stream = match catch_unwind(AssertUnwindSafe(|| service.execute_with_session(cmd, &session))) {
+                    Ok(res) => AssertUnwindSafe(res).catch_unwind().boxed(),
+                    Err(e) => futures::stream::once(future::ready(Err(e))).boxed(),
+                };
+                let mut ticket = Some(ticket);
+                while let Some(res) = stream.next().await {
+                    let res = res.unwrap_or_else(|e| {
+                        warn!("Handler panicked on connection {}: {}", session.peer, panic_message(&e));
+                        Arc::new(KvError::Internal("internal server error".into()).into())
+                    });
+                    // 分块上传只返回状态，不返回被覆盖的旧值
+                    let res = if uploaded && res.state_code == 200 { Arc::new(CommandResponse::ok()) } else { res };
+                    // 第一个响应带有订阅 id，记录下来在 stream 结束时取消
+                    if let Some(topic) = topic.take() {
+                        ticket = None;
+                        if let (200, Some(Ok(id))) = (res.state_code, res.values.first().map(i64::try_from)) {
+                            if !owned.add(topic.clone(), id as u32) {
+                                subscription::unsubscribe(&service, &session, &topic, id as u32);
+                            }
+                        }
+                    }
+                    if tx.send(with_request_id(res, request_id)).await.is_err() {
+                        break;
+                    }
+                }
+                drop(ticket);
+            });
         }
         Ok(())
     }
+
+    // 返回 false 时 stream 已经无法写入
+    async fn send(&mut self, res: Arc<CommandResponse>) -> bool {
+        match self.stream.send(&res).await {
+            Ok(_) => true,
+            // 响应超过协商的最大帧长度时，改为返回错误，避免客户端一直等待
+            Err(e @ KvError::FrameTooLarge(_, _)) => {
+                warn!("Response to {} is too large: {}", self.session.peer, e);
+                let res = CommandResponse { request_id: res.request_id, ..e.into() };
+                if self.stream.send(&res).await.is_err() {
+                    warn!("Failed to send command response");
+                    return false;
+                }
+                true
+            },
+            Err(_) => {
+                warn!("Failed to send command response");
+                false
+            },
+        }
+    }
+
+    // offset 为 0 的块开始新的上传并检查权限，收齐并校验后转换成 Hset，其他命令原样返回
+    fn assemble(&mut self, cmd: CommandRequest) -> Result<Option<CommandRequest>> {
+        let HsetChunk { table, key, chunk } = match cmd.request_data {
+            Some(RequestData::HsetChunk(x)) => x,
+            request_data => return Ok(Some(CommandRequest { request_data, ..cmd })),
+        };
+        let chunk = chunk.unwrap_or_default();
+
+        if chunk.offset == 0 {
+            self.upload = None;
+            let cmd = CommandRequest::new_hset_chunk(&table, &key, Default::default());
+            self.service.authorize(&cmd, &self.session)?;
+            let [connection, global] = self.service.upload_budgets(&self.session);
+            let buf = ChunkBuffer::new(&chunk, MAX_CHUNKED_VALUE.min(self.service.upload_limit()))?
+                .with_budget(connection)
+                .with_budget(global);
+            self.upload = Some((table.clone(), key.clone(), buf));
+        }
+
+        let Some((t, k, buf)) = self.upload.as_mut() else {
+            return Err(KvError::InvalidChunk("no upload in progress".into()));
+        };
+        if *t != table || *k != key {
+            return Err(KvError::InvalidChunk("table or key changed during upload".into()));
+        }
+        if !buf.push(chunk)? {
+            return Ok(None);
+        }
+
+        let (table, key, buf) = self.upload.take().unwrap();
+        Ok(Some(CommandRequest { request_id: cmd.request_id, ..CommandRequest::new_hset(table, key, buf.finish()?.into()) }))
+    }
+}
+
+// 响应可能被多个订阅者共享，需要设置 request_id 时复制一份
+fn with_request_id(res: Arc<CommandResponse>, request_id: u64) -> Arc<CommandResponse> {
+    if request_id == 0 {
+        return res;
+    }
+    let mut res = Arc::unwrap_or_clone(res);
+    res.request_id = request_id;
+    Arc::new(res)
+}
+
+fn panic_message(e: &Box<dyn Any + Send>) -> &str {
+    if let Some(s) = e.downcast_ref::<&str>() {
+        s
+    } else if let Some(s) = e.downcast_ref::<String>() {
+        s
+    } else {
+        "unknown panic"
+    }
 }
 
 pub struct ProstClientStream<S> {
     stream: ProstStream<S, CommandResponse, CommandRequest>,
+    // 下一个流水线请求使用的 request_id
+    next_id: u64,
 }
 
 impl<S> ProstClientStream<S>
@@ -59,14 +326,87 @@ where
     pub fn new(stream: S) -> Self {
         Self {
             stream: ProstStream::new(stream),
+            next_id: 1,
         }
     }
 
+    pub fn with_options(mut self, options: FrameOptions) -> Self {
+        self.stream = self.stream.with_options(options);
+        self
+    }
+
     pub async fn execute_unary(&mut self, cmd: &CommandRequest) -> Result<CommandResponse> {
         self.stream.send(cmd).await?;
         self.stream.next().await.ok_or_else(|| KvError::Internal("Didn't get any response".into()))?
     }
 
+    // 在同一个 stream 上流水线发送多个一元请求，服务端并发处理，响应按 request_id 匹配后按请求的顺序返回
+    pub async fn execute_pipeline(&mut self, cmds: Vec<CommandRequest>) -> Result<Vec<CommandResponse>> {
+        let first = self.next_id;
+        self.next_id += cmds.len() as u64;
+        let cmds = cmds
+            .into_iter()
+            .zip(first..)
+            .map(|(cmd, request_id)| CommandRequest { request_id, ..cmd })
+            .collect::<Vec<_>>();
+
+        // 边发送边接收，避免请求较多时双方的缓冲区都被填满
+        let (mut sink, mut stream) = StreamExt::split::<&CommandRequest>(&mut self.stream);
+        let send = async {
+            for cmd in cmds.iter() {
+                sink.feed(cmd).await?;
+            }
+            sink.flush().await
+        };
+        let recv = async {
+            let mut responses = vec![None; cmds.len()];
+            for _ in 0..cmds.len() {
+                let res = stream.next().await.ok_or_else(|| KvError::Internal("Didn't get any response".into()))??;
+                match res.request_id.checked_sub(first).and_then(|i| responses.get_mut(i as usize)) {
+                    Some(x @ None) => *x = Some(res),
+                    _ => return Err(KvError::Internal(format!("unexpected request id {}", res.request_id))),
+                }
+            }
+            Ok(responses.into_iter().flatten().collect())
+        };
+        let (_, responses) = futures::try_join!(send, recv)?;
+        Ok(responses)
+    }
+
+    // 把 data 按 chunk_size 分块上传，返回最后一块的响应，中途失败时返回失败的响应
+    pub async fn hset_chunked(&mut self, table: &str, key: &str, data: &[u8], chunk_size: usize) -> Result<CommandResponse> {
+        let mut res = CommandResponse::default();
+        for chunk in split(data, chunk_size) {
+            res = self.execute_unary(&CommandRequest::new_hset_chunk(table, key, chunk)).await?;
+            if res.state_code != 200 {
+                break;
+            }
+        }
+        Ok(res)
+    }
+
+    // 分块下载 binary value，校验长度和 checksum 后组装成一个响应；value 不是 binary 或者出错时直接返回响应
+    pub async fn hget_chunked(&mut self, table: &str, key: &str, chunk_size: u32) -> Result<CommandResponse> {
+        self.stream.send(&CommandRequest::new_hget_chunked(table, key, chunk_size)).await?;
+
+        let mut buf: Option<ChunkBuffer> = None;
+        loop {
+            let res = self.stream.next().await.ok_or_else(|| KvError::Internal("Didn't get any response".into()))??;
+            let Some(chunk) = res.chunk else {
+                return Ok(res);
+            };
+
+            let complete = match buf.as_mut() {
+                Some(buf) => buf.push(chunk)?,
+                None => buf.insert(ChunkBuffer::new(&chunk, MAX_CHUNKED_VALUE)?).push(chunk)?,
+            };
+            if complete {
+                let data = buf.take().unwrap().finish()?;
+                return Ok(Value::from(data).into());
+            }
+        }
+    }
+
     pub async fn execute_streaming(self, cmd: &CommandRequest) -> Result<StreamResult> {
         let mut this = self.stream;
 
@@ -75,4 +415,330 @@ where
 
         StreamResult::new(this).await
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use bytes::{BufMut, BytesMut};
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+    use tokio_util::sync::CancellationToken;
+
+    use crate::{network::frame::FrameCoder, service::{split, MAX_AUTH_FAILURES, MAX_CHUNKED_VALUE, MIN_CHUNK_SIZE}, Acl, Authenticator, Chunk, CommandRequest, CommandResponse, FrameOptions, KvError, MemoryDb, Permission, ServiceInner, Service, storage::Storage};
+
+    use super::{stream::ProstStream, ProstClientStream, ProstServerStream};
+
+    type ClientProstStream = ProstStream<DuplexStream, CommandResponse, CommandRequest>;
+
+    fn start(service: Service) -> DuplexStream {
+        start_with_options(service, FrameOptions::default())
+    }
+
+    fn start_with_options(service: Service, options: FrameOptions) -> DuplexStream {
+        let (client, server) = duplex(4096);
+        tokio::spawn(async move {
+            ProstServerStream::new(server, service).with_options(options).process().await
+        });
+        client
+    }
+
+    async fn send_raw(client: &mut DuplexStream, header: u32, body: &[u8]) {
+        let mut buf = BytesMut::new();
+        buf.put_u32(header);
+        buf.put_slice(body);
+        client.write_all(&buf).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn too_many_auth_failures_should_close_the_stream() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .with_auth(Authenticator::new().token("ci", crate::hash_token("t0ken")))
+            .service();
+        let mut stream = ClientProstStream::new(start(service));
+
+        for _ in 0..MAX_AUTH_FAILURES {
+            stream.send(&CommandRequest::new_auth("", "wrong")).await.unwrap();
+            assert_eq!(stream.next().await.unwrap().unwrap().state_code, 401);
+        }
+        stream.send(&CommandRequest::new_auth("", "t0ken")).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().state_code, 401);
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn malformed_frame_should_close_only_the_stream() {
+        let service = ServiceInner::new(MemoryDb::new()).service();
+
+        // 无法解码的正文以及解压失败的正文都返回错误帧后关闭 stream
+        let cases: [(u32, &[u8]); 2] = [(8, &[0xff; 8]), ((1 << 31) | 4, b"oops")];
+        for (header, body) in cases {
+            let mut client = start(service.clone());
+            send_raw(&mut client, header, body).await;
+
+            let mut stream = ClientProstStream::new(client);
+            let res = stream.next().await.unwrap().unwrap();
+            assert_eq!(res.state_code, 400);
+            assert!(stream.next().await.is_none());
+        }
+
+        // 其他 stream 不受影响
+        let mut stream = ClientProstStream::new(start(service));
+        stream.send(&CommandRequest::new_hset("t1", "k1", "v1".into())).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().state_code, 200);
+    }
+
+    #[tokio::test]
+    async fn oversize_frame_should_return_error() {
+        let service = ServiceInner::new(MemoryDb::new()).service();
+        let options = FrameOptions { codecs: vec![], ..Default::default() }.with_max_frame_size(1000);
+        let mut client = start_with_options(service, options);
+
+        // 头部声明的长度超过限制时不分配内存，跳过正文返回 413，之后的请求正常处理
+        send_raw(&mut client, 2000, &[0xff; 2000]).await;
+        let mut buf = BytesMut::new();
+        CommandRequest::new_hset("t1", "k0", "v0".into()).encode_frame(&mut buf).unwrap();
+        client.write_all(&buf).await.unwrap();
+
+        let mut stream = ClientProstStream::new(client);
+        assert_eq!(stream.next().await.unwrap().unwrap().state_code, 413);
+        assert_eq!(stream.next().await.unwrap().unwrap().state_code, 200);
+
+        // 响应超过限制时同样返回 413
+        for i in 1..4 {
+            let cmd = CommandRequest::new_hset("t1", format!("k{}", i), "v".repeat(400).into());
+            stream.send(&cmd).await.unwrap();
+            assert_eq!(stream.next().await.unwrap().unwrap().state_code, 200);
+        }
+        stream.send(&CommandRequest::new_hget_all("t1")).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().state_code, 413);
+        stream.send(&CommandRequest::new_hget("t1", "k0")).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().values, vec!["v0".into()]);
+    }
+
+    #[tokio::test]
+    async fn handler_panic_should_be_caught() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .fn_received(|cmd| {
+                if cmd == &CommandRequest::new_hget("panic", "k1") {
+                    panic!("handler panic");
+                }
+            })
+            .service();
+
+        let mut stream = ClientProstStream::new(start(service));
+        stream.send(&CommandRequest::new_hget("panic", "k1")).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().state_code, 500);
+
+        // panic 之后同一个 stream 继续处理请求
+        stream.send(&CommandRequest::new_hset("t1", "k1", "v1".into())).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().state_code, 200);
+    }
+
+    #[tokio::test]
+    async fn unsubscribe_unknown_id_should_return_error() {
+        let service = ServiceInner::new(MemoryDb::new()).service();
+
+        let mut stream = ClientProstStream::new(start(service));
+        stream.send(&CommandRequest::unsubscribe("lobby", u32::MAX)).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().state_code, 404);
+
+        stream.send(&CommandRequest::publish("lobby", vec!["hello".into()])).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().state_code, 200);
+    }
+
+    #[tokio::test]
+    async fn dead_client_should_cancel_its_subscriptions() {
+        let service = ServiceInner::new(MemoryDb::new()).service();
+        let closed = CancellationToken::new();
+
+        // 客户端断开后发送消息失败，以及所在的连接结束，两种情况都取消订阅并结束 stream 的处理
+        for disconnect in [true, false] {
+            let (client, server) = duplex(4096);
+            let handle = tokio::spawn({
+                let (service, closed) = (service.clone(), closed.child_token());
+                async move { ProstServerStream::new(server, service).with_closed(closed).process().await }
+            });
+
+            let mut stream = ClientProstStream::new(client);
+            stream.send(&CommandRequest::subscribe("lobby")).await.unwrap();
+            let id = i64::try_from(&stream.next().await.unwrap().unwrap().values[0]).unwrap() as u32;
+
+            if disconnect {
+                drop(stream);
+                service.execute(CommandRequest::publish("lobby", vec!["hello".into()])).next().await.unwrap();
+            } else {
+                closed.cancel();
+            }
+            tokio::time::timeout(Duration::from_secs(1), handle).await.unwrap().unwrap().unwrap();
+            let res = service.execute(CommandRequest::unsubscribe("lobby", id)).next().await.unwrap();
+            assert_eq!(res.state_code, 404);
+        }
+    }
+
+    #[tokio::test]
+    async fn pipelined_requests_should_keep_per_key_order() {
+        let service = ServiceInner::new(MemoryDb::new()).service();
+        let mut stream = ProstClientStream::new(start(service));
+
+        // 同一个 key 上的读写交错发送，每个 get 都应该读到它之前的 set
+        let mut cmds = Vec::new();
+        for round in 0..5 {
+            for i in 0..20 {
+                cmds.push(CommandRequest::new_hset("t1", format!("k{}", i), (round * 100 + i).into()));
+                cmds.push(CommandRequest::new_hget("t1", format!("k{}", i)));
+            }
+        }
+        let res = stream.execute_pipeline(cmds).await.unwrap();
+        assert_eq!(res.len(), 200);
+        for (n, pair) in res.chunks(2).enumerate() {
+            let (round, i) = ((n / 20) as i64, (n % 20) as i64);
+            assert_eq!(pair[1].values, vec![(round * 100 + i).into()]);
+        }
+
+        // 之后的流水线继续使用新的 request_id
+        let res = stream.execute_pipeline(vec![CommandRequest::new_hget("t1", "k0")]).await.unwrap();
+        assert_eq!(res[0].values, vec![400.into()]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn pipelined_requests_should_run_concurrently() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .fn_received(|cmd| {
+                if cmd.request_data == CommandRequest::new_hget("t1", "slow").request_data {
+                    std::thread::sleep(std::time::Duration::from_millis(300));
+                }
+            })
+            .service();
+        let mut stream = ClientProstStream::new(start(service));
+
+        // 慢请求不会阻塞其他 key 上的请求，响应带着各自的 request_id 返回
+        for (request_id, cmd) in [(1, CommandRequest::new_hget("t1", "slow")), (2, CommandRequest::new_hset("t1", "fast", "v".into()))] {
+            stream.send(&CommandRequest { request_id, ..cmd }).await.unwrap();
+        }
+        assert_eq!(stream.next().await.unwrap().unwrap().request_id, 2);
+        assert_eq!(stream.next().await.unwrap().unwrap().request_id, 1);
+
+        // request_id 为 0 的请求仍然按顺序处理
+        stream.send(&CommandRequest::new_hget("t1", "slow")).await.unwrap();
+        stream.send(&CommandRequest::new_hget("t1", "fast")).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().state_code, 404);
+        assert_eq!(stream.next().await.unwrap().unwrap().values, vec!["v".into()]);
+    }
+
+    #[tokio::test]
+    async fn chunked_value_should_work() {
+        let service = ServiceInner::new(MemoryDb::new()).service();
+        let options = FrameOptions::default().with_max_frame_size(64 * 1024);
+        let (client, server) = duplex(64 * 1024);
+        tokio::spawn(async move {
+            ProstServerStream::new(server, service).with_options(options.clone()).process().await
+        });
+        let mut stream = ProstClientStream::new(client).with_options(FrameOptions::default().with_max_frame_size(64 * 1024));
+
+        // value 超过最大帧长度，只能分块传输
+        let data = (0..1024 * 1024).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+        let res = stream.execute_unary(&CommandRequest::new_hset("t1", "blob", data.clone().into())).await;
+        assert!(matches!(res, Err(KvError::FrameTooLarge(_, _))));
+
+        let res = stream.hset_chunked("t1", "blob", &data, 60 * 1024).await.unwrap();
+        assert_eq!(res, CommandResponse::ok());
+        let res = stream.hget_chunked("t1", "blob", 60 * 1024).await.unwrap();
+        assert_eq!(res.values, vec![data.into()]);
+
+        // 非 binary 的 value 和不存在的 key 直接返回普通响应
+        stream.execute_unary(&CommandRequest::new_hset("t1", "k1", "v1".into())).await.unwrap();
+        let res = stream.hget_chunked("t1", "k1", 0).await.unwrap();
+        assert_eq!(res.values, vec!["v1".into()]);
+        assert_eq!(stream.hget_chunked("t1", "k2", 0).await.unwrap().state_code, 404);
+    }
+
+    #[tokio::test]
+    async fn chunk_size_should_be_clamped() {
+        let db = MemoryDb::new();
+        let data = (0..200_000).map(|x| (x % 251) as u8).collect::<Vec<_>>();
+        db.set("t1", "blob", data.clone().into()).unwrap();
+        let service = ServiceInner::new(db).service();
+        let mut stream = ClientProstStream::new(start_with_options(service, FrameOptions::default().with_max_frame_size(64 * 1024)));
+
+        // 过小的块按下限生成，超过最大帧长度的块缩小到帧内，之后的请求不受影响
+        for (requested, expected) in [(1, data.len().div_ceil(MIN_CHUNK_SIZE)), (1 << 20, data.len().div_ceil(63 * 1024))] {
+            stream.send(&CommandRequest::new_hget_chunked("t1", "blob", requested)).await.unwrap();
+            for _ in 0..expected {
+                let res = stream.next().await.unwrap().unwrap();
+                assert_eq!(res.state_code, 200);
+                assert!(res.chunk.is_some());
+            }
+        }
+        stream.send(&CommandRequest::new_hget("t1", "missing")).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap().state_code, 404);
+    }
+
+    #[tokio::test]
+    async fn invalid_chunks_should_be_rejected() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .with_acl(Acl::default().rule("*", &["t1"], &[], &[Permission::Read, Permission::Write]))
+            .service();
+        let mut stream = ProstClientStream::new(start(service.clone()));
+
+        let data = vec![1u8; 10000];
+        let mut chunks = split(&data, 4000);
+        chunks[2].data[0] = 2;
+        for chunk in chunks {
+            stream.execute_unary(&CommandRequest::new_hset_chunk("t1", "blob", chunk)).await.unwrap();
+        }
+        // 校验失败时不写入，最后一块返回错误
+        let res = stream.execute_unary(&CommandRequest::new_hget("t1", "blob")).await.unwrap();
+        assert_eq!(res.state_code, 404);
+
+        // 没有开始的上传、中途更换 key
+        let chunks = split(&data, 4000);
+        let res = stream.execute_unary(&CommandRequest::new_hset_chunk("t1", "blob", chunks[1].clone())).await.unwrap();
+        assert_eq!(res.state_code, 400);
+        stream.execute_unary(&CommandRequest::new_hset_chunk("t1", "blob", chunks[0].clone())).await.unwrap();
+        let res = stream.execute_unary(&CommandRequest::new_hset_chunk("t1", "other", chunks[1].clone())).await.unwrap();
+        assert_eq!(res.state_code, 400);
+
+        // 第一块声明很大的 total_len 但只带少量数据，服务端不会按声明的长度分配内存
+        let chunk = Chunk { total_len: service.upload_limit(), data: vec![1; 100], ..Default::default() };
+        let res = stream.execute_unary(&CommandRequest::new_hset_chunk("t1", "huge", chunk)).await.unwrap();
+        assert_eq!(res.state_code, 200);
+        let res = stream.execute_unary(&CommandRequest::new_hget("t1", "huge")).await.unwrap();
+        assert_eq!(res.state_code, 404);
+
+        // 没有权限时第一块就被拒绝
+        let res = stream.hset_chunked("t2", "blob", &data, 4000).await.unwrap();
+        assert_eq!(res.state_code, 403);
+
+        // 失败之后可以重新上传
+        assert_eq!(stream.hset_chunked("t1", "blob", &data, 4000).await.unwrap(), CommandResponse::ok());
+        assert_eq!(stream.hget_chunked("t1", "blob", 4000).await.unwrap().values, vec![data.clone().into()]);
+
+        // 分块命令只能由连接组装，直接交给 service 时返回错误
+        let cmd = CommandRequest::new_hset_chunk("t1", "blob", split(&data, 4000).remove(0));
+        let res = service.execute(cmd).next().await.unwrap();
+        assert_eq!(res.state_code, 400);
+    }
+
+    #[tokio::test]
+    async fn uploads_should_be_limited() {
+        let service: Service = ServiceInner::new(MemoryDb::new()).with_upload_limits(8000, 10000).service();
+        let data = vec![1u8; 10000];
+
+        // 超过单个连接的额度
+        let mut stream = ProstClientStream::new(start(service.clone()));
+        assert_eq!(stream.hset_chunked("t1", "blob", &data, 4000).await.unwrap().state_code, 400);
+        assert_eq!(stream.hset_chunked("t1", "blob", &data[..6000], 4000).await.unwrap(), CommandResponse::ok());
+
+        // 两个连接上未完成的上传超过全局额度
+        let mut other = ProstClientStream::new(start(service.clone()));
+        let chunks = split(&data[..7000], 4000);
+        for (s, key) in [(&mut stream, "a"), (&mut other, "b")] {
+            let res = s.execute_unary(&CommandRequest::new_hset_chunk("t1", key, chunks[0].clone())).await.unwrap();
+            assert_eq!(res.state_code, 200);
+        }
+        let res = stream.execute_unary(&CommandRequest::new_hset_chunk("t1", "a", chunks[1].clone())).await.unwrap();
+        assert_eq!(res.state_code, 400);
+    }
+}
@@ -1,16 +1,83 @@
-use std::marker::PhantomData;
+use std::{marker::PhantomData, time::Instant};
 
-use futures::{TryStreamExt, Future, future};
+use futures::{stream, StreamExt, TryStreamExt, Future, future::{self, Either}};
+use serde::Deserialize;
 use tokio::{io::{AsyncWrite, AsyncRead}, spawn};
-use tokio_util::compat::{TokioAsyncReadCompatExt, FuturesAsyncReadCompatExt, Compat};
+use tokio_util::{compat::{TokioAsyncReadCompatExt, FuturesAsyncReadCompatExt, Compat}, sync::CancellationToken};
+use tracing::log::warn;
 use yamux::{Control, Connection, Config, Mode, ConnectionError, WindowUpdateMode};
 
-use crate::ProstClientStream;
+use crate::{pb::Handshake, ProstClientStream};
 
+use super::{client_handshake, FrameOptions};
 
 
+// yamux 要求的最小接收窗口
+const MIN_RECEIVE_WINDOW: u32 = 256 * 1024;
+
+// 服务端 yamux 连接的配置，没有配置的项使用 yamux 的默认值
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct YamuxOptions {
+    // 每个 stream 的接收窗口（字节），小于 256 KiB 时使用 256 KiB
+    pub receive_window: Option<u32>,
+    // 每个 stream 缓存的最大字节数
+    pub max_buffer_size: Option<usize>,
+    // 每个连接同时打开的 stream 数，超过时 yamux 关闭整个连接
+    pub max_streams: Option<usize>,
+    // 每个连接每秒允许打开的 stream 数，超过的 stream 直接关闭
+    pub max_stream_rate: Option<u32>,
+}
+
+impl YamuxOptions {
+    // 窗口在读取数据后更新，避免对端在处理慢时持续发送
+    pub fn config(&self) -> Config {
+        let mut config = Config::default();
+        config.set_window_update_mode(WindowUpdateMode::OnRead);
+        if let Some(n) = self.receive_window {
+            config.set_receive_window(n.max(MIN_RECEIVE_WINDOW));
+        }
+        if let Some(n) = self.max_buffer_size {
+            config.set_max_buffer_size(n);
+        }
+        if let Some(n) = self.max_streams {
+            config.set_max_num_streams(n);
+        }
+        config
+    }
+}
+
+// 令牌桶，容量和每秒补充的数量都为 rate
+struct RateLimiter {
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl RateLimiter {
+    fn new(rate: u32) -> Self {
+        Self { rate: rate as f64, tokens: rate as f64, last: Instant::now() }
+    }
+
+    fn allow(&mut self) -> bool {
+        let now = Instant::now();
+        self.tokens = (self.tokens + now.duration_since(self.last).as_secs_f64() * self.rate).min(self.rate);
+        self.last = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
 pub struct YamuxCtrl<S> {
     ctrl: Control,
+    // 客户端握手得到的协商结果以及据此选择的压缩配置
+    handshake: Handshake,
+    options: FrameOptions,
+    // 连接结束（对端关闭、出错或者调用 close）时取消
+    closed: CancellationToken,
     _s: PhantomData<S>,
 }
 
@@ -18,8 +85,17 @@ impl<S> YamuxCtrl<S>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static
 {
-    pub fn new_client(stream: S, config: Option<Config>) -> Self {
-        Self::new(stream, config, Mode::Client, |_stream| future::ready(Ok(())))
+    pub async fn new_client(stream: S, config: Option<Config>) -> crate::Result<Self> {
+        Self::new_client_with_options(stream, config, FrameOptions::default()).await
+    }
+
+    // 先完成协议版本握手，再建立 yamux 连接
+    pub async fn new_client_with_options(mut stream: S, config: Option<Config>, options: FrameOptions) -> crate::Result<Self> {
+        let handshake = client_handshake(&mut stream, &Handshake::new(&options, vec![])).await?;
+        let mut ctrl = Self::new(stream, config, Mode::Client, |_stream| future::ready(Ok(())));
+        ctrl.options = options.negotiated(&handshake);
+        ctrl.handshake = handshake;
+        Ok(ctrl)
     }
 
     pub fn new_server<F, Fut>(stream: S, config: Option<Config>, f: F) -> Self 
@@ -31,6 +107,24 @@ where
         Self::new(stream, config, Mode::Server, f)
     }
 
+    // 使用 options 生成的配置，并限制对端打开 stream 的速度
+    pub fn new_server_with_options<F, Fut>(stream: S, options: &YamuxOptions, mut f: F) -> Self
+    where
+        F: FnMut(yamux::Stream) -> Fut,
+        F: Send + 'static,
+        Fut: Future<Output = Result<(), ConnectionError>> + Send + 'static
+    {
+        let mut limiter = options.max_stream_rate.map(RateLimiter::new);
+        Self::new(stream, Some(options.config()), Mode::Server, move |stream| {
+            if limiter.as_mut().is_some_and(|x| !x.allow()) {
+                warn!("Too many streams opened, closing {}", stream.id());
+                return Either::Left(future::ready(Ok(())));
+            }
+            Either::Right(f(stream))
+        })
+    }
+
+    // 传入的 config 原样使用，没有时使用 YamuxOptions 的默认配置
     fn new<F, Fut>(stream: S, config: Option<Config>, mode: Mode, f: F) -> Self 
     where
         F: FnMut(yamux::Stream) -> Fut,
@@ -38,39 +132,59 @@ where
         Fut: Future<Output = Result<(), ConnectionError>> + Send + 'static,
     {
 
-        let mut config = config.unwrap_or_default();
-        config.set_window_update_mode(WindowUpdateMode::OnRead);
-
+        let config = config.unwrap_or_else(|| YamuxOptions::default().config());
         let conn = Connection::new(stream.compat(), config, mode);
 
         // 0.11 failed
         // let (ctrl, conn) = Control::new(conn);
         // spawn(conn.try_for_each_concurrent(None, f));
 
+        // 不再有新的 stream 时连接已经结束，不用等待处理中的 stream
         let ctrl = conn.control();
-        spawn(yamux::into_stream(conn).try_for_each_concurrent(None, f));
+        let closed = CancellationToken::new();
+        let guard = closed.clone().drop_guard();
+        let end = stream::once(future::lazy(move |_| drop(guard))).filter_map(|_| future::ready(None));
+        spawn(yamux::into_stream(conn).chain(end).try_for_each_concurrent(None, f));
 
         Self {
             ctrl,
+            handshake: Handshake::default(),
+            options: FrameOptions::default(),
+            closed,
             _s: PhantomData,
         }
     }
 
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
     pub async fn open_stream(&mut self) -> crate::Result<ProstClientStream<Compat<yamux::Stream>>> {
         let stream = self.ctrl.open_stream().await?;
-        Ok(ProstClientStream::new(stream.compat()))
+        Ok(ProstClientStream::new(stream.compat()).with_options(self.options.clone()))
+    }
+
+    // 关闭整个连接，连接上所有的 stream 都会结束
+    pub async fn close(&mut self) -> crate::Result<()> {
+        self.ctrl.close().await?;
+        Ok(())
+    }
+
+    pub fn closed(&self) -> CancellationToken {
+        self.closed.clone()
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    use std::{task::{Poll, Context}, pin::Pin};
+    use std::{task::{Poll, Context}, pin::Pin, time::Duration};
 
     use bytes::{BytesMut, BufMut};
+    use futures::StreamExt;
     use tokio::io::{AsyncRead, ReadBuf, AsyncWrite};
 
-    use crate::network::multiplex::YamuxCtrl;
+    use crate::{network::multiplex::{RateLimiter, YamuxCtrl, YamuxOptions}, CommandRequest, KeepaliveOptions, MemoryDb, Service, ServiceInner, Session};
 
 
     #[derive(Debug)]
@@ -116,8 +230,63 @@ mod tests {
     #[tokio::test]
     async fn open_stream_should_work() {
         let stream = DummyStream { stream: BytesMut::new() };
-        let mut ctrl = YamuxCtrl::new_client(stream, None);
+        let mut ctrl = YamuxCtrl::new_client(stream, None).await.unwrap();
         let res = ctrl.open_stream().await;
         assert!(res.is_ok());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn rate_limiter_should_work() {
+        let mut limiter = RateLimiter::new(2);
+        assert!(limiter.allow());
+        assert!(limiter.allow());
+        assert!(!limiter.allow());
+
+        // 经过一段时间后补充令牌
+        limiter.last -= std::time::Duration::from_millis(600);
+        assert!(limiter.allow());
+        assert!(!limiter.allow());
+    }
+
+    #[tokio::test]
+    async fn server_should_limit_stream_rate() {
+        let (client, server) = tokio::io::duplex(4096);
+        let options = YamuxOptions { max_stream_rate: Some(2), ..Default::default() };
+        let service = ServiceInner::new(MemoryDb::new()).with_yamux_options(options).service();
+        tokio::spawn(crate::serve_connection(server, service, Session::default()));
+
+        let mut ctrl = YamuxCtrl::new_client(client, None).await.unwrap();
+        for _ in 0..2 {
+            let mut stream = ctrl.open_stream().await.unwrap();
+            let res = stream.execute_unary(&CommandRequest::new_hset("t1", "k1", "v1".into())).await.unwrap();
+            assert_eq!(res.state_code, 200);
+        }
+
+        // 超过速度限制的 stream 被直接关闭
+        let mut stream = ctrl.open_stream().await.unwrap();
+        assert!(stream.execute_unary(&CommandRequest::new_hset("t1", "k1", "v1".into())).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn server_should_close_idle_connection() {
+        let (client, server) = tokio::io::duplex(4096);
+        let options = KeepaliveOptions { idle_timeout: Some(1), ..Default::default() };
+        let service: Service = ServiceInner::new(MemoryDb::new()).with_keepalive_options(options).service();
+        tokio::spawn(crate::serve_connection(server, service.clone(), Session::default()));
+
+        // 有打开的 stream（订阅）时连接不算空闲
+        let mut ctrl = YamuxCtrl::new_client(client, None).await.unwrap();
+        let stream = ctrl.open_stream().await.unwrap();
+        let mut messages = stream.execute_streaming(&CommandRequest::subscribe("lobby")).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        service.execute(CommandRequest::publish("lobby", vec!["hello".into()])).next().await.unwrap();
+        let res = messages.next().await.unwrap().unwrap();
+        assert_eq!(res.values, vec!["hello".into()]);
+
+        // 订阅结束后连接上没有 stream，超过 idle_timeout 时服务端关闭连接
+        let res = service.execute(CommandRequest::unsubscribe("lobby", messages.id as u32)).next().await.unwrap();
+        assert_eq!(res.state_code, 200);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(ctrl.open_stream().await.is_err());
+    }
+}
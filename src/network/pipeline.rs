@@ -0,0 +1,249 @@
+use std::collections::HashMap;
+
+use tokio::sync::watch;
+
+use crate::pb::{command_request::RequestData, CommandRequest};
+
+// 每调度这么多请求清理一次已经完成的记录
+const GC_INTERVAL: usize = 1024;
+
+// 请求之间的依赖范围：同一个 key、整个 table、同一个 topic 或者整个连接
+#[derive(Debug, PartialEq, Eq)]
+enum Scope<'a> {
+    Keys(&'a str, Vec<&'a str>),
+    Table(&'a str),
+    Topic(&'a str),
+    Global,
+}
+
+fn scope(cmd: &CommandRequest) -> Scope<'_> {
+    fn keys<'a>(table: &'a str, mut keys: Vec<&'a str>) -> Scope<'a> {
+        keys.sort_unstable();
+        keys.dedup();
+        Scope::Keys(table, keys)
+    }
+
+    match &cmd.request_data {
+        Some(RequestData::Hget(x)) => keys(&x.table, vec![&x.key]),
+        Some(RequestData::Hset(x)) => keys(&x.table, x.pair.iter().map(|x| x.key.as_str()).collect()),
+        Some(RequestData::Hexists(x)) => keys(&x.table, vec![&x.key]),
+        Some(RequestData::Hdelete(x)) => keys(&x.table, vec![&x.key]),
+        Some(RequestData::Hmget(x)) => keys(&x.table, x.keys.iter().map(|x| x.as_str()).collect()),
+        Some(RequestData::Hmset(x)) => keys(&x.table, x.pairs.iter().map(|x| x.key.as_str()).collect()),
+        Some(RequestData::Hmexists(x)) => keys(&x.table, x.keys.iter().map(|x| x.as_str()).collect()),
+        Some(RequestData::Hmdelete(x)) => keys(&x.table, x.keys.iter().map(|x| x.as_str()).collect()),
+        Some(RequestData::JsonGet(x)) => keys(&x.table, vec![&x.key]),
+        Some(RequestData::JsonSet(x)) => keys(&x.table, vec![&x.key]),
+        Some(RequestData::JsonDel(x)) => keys(&x.table, vec![&x.key]),
+        Some(RequestData::JsonAppend(x)) => keys(&x.table, vec![&x.key]),
+        Some(RequestData::JsonIncr(x)) => keys(&x.table, vec![&x.key]),
+        Some(RequestData::Hhistory(x)) => keys(&x.table, vec![&x.key]),
+        Some(RequestData::HsetChunk(x)) => keys(&x.table, vec![&x.key]),
+        Some(RequestData::HgetChunked(x)) => keys(&x.table, vec![&x.key]),
+        Some(RequestData::Hgetall(x)) => Scope::Table(&x.table),
+        Some(RequestData::CreateIndex(x)) => Scope::Table(&x.table),
+        Some(RequestData::DropIndex(x)) => Scope::Table(&x.table),
+        Some(RequestData::QueryIndex(x)) => Scope::Table(&x.table),
+        Some(RequestData::Aggregate(x)) => Scope::Table(&x.table),
+        Some(RequestData::EnableHistory(x)) => Scope::Table(&x.table),
+        Some(RequestData::DisableHistory(x)) => Scope::Table(&x.table),
+        Some(RequestData::Subscribe(x)) => Scope::Topic(&x.topic),
+        Some(RequestData::Unsubscribe(x)) => Scope::Topic(&x.topic),
+        Some(RequestData::Publish(x)) => Scope::Topic(&x.topic),
+        // Auth 会改变连接的身份，需要等之前的请求完成，之后的请求也要等它完成
+        Some(RequestData::Auth(_)) | None => Scope::Global,
+    }
+}
+
+// 请求完成（Ticket 被 drop）时 sender 被关闭
+type Done = watch::Receiver<()>;
+
+fn is_done(x: &Done) -> bool {
+    x.has_changed().is_err()
+}
+
+// 调度时返回的凭证，ready 返回后才能执行请求，执行完并发送响应后 drop
+pub struct Ticket {
+    deps: Vec<Done>,
+    _done: watch::Sender<()>,
+}
+
+impl Ticket {
+    pub async fn ready(&mut self) {
+        for x in self.deps.iter_mut() {
+            // 从不发送值，只有在前序请求完成时返回
+            let _ = x.changed().await;
+        }
+        self.deps.clear();
+    }
+}
+
+#[derive(Debug, Default)]
+struct TableLane {
+    // 最近一个作用于整个 table 的请求
+    barrier: Option<Done>,
+    keys: HashMap<String, Done>,
+}
+
+// 同一个 stream 上的请求并发执行，只有作用范围重叠的请求按收到的顺序执行
+// request_id 为 0 的请求之间也按顺序执行，保持旧客户端看到的行为
+#[derive(Debug, Default)]
+pub struct Scheduler {
+    global: Option<Done>,
+    ordered: Option<Done>,
+    tables: HashMap<String, TableLane>,
+    topics: HashMap<String, Done>,
+    scheduled: usize,
+}
+
+impl Scheduler {
+    pub fn schedule(&mut self, cmd: &CommandRequest) -> Ticket {
+        let (tx, done) = watch::channel(());
+        let mut deps: Vec<Done> = self.global.iter().cloned().collect();
+
+        match scope(cmd) {
+            Scope::Keys(table, keys) => {
+                let lane = self.tables.entry(table.into()).or_default();
+                deps.extend(lane.barrier.clone());
+                for key in keys {
+                    deps.extend(lane.keys.insert(key.into(), done.clone()));
+                }
+            },
+            Scope::Table(table) => {
+                let lane = self.tables.entry(table.into()).or_default();
+                deps.extend(lane.barrier.replace(done.clone()));
+                deps.extend(lane.keys.drain().map(|(_, x)| x));
+            },
+            Scope::Topic(topic) => deps.extend(self.topics.insert(topic.into(), done.clone())),
+            Scope::Global => {
+                for (_, lane) in self.tables.drain() {
+                    deps.extend(lane.barrier);
+                    deps.extend(lane.keys.into_values());
+                }
+                deps.extend(self.topics.drain().map(|(_, x)| x));
+                deps.extend(self.ordered.clone());
+                self.global = Some(done.clone());
+            },
+        }
+        if cmd.request_id == 0 {
+            deps.extend(self.ordered.replace(done));
+        }
+        deps.retain(|x| !is_done(x));
+
+        self.scheduled += 1;
+        if self.scheduled.is_multiple_of(GC_INTERVAL) {
+            self.gc();
+        }
+        Ticket { deps, _done: tx }
+    }
+
+    fn gc(&mut self) {
+        let take_done = |x: &mut Option<Done>| if x.as_ref().is_some_and(is_done) { *x = None };
+        take_done(&mut self.global);
+        take_done(&mut self.ordered);
+        self.tables.retain(|_, lane| {
+            take_done(&mut lane.barrier);
+            lane.keys.retain(|_, x| !is_done(x));
+            lane.barrier.is_some() || !lane.keys.is_empty()
+        });
+        self.topics.retain(|_, x| !is_done(x));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::FutureExt;
+    use tokio::time::timeout;
+
+    use crate::{CommandRequest, Hmget, command_request::RequestData};
+
+    use super::{scope, Scheduler, Scope, Ticket};
+
+    fn with_id(mut cmd: CommandRequest, request_id: u64) -> CommandRequest {
+        cmd.request_id = request_id;
+        cmd
+    }
+
+    async fn is_ready(ticket: &mut Ticket) -> bool {
+        timeout(Duration::from_millis(10), ticket.ready()).await.is_ok()
+    }
+
+    #[test]
+    fn scope_should_work() {
+        assert_eq!(scope(&CommandRequest::new_hset("t1", "k1", "v1".into())), Scope::Keys("t1", vec!["k1"]));
+        let cmd = CommandRequest { request_data: Some(RequestData::Hmget(Hmget { table: "t1".into(), keys: vec!["k2".into(), "k1".into(), "k2".into()] })), ..Default::default() };
+        assert_eq!(scope(&cmd), Scope::Keys("t1", vec!["k1", "k2"]));
+        assert_eq!(scope(&CommandRequest::new_hget_all("t1")), Scope::Table("t1"));
+        assert_eq!(scope(&CommandRequest::publish("lobby", vec![])), Scope::Topic("lobby"));
+        assert_eq!(scope(&CommandRequest::new_auth("alice", "secret")), Scope::Global);
+    }
+
+    #[tokio::test]
+    async fn scheduler_should_order_overlapping_requests() {
+        let mut scheduler = Scheduler::default();
+        let mut set = scheduler.schedule(&with_id(CommandRequest::new_hset("t1", "k1", "v1".into()), 1));
+        let mut get = scheduler.schedule(&with_id(CommandRequest::new_hget("t1", "k1"), 2));
+        let mut other = scheduler.schedule(&with_id(CommandRequest::new_hget("t1", "k2"), 3));
+        let mut all = scheduler.schedule(&with_id(CommandRequest::new_hget_all("t1"), 4));
+        let mut topic = scheduler.schedule(&with_id(CommandRequest::publish("lobby", vec![]), 5));
+
+        // 不同的 key 和 topic 不需要等待
+        assert!(is_ready(&mut set).await);
+        assert!(is_ready(&mut other).await);
+        assert!(is_ready(&mut topic).await);
+        assert!(!is_ready(&mut get).await);
+
+        // 同一个 key 的请求按顺序执行，整个 table 的请求等待之前的所有 key
+        drop(set);
+        assert!(is_ready(&mut get).await);
+        drop(other);
+        assert!(!is_ready(&mut all).await);
+        drop(get);
+        assert!(is_ready(&mut all).await);
+
+        let mut after = scheduler.schedule(&with_id(CommandRequest::new_hget("t1", "k3"), 6));
+        assert!(!is_ready(&mut after).await);
+        drop(all);
+        assert!(is_ready(&mut after).await);
+    }
+
+    #[tokio::test]
+    async fn scheduler_should_order_global_and_legacy_requests() {
+        let mut scheduler = Scheduler::default();
+
+        // request_id 为 0 的请求之间总是按顺序执行
+        let mut first = scheduler.schedule(&CommandRequest::new_hget("t1", "k1"));
+        let mut second = scheduler.schedule(&CommandRequest::publish("lobby", vec![]));
+        assert!(is_ready(&mut first).await);
+        assert!(!is_ready(&mut second).await);
+        drop(first);
+        assert!(is_ready(&mut second).await);
+
+        // Auth 等待之前的所有请求，之后的请求等待 Auth
+        let mut auth = scheduler.schedule(&with_id(CommandRequest::new_auth("alice", "secret"), 1));
+        let mut get = scheduler.schedule(&with_id(CommandRequest::new_hget("t2", "k1"), 2));
+        assert!(!is_ready(&mut auth).await);
+        drop(second);
+        assert!(is_ready(&mut auth).await);
+        assert!(!is_ready(&mut get).await);
+        drop(auth);
+        assert!(get.ready().now_or_never().is_some());
+    }
+
+    #[test]
+    fn scheduler_should_forget_finished_requests() {
+        let mut scheduler = Scheduler::default();
+        let mut tickets = Vec::new();
+        for i in 0..super::GC_INTERVAL {
+            tickets.push(scheduler.schedule(&with_id(CommandRequest::new_hget("t1", format!("k{}", i)), i as u64 + 1)));
+            if tickets.len() > 2 {
+                tickets.remove(0);
+            }
+        }
+        // 清理时只有最近的三个请求还未完成
+        assert_eq!(scheduler.tables["t1"].keys.len(), 3);
+    }
+}
@@ -0,0 +1,201 @@
+use std::{net::SocketAddr, pin::Pin, task::{Context, Poll}};
+
+use quinn::{Connecting, Connection, Endpoint, RecvStream, SendStream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_rustls::rustls::Certificate;
+
+use crate::{pb::Handshake, KvError, ProstClientStream, Result};
+
+use super::{client_handshake, FrameOptions, KeepaliveOptions, TlsClientConnector, TlsServerAcceptor};
+
+// QUIC 连接上 ALPN 协商的协议名
+const ALPN: &[u8] = b"kv";
+
+// 一个双向的 QUIC stream，相当于 TCP 上的一个 yamux stream
+pub struct QuicStream {
+    send: SendStream,
+    recv: RecvStream,
+}
+
+impl AsyncRead for QuicStream {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().recv).poll_read(cx, buf)
+    }
+}
+
+impl AsyncWrite for QuicStream {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.get_mut().send).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().send).poll_shutdown(cx)
+    }
+}
+
+// 服务端的 QUIC 连接，客户端打开的第一个 stream 用于协议握手，之后的每个 stream 处理一组请求
+pub struct QuicConnection {
+    conn: Connection,
+}
+
+impl QuicConnection {
+    pub fn peer(&self) -> String {
+        self.conn.remote_address().to_string()
+    }
+
+    // 连接关闭时返回 None
+    pub async fn accept_stream(&self) -> Result<Option<QuicStream>> {
+        match self.conn.accept_bi().await {
+            Ok((send, recv)) => Ok(Some(QuicStream { send, recv })),
+            Err(quinn::ConnectionError::ApplicationClosed(_) | quinn::ConnectionError::LocallyClosed) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn close(&self, reason: &str) {
+        self.conn.close(0u32.into(), reason.as_bytes());
+    }
+}
+
+// QUIC 总是使用 tls，证书配置与 tcp 的 tls 相同
+pub struct QuicListener {
+    endpoint: Endpoint,
+    acceptor: TlsServerAcceptor,
+}
+
+impl QuicListener {
+    pub async fn bind(addr: &str, acceptor: TlsServerAcceptor) -> Result<Self> {
+        let addr = resolve(addr).await?;
+        let endpoint = Endpoint::server(acceptor.quic_config(ALPN), addr)?;
+        Ok(Self { endpoint, acceptor })
+    }
+
+    // 使用 keepalive 配置的 PING 间隔和空闲超时，只影响之后建立的连接
+    pub fn set_keepalive(&self, keepalive: &KeepaliveOptions) -> Result<()> {
+        let mut config = self.acceptor.quic_config(ALPN);
+        config.transport_config(keepalive.quic_transport()?);
+        self.endpoint.set_server_config(Some(config));
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> Result<String> {
+        Ok(self.endpoint.local_addr()?.to_string())
+    }
+
+    // endpoint 关闭时返回 None
+    pub async fn accept(&self) -> Option<Connecting> {
+        self.endpoint.accept().await
+    }
+
+    // 完成 tls 握手，返回连接以及客户端证书中的身份
+    pub async fn establish(&self, connecting: Connecting) -> Result<(QuicConnection, Option<String>)> {
+        let conn = connecting.await?;
+        let certs = conn.peer_identity().and_then(|x| x.downcast::<Vec<Certificate>>().ok());
+        let identity = self.acceptor.identity(certs.as_deref().map(|x| x.as_slice()))?;
+        Ok((QuicConnection { conn }, identity))
+    }
+
+    pub fn close(&self) {
+        self.endpoint.close(0u32.into(), b"shutdown");
+    }
+}
+
+// 客户端的 QUIC 连接，用法与 YamuxCtrl 相同
+pub struct QuicCtrl {
+    _endpoint: Endpoint,
+    conn: Connection,
+    handshake: Handshake,
+    options: FrameOptions,
+}
+
+impl QuicCtrl {
+    // 连接后先在第一个 stream 上完成协议版本握手
+    pub async fn connect(addr: &str, connector: &TlsClientConnector, options: FrameOptions) -> Result<Self> {
+        let addr = resolve(addr).await?;
+        let bind: SocketAddr = match addr {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let mut endpoint = Endpoint::client(bind)?;
+        endpoint.set_default_client_config(connector.quic_config(ALPN));
+        let conn = endpoint.connect(addr, connector.domain())?.await?;
+
+        let (send, recv) = conn.open_bi().await?;
+        let handshake = client_handshake(&mut QuicStream { send, recv }, &Handshake::new(&options, vec![])).await?;
+        Ok(Self {
+            _endpoint: endpoint,
+            conn,
+            options: options.negotiated(&handshake),
+            handshake,
+        })
+    }
+
+    pub fn handshake(&self) -> &Handshake {
+        &self.handshake
+    }
+
+    pub async fn open_stream(&mut self) -> Result<ProstClientStream<QuicStream>> {
+        let (send, recv) = self.conn.open_bi().await?;
+        Ok(ProstClientStream::new(QuicStream { send, recv }).with_options(self.options.clone()))
+    }
+}
+
+async fn resolve(addr: &str) -> Result<SocketAddr> {
+    tokio::net::lookup_host(addr).await?.next().ok_or_else(|| KvError::Invalid(format!("invalid address {}", addr)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use crate::{network::tls::tests::{generate_ca, generate_cert}, CommandRequest, FrameOptions, MemoryDb, ServiceInner, TlsClientConnector, TlsServerAcceptor};
+
+    use super::{QuicCtrl, QuicListener};
+
+    async fn start() -> (String, String) {
+        let ca = generate_ca();
+        let (cert, key) = generate_cert(&ca, "kvserver.acme.inc");
+        let acceptor = TlsServerAcceptor::new(&cert, &key, None).unwrap();
+        let listener = QuicListener::bind("127.0.0.1:0", acceptor).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(crate::serve_quic_listener(listener, ServiceInner::new(MemoryDb::new()).service()));
+        (addr, ca.serialize_pem().unwrap())
+    }
+
+    #[tokio::test]
+    async fn quic_should_work() {
+        let (addr, ca) = start().await;
+        let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(&ca)).unwrap();
+        let mut ctrl = QuicCtrl::connect(&addr, &connector, FrameOptions::default()).await.unwrap();
+        assert_eq!(ctrl.handshake().error, "");
+
+        let mut stream = ctrl.open_stream().await.unwrap();
+        let res = stream.execute_unary(&CommandRequest::new_hset("t1", "k1", "v1".into())).await.unwrap();
+        assert_eq!(res.state_code, 200);
+        let res = stream.execute_unary(&CommandRequest::new_hget("t1", "k1")).await.unwrap();
+        assert_eq!(res.values, vec!["v1".into()]);
+
+        // 每个 stream 独立，订阅和发布在不同的 stream 上
+        let subscriber = ctrl.open_stream().await.unwrap();
+        let mut messages = subscriber.execute_streaming(&CommandRequest::subscribe("lobby")).await.unwrap();
+        let mut publisher = ctrl.open_stream().await.unwrap();
+        publisher.execute_unary(&CommandRequest::publish("lobby", vec!["hello".into()])).await.unwrap();
+        let res = tokio::time::timeout(Duration::from_secs(1), messages.next()).await.unwrap().unwrap().unwrap();
+        assert_eq!(res.values, vec!["hello".into()]);
+    }
+
+    #[tokio::test]
+    async fn quic_should_reject_untrusted_server() {
+        let (addr, _) = start().await;
+        let other = generate_ca().serialize_pem().unwrap();
+        let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(&other)).unwrap();
+        assert!(QuicCtrl::connect(&addr, &connector, FrameOptions::default()).await.is_err());
+    }
+}
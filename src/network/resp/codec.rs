@@ -0,0 +1,281 @@
+use bytes::{Buf, BufMut, BytesMut};
+
+use crate::{KvError, Result};
+
+// 一个命令最多包含的参数个数
+const MAX_ARGS: usize = 1024 * 1024;
+
+// 回复给客户端的值，RESP2 下 null、map 和 push 会转换成兼容的表示
+#[derive(Debug, Clone, PartialEq)]
+pub enum RespValue {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Null,
+    Array(Vec<RespValue>),
+    Map(Vec<(RespValue, RespValue)>),
+    Push(Vec<RespValue>),
+}
+
+impl RespValue {
+    pub fn ok() -> Self {
+        Self::Simple("OK".into())
+    }
+
+    pub fn error(msg: impl Into<String>) -> Self {
+        Self::Error(msg.into())
+    }
+
+    pub fn bulk(data: impl AsRef<[u8]>) -> Self {
+        Self::Bulk(data.as_ref().to_vec())
+    }
+
+    // resp3 为 false 时按 RESP2 编码
+    pub fn encode(&self, buf: &mut BytesMut, resp3: bool) {
+        match self {
+            Self::Simple(s) => put_line(buf, b'+', s),
+            Self::Error(s) => put_line(buf, b'-', s),
+            Self::Integer(n) => put_line(buf, b':', n),
+            Self::Bulk(data) => {
+                put_line(buf, b'$', data.len());
+                buf.put_slice(data);
+                buf.put_slice(b"\r\n");
+            },
+            Self::Null if resp3 => buf.put_slice(b"_\r\n"),
+            Self::Null => buf.put_slice(b"$-1\r\n"),
+            Self::Array(items) => put_items(buf, b'*', items, resp3),
+            Self::Push(items) => put_items(buf, if resp3 { b'>' } else { b'*' }, items, resp3),
+            Self::Map(pairs) => {
+                if resp3 {
+                    put_line(buf, b'%', pairs.len());
+                } else {
+                    put_line(buf, b'*', pairs.len() * 2);
+                }
+                for (k, v) in pairs {
+                    k.encode(buf, resp3);
+                    v.encode(buf, resp3);
+                }
+            },
+        }
+    }
+}
+
+fn put_line(buf: &mut BytesMut, prefix: u8, s: impl ToString) {
+    buf.put_u8(prefix);
+    buf.put_slice(s.to_string().as_bytes());
+    buf.put_slice(b"\r\n");
+}
+
+fn put_items(buf: &mut BytesMut, prefix: u8, items: &[RespValue], resp3: bool) {
+    put_line(buf, prefix, items.len());
+    for item in items {
+        item.encode(buf, resp3);
+    }
+}
+
+fn protocol_error(msg: impl AsRef<str>) -> KvError {
+    KvError::Invalid(format!("Protocol error: {}", msg.as_ref()))
+}
+
+fn parse_len(line: &[u8], limit: usize) -> Result<usize> {
+    let n = std::str::from_utf8(line)
+        .ok()
+        .and_then(|x| x.parse::<i64>().ok())
+        .ok_or_else(|| protocol_error("invalid length"))?;
+    match usize::try_from(n) {
+        Ok(n) if n <= limit => Ok(n),
+        _ => Err(protocol_error(format!("invalid length {}", n))),
+    }
+}
+
+fn strip_cr(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+// 增量解析命令（bulk string 数组或者 inline 命令），解析出的参数立即从 buf 中移除，
+// 数据不完整时保留状态，读到更多数据后从上次停下的位置继续，不会重复扫描已经收到的数据
+// limit 为单个命令（包括所有参数）的最大长度，超过时返回协议错误
+#[derive(Debug)]
+pub struct CommandDecoder {
+    limit: usize,
+    // bulk string 数组剩余的参数个数以及已经解析出的参数
+    pending: Option<(usize, Vec<Vec<u8>>)>,
+    // 当前命令已经从 buf 中移除的字节数
+    consumed: usize,
+    // buf 中已经查找过换行符的位置
+    scanned: usize,
+}
+
+impl CommandDecoder {
+    pub fn new(limit: usize) -> Self {
+        Self { limit, pending: None, consumed: 0, scanned: 0 }
+    }
+
+    pub fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Vec<Vec<u8>>>> {
+        loop {
+            let Some(end) = self.find_line(buf)? else {
+                return Ok(None);
+            };
+
+            let Some((remaining, args)) = self.pending.as_mut() else {
+                let line = strip_cr(&buf[..end]);
+                if let Some(n) = line.strip_prefix(b"*") {
+                    let n = parse_len(n, MAX_ARGS)?;
+                    self.pending = Some((n, Vec::with_capacity(n.min(64))));
+                    self.advance(buf, end + 1);
+                    if n == 0 {
+                        return Ok(self.finish());
+                    }
+                    continue;
+                }
+
+                let args = line
+                    .split(|x| x.is_ascii_whitespace())
+                    .filter(|x| !x.is_empty())
+                    .map(|x| x.to_vec())
+                    .collect();
+                self.advance(buf, end + 1);
+                self.finish();
+                return Ok(Some(args));
+            };
+
+            let len = match strip_cr(&buf[..end]).strip_prefix(b"$") {
+                Some(len) => parse_len(len, self.limit)?,
+                None => return Err(protocol_error("expected '$'")),
+            };
+            // 按声明的长度提前检查，不用等数据全部到达
+            let size = end + 1 + len + 2;
+            if self.consumed + size > self.limit {
+                return Err(protocol_error("too big request"));
+            }
+            if buf.len() < size {
+                return Ok(None);
+            }
+            if &buf[size - 2..size] != b"\r\n" {
+                return Err(protocol_error("invalid bulk length"));
+            }
+            args.push(buf[end + 1..size - 2].to_vec());
+            *remaining -= 1;
+            let done = *remaining == 0;
+            self.advance(buf, size);
+            if done {
+                return Ok(self.finish());
+            }
+        }
+    }
+
+    // 返回下一行换行符的位置，inline 命令允许只用 \n 换行
+    fn find_line(&mut self, buf: &BytesMut) -> Result<Option<usize>> {
+        match buf[self.scanned..].iter().position(|x| *x == b'\n') {
+            Some(n) => {
+                self.scanned += n;
+                Ok(Some(self.scanned))
+            },
+            None if self.consumed + buf.len() > self.limit => Err(protocol_error("too big request")),
+            None => {
+                self.scanned = buf.len();
+                Ok(None)
+            },
+        }
+    }
+
+    fn advance(&mut self, buf: &mut BytesMut, n: usize) {
+        buf.advance(n);
+        self.consumed += n;
+        self.scanned = 0;
+    }
+
+    fn finish(&mut self) -> Option<Vec<Vec<u8>>> {
+        self.consumed = 0;
+        self.pending.take().map(|(_, args)| args)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use bytes::BytesMut;
+
+    use crate::KvError;
+
+    use super::{CommandDecoder, RespValue};
+
+    fn parse_command(buf: &mut BytesMut, limit: usize) -> crate::Result<Option<Vec<Vec<u8>>>> {
+        CommandDecoder::new(limit).decode(buf)
+    }
+
+    fn encode(value: RespValue, resp3: bool) -> String {
+        let mut buf = BytesMut::new();
+        value.encode(&mut buf, resp3);
+        String::from_utf8(buf.to_vec()).unwrap()
+    }
+
+    #[test]
+    fn parse_command_should_work() {
+        let mut buf = BytesMut::from(&b"*3\r\n$4\r\nHGET\r\n$2\r\nt1\r\n$0\r\n\r\nPING hello\r\nQUIT\n"[..]);
+        assert_eq!(parse_command(&mut buf, 1024).unwrap().unwrap(), vec![b"HGET".to_vec(), b"t1".to_vec(), vec![]]);
+        assert_eq!(parse_command(&mut buf, 1024).unwrap().unwrap(), vec![b"PING".to_vec(), b"hello".to_vec()]);
+        assert_eq!(parse_command(&mut buf, 1024).unwrap().unwrap(), vec![b"QUIT".to_vec()]);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn parse_partial_command_should_resume() {
+        let data = b"*2\r\n$4\r\nECHO\r\n$12\r\nhello\r\nworld\r\nPING\r\n";
+        let command = data.len() - "PING\r\n".len();
+
+        // 每次只收到一个字节，解析状态在多次调用之间保留
+        let mut decoder = CommandDecoder::new(1024);
+        let mut buf = BytesMut::new();
+        for (i, x) in data.iter().enumerate() {
+            buf.extend_from_slice(&[*x]);
+            match decoder.decode(&mut buf).unwrap() {
+                Some(args) if i == command - 1 => assert_eq!(args, vec![b"ECHO".to_vec(), b"hello\r\nworld".to_vec()]),
+                Some(args) if i == data.len() - 1 => assert_eq!(args, vec![b"PING".to_vec()]),
+                res => assert!(res.is_none(), "unexpected command at {}", i),
+            }
+        }
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn parse_invalid_command_should_fail() {
+        let cases: [&[u8]; 4] = [b"*1\r\n:1\r\n", b"*1\r\n$-1\r\n", b"*1\r\n$2\r\nabc\r\n", b"*x\r\n"];
+        for data in cases {
+            let res = parse_command(&mut BytesMut::from(data), 1024);
+            assert!(matches!(res, Err(KvError::Invalid(_))));
+        }
+
+        // 超过长度限制
+        let res = parse_command(&mut BytesMut::from(&b"*1\r\n$2000\r\n"[..]), 1024);
+        assert!(matches!(res, Err(KvError::Invalid(_))));
+        let res = parse_command(&mut BytesMut::from(&[b'a'; 2000][..]), 1024);
+        assert!(matches!(res, Err(KvError::Invalid(_))));
+
+        // 每个参数都没有超过限制，但整个命令超过
+        let mut decoder = CommandDecoder::new(1024);
+        let mut buf = BytesMut::from(&b"*100\r\n"[..]);
+        let mut res = Ok(None);
+        for _ in 0..100 {
+            buf.extend_from_slice(format!("$100\r\n{}\r\n", "a".repeat(100)).as_bytes());
+            res = decoder.decode(&mut buf);
+            if res.is_err() {
+                break;
+            }
+        }
+        assert!(matches!(res, Err(KvError::Invalid(_))));
+        assert!(buf.len() < 1024);
+    }
+
+    #[test]
+    fn encode_should_work() {
+        let value = RespValue::Map(vec![(RespValue::bulk("k1"), RespValue::Null), (RespValue::bulk("k2"), RespValue::Integer(1))]);
+        assert_eq!(encode(value.clone(), false), "*4\r\n$2\r\nk1\r\n$-1\r\n$2\r\nk2\r\n:1\r\n");
+        assert_eq!(encode(value, true), "%2\r\n$2\r\nk1\r\n_\r\n$2\r\nk2\r\n:1\r\n");
+
+        let value = RespValue::Push(vec![RespValue::ok(), RespValue::error("ERR oops"), RespValue::Integer(-1), RespValue::Array(vec![])]);
+        assert_eq!(encode(value.clone(), false), "*4\r\n+OK\r\n-ERR oops\r\n:-1\r\n*0\r\n");
+        assert_eq!(encode(value, true), ">4\r\n+OK\r\n-ERR oops\r\n:-1\r\n*0\r\n");
+    }
+}
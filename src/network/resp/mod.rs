@@ -0,0 +1,568 @@
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use bytes::BytesMut;
+use futures::StreamExt;
+use tokio::{io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt}, sync::mpsc::{self, error::TrySendError}};
+use tokio_util::sync::CancellationToken;
+use tracing::log::warn;
+
+use crate::{pb::{value, CommandRequest, CommandResponse, KvPair, Value}, service::{Service, Session}, storage::Storage, Result};
+
+use self::codec::{CommandDecoder, RespValue};
+
+mod codec;
+
+const READ_BUF_SIZE: usize = 8 * 1024;
+// 每个连接最多缓存这么多条还没有写出的订阅消息，超过后关闭连接，与 Redis 的 pubsub 输出缓冲区限制一致
+const RESP_SUBSCRIPTION_BUFFER: usize = 1024;
+
+// 把 Redis 协议（RESP2/RESP3）的命令转换成 CommandRequest 交给同一个 Service 处理，再把响应转换回 RESP
+// 同一个连接上的命令按顺序处理，订阅的消息通过 channel 转发
+pub struct RespServerStream<S, Store> {
+    stream: S,
+    service: Service<Store>,
+    session: Session,
+    rbuf: BytesMut,
+    wbuf: BytesMut,
+    resp3: bool,
+    // 订阅的 topic 以及 service 分配的订阅 id
+    subscriptions: HashMap<String, u32>,
+    tx: mpsc::Sender<(String, Arc<CommandResponse>)>,
+    rx: mpsc::Receiver<(String, Arc<CommandResponse>)>,
+    // 客户端读取太慢，订阅消息的缓冲区已满
+    lagged: CancellationToken,
+}
+
+impl<S, Store> RespServerStream<S, Store>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+    Store: Storage,
+{
+    pub fn new(stream: S, service: Service<Store>) -> Self {
+        let (tx, rx) = mpsc::channel(RESP_SUBSCRIPTION_BUFFER);
+        Self {
+            stream,
+            service,
+            session: Session::default(),
+            rbuf: BytesMut::new(),
+            wbuf: BytesMut::new(),
+            resp3: false,
+            subscriptions: HashMap::new(),
+            tx,
+            rx,
+            lagged: CancellationToken::new(),
+        }
+    }
+
+    pub fn with_session(mut self, session: Session) -> Self {
+        self.session = session;
+        self
+    }
+
+    pub fn with_subscription_buffer(mut self, capacity: usize) -> Self {
+        (self.tx, self.rx) = mpsc::channel(capacity.max(1));
+        self
+    }
+
+    pub async fn process(mut self) -> Result<()> {
+        // 写回复时也可能因为客户端不读取而阻塞，所以在外层等待
+        let lagged = self.lagged.clone();
+        let res = tokio::select! {
+            res = self.run() => res,
+            _ = lagged.cancelled() => {
+                warn!("Subscriber {} is too slow, closing the connection", self.session.peer);
+                Ok(())
+            },
+        };
+        // 连接关闭时取消所有订阅
+        for (topic, id) in std::mem::take(&mut self.subscriptions) {
+            self.execute(CommandRequest::unsubscribe(topic, id)).await;
+        }
+        res
+    }
+
+    async fn run(&mut self) -> Result<()> {
+        let shutdown = self.service.shutdown_handle();
+        // 整个命令不能超过最大帧长度
+        let mut decoder = CommandDecoder::new(self.service.frame_options().max_frame_size);
+        loop {
+            loop {
+                let args = match decoder.decode(&mut self.rbuf) {
+                    Ok(Some(args)) => args,
+                    Ok(None) => break,
+                    // 协议错误时返回错误并关闭连接
+                    Err(e) => {
+                        warn!("Invalid RESP request from {}: {}", self.session.peer, e);
+                        self.write(RespValue::error(format!("ERR {}", e))).await?;
+                        return Ok(());
+                    },
+                };
+                if args.is_empty() {
+                    continue;
+                }
+
+                // AUTH 失败次数达到上限后关闭连接
+                if args[0].eq_ignore_ascii_case(b"auth") && self.session.auth_locked() {
+                    warn!("Too many failed authentication attempts from {}", self.session.peer);
+                    self.write(RespValue::error("WRONGPASS too many failed authentication attempts")).await?;
+                    return Ok(());
+                }
+
+                let _guard = shutdown.enter();
+                let quit = args[0].eq_ignore_ascii_case(b"quit");
+                let reply = self.handle(args).await;
+                for value in reply {
+                    value.encode(&mut self.wbuf, self.resp3);
+                }
+                if quit {
+                    self.flush().await?;
+                    return Ok(());
+                }
+            }
+            self.flush().await?;
+
+            self.rbuf.reserve(READ_BUF_SIZE);
+            tokio::select! {
+                n = self.stream.read_buf(&mut self.rbuf) => {
+                    if n? == 0 {
+                        return Ok(());
+                    }
+                },
+                Some((topic, res)) = self.rx.recv() => self.forward(topic, &res),
+                _ = shutdown.wait() => return Ok(()),
+            }
+        }
+    }
+
+    async fn write(&mut self, value: RespValue) -> Result<()> {
+        value.encode(&mut self.wbuf, self.resp3);
+        self.flush().await
+    }
+
+    async fn flush(&mut self) -> Result<()> {
+        if !self.wbuf.is_empty() {
+            self.stream.write_all(&self.wbuf).await?;
+            self.stream.flush().await?;
+            self.wbuf.clear();
+        }
+        Ok(())
+    }
+
+    // 只取第一个响应，Service panic 或者没有响应时返回 500
+    fn execute(&self, cmd: CommandRequest) -> impl Future<Output = Arc<CommandResponse>> + Send + 'static {
+        let mut stream = self.service.execute_with_session(cmd, &self.session);
+        async move {
+            match stream.next().await {
+                Some(res) => res,
+                None => Arc::new(CommandResponse { state_code: 500, msg: "no response".into(), ..Default::default() }),
+            }
+        }
+    }
+
+    // 把订阅收到的消息按 RESP 的格式转发，每个 value 是一条消息
+    fn forward(&mut self, topic: String, res: &CommandResponse) {
+        if res.exit {
+            return;
+        }
+        for value in res.values.iter() {
+            let msg = RespValue::Push(vec![RespValue::bulk("message"), RespValue::bulk(&topic), to_resp(value)]);
+            msg.encode(&mut self.wbuf, self.resp3);
+        }
+    }
+
+    // 返回一个或多个回复，SUBSCRIBE 和 UNSUBSCRIBE 对每个 topic 回复一次
+    // value 使用原始的字节，其他参数转换成字符串
+    async fn handle(&mut self, raw: Vec<Vec<u8>>) -> Vec<RespValue> {
+        let name = String::from_utf8_lossy(&raw[0]).to_ascii_uppercase();
+        let raw = &raw[1..];
+        let args = raw.iter().map(|x| String::from_utf8_lossy(x).into_owned()).collect::<Vec<_>>();
+
+        // RESP2 下订阅后只允许订阅相关的命令
+        let allowed = ["SUBSCRIBE", "UNSUBSCRIBE", "PING", "QUIT", "RESET"];
+        if !self.resp3 && !self.subscriptions.is_empty() && !allowed.contains(&name.as_str()) {
+            return vec![RespValue::error(format!("ERR Can't execute '{}' in subscribed mode", name.to_lowercase()))];
+        }
+
+        match (name.as_str(), args.as_slice()) {
+            ("PING", []) if !self.subscriptions.is_empty() && !self.resp3 => {
+                vec![RespValue::Array(vec![RespValue::bulk("pong"), RespValue::bulk("")])]
+            },
+            ("PING", []) => vec![RespValue::Simple("PONG".into())],
+            ("PING" | "ECHO", [msg]) => vec![RespValue::bulk(msg)],
+            ("QUIT" | "SELECT" | "RESET" | "CLIENT", _) => vec![RespValue::ok()],
+            // redis-cli 启动时会查询命令文档
+            ("COMMAND", _) => vec![RespValue::Array(vec![])],
+            ("HELLO", _) => vec![self.hello(&args).await],
+            ("AUTH", [password]) => vec![self.auth("", password).await],
+            ("AUTH", [username, password]) => vec![self.auth(username, password).await],
+            ("HGET", [table, key]) => {
+                let res = self.execute(CommandRequest::new_hget(table, key)).await;
+                match res.state_code {
+                    404 => vec![RespValue::Null],
+                    _ => vec![value_reply(&res)],
+                }
+            },
+            ("HMGET", [table, keys @ ..]) if !keys.is_empty() => {
+                let res = self.execute(CommandRequest::new_hmget(table, keys.to_vec())).await;
+                vec![reply(&res, || RespValue::Array(res.values.iter().map(to_resp).collect()))]
+            },
+            ("HSET" | "HMSET", [table, pairs @ ..]) if !pairs.is_empty() && pairs.len() % 2 == 0 => {
+                let pairs = pairs
+                    .chunks(2)
+                    .zip(raw[1..].chunks(2))
+                    .map(|(x, raw)| KvPair::from((x[0].clone(), from_bytes(&raw[1]))))
+                    .collect::<Vec<_>>();
+                let res = self.execute(CommandRequest::new_hmset(table, pairs)).await;
+                let added = res.values.iter().filter(|x| x.value.is_none()).count();
+                match name.as_str() {
+                    "HSET" => vec![reply(&res, || RespValue::Integer(added as _))],
+                    _ => vec![reply(&res, RespValue::ok)],
+                }
+            },
+            ("HDEL", [table, keys @ ..]) if !keys.is_empty() => {
+                let res = self.execute(CommandRequest::new_hmdelete(table, keys.to_vec())).await;
+                let deleted = res.values.iter().filter(|x| x.value.is_some()).count();
+                vec![reply(&res, || RespValue::Integer(deleted as _))]
+            },
+            ("HEXISTS", [table, key]) => {
+                let res = self.execute(CommandRequest::new_hexists(table, key)).await;
+                let exists = matches!(res.values.first(), Some(Value { value: Some(value::Value::Bool(true)) }));
+                vec![reply(&res, || RespValue::Integer(exists as _))]
+            },
+            ("HGETALL" | "HKEYS" | "HVALS" | "HLEN", [table]) => {
+                let res = self.execute(CommandRequest::new_hget_all(table)).await;
+                let pairs = res.pairs.iter();
+                vec![reply(&res, || match name.as_str() {
+                    "HGETALL" => RespValue::Map(pairs.map(|x| (RespValue::bulk(&x.key), x.value.as_ref().map_or(RespValue::Null, to_resp))).collect()),
+                    "HKEYS" => RespValue::Array(pairs.map(|x| RespValue::bulk(&x.key)).collect()),
+                    "HVALS" => RespValue::Array(pairs.map(|x| x.value.as_ref().map_or(RespValue::Null, to_resp)).collect()),
+                    _ => RespValue::Integer(res.pairs.len() as _),
+                })]
+            },
+            ("PUBLISH", [topic, _]) => {
+                let res = self.execute(CommandRequest::publish(topic, vec![from_bytes(&raw[1])])).await;
+                // 消息异步投递，无法得到接收者的数量
+                vec![reply(&res, || RespValue::Integer(0))]
+            },
+            ("SUBSCRIBE", topics) if !topics.is_empty() => {
+                let mut replies = vec![];
+                for topic in topics {
+                    replies.push(self.subscribe(topic).await);
+                }
+                replies
+            },
+            ("UNSUBSCRIBE", topics) => {
+                let topics = match topics {
+                    [] => self.subscriptions.keys().cloned().collect(),
+                    _ => topics.to_vec(),
+                };
+                if topics.is_empty() {
+                    return vec![RespValue::Push(vec![RespValue::bulk("unsubscribe"), RespValue::Null, RespValue::Integer(0)])];
+                }
+                let mut replies = vec![];
+                for topic in topics {
+                    replies.push(self.unsubscribe(&topic).await);
+                }
+                replies
+            },
+            (name, _) if KNOWN_COMMANDS.contains(&name) => {
+                vec![RespValue::error(format!("ERR wrong number of arguments for '{}' command", name.to_lowercase()))]
+            },
+            (name, _) => vec![RespValue::error(format!("ERR unknown command '{}'", name.to_lowercase()))],
+        }
+    }
+
+    // HELLO [protover [AUTH username password] [SETNAME name]]
+    async fn hello(&mut self, args: &[String]) -> RespValue {
+        let resp3 = match args.first().map(|x| x.as_str()) {
+            None => self.resp3,
+            Some("2") => false,
+            Some("3") => true,
+            Some(_) => return RespValue::error("NOPROTO unsupported protocol version"),
+        };
+        if let Some(i) = args.iter().position(|x| x.eq_ignore_ascii_case("auth")) {
+            let [username, password] = match args.get(i + 1..i + 3) {
+                Some([username, password]) => [username, password],
+                _ => return RespValue::error("ERR syntax error in HELLO option 'auth'"),
+            };
+            let res = self.auth(username, password).await;
+            if matches!(res, RespValue::Error(_)) {
+                return res;
+            }
+        }
+
+        self.resp3 = resp3;
+        RespValue::Map(vec![
+            (RespValue::bulk("server"), RespValue::bulk("kvserver")),
+            (RespValue::bulk("version"), RespValue::bulk(env!("CARGO_PKG_VERSION"))),
+            (RespValue::bulk("proto"), RespValue::Integer(if resp3 { 3 } else { 2 })),
+            (RespValue::bulk("mode"), RespValue::bulk("standalone")),
+            (RespValue::bulk("role"), RespValue::bulk("master")),
+            (RespValue::bulk("modules"), RespValue::Array(vec![])),
+        ])
+    }
+
+    async fn auth(&mut self, username: &str, password: &str) -> RespValue {
+        // Redis 的默认用户相当于使用 token 认证
+        let username = if username == "default" { "" } else { username };
+        let res = self.execute(CommandRequest::new_auth(username, password)).await;
+        match res.state_code {
+            200 => RespValue::ok(),
+            _ => RespValue::error(format!("WRONGPASS {}", res.msg)),
+        }
+    }
+
+    async fn subscribe(&mut self, topic: &str) -> RespValue {
+        if !self.subscriptions.contains_key(topic) {
+            let mut stream = self.service.execute_with_session(CommandRequest::subscribe(topic), &self.session);
+            let res = stream.next().await.unwrap_or_default();
+            let id = match res.values.first().map(i64::try_from) {
+                Some(Ok(id)) if res.state_code == 200 => id as u32,
+                _ => return error_reply(&res),
+            };
+            self.subscriptions.insert(topic.into(), id);
+
+            // 收到 exit（取消订阅或者服务关闭）后结束转发，缓冲区满时通知连接关闭
+            let tx = self.tx.clone();
+            let lagged = self.lagged.clone();
+            let topic = topic.to_string();
+            tokio::spawn(async move {
+                while let Some(res) = stream.next().await {
+                    if res.exit {
+                        break;
+                    }
+                    match tx.try_send((topic.clone(), res)) {
+                        Ok(_) => {},
+                        Err(TrySendError::Full(_)) => {
+                            lagged.cancel();
+                            break;
+                        },
+                        Err(TrySendError::Closed(_)) => break,
+                    }
+                }
+            });
+        }
+        RespValue::Push(vec![RespValue::bulk("subscribe"), RespValue::bulk(topic), RespValue::Integer(self.subscriptions.len() as _)])
+    }
+
+    async fn unsubscribe(&mut self, topic: &str) -> RespValue {
+        if let Some(id) = self.subscriptions.remove(topic) {
+            let res = self.execute(CommandRequest::unsubscribe(topic, id)).await;
+            if res.state_code != 200 {
+                warn!("Failed to unsubscribe {} for {}: {}", topic, self.session.peer, res.msg);
+            }
+        }
+        RespValue::Push(vec![RespValue::bulk("unsubscribe"), RespValue::bulk(topic), RespValue::Integer(self.subscriptions.len() as _)])
+    }
+}
+
+const KNOWN_COMMANDS: [&str; 17] = [
+    "PING", "ECHO", "HELLO", "AUTH", "HGET", "HMGET", "HSET", "HMSET", "HDEL", "HEXISTS",
+    "HGETALL", "HKEYS", "HVALS", "HLEN", "PUBLISH", "SUBSCRIBE", "UNSUBSCRIBE",
+];
+
+// 非 200 的响应转换成错误，否则使用 f 生成回复
+fn reply(res: &CommandResponse, f: impl FnOnce() -> RespValue) -> RespValue {
+    match res.state_code {
+        200 => f(),
+        _ => error_reply(res),
+    }
+}
+
+fn error_reply(res: &CommandResponse) -> RespValue {
+    let prefix = match res.state_code {
+        401 => "NOAUTH",
+        403 => "NOPERM",
+        _ => "ERR",
+    };
+    RespValue::error(format!("{} {}", prefix, res.msg))
+}
+
+fn value_reply(res: &CommandResponse) -> RespValue {
+    reply(res, || res.values.first().map_or(RespValue::Null, to_resp))
+}
+
+// 所有的值都以 bulk string 返回，与 Redis 的 hash 保持一致
+fn to_resp(value: &Value) -> RespValue {
+    match &value.value {
+        None => RespValue::Null,
+        Some(value::Value::String(s)) => RespValue::bulk(s),
+        Some(value::Value::Binary(data)) => RespValue::bulk(data),
+        Some(value::Value::Integer(n)) => RespValue::bulk(n.to_string()),
+        Some(value::Value::Float(x)) => RespValue::bulk(x.to_string()),
+        Some(value::Value::Bool(b)) => RespValue::bulk(b.to_string()),
+        Some(value::Value::Json(s)) => RespValue::bulk(s),
+    }
+}
+
+// 合法的 utf8 保存为字符串，否则保存为 binary
+fn from_bytes(data: &[u8]) -> Value {
+    match std::str::from_utf8(data) {
+        Ok(s) => s.into(),
+        Err(_) => data.to_vec().into(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    use crate::{Acl, Authenticator, FrameOptions, MemoryDb, Permission, Service, ServiceInner, Session};
+
+    use super::RespServerStream;
+
+    fn start(service: Service) -> DuplexStream {
+        let (client, server) = duplex(4096);
+        tokio::spawn(RespServerStream::new(server, service).with_session(Session::new("test", None)).process());
+        client
+    }
+
+    // 发送命令并读取指定长度的回复
+    async fn call(client: &mut DuplexStream, cmd: &str, expected: &str) {
+        client.write_all(cmd.as_bytes()).await.unwrap();
+        let mut buf = vec![0; expected.len()];
+        tokio::time::timeout(Duration::from_secs(1), client.read_exact(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(String::from_utf8_lossy(&buf), expected, "command: {:?}", cmd);
+    }
+
+    #[tokio::test]
+    async fn resp_hash_commands_should_work() {
+        let mut client = start(ServiceInner::new(MemoryDb::new()).service());
+
+        call(&mut client, "PING\r\n", "+PONG\r\n").await;
+        call(&mut client, "*4\r\n$4\r\nHSET\r\n$2\r\nt1\r\n$2\r\nk1\r\n$2\r\nv1\r\n", ":1\r\n").await;
+        call(&mut client, "HSET t1 k1 v2 k2 v3\r\n", ":1\r\n").await;
+        call(&mut client, "HGET t1 k1\r\n", "$2\r\nv2\r\n").await;
+        call(&mut client, "HGET t1 k9\r\n", "$-1\r\n").await;
+        call(&mut client, "HMGET t1 k1 k9\r\n", "*2\r\n$2\r\nv2\r\n$-1\r\n").await;
+        call(&mut client, "HEXISTS t1 k2\r\n", ":1\r\n").await;
+        call(&mut client, "HLEN t1\r\n", ":2\r\n").await;
+        call(&mut client, "HDEL t1 k2 k9\r\n", ":1\r\n").await;
+        call(&mut client, "HGETALL t1\r\n", "*2\r\n$2\r\nk1\r\n$2\r\nv2\r\n").await;
+        call(&mut client, "HMSET t1 k3\r\n", "-ERR wrong number of arguments for 'hmset' command\r\n").await;
+        call(&mut client, "FLUSHALL\r\n", "-ERR unknown command 'flushall'\r\n").await;
+
+        // 切换到 RESP3 后 map 和 null 使用新的类型
+        client.write_all(b"HELLO 3\r\n").await.unwrap();
+        let mut buf = [0u8; 2];
+        client.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"%6");
+        let mut rest = vec![0u8; 1024];
+        let n = client.read(&mut rest).await.unwrap();
+        assert!(String::from_utf8_lossy(&rest[..n]).contains("kvserver"));
+        call(&mut client, "HGETALL t1\r\n", "%1\r\n$2\r\nk1\r\n$2\r\nv2\r\n").await;
+        call(&mut client, "HGET t1 k9\r\n", "_\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn resp_pubsub_should_work() {
+        let service = ServiceInner::new(MemoryDb::new()).service();
+        let mut subscriber = start(service.clone());
+        let mut publisher = start(service);
+
+        call(&mut subscriber, "SUBSCRIBE lobby\r\n", "*3\r\n$9\r\nsubscribe\r\n$5\r\nlobby\r\n:1\r\n").await;
+        call(&mut subscriber, "HGET t1 k1\r\n", "-ERR Can't execute 'hget' in subscribed mode\r\n").await;
+        call(&mut publisher, "PUBLISH lobby hello\r\n", ":0\r\n").await;
+
+        let expected = "*3\r\n$7\r\nmessage\r\n$5\r\nlobby\r\n$5\r\nhello\r\n";
+        let mut buf = vec![0; expected.len()];
+        tokio::time::timeout(Duration::from_secs(1), subscriber.read_exact(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(String::from_utf8_lossy(&buf), expected);
+
+        call(&mut subscriber, "UNSUBSCRIBE\r\n", "*3\r\n$11\r\nunsubscribe\r\n$5\r\nlobby\r\n:0\r\n").await;
+        call(&mut subscriber, "HGET t1 k1\r\n", "$-1\r\n").await;
+    }
+
+    #[tokio::test]
+    async fn resp_slow_subscriber_should_be_disconnected() {
+        let service = ServiceInner::new(MemoryDb::new()).service();
+        let (mut subscriber, server) = duplex(1024);
+        let stream = RespServerStream::new(server, service.clone()).with_subscription_buffer(2);
+        tokio::spawn(stream.with_session(Session::new("test", None)).process());
+        let mut publisher = start(service);
+
+        call(&mut subscriber, "SUBSCRIBE lobby\r\n", "*3\r\n$9\r\nsubscribe\r\n$5\r\nlobby\r\n:1\r\n").await;
+        // 订阅者不读取，消息先填满 duplex 再填满订阅缓冲区
+        let data = "a".repeat(512);
+        for _ in 0..20 {
+            call(&mut publisher, &format!("PUBLISH lobby {}\r\n", data), ":0\r\n").await;
+        }
+
+        let mut buf = vec![];
+        tokio::time::timeout(Duration::from_secs(1), subscriber.read_to_end(&mut buf)).await.unwrap().unwrap();
+        assert!(buf.len() < 20 * data.len());
+    }
+
+    // 发送命令并读取一行回复
+    async fn call_line(client: &mut DuplexStream, cmd: &str) -> String {
+        client.write_all(cmd.as_bytes()).await.unwrap();
+        let mut line = vec![];
+        while !line.ends_with(b"\r\n") {
+            line.push(client.read_u8().await.unwrap());
+        }
+        String::from_utf8(line).unwrap()
+    }
+
+    #[tokio::test]
+    async fn resp_auth_and_acl_should_work() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .with_auth(Authenticator::new().token("ci", crate::hash_token("t0ken")))
+            .with_acl(Acl::default().rule("ci", &["t1"], &[], &[Permission::Read, Permission::Write]))
+            .service();
+        let mut client = start(service);
+
+        assert!(call_line(&mut client, "HGET t1 k1\r\n").await.starts_with("-NOAUTH"));
+        assert!(call_line(&mut client, "AUTH wrong\r\n").await.starts_with("-WRONGPASS"));
+        call(&mut client, "AUTH t0ken\r\n", "+OK\r\n").await;
+        call(&mut client, "HSET t1 k1 v1\r\n", ":1\r\n").await;
+        assert!(call_line(&mut client, "HSET t2 k1 v1\r\n").await.starts_with("-NOPERM"));
+    }
+
+    #[tokio::test]
+    async fn resp_too_many_auth_failures_should_close_connection() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .with_auth(Authenticator::new().token("ci", crate::hash_token("t0ken")))
+            .service();
+        let mut client = start(service);
+
+        for _ in 0..crate::service::MAX_AUTH_FAILURES {
+            assert!(call_line(&mut client, "AUTH wrong\r\n").await.starts_with("-WRONGPASS"));
+        }
+        client.write_all(b"AUTH t0ken\r\n").await.unwrap();
+        let mut buf = String::new();
+        client.read_to_string(&mut buf).await.unwrap();
+        assert_eq!(buf, "-WRONGPASS too many failed authentication attempts\r\n");
+    }
+
+    #[tokio::test]
+    async fn resp_protocol_error_should_close_connection() {
+        let mut client = start(ServiceInner::new(MemoryDb::new()).service());
+        client.write_all(b"*1\r\n$x\r\n").await.unwrap();
+
+        let mut buf = String::new();
+        client.read_to_string(&mut buf).await.unwrap();
+        assert!(buf.starts_with("-ERR Protocol error"));
+    }
+
+    #[tokio::test]
+    async fn resp_oversized_command_should_close_connection() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .with_frame_options(FrameOptions::default().with_max_frame_size(1024))
+            .service();
+        let mut client = start(service);
+
+        // 每个参数都小于限制，但整个命令超过限制
+        let mut cmd = b"*100000\r\n$4\r\nHSET\r\n".to_vec();
+        for _ in 0..20 {
+            cmd.extend_from_slice(format!("$100\r\n{}\r\n", "a".repeat(100)).as_bytes());
+        }
+        client.write_all(&cmd).await.unwrap();
+
+        let mut buf = String::new();
+        tokio::time::timeout(Duration::from_secs(1), client.read_to_string(&mut buf)).await.unwrap().unwrap();
+        assert_eq!(buf, "-ERR Protocol error: too big request\r\n");
+    }
+}
@@ -1,12 +1,15 @@
 use std::{marker::PhantomData, pin::Pin, task::{Context, Poll, ready}};
 
-use bytes::BytesMut;
-use futures::{Stream, FutureExt, Sink};
+use bytes::{Buf, BytesMut};
+use futures::{Stream, Sink};
 use tokio::io::{AsyncWrite, AsyncRead};
+use tokio_util::io::poll_read_buf;
 
-use crate::{network::frame::read_frame, KvError};
+use crate::KvError;
 
-use super::frame::FrameCoder;
+use super::{compression::FrameOptions, frame::{FrameCoder, frame_len, LEN_LEN}};
+
+const READ_BUF_SIZE: usize = 8 * 1024;
 
 
 
@@ -17,6 +20,10 @@ pub struct ProstStream<S, In, Out> {
     wbuf: BytesMut,
 
     written: usize,
+    // 超长帧中尚未读取、需要丢弃的字节数
+    discard: usize,
+
+    options: FrameOptions,
 
     _in: PhantomData<In>,
     _out: PhantomData<Out>,
@@ -29,10 +36,21 @@ impl<S, In, Out> ProstStream<S, In, Out> {
             rbuf: BytesMut::new(),
             wbuf: BytesMut::new(),
             written: 0,
+            discard: 0,
+            options: FrameOptions::default(),
             _in: PhantomData,
             _out: PhantomData,
         }
     }
+
+    pub fn with_options(mut self, options: FrameOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn options(&self) -> &FrameOptions {
+        &self.options
+    }
 }
 
 impl<S, In, Out> Stream for ProstStream<S, In, Out> 
@@ -46,13 +64,42 @@ where
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         let this = self.get_mut();
 
-        let mut buf = this.rbuf.split_off(0);
-        let fut = read_frame(&mut this.stream, &mut buf);
-        ready!(Box::pin(fut).poll_unpin(cx))?;
-
-        let item = In::decode_frame(&mut buf);
-        this.rbuf.unsplit(buf);
-        Poll::Ready(Some(item))
+        // 未读完的头部和正文一直保留在 rbuf 中，Pending 之后再次 poll 时继续读取
+        loop {
+            if this.discard > 0 {
+                let n = this.discard.min(this.rbuf.len());
+                this.rbuf.advance(n);
+                this.discard -= n;
+            }
+
+            if this.discard == 0 {
+                match frame_len(&this.rbuf, this.options.max_frame_size) {
+                    Ok(Some(len)) => {
+                        let mut frame = this.rbuf.split_to(len);
+                        return Poll::Ready(Some(In::decode_frame_with(&mut frame, this.options.max_frame_size)));
+                    },
+                    Ok(None) => {},
+                    // 超长的帧不缓存，边读边丢弃，之后的帧可以继续读取
+                    Err(KvError::FrameTooLarge(len, max)) => {
+                        this.discard = len + LEN_LEN;
+                        return Poll::Ready(Some(Err(KvError::FrameTooLarge(len, max))));
+                    },
+                    Err(e) => return Poll::Ready(Some(Err(e))),
+                }
+            }
+
+            this.rbuf.reserve(READ_BUF_SIZE);
+            let n = ready!(poll_read_buf(Pin::new(&mut this.stream), cx, &mut this.rbuf))?;
+            if n == 0 {
+                // 在帧边界上关闭视为正常结束，帧读到一半关闭则是错误
+                if this.rbuf.is_empty() && this.discard == 0 {
+                    return Poll::Ready(None);
+                }
+                this.rbuf.clear();
+                this.discard = 0;
+                return Poll::Ready(Some(Err(KvError::FrameError)));
+            }
+        }
     }
 }
 
@@ -71,7 +118,7 @@ where
     fn start_send(self: Pin<&mut Self>, item: &Out) -> Result<(), Self::Error> {
         let this = self.get_mut();
 
-        item.encode_frame(&mut this.wbuf)?;
+        item.encode_frame_with(&mut this.wbuf, &this.options)?;
 
         Ok(())
     }
@@ -80,7 +127,8 @@ where
         let this = self.get_mut();
 
         while this.written != this.wbuf.len() {
-            let n = ready!(Pin::new(&mut this.stream).poll_write(cx, &this.wbuf))?;
+            // 上次可能只写入了一部分，从未写入的位置继续
+            let n = ready!(Pin::new(&mut this.stream).poll_write(cx, &this.wbuf[this.written..]))?;
             this.written += n;
         }
         this.written = 0;
@@ -111,7 +159,7 @@ mod tests {
     use futures::{SinkExt, StreamExt};
     use tokio::io::{AsyncRead, ReadBuf, AsyncWrite};
 
-    use crate::pb::CommandRequest;
+    use crate::{network::{compression::FrameOptions, frame::FrameCoder}, pb::{CommandRequest, CommandResponse}, KvError};
 
     use super::ProstStream;
 
@@ -127,10 +175,7 @@ mod tests {
             _cx: &mut Context<'_>,
             buf: &mut ReadBuf<'_>,
         ) -> Poll<std::io::Result<()>> {
-            let len = buf.capacity();
-
-            let size = self.stream.capacity();
-            assert!(size > 0);
+            let len = buf.remaining().min(self.stream.len());
 
             let data = self.get_mut().stream.split_to(len);
             buf.put_slice(&data);
@@ -176,4 +221,126 @@ mod tests {
             assert_eq!(res, cmd);
         }
     }
-}
\ No newline at end of file
+
+    // 模拟慢速网络：每次最多返回 chunk 个字节，并且每隔一次 poll 返回 Pending
+    struct ChunkedStream {
+        data: BytesMut,
+        chunk: usize,
+        pending: bool,
+        // 写入的数据，每次最多写入 chunk 个字节
+        written: BytesMut,
+    }
+
+    impl AsyncRead for ChunkedStream {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            let this = self.get_mut();
+
+            this.pending = !this.pending;
+            if this.pending {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+
+            let len = this.chunk.min(buf.remaining()).min(this.data.len());
+            let data = this.data.split_to(len);
+            buf.put_slice(&data);
+
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncWrite for ChunkedStream {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize, std::io::Error>> {
+            let len = self.chunk.min(buf.len());
+            self.get_mut().written.extend_from_slice(&buf[..len]);
+            Poll::Ready(Ok(len))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<(), std::io::Error>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn chunked_stream(frames: &[CommandResponse], chunk: usize) -> ProstStream<ChunkedStream, CommandResponse, CommandRequest> {
+        let mut data = BytesMut::new();
+        for frame in frames {
+            frame.encode_frame(&mut data).unwrap();
+        }
+        ProstStream::new(ChunkedStream { data, chunk, pending: false, written: BytesMut::new() })
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_resume_partial_frames() {
+        let mut large = CommandResponse::ok();
+        large.values = vec![vec![1u8; 20000].into(); 4];
+        let frames = vec![CommandResponse::ok(), large, 42.into(), CommandResponse::ok()];
+
+        for chunk in [1, 3, 7, 1500, 100000] {
+            let mut stream = chunked_stream(&frames, chunk);
+            for frame in frames.iter() {
+                let res = stream.next().await.unwrap().unwrap();
+                assert_eq!(&res, frame);
+            }
+            assert!(stream.next().await.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_resume_partial_writes() {
+        let cmds = [CommandRequest::new_hget("t1", "k1"), CommandRequest::new_hset("t1", "k2", "v2".into())];
+        let mut expected = BytesMut::new();
+        for cmd in cmds.iter() {
+            cmd.encode_frame(&mut expected).unwrap();
+        }
+
+        let mut stream = chunked_stream(&[], 7);
+        for cmd in cmds.iter() {
+            stream.feed(cmd).await.unwrap();
+        }
+        stream.flush().await.unwrap();
+        assert_eq!(stream.stream.written, expected);
+    }
+
+    #[tokio::test]
+    async fn prost_stream_truncated_frame_should_fail() {
+        let mut stream = chunked_stream(&[CommandResponse::ok(), 42.into()], 2);
+        let len = stream.stream.data.len();
+        stream.stream.data.truncate(len - 1);
+
+        assert_eq!(stream.next().await.unwrap().unwrap(), CommandResponse::ok());
+        assert!(matches!(stream.next().await, Some(Err(KvError::FrameError))));
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn prost_stream_should_skip_oversize_frames() {
+        let mut large = CommandResponse::ok();
+        large.values = vec![vec![1u8; 20000].into(); 4];
+        let frames = vec![CommandResponse::ok(), large, 42.into()];
+
+        for chunk in [7, 1500, 100000] {
+            let stream = chunked_stream(&frames, chunk);
+            let options = FrameOptions { codecs: vec![], ..Default::default() }.with_max_frame_size(1000);
+            let mut stream = stream.with_options(options);
+
+            assert_eq!(stream.next().await.unwrap().unwrap(), CommandResponse::ok());
+            assert!(matches!(stream.next().await, Some(Err(KvError::FrameTooLarge(_, 1000)))));
+            assert_eq!(stream.next().await.unwrap().unwrap(), 42.into());
+            assert!(stream.next().await.is_none());
+            // 超长的帧没有缓存在 rbuf 中
+            assert!(stream.rbuf.capacity() < 20000);
+        }
+    }
+}
@@ -18,11 +18,11 @@ impl StreamResult {
         S: Stream<Item = Result<CommandResponse>> + Send + 'static + Unpin
     {
         let id = match stream.next().await {
-            Some(Ok(CommandResponse { state_code: 200, values, exit, msg, pairs  })) => {
+            Some(Ok(CommandResponse { state_code: 200, values, exit, msg, pairs, versions, chunk, request_id })) => {
                 if exit {
                     return Ok(Self {
                         id: 0,
-                        inner: Box::pin(once(Ok(CommandResponse { state_code: 200, msg, values, pairs, exit }))),
+                        inner: Box::pin(once(Ok(CommandResponse { state_code: 200, msg, values, pairs, exit, versions, chunk, request_id }))),
                     });
                 }
                 if values.is_empty() {
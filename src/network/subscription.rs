@@ -0,0 +1,133 @@
+use std::sync::{Arc, Mutex};
+
+use futures::{stream::{self, BoxStream}, FutureExt, Stream, StreamExt};
+use tracing::log::warn;
+
+use crate::{pb::{CommandRequest, CommandResponse}, service::{Service, Session}, storage::Storage, KvError};
+
+// 连接断开（消息的 stream 被 drop）时取消订阅，服务端结束订阅时不需要
+struct Guard<Store: Storage> {
+    service: Service<Store>,
+    session: Session,
+    topic: String,
+    id: u32,
+    active: bool,
+}
+
+impl<Store: Storage> Guard<Store> {
+    // 服务端已经结束订阅
+    fn finish(mut self) {
+        self.active = false;
+    }
+}
+
+impl<Store: Storage> Drop for Guard<Store> {
+    fn drop(&mut self) {
+        if self.active {
+            unsubscribe(&self.service, &self.session, &self.topic, self.id);
+        }
+    }
+}
+
+// 原生协议的 stream 上建立的订阅，客户端断开或者连接结束时全部取消
+#[derive(Debug, Clone, Default)]
+pub(super) struct Owned(Arc<Mutex<OwnedInner>>);
+
+#[derive(Debug, Default)]
+struct OwnedInner {
+    closed: bool,
+    subscriptions: Vec<(String, u32)>,
+}
+
+impl Owned {
+    // 已经关闭时返回 false，调用者需要自己取消订阅
+    pub fn add(&self, topic: String, id: u32) -> bool {
+        let mut owned = self.0.lock().unwrap();
+        if owned.closed {
+            return false;
+        }
+        owned.subscriptions.push((topic, id));
+        true
+    }
+
+    // 取消所有订阅，之后建立的订阅由 add 的调用者取消
+    pub fn close<Store: Storage>(&self, service: &Service<Store>, session: &Session) {
+        let subscriptions = {
+            let mut owned = self.0.lock().unwrap();
+            owned.closed = true;
+            std::mem::take(&mut owned.subscriptions)
+        };
+        for (topic, id) in subscriptions {
+            unsubscribe(service, session, &topic, id);
+        }
+    }
+}
+
+// 取消订阅在 execute 时已经完成，响应立即可用，不需要 runtime（Guard 可能在 runtime 之外被 drop）
+// 订阅已经被客户端取消（404）时忽略
+pub(super) fn unsubscribe<Store: Storage>(service: &Service<Store>, session: &Session, topic: &str, id: u32) {
+    let mut stream = service.execute_with_session(CommandRequest::unsubscribe(topic, id), session);
+    match stream.next().now_or_never().flatten() {
+        Some(res) if res.state_code == 200 || res.state_code == 404 => {},
+        res => warn!("Failed to unsubscribe {} for {}: {:?}", topic, session.peer, res.map(|x| x.msg.clone())),
+    }
+}
+
+// 给没有 Unsubscribe 命令的协议（HTTP、gRPC）使用：订阅成功时返回订阅 id 和之后收到的消息，失败时返回错误的响应
+// 收到 exit 后 stream 结束，stream 被 drop 时自动取消订阅
+pub async fn subscribe<Store: Storage>(
+    service: &Service<Store>,
+    session: &Session,
+    topic: &str,
+) -> Result<(u32, impl Stream<Item = Arc<CommandResponse>> + Send + 'static), Arc<CommandResponse>> {
+    let mut stream = service.execute_with_session(CommandRequest::subscribe(topic), session);
+    let res = stream.next().await.unwrap_or_default();
+    if res.state_code != 200 {
+        return Err(res);
+    }
+    let id = match res.values.first().map(i64::try_from) {
+        Some(Ok(id)) => id as u32,
+        _ => return Err(Arc::new(KvError::Internal("invalid subscription id".into()).into())),
+    };
+
+    let guard = Guard { service: service.clone(), session: session.clone(), topic: topic.into(), id, active: true };
+    Ok((id, messages(guard, stream)))
+}
+
+fn messages<Store: Storage>(guard: Guard<Store>, stream: BoxStream<'static, Arc<CommandResponse>>) -> impl Stream<Item = Arc<CommandResponse>> + Send {
+    stream::unfold((guard, stream), |(guard, mut stream)| async move {
+        let res = stream.next().await?;
+        if res.exit {
+            guard.finish();
+            return None;
+        }
+        Some((res, (guard, stream)))
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use futures::StreamExt;
+    use tokio::runtime::Runtime;
+
+    use crate::{CommandRequest, MemoryDb, ServiceInner, Session};
+
+    use super::subscribe;
+
+    #[test]
+    fn subscription_should_be_released_outside_runtime() {
+        let service = ServiceInner::new(MemoryDb::new()).service();
+        let session = Session::new("test", None);
+
+        let rt = Runtime::new().unwrap();
+        let (id, messages) = rt.block_on(subscribe(&service, &session, "lobby")).unwrap();
+        drop(rt);
+        // runtime 已经关闭，drop 时同步取消订阅
+        drop(messages);
+
+        let mut stream = service.execute_with_session(CommandRequest::unsubscribe("lobby", id), &session);
+        let res = Runtime::new().unwrap().block_on(stream.next()).unwrap();
+        assert_eq!(res.state_code, 404);
+    }
+}
@@ -0,0 +1,343 @@
+use std::{io::Cursor, sync::Arc};
+
+use rustls_pemfile::Item;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::{
+    client, server,
+    rustls::{
+        server::AllowAnyAuthenticatedClient, Certificate, ClientConfig, OwnedTrustAnchor, PrivateKey,
+        RootCertStore, ServerConfig, ServerName,
+    },
+    TlsAcceptor, TlsConnector,
+};
+use x509_parser::prelude::{GeneralName, parse_x509_certificate};
+
+use crate::{KvError, Result};
+
+#[derive(Clone)]
+pub struct TlsServerAcceptor {
+    inner: Arc<ServerConfig>,
+    client_auth: bool,
+}
+
+#[derive(Clone)]
+pub struct TlsClientConnector {
+    inner: Arc<ClientConfig>,
+    domain: Arc<String>,
+}
+
+impl TlsServerAcceptor {
+    // cert、key 和 client_ca 为 PEM 格式的内容，指定 client_ca 时要求客户端提供由它签发的证书
+    pub fn new(cert: &str, key: &str, client_ca: Option<&str>) -> Result<Self> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+        let builder = match client_ca {
+            Some(ca) => builder.with_client_cert_verifier(AllowAnyAuthenticatedClient::new(load_roots(ca)?).boxed()),
+            None => builder.with_no_client_auth(),
+        };
+        let config = builder.with_single_cert(load_certs(cert)?, load_key(key)?)?;
+
+        Ok(Self {
+            inner: Arc::new(config),
+            client_auth: client_ca.is_some(),
+        })
+    }
+
+    // 返回 tls 连接以及客户端证书中的身份
+    pub async fn accept<S>(&self, stream: S) -> Result<(server::TlsStream<S>, Option<String>)>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send
+    {
+        let acceptor = TlsAcceptor::from(self.inner.clone());
+        let stream = acceptor.accept(stream).await?;
+        let identity = self.identity(stream.get_ref().1.peer_certificates())?;
+        Ok((stream, identity))
+    }
+
+    // 要求客户端证书时，证书中必须能取到身份
+    pub(super) fn identity(&self, certs: Option<&[Certificate]>) -> Result<Option<String>> {
+        let identity = certs.and_then(|x| x.first()).and_then(|x| cert_identity(&x.0));
+        if self.client_auth && identity.is_none() {
+            return Err(KvError::CertificateParseError("client identity (CN or SAN)"));
+        }
+        Ok(identity)
+    }
+
+    // 使用相同证书配置的 QUIC 服务端配置
+    pub(super) fn quic_config(&self, alpn: &[u8]) -> quinn::ServerConfig {
+        let mut config = (*self.inner).clone();
+        config.alpn_protocols = vec![alpn.to_vec()];
+        quinn::ServerConfig::with_crypto(Arc::new(config))
+    }
+}
+
+impl TlsClientConnector {
+    // identity 为客户端的 (cert, key)，没有指定 ca 时使用 webpki 内置的根证书校验服务端
+    pub fn new(domain: impl Into<String>, identity: Option<(&str, &str)>, ca: Option<&str>) -> Result<Self> {
+        let roots = match ca {
+            Some(ca) => load_roots(ca)?,
+            None => {
+                let mut roots = RootCertStore::empty();
+                roots.add_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.iter().map(|x| {
+                    OwnedTrustAnchor::from_subject_spki_name_constraints(x.subject, x.spki, x.name_constraints)
+                }));
+                roots
+            },
+        };
+
+        let builder = ClientConfig::builder()
+            .with_safe_defaults()
+            .with_root_certificates(roots);
+        let config = match identity {
+            Some((cert, key)) => builder.with_client_auth_cert(load_certs(cert)?, load_key(key)?)?,
+            None => builder.with_no_client_auth(),
+        };
+
+        Ok(Self {
+            inner: Arc::new(config),
+            domain: Arc::new(domain.into()),
+        })
+    }
+
+    pub async fn connect<S>(&self, stream: S) -> Result<client::TlsStream<S>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send
+    {
+        let domain = ServerName::try_from(self.domain.as_str())
+            .map_err(|_| KvError::Invalid(format!("invalid domain {}", self.domain)))?;
+
+        let connector = TlsConnector::from(self.inner.clone());
+        Ok(connector.connect(domain, stream).await?)
+    }
+
+    pub(super) fn domain(&self) -> &str {
+        &self.domain
+    }
+
+    // 使用相同证书配置的 QUIC 客户端配置
+    pub(super) fn quic_config(&self, alpn: &[u8]) -> quinn::ClientConfig {
+        let mut config = (*self.inner).clone();
+        config.alpn_protocols = vec![alpn.to_vec()];
+        quinn::ClientConfig::new(Arc::new(config))
+    }
+}
+
+fn load_certs(pem: &str) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut Cursor::new(pem))
+        .map_err(|_| KvError::CertificateParseError("cert"))?;
+    if certs.is_empty() {
+        return Err(KvError::CertificateParseError("cert"));
+    }
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn load_roots(pem: &str) -> Result<RootCertStore> {
+    let mut roots = RootCertStore::empty();
+    for cert in load_certs(pem)? {
+        roots.add(&cert).map_err(|_| KvError::CertificateParseError("ca"))?;
+    }
+    Ok(roots)
+}
+
+// 证书中的身份优先取 subject 的 CN，其次取第一个 DNS 或 email 类型的 SAN
+fn cert_identity(der: &[u8]) -> Option<String> {
+    let (_, cert) = parse_x509_certificate(der).ok()?;
+
+    let cn = cert.subject().iter_common_name().next().and_then(|x| x.as_str().ok());
+    if let Some(cn) = cn {
+        return Some(cn.to_string());
+    }
+
+    let san = cert.subject_alternative_name().ok()??;
+    san.value.general_names.iter().find_map(|x| match x {
+        GeneralName::DNSName(x) | GeneralName::RFC822Name(x) => Some(x.to_string()),
+        _ => None,
+    })
+}
+
+fn load_key(pem: &str) -> Result<PrivateKey> {
+    let mut reader = Cursor::new(pem);
+    loop {
+        match rustls_pemfile::read_one(&mut reader).map_err(|_| KvError::CertificateParseError("key"))? {
+            Some(Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key)) => return Ok(PrivateKey(key)),
+            Some(_) => continue,
+            None => return Err(KvError::CertificateParseError("key")),
+        }
+    }
+}
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::time::Duration;
+
+    use rcgen::{BasicConstraints, Certificate, CertificateParams, DistinguishedName, IsCa};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::{TcpListener, TcpStream}, sync::oneshot};
+
+    use crate::{connect, serve_listener, CommandRequest, KeepaliveOptions, Listener, MemoryDb, ServiceInner};
+
+    use super::{cert_identity, TlsClientConnector, TlsServerAcceptor};
+
+    // 测试时生成自签名的 ca
+    pub(crate) fn generate_ca() -> Certificate {
+        let mut params = CertificateParams::new(vec![]);
+        params.is_ca = IsCa::Ca(BasicConstraints::Unconstrained);
+        params.distinguished_name.push(rcgen::DnType::CommonName, "kvserver ca");
+        Certificate::from_params(params).unwrap()
+    }
+
+    // 由 ca 签发证书，返回 (cert, key) 的 PEM 内容
+    pub(crate) fn generate_cert(ca: &Certificate, name: &str) -> (String, String) {
+        let mut params = CertificateParams::new(vec![name.to_string()]);
+        params.distinguished_name.push(rcgen::DnType::CommonName, name);
+        let cert = Certificate::from_params(params).unwrap();
+        (cert.serialize_pem_with_signer(ca).unwrap(), cert.serialize_private_key_pem())
+    }
+
+    async fn echo_server(acceptor: TlsServerAcceptor) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            if let Ok((mut stream, _)) = acceptor.accept(stream).await {
+                let mut buf = [0u8; 5];
+                stream.read_exact(&mut buf).await.unwrap();
+                stream.write_all(&buf).await.unwrap();
+            }
+        });
+        addr
+    }
+
+    #[tokio::test]
+    async fn tls_should_work() {
+        let ca = generate_ca();
+        let (cert, key) = generate_cert(&ca, "kvserver.acme.inc");
+        let acceptor = TlsServerAcceptor::new(&cert, &key, None).unwrap();
+        let addr = echo_server(acceptor).await;
+
+        let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(&ca.serialize_pem().unwrap())).unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        let mut stream = connector.connect(stream).await.unwrap();
+        stream.write_all(b"hello").await.unwrap();
+        let mut buf = [0u8; 5];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello");
+    }
+
+    #[tokio::test]
+    async fn tls_with_unknown_ca_or_domain_should_fail() {
+        let ca = generate_ca();
+        let (cert, key) = generate_cert(&ca, "kvserver.acme.inc");
+        let acceptor = TlsServerAcceptor::new(&cert, &key, None).unwrap();
+
+        let other = generate_ca().serialize_pem().unwrap();
+        let addr = echo_server(acceptor.clone()).await;
+        let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(&other)).unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(connector.connect(stream).await.is_err());
+
+        let addr = echo_server(acceptor).await;
+        let connector = TlsClientConnector::new("other.acme.inc", None, Some(&ca.serialize_pem().unwrap())).unwrap();
+        let stream = TcpStream::connect(addr).await.unwrap();
+        assert!(connector.connect(stream).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_server_should_work() {
+        let ca = generate_ca();
+        let (cert, key) = generate_cert(&ca, "kvserver.acme.inc");
+        let acceptor = TlsServerAcceptor::new(&cert, &key, None).unwrap();
+        let listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener, ServiceInner::new(MemoryDb::new()).service(), Some(acceptor)));
+
+        let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(&ca.serialize_pem().unwrap())).unwrap();
+        let mut ctrl = connect(&addr, Some(&connector)).await.unwrap();
+        let mut stream = ctrl.open_stream().await.unwrap();
+        stream.execute_unary(&CommandRequest::new_hset("t1", "k1", "v1".into())).await.unwrap();
+        let res = stream.execute_unary(&CommandRequest::new_hget("t1", "k1")).await.unwrap();
+        assert_eq!(res.values, vec!["v1".into()]);
+
+        // 明文连接无法通过 tls 握手
+        assert!(connect(&addr, None).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn tls_handshake_should_time_out() {
+        let ca = generate_ca();
+        let (cert, key) = generate_cert(&ca, "kvserver.acme.inc");
+        let acceptor = TlsServerAcceptor::new(&cert, &key, None).unwrap();
+        let service = ServiceInner::new(MemoryDb::new())
+            .with_keepalive_options(KeepaliveOptions { idle_timeout: Some(1), ..Default::default() })
+            .service();
+        let listener = Listener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve_listener(listener, service, Some(acceptor)));
+
+        // 连接后不发送数据，超时后服务端关闭连接
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let mut buf = [0u8; 1];
+        let res = tokio::time::timeout(Duration::from_secs(5), stream.read(&mut buf)).await.unwrap();
+        assert!(matches!(res, Ok(0) | Err(_)));
+    }
+
+    // 服务端握手的结果通过 channel 返回：成功时为客户端身份
+    async fn mtls_server(acceptor: TlsServerAcceptor) -> (String, oneshot::Receiver<Option<Option<String>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let (tx, rx) = oneshot::channel();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let res = acceptor.accept(stream).await.ok().map(|(_, identity)| identity);
+            tx.send(res).unwrap();
+        });
+        (addr, rx)
+    }
+
+    #[tokio::test]
+    async fn mtls_should_work() {
+        let ca = generate_ca();
+        let ca_pem = ca.serialize_pem().unwrap();
+        let (cert, key) = generate_cert(&ca, "kvserver.acme.inc");
+        let acceptor = TlsServerAcceptor::new(&cert, &key, Some(&ca_pem)).unwrap();
+
+        // 由 ca 签发的客户端证书
+        let (client_cert, client_key) = generate_cert(&ca, "alice");
+        let connector = TlsClientConnector::new("kvserver.acme.inc", Some((&client_cert, &client_key)), Some(&ca_pem)).unwrap();
+        let (addr, rx) = mtls_server(acceptor.clone()).await;
+        let _stream = connector.connect(TcpStream::connect(addr).await.unwrap()).await;
+        assert_eq!(rx.await.unwrap(), Some(Some("alice".to_string())));
+
+        // 没有客户端证书
+        let connector = TlsClientConnector::new("kvserver.acme.inc", None, Some(&ca_pem)).unwrap();
+        let (addr, rx) = mtls_server(acceptor.clone()).await;
+        let _stream = connector.connect(TcpStream::connect(addr).await.unwrap()).await;
+        assert_eq!(rx.await.unwrap(), None);
+
+        // 未知 ca 签发的客户端证书
+        let (client_cert, client_key) = generate_cert(&generate_ca(), "mallory");
+        let connector = TlsClientConnector::new("kvserver.acme.inc", Some((&client_cert, &client_key)), Some(&ca_pem)).unwrap();
+        let (addr, rx) = mtls_server(acceptor).await;
+        let _stream = connector.connect(TcpStream::connect(addr).await.unwrap()).await;
+        assert_eq!(rx.await.unwrap(), None);
+    }
+
+    #[test]
+    fn cert_identity_should_work() {
+        let ca = generate_ca();
+        let (cert, _) = generate_cert(&ca, "alice");
+        let der = rustls_pemfile::certs(&mut cert.as_bytes()).unwrap();
+        assert_eq!(cert_identity(&der[0]), Some("alice".to_string()));
+
+        // 没有 CN 时使用 SAN
+        let mut params = CertificateParams::new(vec!["bob.acme.inc".to_string()]);
+        params.distinguished_name = DistinguishedName::new();
+        let cert = Certificate::from_params(params).unwrap();
+        assert_eq!(cert_identity(&cert.serialize_der_with_signer(&ca).unwrap()), Some("bob.acme.inc".to_string()));
+    }
+
+    #[test]
+    fn invalid_pem_should_fail() {
+        assert!(TlsServerAcceptor::new("invalid", "invalid", None).is_err());
+        assert!(TlsClientConnector::new("kvserver.acme.inc", None, Some("invalid")).is_err());
+    }
+}
@@ -14,7 +14,22 @@ impl CommandRequest {
             request_data: Some(RequestData::Hget(Hget {
                 table: table.into(),
                 key: key.into(),
-            }))
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    // version 和 time 同时给出时以 version 为准，0 表示不限制
+    pub fn new_hget_as_of(table: impl Into<String>, key: impl Into<String>, as_of_version: u64, as_of_time: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::Hget(Hget {
+                table: table.into(),
+                key: key.into(),
+                as_of_version,
+                as_of_time,
+            })),
+            ..Default::default()
         }
     }
 
@@ -23,7 +38,8 @@ impl CommandRequest {
             request_data: Some(RequestData::Hmget(Hmget {
                 table: table.into(),
                 keys: keys,
-            }))
+            })),
+            ..Default::default()
         }
     }
 
@@ -32,7 +48,8 @@ impl CommandRequest {
             request_data: Some(RequestData::Hset(Hset {
                 table: table.into(),
                 pair: Some((key.into(), value).into()),
-            }))
+            })),
+            ..Default::default()
         }
     }
 
@@ -41,7 +58,8 @@ impl CommandRequest {
             request_data: Some(RequestData::Hmset(Hmset {
                 table: table.into(),
                 pairs,
-            }))
+            })),
+            ..Default::default()
         }
     }
 
@@ -50,7 +68,8 @@ impl CommandRequest {
             request_data: Some(RequestData::Hexists(Hexists {
                 table: table.into(),
                 key: key.into(),
-            }))
+            })),
+            ..Default::default()
         }
     }
 
@@ -59,7 +78,8 @@ impl CommandRequest {
             request_data: Some(RequestData::Hmexists(Hmexists {
                 table: table.into(),
                 keys
-            }))
+            })),
+            ..Default::default()
         }
     }
 
@@ -68,7 +88,8 @@ impl CommandRequest {
             request_data: Some(RequestData::Hdelete(Hdelete {
                 table: table.into(),
                 key: key.into(),
-            }))
+            })),
+            ..Default::default()
         }
     }
 
@@ -77,15 +98,194 @@ impl CommandRequest {
             request_data: Some(RequestData::Hmdelete(Hmdelete {
                 table: table.into(),
                 keys
-            }))
+            })),
+            ..Default::default()
         }
     }
 
     pub fn new_hget_all(table: impl Into<String>) -> Self {
         Self {
             request_data: Some(RequestData::Hgetall(Hgetall {
-                table: table.into()
+                table: table.into(),
+                filter: String::new(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hget_all_with_filter(table: impl Into<String>, filter: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hgetall(Hgetall {
+                table: table.into(),
+                filter: filter.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_json_get(table: impl Into<String>, key: impl Into<String>, path: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::JsonGet(JsonGet {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_json_set(table: impl Into<String>, key: impl Into<String>, path: impl Into<String>, value: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::JsonSet(JsonSet {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+                value: Some(value),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_json_del(table: impl Into<String>, key: impl Into<String>, path: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::JsonDel(JsonDel {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_json_append(table: impl Into<String>, key: impl Into<String>, path: impl Into<String>, values: Vec<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::JsonAppend(JsonAppend {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+                values,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_json_incr(table: impl Into<String>, key: impl Into<String>, path: impl Into<String>, by: Value) -> Self {
+        Self {
+            request_data: Some(RequestData::JsonIncr(JsonIncr {
+                table: table.into(),
+                key: key.into(),
+                path: path.into(),
+                by: Some(by),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_create_index(table: impl Into<String>, index: impl Into<String>, path: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::CreateIndex(CreateIndex {
+                table: table.into(),
+                index: index.into(),
+                path: path.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_drop_index(table: impl Into<String>, index: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::DropIndex(DropIndex {
+                table: table.into(),
+                index: index.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_query_index(table: impl Into<String>, index: impl Into<String>, eq: Option<Value>, min: Option<Value>, max: Option<Value>) -> Self {
+        Self {
+            request_data: Some(RequestData::QueryIndex(QueryIndex {
+                table: table.into(),
+                index: index.into(),
+                eq,
+                min,
+                max,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_aggregate(table: impl Into<String>, path: impl Into<String>, filter: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Aggregate(Aggregate {
+                table: table.into(),
+                path: path.into(),
+                filter: filter.into(),
+                ..Default::default()
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hhistory(table: impl Into<String>, key: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Hhistory(Hhistory {
+                table: table.into(),
+                key: key.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_enable_history(table: impl Into<String>, max_versions: u32, retention_secs: u64) -> Self {
+        Self {
+            request_data: Some(RequestData::EnableHistory(EnableHistory {
+                table: table.into(),
+                max_versions,
+                retention_secs,
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_disable_history(table: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::DisableHistory(DisableHistory {
+                table: table.into(),
             })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_auth(username: impl Into<String>, password: impl Into<String>) -> Self {
+        Self {
+            request_data: Some(RequestData::Auth(Auth {
+                username: username.into(),
+                password: password.into(),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hset_chunk(table: impl Into<String>, key: impl Into<String>, chunk: Chunk) -> Self {
+        Self {
+            request_data: Some(RequestData::HsetChunk(HsetChunk {
+                table: table.into(),
+                key: key.into(),
+                chunk: Some(chunk),
+            })),
+            ..Default::default()
+        }
+    }
+
+    pub fn new_hget_chunked(table: impl Into<String>, key: impl Into<String>, chunk_size: u32) -> Self {
+        Self {
+            request_data: Some(RequestData::HgetChunked(HgetChunked {
+                table: table.into(),
+                key: key.into(),
+                chunk_size,
+            })),
+            ..Default::default()
         }
     }
 
@@ -94,6 +294,7 @@ impl CommandRequest {
             request_data: Some(RequestData::Subscribe (Subscribe { 
                 topic: topic.into() 
             })),
+            ..Default::default()
         }
     }
 
@@ -103,6 +304,7 @@ impl CommandRequest {
                 topic: topic.into(),
                 id,
             })),
+            ..Default::default()
         }
     }
 
@@ -111,7 +313,8 @@ impl CommandRequest {
             request_data: Some(RequestData::Publish(Publish {
                 topic: topic.into(),
                 data,
-            }))
+            })),
+            ..Default::default()
         }
     }
 }
@@ -123,7 +326,8 @@ impl CommandResponse {
             msg: msg.into(),
             values,
             pairs,
-            exit
+            exit,
+            ..Default::default()
         }
     }
 
@@ -204,6 +408,33 @@ impl From<Vec<u8>> for Value {
     }
 }
 
+impl From<serde_json::Value> for Value {
+    fn from(value: serde_json::Value) -> Self {
+        Self {
+            value: Some(value::Value::Json(value.to_string())),
+        }
+    }
+}
+
+impl TryFrom<Value> for serde_json::Value {
+    type Error = KvError;
+
+    fn try_from(value: Value) -> Result<Self, Self::Error> {
+        let res = match value.value {
+            Some(value::Value::String(x)) => x.into(),
+            Some(value::Value::Integer(x)) => x.into(),
+            Some(value::Value::Float(x)) => serde_json::Number::from_f64(x)
+                .map(serde_json::Value::Number)
+                .ok_or(KvError::ConvertError)?,
+            Some(value::Value::Bool(x)) => x.into(),
+            Some(value::Value::Binary(x)) => x.into(),
+            Some(value::Value::Json(x)) => serde_json::from_str(&x)?,
+            None => serde_json::Value::Null,
+        };
+        Ok(res)
+    }
+}
+
 impl TryFrom<&[u8]> for Value {
     type Error = KvError;
 
@@ -247,8 +478,7 @@ impl From<i64> for CommandResponse {
             state_code: 200,
             msg: "ok".to_string(),
             values: vec![value.into()],
-            pairs: vec![],
-            exit: false,
+            ..Default::default()
         }
     }
 }
@@ -285,7 +515,12 @@ impl From<KvError> for CommandResponse {
 
         match value {
             KvError::NotFound(_, _) => res.state_code = StatusCode::NOT_FOUND.as_u16() as _,
+            KvError::IndexNotFound(_, _) => res.state_code = StatusCode::NOT_FOUND.as_u16() as _,
             KvError::InvalidCommand(_) => res.state_code = StatusCode::BAD_REQUEST.as_u16() as _,
+            KvError::Unauthorized(_) => res.state_code = StatusCode::UNAUTHORIZED.as_u16() as _,
+            KvError::Forbidden(_) => res.state_code = StatusCode::FORBIDDEN.as_u16() as _,
+            KvError::FrameTooLarge(_, _) => res.state_code = StatusCode::PAYLOAD_TOO_LARGE.as_u16() as _,
+            KvError::Internal(_) => res.state_code = StatusCode::INTERNAL_SERVER_ERROR.as_u16() as _,
             _ => (),
         }
 
@@ -302,4 +537,15 @@ impl From<Vec<KvPair>> for CommandResponse {
             ..Default::default()
         }
     }
-}
\ No newline at end of file
+}
+
+impl From<Vec<Version>> for CommandResponse {
+    fn from(value: Vec<Version>) -> Self {
+        Self {
+            state_code: 200,
+            msg: "ok".to_string(),
+            versions: value,
+            ..Default::default()
+        }
+    }
+}
@@ -0,0 +1,187 @@
+use serde::Deserialize;
+
+use crate::{command_request::RequestData, KvError, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Permission {
+    Read,
+    Write,
+    Delete,
+    // 表的管理权限（索引、历史记录），同时包含读写删
+    Admin,
+    Subscribe,
+    Publish,
+}
+
+// user、tables、topics 都支持 * 和 ? 通配符，user 为 * 时也匹配未认证的连接
+#[derive(Debug, Clone, Deserialize)]
+pub struct AclRule {
+    pub user: String,
+    #[serde(default)]
+    pub tables: Vec<String>,
+    #[serde(default)]
+    pub topics: Vec<String>,
+    pub permissions: Vec<Permission>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Acl {
+    rules: Vec<AclRule>,
+}
+
+impl Acl {
+    pub fn new(rules: Vec<AclRule>) -> Self {
+        Self { rules }
+    }
+
+    pub fn rule(mut self, user: &str, tables: &[&str], topics: &[&str], permissions: &[Permission]) -> Self {
+        self.rules.push(AclRule {
+            user: user.into(),
+            tables: tables.iter().map(|x| x.to_string()).collect(),
+            topics: topics.iter().map(|x| x.to_string()).collect(),
+            permissions: permissions.to_vec(),
+        });
+        self
+    }
+
+    // 没有任何规则允许时返回 403
+    pub fn authorize(&self, user: Option<&str>, data: &RequestData) -> Result<()> {
+        let Some((permission, name)) = required_permission(data) else {
+            return Ok(());
+        };
+
+        let user = user.unwrap_or_default();
+        let allowed = self.rules.iter().any(|rule| {
+            glob_match(&rule.user, user)
+                && rule.allows(permission)
+                && rule.resources(permission).iter().any(|x| glob_match(x, name))
+        });
+
+        if allowed {
+            Ok(())
+        } else {
+            Err(KvError::Forbidden(format!("{:?} on {} is not allowed for user {:?}", permission, name, user)))
+        }
+    }
+}
+
+impl AclRule {
+    fn allows(&self, permission: Permission) -> bool {
+        self.permissions.iter().any(|x| {
+            *x == permission
+                || (*x == Permission::Admin && matches!(permission, Permission::Read | Permission::Write | Permission::Delete))
+        })
+    }
+
+    fn resources(&self, permission: Permission) -> &[String] {
+        match permission {
+            Permission::Subscribe | Permission::Publish => &self.topics,
+            _ => &self.tables,
+        }
+    }
+}
+
+// 命令需要的权限以及对应的 table 或 topic，不需要权限的命令返回 None
+fn required_permission(data: &RequestData) -> Option<(Permission, &str)> {
+    let res = match data {
+        RequestData::Hget(x) => (Permission::Read, &x.table),
+        RequestData::Hmget(x) => (Permission::Read, &x.table),
+        RequestData::Hexists(x) => (Permission::Read, &x.table),
+        RequestData::Hmexists(x) => (Permission::Read, &x.table),
+        RequestData::Hgetall(x) => (Permission::Read, &x.table),
+        RequestData::JsonGet(x) => (Permission::Read, &x.table),
+        RequestData::QueryIndex(x) => (Permission::Read, &x.table),
+        RequestData::Aggregate(x) => (Permission::Read, &x.table),
+        RequestData::Hhistory(x) => (Permission::Read, &x.table),
+        RequestData::HgetChunked(x) => (Permission::Read, &x.table),
+        RequestData::Hset(x) => (Permission::Write, &x.table),
+        RequestData::Hmset(x) => (Permission::Write, &x.table),
+        RequestData::JsonSet(x) => (Permission::Write, &x.table),
+        RequestData::JsonDel(x) => (Permission::Write, &x.table),
+        RequestData::JsonAppend(x) => (Permission::Write, &x.table),
+        RequestData::JsonIncr(x) => (Permission::Write, &x.table),
+        RequestData::HsetChunk(x) => (Permission::Write, &x.table),
+        RequestData::Hdelete(x) => (Permission::Delete, &x.table),
+        RequestData::Hmdelete(x) => (Permission::Delete, &x.table),
+        RequestData::CreateIndex(x) => (Permission::Admin, &x.table),
+        RequestData::DropIndex(x) => (Permission::Admin, &x.table),
+        RequestData::EnableHistory(x) => (Permission::Admin, &x.table),
+        RequestData::DisableHistory(x) => (Permission::Admin, &x.table),
+        RequestData::Subscribe(x) => (Permission::Subscribe, &x.topic),
+        RequestData::Unsubscribe(x) => (Permission::Subscribe, &x.topic),
+        RequestData::Publish(x) => (Permission::Publish, &x.topic),
+        RequestData::Auth(_) => return None,
+    };
+    Some((res.0, res.1.as_str()))
+}
+
+fn glob_match(pattern: &str, s: &str) -> bool {
+    let (p, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    // 上一个 * 的位置以及它匹配到的 s 的位置，失配时回溯
+    let mut star = None;
+
+    while j < s.len() {
+        if i < p.len() && (p[i] == b'?' || p[i] == s[j]) {
+            i += 1;
+            j += 1;
+        } else if i < p.len() && p[i] == b'*' {
+            star = Some((i, j));
+            i += 1;
+        } else if let Some((si, sj)) = star {
+            i = si + 1;
+            j = sj + 1;
+            star = Some((si, sj + 1));
+        } else {
+            return false;
+        }
+    }
+
+    p[i..].iter().all(|x| *x == b'*')
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::CommandRequest;
+
+    use super::{glob_match, Acl, Permission};
+
+    #[test]
+    fn glob_match_should_work() {
+        assert!(glob_match("*", ""));
+        assert!(glob_match("team_a_*", "team_a_users"));
+        assert!(glob_match("team_?", "team_b"));
+        assert!(glob_match("*.events.*", "app.events.login"));
+        assert!(!glob_match("team_a_*", "team_b_users"));
+        assert!(!glob_match("team_?", "team_bc"));
+        assert!(!glob_match("", "a"));
+    }
+
+    #[test]
+    fn acl_should_work() {
+        let acl = Acl::default()
+            .rule("alice", &["team_a_*"], &[], &[Permission::Read, Permission::Write])
+            .rule("bob", &["team_b_*"], &[], &[Permission::Admin])
+            .rule("*", &[], &["public.*"], &[Permission::Subscribe, Permission::Publish]);
+
+        let check = |user: Option<&str>, cmd: CommandRequest| acl.authorize(user, &cmd.request_data.unwrap()).is_ok();
+
+        assert!(check(Some("alice"), CommandRequest::new_hget("team_a_users", "k1")));
+        assert!(check(Some("alice"), CommandRequest::new_hset("team_a_users", "k1", "v1".into())));
+        assert!(!check(Some("alice"), CommandRequest::new_hdelete("team_a_users", "k1")));
+        assert!(!check(Some("alice"), CommandRequest::new_hget("team_b_users", "k1")));
+
+        assert!(check(Some("bob"), CommandRequest::new_hdelete("team_b_users", "k1")));
+        assert!(check(Some("bob"), CommandRequest::new_create_index("team_b_users", "age", "$.age")));
+        assert!(!check(Some("alice"), CommandRequest::new_create_index("team_a_users", "age", "$.age")));
+
+        assert!(check(None, CommandRequest::subscribe("public.news")));
+        assert!(check(Some("alice"), CommandRequest::publish("public.news", vec![])));
+        assert!(!check(Some("alice"), CommandRequest::subscribe("private.news")));
+        assert!(!check(None, CommandRequest::new_hget("team_a_users", "k1")));
+
+        assert!(check(None, CommandRequest::new_auth("alice", "secret")));
+    }
+}
@@ -0,0 +1,104 @@
+use crate::pb::{KvPair, Value};
+
+use super::filter::Scalar;
+
+// 对数值做 count、sum、min、max、avg 聚合，非数值会被忽略
+// 全部为整数且未溢出时 sum 为整数，否则为浮点数
+#[derive(Debug, Default)]
+pub struct Aggregator {
+    count: i64,
+    int_sum: Option<i64>,
+    float_sum: f64,
+    min: Option<Scalar>,
+    max: Option<Scalar>,
+}
+
+impl Aggregator {
+    pub fn new() -> Self {
+        Self {
+            int_sum: Some(0),
+            ..Default::default()
+        }
+    }
+
+    pub fn push(&mut self, value: Scalar) {
+        let x = match value {
+            Scalar::Integer(x) => {
+                self.int_sum = self.int_sum.and_then(|sum| sum.checked_add(x));
+                x as f64
+            },
+            Scalar::Float(x) => {
+                self.int_sum = None;
+                x
+            },
+            _ => return,
+        };
+
+        self.count += 1;
+        self.float_sum += x;
+        if self.min.as_ref().and_then(|min| value.compare(min)).is_none_or(|x| x.is_lt()) {
+            self.min = Some(value.clone());
+        }
+        if self.max.as_ref().and_then(|max| value.compare(max)).is_none_or(|x| x.is_gt()) {
+            self.max = Some(value);
+        }
+    }
+
+    pub fn finish(self) -> Vec<KvPair> {
+        let sum: Value = match self.int_sum {
+            Some(x) => x.into(),
+            None => self.float_sum.into(),
+        };
+        let avg: Value = match self.count {
+            0 => Value::default(),
+            n => (self.float_sum / n as f64).into(),
+        };
+
+        vec![
+            ("count", self.count.into()).into(),
+            ("sum", sum).into(),
+            ("min", self.min.map(Value::from).unwrap_or_default()).into(),
+            ("max", self.max.map(Value::from).unwrap_or_default()).into(),
+            ("avg", avg).into(),
+        ]
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::pb::{KvPair, Value};
+
+    use super::{Aggregator, Scalar};
+
+    fn get(res: &[KvPair], name: &str) -> Value {
+        res.iter().find(|x| x.key == name).unwrap().value.clone().unwrap()
+    }
+
+    #[test]
+    fn aggregator_should_work() {
+        let mut agg = Aggregator::new();
+        agg.push(Scalar::Integer(3));
+        agg.push(Scalar::Integer(1));
+        agg.push(Scalar::String("x".into()));
+        agg.push(Scalar::Integer(2));
+        let res = agg.finish();
+        assert_eq!(get(&res, "count"), 3.into());
+        assert_eq!(get(&res, "sum"), 6.into());
+        assert_eq!(get(&res, "min"), 1.into());
+        assert_eq!(get(&res, "max"), 3.into());
+        assert_eq!(get(&res, "avg"), 2.0.into());
+
+        let mut agg = Aggregator::new();
+        agg.push(Scalar::Integer(1));
+        agg.push(Scalar::Float(0.5));
+        let res = agg.finish();
+        assert_eq!(get(&res, "sum"), 1.5.into());
+        assert_eq!(get(&res, "min"), 0.5.into());
+
+        let res = Aggregator::new().finish();
+        assert_eq!(get(&res, "count"), 0.into());
+        assert_eq!(get(&res, "min"), Value::default());
+        assert_eq!(get(&res, "avg"), Value::default());
+    }
+}
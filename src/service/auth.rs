@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use sha2::{Digest, Sha256};
+
+// 用户密码保存为 argon2 的 PHC 字符串，token 保存为 sha256 的十六进制字符串，配置中不出现明文
+#[derive(Debug, Default, Clone)]
+pub struct Authenticator {
+    users: HashMap<String, String>,
+    tokens: HashMap<String, String>,
+}
+
+impl Authenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn user(mut self, name: impl Into<String>, password_hash: impl Into<String>) -> Self {
+        self.users.insert(name.into(), password_hash.into());
+        self
+    }
+
+    pub fn token(mut self, name: impl Into<String>, token_hash: impl Into<String>) -> Self {
+        self.tokens.insert(token_hash.into().to_lowercase(), name.into());
+        self
+    }
+
+    // 认证通过时返回用户名，username 为空时把 password 当作 token
+    pub fn verify(&self, username: &str, password: &str) -> Option<String> {
+        if username.is_empty() {
+            return self.tokens.get(&hash_token(password)).cloned();
+        }
+
+        let hash = self.users.get(username)?;
+        let hash = PasswordHash::new(hash).ok()?;
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .ok()
+            .map(|_| username.to_string())
+    }
+}
+
+pub fn hash_token(token: &str) -> String {
+    sha256_hex(token.as_bytes())
+}
+
+pub(crate) fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|x| format!("{:02x}", x))
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use argon2::{Argon2, PasswordHasher, password_hash::SaltString};
+
+    use super::{Authenticator, hash_token};
+
+    fn hash_password(password: &str) -> String {
+        let salt = SaltString::from_b64("c29tZXNhbHRzb21lc2FsdA").unwrap();
+        Argon2::default().hash_password(password.as_bytes(), &salt).unwrap().to_string()
+    }
+
+    #[test]
+    fn authenticator_should_work() {
+        let auth = Authenticator::new()
+            .user("alice", hash_password("secret"))
+            .token("ci", hash_token("t0ken"));
+
+        assert_eq!(auth.verify("alice", "secret"), Some("alice".to_string()));
+        assert_eq!(auth.verify("alice", "wrong"), None);
+        assert_eq!(auth.verify("bob", "secret"), None);
+        assert_eq!(auth.verify("", "t0ken"), Some("ci".to_string()));
+        assert_eq!(auth.verify("", "secret"), None);
+        assert_eq!(auth.verify("ci", "t0ken"), None);
+    }
+
+    #[test]
+    fn hash_token_should_work() {
+        assert_eq!(hash_token("abc"), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+    }
+}
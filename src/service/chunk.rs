@@ -0,0 +1,337 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
+
+use crate::{pb::{value, Chunk, CommandResponse, Value}, KvError, Result};
+
+use super::auth::sha256_hex;
+
+// 分块下载时默认的块大小，需要小于连接上的最大帧长度
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+// 分块上传的 value 在服务端组装时最多占用的内存
+pub const MAX_CHUNKED_VALUE: u64 = 1 << 30;
+// 同一连接上所有未完成的分块上传加起来默认最多占用的内存，可以通过 ServiceInner::with_upload_limits 修改
+pub const MAX_UPLOAD_PER_CONNECTION: u64 = 64 << 20;
+// 所有连接上未完成的分块上传加起来默认最多占用的内存
+pub const MAX_UPLOADS_IN_PROGRESS: u64 = 512 << 20;
+
+pub fn checksum(data: &[u8]) -> String {
+    sha256_hex(data)
+}
+
+// 分块下载时块大小的下限，避免过小的块放大内存占用和帧的数量
+pub const MIN_CHUNK_SIZE: usize = 4 * 1024;
+// 响应中除了块数据以外的字段（checksum、offset 等）预留的长度
+const CHUNK_OVERHEAD: usize = 1024;
+
+// 客户端请求的块大小，0 表示默认大小；不小于 MIN_CHUNK_SIZE，加上其他字段后不超过 max_frame_size
+pub fn chunk_size(requested: usize, max_frame_size: usize) -> usize {
+    let requested = if requested == 0 { DEFAULT_CHUNK_SIZE } else { requested };
+    requested
+        .max(MIN_CHUNK_SIZE)
+        .min(max_frame_size.saturating_sub(CHUNK_OVERHEAD).max(1))
+}
+
+// 按需生成块，不会一次性复制出所有的块；空的 value 也会生成一个块
+pub struct Chunks {
+    data: Vec<u8>,
+    checksum: String,
+    chunk_size: usize,
+    offset: usize,
+    done: bool,
+}
+
+impl Chunks {
+    pub fn new(data: Vec<u8>, chunk_size: usize) -> Self {
+        Self {
+            checksum: checksum(&data),
+            data,
+            chunk_size: if chunk_size == 0 { DEFAULT_CHUNK_SIZE } else { chunk_size },
+            offset: 0,
+            done: false,
+        }
+    }
+}
+
+impl Iterator for Chunks {
+    type Item = Chunk;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let end = (self.offset + self.chunk_size).min(self.data.len());
+        let chunk = Chunk {
+            offset: self.offset as _,
+            data: self.data[self.offset..end].to_vec(),
+            total_len: self.data.len() as _,
+            checksum: self.checksum.clone(),
+        };
+        self.offset = end;
+        self.done = end == self.data.len();
+        Some(chunk)
+    }
+}
+
+pub fn split(data: &[u8], chunk_size: usize) -> Vec<Chunk> {
+    Chunks::new(data.to_vec(), chunk_size).collect()
+}
+
+// 把 binary value 的响应拆成多个带 chunk 的响应，其他响应原样返回
+pub fn split_response(mut res: CommandResponse, chunk_size: usize) -> impl Iterator<Item = CommandResponse> + Send + 'static {
+    let data = match res.values.as_mut_slice() {
+        [Value { value: Some(value::Value::Binary(data)) }, ..] if res.state_code == 200 => Some(std::mem::take(data)),
+        _ => None,
+    };
+    let (chunks, res) = match data {
+        Some(data) => (Some(Chunks::new(data, chunk_size)), None),
+        None => (None, Some(res)),
+    };
+
+    chunks
+        .into_iter()
+        .flatten()
+        .map(|chunk| CommandResponse { chunk: Some(chunk), ..CommandResponse::ok() })
+        .chain(res)
+}
+
+// 多个 ChunkBuffer 共享的内存额度，按实际收到的数据计算
+#[derive(Debug, Clone)]
+pub struct UploadBudget {
+    used: Arc<AtomicU64>,
+    limit: u64,
+}
+
+impl Default for UploadBudget {
+    fn default() -> Self {
+        Self::new(MAX_UPLOAD_PER_CONNECTION)
+    }
+}
+
+impl UploadBudget {
+    pub fn new(limit: u64) -> Self {
+        Self { used: Default::default(), limit }
+    }
+
+    // 与 self 共享已使用的额度，但使用新的上限
+    pub fn with_limit(&self, limit: u64) -> Self {
+        Self { used: Arc::clone(&self.used), limit }
+    }
+
+    pub fn used(&self) -> u64 {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    fn reserve(&self, size: u64) -> Result<()> {
+        self.used
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| {
+                x.checked_add(size).filter(|x| *x <= self.limit)
+            })
+            .map(|_| ())
+            .map_err(|used| KvError::InvalidChunk(format!(
+                "uploads in progress use {} bytes, limit {}", used, self.limit
+            )))
+    }
+
+    fn release(&self, size: u64) {
+        self.used.fetch_sub(size, Ordering::AcqRel);
+    }
+}
+
+// 按顺序接收块，收齐后校验长度和 checksum
+// total_len 由对端声明，不能据此预先分配内存，数据随收到的块增长
+#[derive(Debug)]
+pub struct ChunkBuffer {
+    total_len: u64,
+    checksum: String,
+    data: Vec<u8>,
+    budgets: Vec<UploadBudget>,
+}
+
+impl ChunkBuffer {
+    // 第一块的 offset 必须为 0，total_len 不能超过 limit
+    pub fn new(chunk: &Chunk, limit: u64) -> Result<Self> {
+        if chunk.offset != 0 {
+            return Err(KvError::InvalidChunk(format!("first chunk starts at {}", chunk.offset)));
+        }
+        if chunk.total_len > limit {
+            return Err(KvError::InvalidChunk(format!("value size {} exceeds limit {}", chunk.total_len, limit)));
+        }
+
+        Ok(Self {
+            total_len: chunk.total_len,
+            checksum: chunk.checksum.clone(),
+            data: Vec::with_capacity(chunk.total_len.min(DEFAULT_CHUNK_SIZE as u64) as _),
+            budgets: vec![],
+        })
+    }
+
+    // 收到的数据同时计入所有的 budget，任意一个超出时 push 返回错误，buffer 释放时归还
+    pub fn with_budget(mut self, budget: UploadBudget) -> Self {
+        self.budgets.push(budget);
+        self
+    }
+
+    // 返回是否已经收齐
+    pub fn push(&mut self, chunk: Chunk) -> Result<bool> {
+        if chunk.offset != self.data.len() as u64 {
+            return Err(KvError::InvalidChunk(format!("expect offset {}, got {}", self.data.len(), chunk.offset)));
+        }
+        if chunk.total_len != self.total_len || chunk.checksum != self.checksum {
+            return Err(KvError::InvalidChunk("total_len or checksum changed".into()));
+        }
+        if self.data.len() + chunk.data.len() > self.total_len as usize {
+            return Err(KvError::InvalidChunk(format!("data exceeds total length {}", self.total_len)));
+        }
+
+        let size = chunk.data.len() as u64;
+        for (i, budget) in self.budgets.iter().enumerate() {
+            if let Err(e) = budget.reserve(size) {
+                self.budgets[..i].iter().for_each(|x| x.release(size));
+                return Err(e);
+            }
+        }
+        self.data.extend_from_slice(&chunk.data);
+        Ok(self.is_complete())
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.data.len() as u64 == self.total_len
+    }
+
+    pub fn finish(mut self) -> Result<Vec<u8>> {
+        if !self.is_complete() {
+            return Err(KvError::InvalidChunk(format!("expect {} bytes, got {}", self.total_len, self.data.len())));
+        }
+        let checksum = checksum(&self.data);
+        if checksum != self.checksum {
+            return Err(KvError::ChecksumMismatch(std::mem::take(&mut self.checksum), checksum));
+        }
+        let data = std::mem::take(&mut self.data);
+        self.budgets.iter().for_each(|x| x.release(data.len() as _));
+        Ok(data)
+    }
+}
+
+impl Drop for ChunkBuffer {
+    fn drop(&mut self) {
+        self.budgets.iter().for_each(|x| x.release(self.data.len() as _));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{CommandResponse, KvError, Value};
+
+    use super::{chunk_size, split, split_response, ChunkBuffer, UploadBudget, DEFAULT_CHUNK_SIZE, MAX_CHUNKED_VALUE, MIN_CHUNK_SIZE};
+
+    fn assemble(chunks: Vec<crate::Chunk>) -> crate::Result<Vec<u8>> {
+        let mut buf = ChunkBuffer::new(&chunks[0], 1 << 20)?;
+        for chunk in chunks {
+            buf.push(chunk)?;
+        }
+        buf.finish()
+    }
+
+    #[test]
+    fn chunk_should_work() {
+        let data = (0..10000).map(|x| x as u8).collect::<Vec<_>>();
+        for chunk_size in [1000, 3333, 10000, 20000] {
+            let chunks = split(&data, chunk_size);
+            assert_eq!(chunks.len(), data.len().div_ceil(chunk_size));
+            assert_eq!(assemble(chunks).unwrap(), data);
+        }
+
+        let chunks = split(&[], 1000);
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(assemble(chunks).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn invalid_chunks_should_fail() {
+        let data = vec![1u8; 3000];
+
+        // 乱序、缺失、篡改数据以及超过限制
+        let mut chunks = split(&data, 1000);
+        chunks.swap(1, 2);
+        assert!(matches!(assemble(chunks), Err(KvError::InvalidChunk(_))));
+
+        let mut chunks = split(&data, 1000);
+        chunks.pop();
+        assert!(matches!(assemble(chunks), Err(KvError::InvalidChunk(_))));
+
+        let mut chunks = split(&data, 1000);
+        chunks[1].data[0] = 2;
+        assert!(matches!(assemble(chunks), Err(KvError::ChecksumMismatch(_, _))));
+
+        let chunks = split(&data, 1000);
+        assert!(matches!(ChunkBuffer::new(&chunks[0], 100), Err(KvError::InvalidChunk(_))));
+        assert!(matches!(ChunkBuffer::new(&chunks[1], 1 << 20), Err(KvError::InvalidChunk(_))));
+    }
+
+    #[test]
+    fn declared_total_len_should_not_be_preallocated() {
+        let chunk = crate::Chunk { total_len: MAX_CHUNKED_VALUE, data: vec![1; 10], ..Default::default() };
+        let mut buf = ChunkBuffer::new(&chunk, MAX_CHUNKED_VALUE).unwrap();
+        assert!(buf.data.capacity() <= DEFAULT_CHUNK_SIZE);
+        assert!(!buf.push(chunk).unwrap());
+        assert!(buf.data.capacity() <= DEFAULT_CHUNK_SIZE);
+    }
+
+    #[test]
+    fn upload_budget_should_be_shared() {
+        let budget = UploadBudget::new(1500);
+        let chunks = split(&[1u8; 1000], 1000);
+        let mut a = ChunkBuffer::new(&chunks[0], 1 << 20).unwrap().with_budget(budget.clone());
+        let mut b = ChunkBuffer::new(&chunks[0], 1 << 20).unwrap().with_budget(budget.clone());
+
+        assert!(a.push(chunks[0].clone()).unwrap());
+        assert!(matches!(b.push(chunks[0].clone()), Err(KvError::InvalidChunk(_))));
+        assert_eq!(budget.used(), 1000);
+
+        // 完成或者放弃的上传归还额度
+        a.finish().unwrap();
+        assert_eq!(budget.used(), 0);
+        assert!(b.push(chunks[0].clone()).unwrap());
+        drop(b);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn upload_should_fit_every_budget() {
+        let connection = UploadBudget::new(1 << 20);
+        let global = UploadBudget::new(1500);
+        let other = connection.with_limit(500);
+        let chunks = split(&[1u8; 1000], 1000);
+
+        let mut a = ChunkBuffer::new(&chunks[0], 1 << 20).unwrap().with_budget(connection.clone()).with_budget(global.clone());
+        assert!(a.push(chunks[0].clone()).unwrap());
+        // 全局额度不足时，已经计入连接额度的部分也要撤销
+        let mut b = ChunkBuffer::new(&chunks[0], 1 << 20).unwrap().with_budget(connection.clone()).with_budget(global.clone());
+        assert!(matches!(b.push(chunks[0].clone()), Err(KvError::InvalidChunk(_))));
+        assert_eq!((connection.used(), global.used()), (1000, 1000));
+
+        // with_limit 共享已使用的额度
+        assert_eq!(other.used(), 1000);
+        drop(a);
+        assert_eq!((other.used(), global.used()), (0, 0));
+    }
+
+    #[test]
+    fn chunk_size_should_be_clamped() {
+        assert_eq!(chunk_size(0, 1 << 20), DEFAULT_CHUNK_SIZE);
+        assert_eq!(chunk_size(1, 1 << 20), MIN_CHUNK_SIZE);
+        assert_eq!(chunk_size(1 << 30, 64 * 1024), 63 * 1024);
+        assert_eq!(chunk_size(1, 2000), 976);
+    }
+
+    #[test]
+    fn split_response_should_work() {
+        let res: CommandResponse = Value::from(vec![1u8; 2500]).into();
+        let chunks = split_response(res, 1000).collect::<Vec<_>>();
+        assert_eq!(chunks.len(), 3);
+        assert!(chunks.iter().all(|x| x.state_code == 200 && x.values.is_empty()));
+
+        let res: CommandResponse = Value::from("hello").into();
+        assert_eq!(split_response(res.clone(), 1000).collect::<Vec<_>>(), vec![res]);
+    }
+}
@@ -1,6 +1,15 @@
+use serde_json::Value as JsonValue;
+
 use crate::pb::{Hget, Hset, Hmget, Hmset, Value, Hexists, Hmexists, Hdelete, Hmdelete, Hgetall};
+use crate::pb::{JsonGet, JsonSet, JsonDel, JsonAppend, JsonIncr, CreateIndex, DropIndex, QueryIndex, Aggregate};
+use crate::pb::{Hhistory, EnableHistory, DisableHistory, HgetChunked};
 use crate::{storage::Storage, pb::CommandResponse};
-use crate::{KvError};
+use crate::{KvError, Result};
+
+use crate::storage::{JsonPath, load_document, incr_number, AsOf, HistoryPolicy};
+
+use super::aggregate::Aggregator;
+use super::filter::{parse_filter, Field};
 
 pub trait CommandService {
     fn execute(self, store: &impl Storage) -> CommandResponse;
@@ -8,9 +17,15 @@ pub trait CommandService {
 
 impl CommandService for Hget {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        let Hget { table, key } = self;
+        let Hget { table, key, as_of_version, as_of_time } = self;
+
+        let res = match (as_of_version, as_of_time) {
+            (0, 0) => store.get(&table, &key),
+            (0, time) => store.get_as_of(&table, &key, AsOf::Time(time)),
+            (version, _) => store.get_as_of(&table, &key, AsOf::Version(version)),
+        };
 
-        match store.get(&table, &key) {
+        match res {
             Ok(Some(value)) => value.into(),
             Ok(None) => KvError::NotFound(table, key).into(),
             Err(e) => e.into(),
@@ -18,6 +33,13 @@ impl CommandService for Hget {
     }
 }
 
+// 读取整个 value，由 Service 拆分成多个响应
+impl CommandService for HgetChunked {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        Hget { table: self.table, key: self.key, ..Default::default() }.execute(store)
+    }
+}
+
 impl CommandService for Hmget {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         let Hmget { table, keys } = self;
@@ -37,9 +59,10 @@ impl CommandService for Hset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         let Hset { table, pair } = self;
 
-        let (key, value) = pair
-            .map(|x| (x.key, x.value.unwrap()))
-            .unwrap();
+        // 请求可能来自 gRPC 等外部接口，缺少 pair 或者 value 时返回错误
+        let Some((key, Some(value))) = pair.map(|x| (x.key, x.value)) else {
+            return KvError::InvalidCommand("hset requires a key and a value".into()).into();
+        };
 
         match store.set(&table, key, value) {
             Ok(Some(value)) => value.into(),
@@ -53,10 +76,14 @@ impl CommandService for Hmset {
     fn execute(self, store: &impl Storage) -> CommandResponse {
         let Hmset { table, pairs } = self;
 
+        // 先检查所有的 pair，避免只写入一部分
+        let Some(pairs) = pairs.into_iter().map(|x| Some((x.key, x.value?))).collect::<Option<Vec<_>>>() else {
+            return KvError::InvalidCommand("hmset requires a value for every key".into()).into();
+        };
+
         let values = pairs
             .into_iter()
-            .map(|pair| {
-                let (key, value) = (pair.key, pair.value.unwrap());
+            .map(|(key, value)| {
                 match store.set(&table, key, value) {
                     Ok(Some(value)) => value,
                     _ => Value { value: None },
@@ -126,10 +153,418 @@ impl CommandService for Hmdelete {
 
 impl CommandService for Hgetall {
     fn execute(self, store: &impl Storage) -> CommandResponse {
-        let table = self.table;
-        match store.get_all(&table) {
+        let Hgetall { table, filter } = self;
+
+        let filter = match parse_filter(&filter) {
+            Ok(filter) => filter,
+            Err(e) => return e.into(),
+        };
+        let res = match filter {
+            Some(filter) => store
+                .get_iter(&table)
+                .map(|iter| iter.filter(|x| filter.matches(x)).collect::<Vec<_>>()),
+            None => store.get_all(&table),
+        };
+        match res {
             Ok(values) => values.into(),
             Err(e) => e.into(),
         }
     }
-}
\ No newline at end of file
+}
+
+impl CommandService for JsonGet {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let JsonGet { table, key, path } = self;
+
+        let res = path.parse::<JsonPath>().and_then(|path| {
+            let doc = store.get(&table, &key)?
+                .ok_or_else(|| KvError::NotFound(table.clone(), key.clone()))?;
+            let doc = load_document(doc)?;
+            path.get(&doc)
+                .map(|x| x.clone().into())
+                .ok_or_else(|| path.not_found())
+        });
+        into_response(res)
+    }
+}
+
+impl CommandService for JsonSet {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let JsonSet { table, key, path, value } = self;
+
+        let res = path.parse::<JsonPath>().and_then(|path| {
+            let value: JsonValue = value.unwrap_or_default().try_into()?;
+            let mut old = None;
+            store.update(&table, &key, |doc| {
+                let mut doc = match doc {
+                    Some(doc) => load_document(doc)?,
+                    None if path.is_root() => JsonValue::Null,
+                    None => return Err(KvError::NotFound(table.clone(), key.clone())),
+                };
+                old = path.set(&mut doc, value.clone())?;
+                Ok(doc.into())
+            })?;
+            Ok(old.map(Value::from).unwrap_or_default())
+        });
+        into_response(res)
+    }
+}
+
+impl CommandService for JsonDel {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let JsonDel { table, key, path } = self;
+
+        let res = path.parse::<JsonPath>().and_then(|path| {
+            if path.is_root() {
+                return Ok(store.delete(&table, &key)?.unwrap_or_default());
+            }
+            let mut removed = None;
+            store.update(&table, &key, |doc| {
+                let doc = doc.ok_or_else(|| KvError::NotFound(table.clone(), key.clone()))?;
+                let mut doc = load_document(doc)?;
+                removed = path.delete(&mut doc)?;
+                Ok(doc.into())
+            })?;
+            Ok(removed.map(Value::from).unwrap_or_default())
+        });
+        into_response(res)
+    }
+}
+
+impl CommandService for JsonAppend {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let JsonAppend { table, key, path, values } = self;
+
+        let res = path.parse::<JsonPath>().and_then(|path| {
+            let values = values
+                .into_iter()
+                .map(JsonValue::try_from)
+                .collect::<Result<Vec<_>>>()?;
+            let mut len = 0;
+            store.update(&table, &key, |doc| {
+                let doc = doc.ok_or_else(|| KvError::NotFound(table.clone(), key.clone()))?;
+                let mut doc = load_document(doc)?;
+                let arr = path.get_mut(&mut doc)
+                    .ok_or_else(|| path.not_found())?
+                    .as_array_mut()
+                    .ok_or_else(|| path.mismatch())?;
+                arr.extend(values.iter().cloned());
+                len = arr.len();
+                Ok(doc.into())
+            })?;
+            Ok((len as i64).into())
+        });
+        into_response(res)
+    }
+}
+
+impl CommandService for JsonIncr {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let JsonIncr { table, key, path, by } = self;
+
+        let res = path.parse::<JsonPath>().and_then(|path| {
+            let by: JsonValue = by.unwrap_or_default().try_into()?;
+            let mut res = JsonValue::Null;
+            store.update(&table, &key, |doc| {
+                let doc = doc.ok_or_else(|| KvError::NotFound(table.clone(), key.clone()))?;
+                let mut doc = load_document(doc)?;
+                let node = path.get_mut(&mut doc).ok_or_else(|| path.not_found())?;
+                res = incr_number(node, &by).ok_or_else(|| path.mismatch())?;
+                Ok(doc.into())
+            })?;
+            match (res.as_i64(), res.as_f64()) {
+                (Some(x), _) => Ok(x.into()),
+                (_, Some(x)) => Ok(x.into()),
+                _ => Err(path.mismatch()),
+            }
+        });
+        into_response(res)
+    }
+}
+
+impl CommandService for CreateIndex {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let CreateIndex { table, index, path } = self;
+
+        match store.create_index(&table, &index, &path) {
+            Ok(()) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for DropIndex {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let DropIndex { table, index } = self;
+
+        match store.drop_index(&table, &index) {
+            Ok(b) => Value::from(b).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for QueryIndex {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let QueryIndex { table, index, eq, min, max } = self;
+
+        let res = match eq {
+            Some(eq) => store.query_index(&table, &index, Some(&eq), Some(&eq)),
+            None => store.query_index(&table, &index, min.as_ref(), max.as_ref()),
+        };
+        match res {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Aggregate {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let Aggregate { table, path, prefix, start, end, filter } = self;
+
+        let res = parse_filter(&filter).and_then(|filter| {
+            let field: Field = path.parse()?;
+            let in_range = |key: &str| {
+                key.starts_with(prefix.as_str())
+                    && (start.is_empty() || key >= start.as_str())
+                    && (end.is_empty() || key < end.as_str())
+            };
+
+            let mut agg = Aggregator::new();
+            store
+                .get_iter(&table)?
+                .filter(|x| in_range(&x.key))
+                .filter(|x| filter.as_ref().is_none_or(|f| f.matches(x)))
+                .filter_map(|x| field.resolve(&x))
+                .for_each(|x| agg.push(x));
+            Ok(agg.finish())
+        });
+        match res {
+            Ok(pairs) => pairs.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for Hhistory {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let Hhistory { table, key } = self;
+
+        match store.history(&table, &key) {
+            Ok(versions) => versions.into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for EnableHistory {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let EnableHistory { table, max_versions, retention_secs } = self;
+
+        // 两个限制都为 0 时保留全部版本
+        match store.enable_history(&table, HistoryPolicy::new(max_versions, retention_secs)) {
+            Ok(_) => CommandResponse::ok(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+impl CommandService for DisableHistory {
+    fn execute(self, store: &impl Storage) -> CommandResponse {
+        let DisableHistory { table } = self;
+
+        match store.disable_history(&table) {
+            Ok(b) => Value::from(b).into(),
+            Err(e) => e.into(),
+        }
+    }
+}
+
+fn into_response(res: Result<Value>) -> CommandResponse {
+    match res {
+        Ok(value) => value.into(),
+        Err(e) => e.into(),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{pb::{command_request::RequestData, CommandRequest, Value}, storage::MemoryDb};
+
+    use super::CommandService;
+
+    fn execute(cmd: CommandRequest, store: &MemoryDb) -> crate::CommandResponse {
+        match cmd.request_data.unwrap() {
+            RequestData::Hget(x) => x.execute(store),
+            RequestData::Hset(x) => x.execute(store),
+            RequestData::Hdelete(x) => x.execute(store),
+            RequestData::Hgetall(x) => x.execute(store),
+            RequestData::JsonGet(x) => x.execute(store),
+            RequestData::JsonSet(x) => x.execute(store),
+            RequestData::JsonDel(x) => x.execute(store),
+            RequestData::JsonAppend(x) => x.execute(store),
+            RequestData::JsonIncr(x) => x.execute(store),
+            RequestData::CreateIndex(x) => x.execute(store),
+            RequestData::DropIndex(x) => x.execute(store),
+            RequestData::QueryIndex(x) => x.execute(store),
+            RequestData::Aggregate(x) => x.execute(store),
+            RequestData::Hhistory(x) => x.execute(store),
+            RequestData::EnableHistory(x) => x.execute(store),
+            RequestData::DisableHistory(x) => x.execute(store),
+            _ => unreachable!(),
+        }
+    }
+
+    fn json(s: &str) -> Value {
+        serde_json::from_str::<serde_json::Value>(s).unwrap().into()
+    }
+
+    #[test]
+    fn json_commands_should_work() {
+        let store = MemoryDb::new();
+
+        let res = execute(CommandRequest::new_json_set("t1", "k1", "$.a", 1.into()), &store);
+        assert_eq!(res.state_code, 404);
+
+        let doc = json(r#"{"a": {"n": 1, "list": []}}"#);
+        let res = execute(CommandRequest::new_json_set("t1", "k1", "$", doc), &store);
+        assert_eq!(res.state_code, 200);
+
+        let res = execute(CommandRequest::new_json_set("t1", "k1", "$.a.name", "x".into()), &store);
+        assert_eq!(res.values, vec![Value::default()]);
+
+        let res = execute(CommandRequest::new_json_incr("t1", "k1", "$.a.n", 2.into()), &store);
+        assert_eq!(res.values, vec![3.into()]);
+        let res = execute(CommandRequest::new_json_incr("t1", "k1", "$.a.name", 2.into()), &store);
+        assert_eq!(res.state_code, 400);
+
+        let values = vec![1.into(), "b".into()];
+        let res = execute(CommandRequest::new_json_append("t1", "k1", "$.a.list", values), &store);
+        assert_eq!(res.values, vec![2.into()]);
+
+        let res = execute(CommandRequest::new_json_get("t1", "k1", "$.a.list[1]"), &store);
+        assert_eq!(res.values, vec![json(r#""b""#)]);
+
+        let res = execute(CommandRequest::new_json_del("t1", "k1", "$.a.list"), &store);
+        assert_eq!(res.values, vec![json(r#"[1, "b"]"#)]);
+
+        let res = execute(CommandRequest::new_json_get("t1", "k1", "$"), &store);
+        assert_eq!(res.values, vec![json(r#"{"a": {"n": 3, "name": "x"}}"#)]);
+
+        execute(CommandRequest::new_hset("t1", "k2", "v2".into()), &store);
+        let res = execute(CommandRequest::new_json_get("t1", "k2", "$"), &store);
+        assert_eq!(res.state_code, 400);
+
+        let res = execute(CommandRequest::new_json_del("t1", "k1", "$"), &store);
+        assert_eq!(res.state_code, 200);
+        let res = execute(CommandRequest::new_json_get("t1", "k1", "$"), &store);
+        assert_eq!(res.state_code, 404);
+    }
+
+    #[test]
+    fn index_commands_should_work() {
+        let store = MemoryDb::new();
+        execute(CommandRequest::new_hset("t1", "k1", json(r#"{"age": 10}"#)), &store);
+        execute(CommandRequest::new_hset("t1", "k2", json(r#"{"age": 20}"#)), &store);
+
+        let res = execute(CommandRequest::new_create_index("t1", "age", "$.age"), &store);
+        assert_eq!(res.state_code, 200);
+
+        let res = execute(CommandRequest::new_json_incr("t1", "k1", "$.age", 10.into()), &store);
+        assert_eq!(res.values, vec![20.into()]);
+
+        let res = execute(CommandRequest::new_query_index("t1", "age", Some(20.into()), None, None), &store);
+        let mut keys = res.pairs.into_iter().map(|x| x.key).collect::<Vec<_>>();
+        keys.sort();
+        assert_eq!(keys, vec!["k1", "k2"]);
+
+        let res = execute(CommandRequest::new_query_index("t1", "age", None, Some(21.into()), None), &store);
+        assert!(res.pairs.is_empty());
+
+        let res = execute(CommandRequest::new_drop_index("t1", "age"), &store);
+        assert_eq!(res.values, vec![true.into()]);
+        let res = execute(CommandRequest::new_query_index("t1", "age", None, None, None), &store);
+        assert_eq!(res.state_code, 404);
+    }
+
+    #[test]
+    fn hgetall_with_filter_should_work() {
+        let store = MemoryDb::new();
+        execute(CommandRequest::new_hset("t1", "user:1", json(r#"{"age": 10}"#)), &store);
+        execute(CommandRequest::new_hset("t1", "user:2", json(r#"{"age": 20}"#)), &store);
+        execute(CommandRequest::new_hset("t1", "admin", json(r#"{"age": 30}"#)), &store);
+
+        let cmd = CommandRequest::new_hget_all_with_filter("t1", r#"key prefix "user:" and $.age >= 15"#);
+        let res = execute(cmd, &store);
+        assert_eq!(res.pairs, vec![("user:2", json(r#"{"age": 20}"#)).into()]);
+
+        let res = execute(CommandRequest::new_hget_all("t1"), &store);
+        assert_eq!(res.pairs.len(), 3);
+
+        let res = execute(CommandRequest::new_hget_all_with_filter("t1", "age >"), &store);
+        assert_eq!(res.state_code, 400);
+
+        let res = execute(CommandRequest::new_hget_all_with_filter("t1", "(".repeat(100_000)), &store);
+        assert_eq!(res.state_code, 400);
+    }
+
+    #[test]
+    fn aggregate_should_work() {
+        let store = MemoryDb::new();
+        execute(CommandRequest::new_hset("t1", "a:1", json(r#"{"n": 1}"#)), &store);
+        execute(CommandRequest::new_hset("t1", "a:2", json(r#"{"n": 2.5}"#)), &store);
+        execute(CommandRequest::new_hset("t1", "a:3", json(r#"{"n": "x"}"#)), &store);
+        execute(CommandRequest::new_hset("t1", "b:1", json(r#"{"n": 100}"#)), &store);
+
+        let mut cmd = CommandRequest::new_aggregate("t1", "$.n", "");
+        if let Some(RequestData::Aggregate(x)) = cmd.request_data.as_mut() {
+            x.prefix = "a:".into();
+        }
+        let res = execute(cmd, &store);
+        assert_eq!(res.pairs, vec![
+            ("count", 2.into()).into(),
+            ("sum", 3.5.into()).into(),
+            ("min", 1.into()).into(),
+            ("max", 2.5.into()).into(),
+            ("avg", 1.75.into()).into(),
+        ]);
+
+        let res = execute(CommandRequest::new_aggregate("t1", "$.n", "$.n > 2"), &store);
+        assert_eq!(res.pairs[0], ("count", 2.into()).into());
+        assert_eq!(res.pairs[1], ("sum", 102.5.into()).into());
+
+        let res = execute(CommandRequest::new_aggregate("t1", "$.", ""), &store);
+        assert_eq!(res.state_code, 400);
+    }
+
+    #[test]
+    fn history_commands_should_work() {
+        let store = MemoryDb::new();
+        let res = execute(CommandRequest::new_hhistory("t1", "k1"), &store);
+        assert_eq!(res.state_code, 400);
+
+        execute(CommandRequest::new_enable_history("t1", 10, 0), &store);
+        execute(CommandRequest::new_hset("t1", "k1", "v1".into()), &store);
+        execute(CommandRequest::new_hset("t1", "k1", "v2".into()), &store);
+        execute(CommandRequest::new_hdelete("t1", "k1"), &store);
+
+        let res = execute(CommandRequest::new_hhistory("t1", "k1"), &store);
+        assert_eq!(res.versions.len(), 3);
+        assert!(res.versions[2].deleted);
+
+        let v1 = res.versions[0].version;
+        let res = execute(CommandRequest::new_hget_as_of("t1", "k1", v1, 0), &store);
+        assert_eq!(res.values, vec!["v1".into()]);
+
+        let res = execute(CommandRequest::new_hget_as_of("t1", "k1", 0, u64::MAX), &store);
+        assert_eq!(res.state_code, 404);
+
+        let res = execute(CommandRequest::new_hget("t1", "k1"), &store);
+        assert_eq!(res.state_code, 404);
+
+        let res = execute(CommandRequest::new_disable_history("t1"), &store);
+        assert_eq!(res.values, vec![true.into()]);
+    }
+}
@@ -0,0 +1,473 @@
+use std::{cmp::Ordering, str::FromStr};
+
+use regex::Regex;
+use serde_json::Value as JsonValue;
+
+use crate::{pb::{value, KvPair, Value}, storage::{JsonPath, load_document}, KvError, Result};
+
+// 过滤表达式，例如：
+//   key prefix "user:" and (value >= 18 or $.vip == true)
+//   not value regex "^tmp-"
+// 字段可以是 key、value 或 $ 开头的 json 路径；比较类型不一致时结果为 false
+#[derive(Debug)]
+pub enum Filter {
+    And(Box<Filter>, Box<Filter>),
+    Or(Box<Filter>, Box<Filter>),
+    Not(Box<Filter>),
+    Compare(Field, Op, Scalar),
+    Prefix(Field, String),
+    Regex(Field, Regex),
+}
+
+#[derive(Debug)]
+pub enum Field {
+    Key,
+    Value,
+    Json(JsonPath),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Op {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Scalar {
+    Null,
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Binary(Vec<u8>),
+}
+
+impl Filter {
+    pub fn matches(&self, pair: &KvPair) -> bool {
+        match self {
+            Filter::And(a, b) => a.matches(pair) && b.matches(pair),
+            Filter::Or(a, b) => a.matches(pair) || b.matches(pair),
+            Filter::Not(a) => !a.matches(pair),
+            Filter::Compare(field, op, rhs) => field
+                .resolve(pair)
+                .and_then(|lhs| lhs.compare(rhs))
+                .is_some_and(|x| op.eval(x)),
+            Filter::Prefix(field, prefix) => matches!(
+                field.resolve(pair),
+                Some(Scalar::String(x)) if x.starts_with(prefix.as_str())
+            ),
+            Filter::Regex(field, re) => matches!(
+                field.resolve(pair),
+                Some(Scalar::String(x)) if re.is_match(&x)
+            ),
+        }
+    }
+}
+
+impl FromStr for Field {
+    type Err = KvError;
+
+    // 空字符串等同于 value
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "" => Ok(Field::Value),
+            x if x.eq_ignore_ascii_case("value") => Ok(Field::Value),
+            x if x.eq_ignore_ascii_case("key") => Ok(Field::Key),
+            x => Ok(Field::Json(x.parse()?)),
+        }
+    }
+}
+
+impl Field {
+    pub fn resolve(&self, pair: &KvPair) -> Option<Scalar> {
+        match self {
+            Field::Key => Some(Scalar::String(pair.key.clone())),
+            Field::Value => pair.value.as_ref().map(Scalar::from),
+            Field::Json(path) => {
+                let doc = load_document(pair.value.clone()?).ok()?;
+                path.get(&doc).and_then(Scalar::from_json)
+            }
+        }
+    }
+}
+
+impl Op {
+    fn eval(self, ord: Ordering) -> bool {
+        match self {
+            Op::Eq => ord == Ordering::Equal,
+            Op::Ne => ord != Ordering::Equal,
+            Op::Lt => ord == Ordering::Less,
+            Op::Le => ord != Ordering::Greater,
+            Op::Gt => ord == Ordering::Greater,
+            Op::Ge => ord != Ordering::Less,
+        }
+    }
+}
+
+impl Scalar {
+    // 整数和浮点数可以互相比较，其余类型只能同类型比较
+    pub fn compare(&self, other: &Scalar) -> Option<Ordering> {
+        match (self, other) {
+            (Scalar::Null, Scalar::Null) => Some(Ordering::Equal),
+            (Scalar::Bool(a), Scalar::Bool(b)) => Some(a.cmp(b)),
+            (Scalar::Integer(a), Scalar::Integer(b)) => Some(a.cmp(b)),
+            (Scalar::Integer(a), Scalar::Float(b)) => (*a as f64).partial_cmp(b),
+            (Scalar::Float(a), Scalar::Integer(b)) => a.partial_cmp(&(*b as f64)),
+            (Scalar::Float(a), Scalar::Float(b)) => a.partial_cmp(b),
+            (Scalar::String(a), Scalar::String(b)) => Some(a.cmp(b)),
+            (Scalar::Binary(a), Scalar::Binary(b)) => Some(a.cmp(b)),
+            _ => None,
+        }
+    }
+
+    fn from_json(value: &JsonValue) -> Option<Self> {
+        match value {
+            JsonValue::Null => Some(Scalar::Null),
+            JsonValue::Bool(x) => Some(Scalar::Bool(*x)),
+            JsonValue::Number(x) => x.as_i64().map(Scalar::Integer).or(x.as_f64().map(Scalar::Float)),
+            JsonValue::String(x) => Some(Scalar::String(x.clone())),
+            _ => None,
+        }
+    }
+}
+
+impl From<&Value> for Scalar {
+    fn from(value: &Value) -> Self {
+        match &value.value {
+            None => Scalar::Null,
+            Some(value::Value::Bool(x)) => Scalar::Bool(*x),
+            Some(value::Value::Integer(x)) => Scalar::Integer(*x),
+            Some(value::Value::Float(x)) => Scalar::Float(*x),
+            Some(value::Value::String(x)) => Scalar::String(x.clone()),
+            Some(value::Value::Binary(x)) => Scalar::Binary(x.clone()),
+            // json 文档只能通过 $ 路径比较，整体作为字符串处理
+            Some(value::Value::Json(x)) => Scalar::String(x.clone()),
+        }
+    }
+}
+
+impl From<Scalar> for Value {
+    fn from(value: Scalar) -> Self {
+        match value {
+            Scalar::Null => Value::default(),
+            Scalar::Bool(x) => x.into(),
+            Scalar::Integer(x) => x.into(),
+            Scalar::Float(x) => x.into(),
+            Scalar::String(x) => x.into(),
+            Scalar::Binary(x) => x.into(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Path(String),
+    Literal(Scalar),
+    Op(Op),
+    LParen,
+    RParen,
+}
+
+fn tokenize(s: &str) -> Result<Vec<Token>> {
+    let invalid = |msg: &str| KvError::Invalid(format!("invalid filter {:?}: {}", s, msg));
+
+    let chars = s.chars().collect::<Vec<_>>();
+    let mut tokens = vec![];
+    let mut i = 0;
+
+    while i < chars.len() {
+        let c = chars[i];
+        match c {
+            _ if c.is_whitespace() => i += 1,
+            '(' => { tokens.push(Token::LParen); i += 1; },
+            ')' => { tokens.push(Token::RParen); i += 1; },
+            '=' | '!' | '<' | '>' => {
+                let next = chars.get(i + 1).copied();
+                let (op, len) = match (c, next) {
+                    ('=', Some('=')) => (Op::Eq, 2),
+                    ('!', Some('=')) => (Op::Ne, 2),
+                    ('<', Some('=')) => (Op::Le, 2),
+                    ('>', Some('=')) => (Op::Ge, 2),
+                    ('<', _) => (Op::Lt, 1),
+                    ('>', _) => (Op::Gt, 1),
+                    ('=', _) => (Op::Eq, 1),
+                    _ => return Err(invalid("unknown operator")),
+                };
+                tokens.push(Token::Op(op));
+                i += len;
+            },
+            '"' | '\'' => {
+                let mut text = String::new();
+                i += 1;
+                loop {
+                    match chars.get(i) {
+                        None => return Err(invalid("unterminated string")),
+                        Some('\\') => {
+                            text.push(*chars.get(i + 1).ok_or_else(|| invalid("unterminated string"))?);
+                            i += 2;
+                        },
+                        Some(x) if *x == c => { i += 1; break; },
+                        Some(x) => { text.push(*x); i += 1; },
+                    }
+                }
+                tokens.push(Token::Literal(Scalar::String(text)));
+            },
+            _ => {
+                // $ 路径中允许出现 [ ] 和引号，直到空白或括号为止
+                let start = i;
+                let mut depth = 0;
+                while i < chars.len() {
+                    match chars[i] {
+                        '[' => depth += 1,
+                        ']' => depth -= 1,
+                        x if depth == 0 && (x.is_whitespace() || "()=!<>".contains(x)) => break,
+                        _ => (),
+                    }
+                    i += 1;
+                }
+                let word: String = chars[start..i].iter().collect();
+                tokens.push(word_token(word));
+            },
+        }
+    }
+
+    Ok(tokens)
+}
+
+fn word_token(word: String) -> Token {
+    if word.starts_with('$') {
+        return Token::Path(word);
+    }
+    match word.as_str() {
+        "true" => Token::Literal(Scalar::Bool(true)),
+        "false" => Token::Literal(Scalar::Bool(false)),
+        "null" => Token::Literal(Scalar::Null),
+        _ => match (word.parse::<i64>(), word.parse::<f64>()) {
+            (Ok(x), _) => Token::Literal(Scalar::Integer(x)),
+            (_, Ok(x)) => Token::Literal(Scalar::Float(x)),
+            _ => Token::Ident(word),
+        },
+    }
+}
+
+// 过滤表达式来自客户端，限制括号 / not 的嵌套深度和谓词个数，
+// 避免解析、匹配以及析构时递归过深导致栈溢出
+const MAX_FILTER_DEPTH: usize = 64;
+const MAX_FILTER_TERMS: usize = 1024;
+
+struct Parser<'a> {
+    src: &'a str,
+    tokens: Vec<Token>,
+    pos: usize,
+    depth: usize,
+    terms: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn invalid(&self, msg: &str) -> KvError {
+        KvError::Invalid(format!("invalid filter {:?}: {}", self.src, msg))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    fn eat_keyword(&mut self, keyword: &str) -> bool {
+        match self.peek() {
+            Some(Token::Ident(x)) if x.eq_ignore_ascii_case(keyword) => {
+                self.pos += 1;
+                true
+            },
+            _ => false,
+        }
+    }
+
+    fn parse_or(&mut self) -> Result<Filter> {
+        let mut lhs = self.parse_and()?;
+        while self.eat_keyword("or") {
+            lhs = Filter::Or(Box::new(lhs), Box::new(self.parse_and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Filter> {
+        let mut lhs = self.parse_unary()?;
+        while self.eat_keyword("and") {
+            lhs = Filter::And(Box::new(lhs), Box::new(self.parse_unary()?));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Filter> {
+        if self.eat_keyword("not") {
+            self.enter()?;
+            let res = Filter::Not(Box::new(self.parse_unary()?));
+            self.depth -= 1;
+            return Ok(res);
+        }
+        if self.peek() == Some(&Token::LParen) {
+            self.pos += 1;
+            self.enter()?;
+            let res = self.parse_or()?;
+            if self.next() != Some(Token::RParen) {
+                return Err(self.invalid("missing )"));
+            }
+            self.depth -= 1;
+            return Ok(res);
+        }
+        self.parse_predicate()
+    }
+
+    fn enter(&mut self) -> Result<()> {
+        self.depth += 1;
+        if self.depth > MAX_FILTER_DEPTH {
+            return Err(self.invalid("nested too deep"));
+        }
+        Ok(())
+    }
+
+    fn parse_predicate(&mut self) -> Result<Filter> {
+        self.terms += 1;
+        if self.terms > MAX_FILTER_TERMS {
+            return Err(self.invalid("too many predicates"));
+        }
+
+        let field = match self.next() {
+            Some(Token::Ident(x)) if x.eq_ignore_ascii_case("key") => Field::Key,
+            Some(Token::Ident(x)) if x.eq_ignore_ascii_case("value") => Field::Value,
+            Some(Token::Path(x)) => Field::Json(x.parse()?),
+            _ => return Err(self.invalid("expect key, value or $ path")),
+        };
+
+        match self.next() {
+            Some(Token::Op(op)) => match self.next() {
+                Some(Token::Literal(x)) => Ok(Filter::Compare(field, op, x)),
+                _ => Err(self.invalid("expect literal")),
+            },
+            Some(Token::Ident(x)) if x.eq_ignore_ascii_case("prefix") => match self.next() {
+                Some(Token::Literal(Scalar::String(x))) => Ok(Filter::Prefix(field, x)),
+                _ => Err(self.invalid("prefix expect string")),
+            },
+            Some(Token::Ident(x)) if x.eq_ignore_ascii_case("regex") => match self.next() {
+                Some(Token::Literal(Scalar::String(x))) => {
+                    let re = Regex::new(&x).map_err(|e| self.invalid(&e.to_string()))?;
+                    Ok(Filter::Regex(field, re))
+                },
+                _ => Err(self.invalid("regex expect string")),
+            },
+            _ => Err(self.invalid("expect operator")),
+        }
+    }
+}
+
+impl FromStr for Filter {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parser = Parser {
+            src: s,
+            tokens: tokenize(s)?,
+            pos: 0,
+            depth: 0,
+            terms: 0,
+        };
+        let res = parser.parse_or()?;
+        if parser.peek().is_some() {
+            return Err(parser.invalid("unexpected trailing input"));
+        }
+        Ok(res)
+    }
+}
+
+// 空字符串表示不过滤
+pub fn parse_filter(s: &str) -> Result<Option<Filter>> {
+    if s.trim().is_empty() {
+        Ok(None)
+    } else {
+        s.parse().map(Some)
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::pb::{KvPair, Value};
+
+    use super::Filter;
+
+    fn pair(key: &str, value: Value) -> KvPair {
+        (key, value).into()
+    }
+
+    fn check(filter: &str, pair: &KvPair) -> bool {
+        filter.parse::<Filter>().unwrap().matches(pair)
+    }
+
+    #[test]
+    fn filter_compare_should_work() {
+        let p = pair("user:1", 18.into());
+        assert!(check("value == 18", &p));
+        assert!(check("value >= 17.5", &p));
+        assert!(check("value != 19", &p));
+        assert!(!check("value < 18", &p));
+        assert!(!check("value == \"18\"", &p));
+        assert!(check("key == 'user:1'", &p));
+        assert!(check("key prefix \"user:\"", &p));
+        assert!(check("key regex \"^user:[0-9]+$\"", &p));
+        assert!(!check("value prefix \"1\"", &p));
+    }
+
+    #[test]
+    fn filter_logic_should_work() {
+        let p = pair("k1", "hello".into());
+        assert!(check("key == \"k1\" and value == \"hello\"", &p));
+        assert!(check("key == \"k2\" or value == \"hello\"", &p));
+        assert!(check("not (key == \"k2\" or value == \"world\")", &p));
+        assert!(!check("NOT key == \"k1\" AND value == \"hello\"", &p));
+        assert!(check("key == \"k2\" and value == \"x\" or key == \"k1\"", &p));
+    }
+
+    #[test]
+    fn filter_json_should_work() {
+        let doc: Value = serde_json::json!({"name": "tom", "age": 20, "tags": ["a", "b"]}).into();
+        let p = pair("k1", doc);
+        assert!(check("$.age > 18 and $.name == \"tom\"", &p));
+        assert!(check("$.tags[1] == \"b\"", &p));
+        assert!(check("$[\"name\"] prefix \"to\"", &p));
+        assert!(!check("$.missing == null", &p));
+        assert!(!check("$.age > 18", &pair("k2", 20.into())));
+    }
+
+    #[test]
+    fn filter_parse_error_should_work() {
+        assert!("".parse::<Filter>().is_err());
+        assert!("value ==".parse::<Filter>().is_err());
+        assert!("foo == 1".parse::<Filter>().is_err());
+        assert!("(value == 1".parse::<Filter>().is_err());
+        assert!("value == 1 value".parse::<Filter>().is_err());
+        assert!("key regex \"(\"".parse::<Filter>().is_err());
+        assert!("key == \"abc".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn filter_nesting_should_be_limited() {
+        let nested = format!("{}value == 1{}", "(".repeat(64), ")".repeat(64));
+        assert!(nested.parse::<Filter>().is_ok());
+        let nested = format!("{}value == 1{}", "(".repeat(65), ")".repeat(65));
+        assert!(nested.parse::<Filter>().is_err());
+        assert!(format!("{}value == 1", "not ".repeat(100_000)).parse::<Filter>().is_err());
+        let chain = vec!["value == 1"; 100_000].join(" and ");
+        assert!(chain.parse::<Filter>().is_err());
+    }
+}
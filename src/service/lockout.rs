@@ -0,0 +1,106 @@
+use std::{net::SocketAddr, time::{Duration, Instant}};
+
+use dashmap::DashMap;
+
+// 同一个用户或者同一个对端地址认证失败这么多次后锁定，重新连接不会重置
+pub const LOCKOUT_FAILURES: u32 = 10;
+// 从最后一次失败开始计算的锁定时长，之后失败次数清零
+pub const LOCKOUT_DURATION: Duration = Duration::from_secs(300);
+// 记录的条目超过该数量时清理已经过期的条目，避免随机的用户名占满内存
+const MAX_ENTRIES: usize = 100_000;
+
+// 所有连接共享的认证失败记录，按用户名和对端 ip 分别计数
+#[derive(Debug)]
+pub struct AuthLockout {
+    failures: DashMap<String, (u32, Instant)>,
+    max_failures: u32,
+    duration: Duration,
+}
+
+impl Default for AuthLockout {
+    fn default() -> Self {
+        Self::new(LOCKOUT_FAILURES, LOCKOUT_DURATION)
+    }
+}
+
+impl AuthLockout {
+    pub fn new(max_failures: u32, duration: Duration) -> Self {
+        Self { failures: DashMap::new(), max_failures, duration }
+    }
+
+    // token 认证没有用户名，只按对端地址计数，避免一个 token 的失败锁定所有 token
+    fn keys(username: &str, peer: &str) -> Vec<String> {
+        // tcp 连接去掉端口，重新连接使用新的端口
+        let ip = peer.parse::<SocketAddr>().map_or(peer.to_string(), |x| x.ip().to_string());
+        let mut keys = vec![format!("peer:{}", ip)];
+        if !username.is_empty() {
+            keys.push(format!("user:{}", username));
+        }
+        keys
+    }
+
+    pub fn is_locked(&self, username: &str, peer: &str) -> bool {
+        Self::keys(username, peer).iter().any(|key| match self.failures.get(key) {
+            Some(x) => x.0 >= self.max_failures && x.1.elapsed() < self.duration,
+            None => false,
+        })
+    }
+
+    pub fn failed(&self, username: &str, peer: &str) {
+        if self.failures.len() >= MAX_ENTRIES {
+            self.failures.retain(|_, x| x.1.elapsed() < self.duration);
+        }
+        for key in Self::keys(username, peer) {
+            let mut entry = self.failures.entry(key).or_insert((0, Instant::now()));
+            let count = if entry.1.elapsed() >= self.duration { 1 } else { entry.0 + 1 };
+            *entry = (count, Instant::now());
+        }
+    }
+
+    // 认证成功后清除用户的失败次数，对端地址的计数等到过期
+    pub fn succeeded(&self, username: &str) {
+        if !username.is_empty() {
+            self.failures.remove(&format!("user:{}", username));
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::AuthLockout;
+
+    #[test]
+    fn lockout_should_work() {
+        let lockout = AuthLockout::new(2, Duration::from_secs(60));
+        lockout.failed("alice", "127.0.0.1:1000");
+        assert!(!lockout.is_locked("alice", "127.0.0.1:1001"));
+        lockout.failed("alice", "127.0.0.2:1000");
+
+        // 同一个用户从其他地址、同一个地址的其他用户都被锁定
+        assert!(lockout.is_locked("alice", "127.0.0.3:1000"));
+        lockout.failed("bob", "127.0.0.1:1002");
+        assert!(lockout.is_locked("carol", "127.0.0.1:1003"));
+        assert!(!lockout.is_locked("carol", "127.0.0.4:1000"));
+
+        lockout.succeeded("alice");
+        assert!(!lockout.is_locked("alice", "127.0.0.3:1000"));
+
+        // token 认证的失败不会锁定其他地址
+        lockout.failed("", "127.0.0.5:1000");
+        lockout.failed("", "127.0.0.5:1001");
+        assert!(lockout.is_locked("", "127.0.0.5:1002"));
+        assert!(!lockout.is_locked("", "127.0.0.6:1000"));
+    }
+
+    #[test]
+    fn lockout_should_expire() {
+        let lockout = AuthLockout::new(1, Duration::from_millis(10));
+        lockout.failed("alice", "127.0.0.1:1000");
+        assert!(lockout.is_locked("alice", "127.0.0.1:1000"));
+        std::thread::sleep(Duration::from_millis(20));
+        assert!(!lockout.is_locked("alice", "127.0.0.1:1000"));
+    }
+}
@@ -1,55 +1,230 @@
+mod acl;
+mod aggregate;
+mod auth;
+mod chunk;
 mod command_service;
+mod filter;
+mod lockout;
+mod session;
+mod shutdown;
 mod topic;
 mod topic_service;
 
-use std::sync::Arc;
+use std::{borrow::Cow, sync::Arc, time::Duration};
 
 use futures::stream;
+use tokio::sync::Semaphore;
+use tracing::log::warn;
 
 use crate::{
-    pb::{command_request::RequestData, CommandRequest, CommandResponse},
+    network::{FrameOptions, KeepaliveOptions, YamuxOptions},
+    pb::{command_request::RequestData, Auth, CommandRequest, CommandResponse},
     service::{command_service::CommandService, topic_service::TopicService},
     storage::{MemoryDb, Storage},
-    KvError,
+    KvError, Result,
 };
 
-use self::{topic::{Topic, Broadcaster}, topic_service::StreamingResponse};
+use self::{lockout::AuthLockout, topic::{Topic, Broadcaster}, topic_service::StreamingResponse};
+
+pub use acl::{Acl, AclRule, Permission};
+pub use auth::{Authenticator, hash_token};
+pub use lockout::{LOCKOUT_DURATION, LOCKOUT_FAILURES};
+pub use session::{Session, MAX_AUTH_FAILURES};
+pub use chunk::{chunk_size, split, ChunkBuffer, UploadBudget, MAX_CHUNKED_VALUE, MAX_UPLOAD_PER_CONNECTION, MAX_UPLOADS_IN_PROGRESS, MIN_CHUNK_SIZE};
+pub use shutdown::{ShutdownHandle, shutdown_signal};
+
+// 同时在 blocking 线程池中校验密码的 Auth 数量上限
+const MAX_CONCURRENT_AUTH: usize = 4;
 
 pub struct Service<Store = MemoryDb> {
     inner: Arc<ServiceInner<Store>>,
-    broadcaster: Arc<Broadcaster>
+    broadcaster: Arc<Broadcaster>,
+    shutdown: ShutdownHandle,
 }
 
 impl<Store> Clone for Service<Store> {
     fn clone(&self) -> Self {
         Self { 
             inner: Arc::clone(&self.inner), 
-            broadcaster: Arc::clone(&self.broadcaster) 
+            broadcaster: Arc::clone(&self.broadcaster),
+            shutdown: self.shutdown.clone(),
         }
     }
 }
 
 impl<Store: Storage> Service<Store> {
+    // 握手时告诉客户端可用的认证方式
+    pub fn auth_methods(&self, session: &Session) -> Vec<String> {
+        let mut methods = vec![];
+        if self.inner.auth.is_some() {
+            methods.extend(["password".to_string(), "token".to_string()]);
+        }
+        if session.identity.is_some() {
+            methods.push("mtls".into());
+        }
+        methods
+    }
+
+    pub fn frame_options(&self) -> &FrameOptions {
+        &self.inner.frame_options
+    }
+
+    pub fn yamux_options(&self) -> &YamuxOptions {
+        &self.inner.yamux_options
+    }
+
+    pub fn keepalive_options(&self) -> &KeepaliveOptions {
+        &self.inner.keepalive_options
+    }
+
+    // 分块上传计入的额度：session 所在连接的额度和所有连接共享的额度
+    pub fn upload_budgets(&self, session: &Session) -> [UploadBudget; 2] {
+        [session.uploads().with_limit(self.inner.upload_limit), self.inner.uploads.clone()]
+    }
+
+    pub fn upload_limit(&self) -> u64 {
+        self.inner.upload_limit
+    }
+
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    // 停止接受新的请求，通知所有订阅者退出，最多等待 deadline 让处理中的请求完成，最后 flush 存储
+    pub async fn graceful_shutdown(&self, deadline: Duration) -> Result<()> {
+        self.shutdown.shutdown();
+        self.broadcaster.close_all();
+        if !self.shutdown.drain(deadline).await {
+            warn!("Shutdown deadline {:?} exceeded, dropping in-flight requests", deadline);
+        }
+        self.inner.store.flush()
+    }
+
     pub fn execute(&self, cmd: CommandRequest) -> StreamingResponse {
-        self.inner.on_received.notify(&cmd);
-        let mut res = dispatch(cmd.clone(), &self.inner.store);
+        self.execute_with_session(cmd, &Session::default())
+    }
+
+    pub fn execute_with_session(&self, cmd: CommandRequest, session: &Session) -> StreamingResponse {
+        let redacted = redact(&cmd);
+        self.inner.on_received.notify(&redacted);
+        for f in &self.inner.on_session_received {
+            f(session, &redacted)
+        }
+        if let (Some(RequestData::Auth(x)), Some(_)) = (&cmd.request_data, self.inner.auth.as_ref()) {
+            return self.auth(x.clone(), session.clone());
+        }
+        let mut res = match self.authenticate(&cmd, session) {
+            Some(res) => res,
+            None => dispatch(cmd.clone(), &self.inner.store, self.inner.acl.as_ref(), session),
+        };
         self.inner.on_executed.notify(&res);
         // before send
         self.inner.on_before_send.notify_mut(&mut res);
         if res == CommandResponse::default() {
-            dispatch_stream(cmd, Arc::clone(&self.broadcaster))
+            dispatch_stream(cmd, Arc::clone(&self.broadcaster), session)
+        } else if let Some(RequestData::HgetChunked(x)) = &cmd.request_data {
+            let chunk_size = chunk::chunk_size(x.chunk_size as _, self.inner.frame_options.max_frame_size);
+            Box::pin(stream::iter(chunk::split_response(res, chunk_size).map(Arc::new)))
         } else {
             Box::pin(stream::once(async { Arc::new(res) }))
         }
         
     }
+
+    // 只做认证和 acl 检查而不执行命令，分块上传在收到第一块时检查
+    pub fn authorize(&self, cmd: &CommandRequest, session: &Session) -> Result<()> {
+        if self.inner.auth.is_some() && session.user().is_none() {
+            return Err(KvError::Unauthorized("authentication required".into()));
+        }
+        match (self.inner.acl.as_ref(), cmd.request_data.as_ref()) {
+            (Some(acl), Some(data)) => acl.authorize(session.user().as_deref(), data),
+            _ => Ok(()),
+        }
+    }
+
+    // 处理配置了认证时的 Auth 命令，argon2 校验比较慢，放到 blocking 线程池中执行，避免占用 runtime 的工作线程
+    fn auth(&self, x: Auth, session: Session) -> StreamingResponse {
+        let service = self.clone();
+        Box::pin(stream::once(async move {
+            let mut res = service.verify(x, &session).await;
+            service.inner.on_executed.notify(&res);
+            service.inner.on_before_send.notify_mut(&mut res);
+            Arc::new(res)
+        }))
+    }
+
+    // Auth 必须是连接上的第一个请求，认证成功后不能再切换用户；
+    // 连接上的失败次数达到上限，或者用户、对端地址在所有连接上的失败次数达到上限被锁定时不再校验
+    async fn verify(&self, x: Auth, session: &Session) -> CommandResponse {
+        if session.is_authenticated() {
+            return KvError::InvalidCommand("already authenticated".into()).into();
+        }
+        if !session.begin_auth() {
+            return KvError::Unauthorized("too many failed authentication attempts".into()).into();
+        }
+        let lockout = &self.inner.lockout;
+        if lockout.is_locked(&x.username, &session.peer) {
+            return KvError::Unauthorized("too many failed authentication attempts, try again later".into()).into();
+        }
+        let username = x.username.clone();
+
+        let _permit = self.inner.auth_permits.acquire().await;
+        let service = self.clone();
+        let user = tokio::task::spawn_blocking(move || {
+            service.inner.auth.as_ref().and_then(|auth| auth.verify(&x.username, &x.password))
+        })
+        .await;
+
+        match user {
+            Ok(Some(user)) => {
+                session.auth_succeeded();
+                lockout.succeeded(&username);
+                match session.set_user(user) {
+                    true => CommandResponse::ok(),
+                    false => KvError::InvalidCommand("already authenticated".into()).into(),
+                }
+            },
+            Ok(None) => {
+                lockout.failed(&username, &session.peer);
+                KvError::Unauthorized("invalid username or password".into()).into()
+            },
+            Err(e) => KvError::Internal(e.to_string()).into(),
+        }
+    }
+
+    // 没有配置认证时 Auth 直接返回成功；配置了认证时，未认证的连接上的其他命令返回 401
+    fn authenticate(&self, cmd: &CommandRequest, session: &Session) -> Option<CommandResponse> {
+        let auth = self.inner.auth.as_ref();
+        match (&cmd.request_data, auth) {
+            (Some(RequestData::Auth(_)), None) => Some(CommandResponse::ok()),
+            (_, Some(_)) if session.user().is_none() => {
+                Some(KvError::Unauthorized("authentication required".into()).into())
+            },
+            _ => None,
+        }
+    }
 }
 
-fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
+// 交给 hook 的请求中不包含 Auth 的密码
+fn redact(cmd: &CommandRequest) -> Cow<'_, CommandRequest> {
+    match &cmd.request_data {
+        Some(RequestData::Auth(x)) => Cow::Owned(CommandRequest {
+            request_data: Some(RequestData::Auth(Auth { password: "******".into(), ..x.clone() })),
+            ..cmd.clone()
+        }),
+        _ => Cow::Borrowed(cmd),
+    }
+}
+
+// 所有命令（包括交给 dispatch_stream 的 topic 命令）都先在这里检查 acl
+fn dispatch(cmd: CommandRequest, store: &impl Storage, acl: Option<&Acl>, session: &Session) -> CommandResponse {
     let data = cmd.request_data;
     let Some(data) = data else {
         return KvError::InvalidCommand("".to_string()).into();
     };
+    if let Some(Err(e)) = acl.map(|acl| acl.authorize(session.user().as_deref(), &data)) {
+        return e.into();
+    }
     match data {
         RequestData::Hget(x) => x.execute(store),
         RequestData::Hmget(x) => x.execute(store),
@@ -60,23 +235,58 @@ fn dispatch(cmd: CommandRequest, store: &impl Storage) -> CommandResponse {
         RequestData::Hdelete(x) => x.execute(store),
         RequestData::Hmdelete(x) => x.execute(store),
         RequestData::Hgetall(x) => x.execute(store),
+        RequestData::JsonGet(x) => x.execute(store),
+        RequestData::JsonSet(x) => x.execute(store),
+        RequestData::JsonDel(x) => x.execute(store),
+        RequestData::JsonAppend(x) => x.execute(store),
+        RequestData::JsonIncr(x) => x.execute(store),
+        RequestData::CreateIndex(x) => x.execute(store),
+        RequestData::DropIndex(x) => x.execute(store),
+        RequestData::QueryIndex(x) => x.execute(store),
+        RequestData::Aggregate(x) => x.execute(store),
+        RequestData::Hhistory(x) => x.execute(store),
+        RequestData::EnableHistory(x) => x.execute(store),
+        RequestData::DisableHistory(x) => x.execute(store),
+        RequestData::HgetChunked(x) => x.execute(store),
+        // 分块上传由连接组装成 Hset 后再执行
+        RequestData::HsetChunk(_) => KvError::InvalidCommand("hset_chunk must be sent over a stream".into()).into(),
         _ => CommandResponse::default(),
     }
 }
 
-fn dispatch_stream(cmd: CommandRequest, topic: impl Topic) -> StreamingResponse {
+fn dispatch_stream(cmd: CommandRequest, topic: impl Topic, session: &Session) -> StreamingResponse {
 
     match cmd.request_data {
-        Some(RequestData::Subscribe(x)) => x.execute(topic),
-        Some(RequestData::Unsubscribe(x)) => x.execute(topic),
-        Some(RequestData::Publish(x)) => x.execute(topic),
-        _ => unreachable!(),
+        Some(RequestData::Subscribe(x)) => x.execute(topic, session),
+        Some(RequestData::Unsubscribe(x)) => x.execute(topic, session),
+        Some(RequestData::Publish(x)) => x.execute(topic, session),
+        // hook 可能把响应改成默认值，此时不是 topic 命令
+        data => {
+            let res = Arc::new(KvError::InvalidCommand(format!("{:?}", data)).into());
+            Box::pin(stream::once(async move { res }))
+        },
     }
 }
 
 pub struct ServiceInner<Store> {
     store: Store,
+    auth: Option<Authenticator>,
+    auth_permits: Semaphore,
+    // 所有连接共享的认证失败次数，按用户和对端地址锁定
+    lockout: AuthLockout,
+    acl: Option<Acl>,
+    // 服务端支持的压缩算法以及阈值、等级，连接握手时与客户端协商
+    frame_options: FrameOptions,
+    // 服务端 yamux 连接的窗口、缓存和 stream 数量限制
+    yamux_options: YamuxOptions,
+    // 连接的 keepalive 探测、断开检测和空闲超时
+    keepalive_options: KeepaliveOptions,
+    // 每个连接上未完成的分块上传最多占用的内存
+    upload_limit: u64,
+    // 所有连接共享的分块上传额度
+    uploads: UploadBudget,
     on_received: Vec<fn(&CommandRequest)>,
+    on_session_received: Vec<fn(&Session, &CommandRequest)>,
     on_executed: Vec<fn(&CommandResponse)>,
     on_before_send: Vec<fn(&mut CommandResponse)>,
     on_after_send: Vec<fn()>,
@@ -86,7 +296,17 @@ impl<Store: Storage> ServiceInner<Store> {
     pub fn new(store: Store) -> Self {
         Self {
             store,
+            auth: None,
+            auth_permits: Semaphore::new(MAX_CONCURRENT_AUTH),
+            lockout: AuthLockout::default(),
+            acl: None,
+            frame_options: FrameOptions::default(),
+            yamux_options: YamuxOptions::default(),
+            keepalive_options: KeepaliveOptions::default(),
+            upload_limit: MAX_UPLOAD_PER_CONNECTION,
+            uploads: UploadBudget::new(MAX_UPLOADS_IN_PROGRESS),
             on_received: vec![],
+            on_session_received: vec![],
             on_executed: vec![],
             on_before_send: vec![],
             on_after_send: vec![],
@@ -97,11 +317,56 @@ impl<Store: Storage> ServiceInner<Store> {
         self.into()
     }
 
+    // 开启认证，连接需要先执行 Auth 命令或者使用 tls 客户端证书
+    pub fn with_auth(mut self, auth: Authenticator) -> Self {
+        self.auth = Some(auth);
+        self
+    }
+
+    // 同一个用户或者同一个对端地址失败 max_failures 次后，锁定 duration 时间
+    pub fn with_auth_lockout(mut self, max_failures: u32, duration: Duration) -> Self {
+        self.lockout = AuthLockout::new(max_failures, duration);
+        self
+    }
+
+    // 开启 acl 后，只有规则允许的用户才能访问对应的 table 和 topic
+    pub fn with_acl(mut self, acl: Acl) -> Self {
+        self.acl = Some(acl);
+        self
+    }
+
+    pub fn with_frame_options(mut self, options: FrameOptions) -> Self {
+        self.frame_options = options;
+        self
+    }
+
+    pub fn with_yamux_options(mut self, options: YamuxOptions) -> Self {
+        self.yamux_options = options;
+        self
+    }
+
+    pub fn with_keepalive_options(mut self, options: KeepaliveOptions) -> Self {
+        self.keepalive_options = options;
+        self
+    }
+
+    // 每个连接以及所有连接上未完成的分块上传最多占用的内存
+    pub fn with_upload_limits(mut self, per_connection: u64, total: u64) -> Self {
+        self.upload_limit = per_connection;
+        self.uploads = UploadBudget::new(total);
+        self
+    }
+
     pub fn fn_received(mut self, f: fn(&CommandRequest)) -> Self {
         self.on_received.push(f);
         self
     }
 
+    pub fn fn_session_received(mut self, f: fn(&Session, &CommandRequest)) -> Self {
+        self.on_session_received.push(f);
+        self
+    }
+
     pub fn fn_executed(mut self, f: fn(&CommandResponse)) -> Self {
         self.on_executed.push(f);
         self
@@ -123,6 +388,7 @@ impl<Store: Storage> From<ServiceInner<Store>> for Service<Store> {
         Self {
             inner: Arc::new(value),
             broadcaster: Default::default(),
+            shutdown: Default::default(),
         }
     }
 }
@@ -153,14 +419,16 @@ impl<Arg> NotifyMut<Arg> for Vec<fn(&mut Arg)> {
 
 #[cfg(test)]
 mod tests {
+    use std::time::Duration;
+
     use futures::StreamExt;
 
     use crate::{
-        pb::{CommandRequest, CommandResponse},
+        pb::{command_request::RequestData, CommandRequest, CommandResponse},
         storage::MemoryDb,
     };
 
-    use super::ServiceInner;
+    use super::{hash_token, Acl, Authenticator, Permission, ServiceInner, Session, MAX_AUTH_FAILURES};
 
     fn fn_received(cmd: &CommandRequest) {
         println!("on received command request: {:?}", cmd);
@@ -194,4 +462,141 @@ mod tests {
         let cmd = stream.next().await.unwrap();
         assert_eq!(&cmd.msg, "OK");
     }
+
+    fn fn_session_received(session: &Session, _cmd: &CommandRequest) {
+        assert_eq!(session.identity.as_deref(), Some("alice"));
+    }
+
+    #[tokio::test]
+    async fn session_should_be_passed_to_hooks() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .fn_session_received(fn_session_received)
+            .service();
+
+        let session = Session::new("127.0.0.1:1234", Some("alice".to_string()));
+        let mut stream = service.execute_with_session(CommandRequest::new_hget("t1", "k1"), &session);
+        let res = stream.next().await.unwrap();
+        assert_eq!(res.state_code, 404);
+    }
+
+    #[tokio::test]
+    async fn auth_should_work() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .with_auth(Authenticator::new().token("ci", hash_token("t0ken")))
+            .service();
+        let session = Session::new("127.0.0.1:1234", None);
+
+        let execute = |cmd: CommandRequest| {
+            let mut stream = service.execute_with_session(cmd, &session);
+            async move { stream.next().await.unwrap() }
+        };
+
+        let res = execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(res.state_code, 401);
+        let res = execute(CommandRequest::new_auth("", "wrong")).await;
+        assert_eq!(res.state_code, 401);
+        let res = execute(CommandRequest::new_auth("", "t0ken")).await;
+        assert_eq!(res.state_code, 200);
+        assert_eq!(session.user(), Some("ci".to_string()));
+        let res = execute(CommandRequest::new_hget("t1", "k1")).await;
+        assert_eq!(res.state_code, 404);
+
+        // 认证成功后不能再切换用户
+        let res = execute(CommandRequest::new_auth("", "t0ken")).await;
+        assert_eq!(res.state_code, 400);
+        assert_eq!(session.user(), Some("ci".to_string()));
+
+        // tls 客户端证书的身份视为已认证
+        let session = Session::new("127.0.0.1:1235", Some("alice".to_string()));
+        let mut stream = service.execute_with_session(CommandRequest::new_hget("t1", "k1"), &session);
+        assert_eq!(stream.next().await.unwrap().state_code, 404);
+    }
+
+    #[tokio::test]
+    async fn auth_failures_should_be_limited() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .with_auth(Authenticator::new().token("ci", hash_token("t0ken")))
+            .service();
+        let session = Session::new("127.0.0.1:1234", None);
+
+        let execute = |cmd: CommandRequest, session: &Session| {
+            let mut stream = service.execute_with_session(cmd, session);
+            async move { stream.next().await.unwrap() }
+        };
+
+        for _ in 0..MAX_AUTH_FAILURES {
+            let res = execute(CommandRequest::new_auth("", "wrong"), &session).await;
+            assert_eq!(res.msg, "unauthorized: invalid username or password");
+        }
+        assert!(session.auth_locked());
+
+        // 达到上限后正确的 token 也会被拒绝，同一连接上为单个请求创建的 session 共享计数
+        let res = execute(CommandRequest::new_auth("", "t0ken"), &session).await;
+        assert_eq!(res.state_code, 401);
+        let res = execute(CommandRequest::new_auth("", "t0ken"), &session.fork()).await;
+        assert_eq!(res.state_code, 401);
+        assert_eq!(session.user(), None);
+
+        // 认证成功不计入失败次数
+        let session = Session::new("127.0.0.1:1235", None);
+        for _ in 0..MAX_AUTH_FAILURES {
+            let res = execute(CommandRequest::new_auth("", "t0ken"), &session.fork()).await;
+            assert_eq!(res.state_code, 200);
+        }
+        assert!(!session.auth_locked());
+    }
+
+    #[tokio::test]
+    async fn auth_failures_should_lock_out_across_connections() {
+        let service = ServiceInner::new(MemoryDb::new())
+            .with_auth(Authenticator::new().token("ci", hash_token("t0ken")))
+            .with_auth_lockout(2, Duration::from_secs(60))
+            .fn_received(fn_received_auth)
+            .service();
+
+        let execute = |cmd: CommandRequest, session: Session| {
+            let mut stream = service.execute_with_session(cmd, &session);
+            async move { stream.next().await.unwrap() }
+        };
+
+        // 每次重新连接，对端的端口不同
+        for port in [1000, 1001] {
+            let res = execute(CommandRequest::new_auth("", "wrong"), Session::new(format!("127.0.0.1:{}", port), None)).await;
+            assert_eq!(res.msg, "unauthorized: invalid username or password");
+        }
+        let res = execute(CommandRequest::new_auth("", "t0ken"), Session::new("127.0.0.1:1002", None)).await;
+        assert_eq!(res.state_code, 401);
+        let res = execute(CommandRequest::new_auth("", "t0ken"), Session::new("127.0.0.2:1000", None)).await;
+        assert_eq!(res.state_code, 200);
+    }
+
+    // hook 收到的 Auth 请求中不包含密码
+    fn fn_received_auth(cmd: &CommandRequest) {
+        if let Some(RequestData::Auth(x)) = &cmd.request_data {
+            assert_ne!(x.password, "t0ken");
+            assert_ne!(x.password, "wrong");
+        }
+    }
+
+    #[tokio::test]
+    async fn acl_should_work() {
+        let acl = Acl::default()
+            .rule("alice", &["t1"], &[], &[Permission::Read])
+            .rule("*", &[], &["news"], &[Permission::Publish]);
+        let service = ServiceInner::new(MemoryDb::new()).with_acl(acl).service();
+        let alice = Session::new("127.0.0.1:1234", Some("alice".to_string()));
+        let bob = Session::new("127.0.0.1:1235", Some("bob".to_string()));
+
+        let mut stream = service.execute_with_session(CommandRequest::new_hget("t1", "k1"), &alice);
+        assert_eq!(stream.next().await.unwrap().state_code, 404);
+        let mut stream = service.execute_with_session(CommandRequest::new_hget("t1", "k1"), &bob);
+        assert_eq!(stream.next().await.unwrap().state_code, 403);
+        let mut stream = service.execute_with_session(CommandRequest::new_hset("t1", "k1", "v1".into()), &alice);
+        assert_eq!(stream.next().await.unwrap().state_code, 403);
+
+        let mut stream = service.execute_with_session(CommandRequest::subscribe("news"), &bob);
+        assert_eq!(stream.next().await.unwrap().state_code, 403);
+        let mut stream = service.execute_with_session(CommandRequest::publish("news", vec![]), &bob);
+        assert!(stream.next().await.unwrap().exit);
+    }
 }
@@ -0,0 +1,89 @@
+use std::sync::{atomic::{AtomicU32, AtomicU64, Ordering}, Arc, Mutex};
+
+use super::chunk::UploadBudget;
+
+// 同一连接上 Auth 最多失败的次数，达到后不再校验密码，连接上的 stream 收到下一个 Auth 时关闭
+pub const MAX_AUTH_FAILURES: u32 = 3;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+// 连接的上下文，在连接建立时确定，随每个请求一起交给 Service
+#[derive(Debug, Clone, Default)]
+pub struct Session {
+    // 每个 session 唯一，订阅只能由建立它的 session 取消；Service::execute 使用的默认 session 为 0
+    id: u64,
+    // 对端地址
+    pub peer: String,
+    // tls 客户端证书中的身份（CN 或 SAN），未使用客户端证书时为 None
+    pub identity: Option<String>,
+    // Auth 命令认证通过的用户，同一连接上的所有 stream 共享
+    user: Arc<Mutex<Option<String>>>,
+    // 连接上所有 stream 未完成的分块上传共享的内存额度，上限由 Service 的配置决定
+    uploads: UploadBudget,
+    // Auth 失败的次数，进行中的认证也先计入
+    auth_failures: Arc<AtomicU32>,
+}
+
+impl Session {
+    pub fn new(peer: impl Into<String>, identity: Option<String>) -> Self {
+        Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            peer: peer.into(),
+            identity,
+            user: Default::default(),
+            uploads: Default::default(),
+            auth_failures: Default::default(),
+        }
+    }
+
+    // 为单个请求创建的 session，不共享认证结果，但共享连接上的 Auth 失败次数
+    // gateway 和 grpc 的每个请求单独认证
+    pub fn fork(&self) -> Self {
+        Self {
+            auth_failures: Arc::clone(&self.auth_failures),
+            ..Self::new(&self.peer, self.identity.clone())
+        }
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    // 当前连接的用户，没有通过 Auth 认证时使用 tls 客户端证书中的身份
+    pub fn user(&self) -> Option<String> {
+        self.user.lock().unwrap().clone().or_else(|| self.identity.clone())
+    }
+
+    // 只能通过 Auth 认证一次，已经认证过时返回 false
+    pub fn set_user(&self, user: impl Into<String>) -> bool {
+        let mut current = self.user.lock().unwrap();
+        if current.is_some() {
+            return false;
+        }
+        *current = Some(user.into());
+        true
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.user.lock().unwrap().is_some()
+    }
+
+    // 开始一次认证，失败次数已经达到上限时返回 false；认证成功后调用 auth_succeeded 撤销计数
+    pub fn begin_auth(&self) -> bool {
+        self.auth_failures
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |x| (x < MAX_AUTH_FAILURES).then_some(x + 1))
+            .is_ok()
+    }
+
+    pub fn auth_succeeded(&self) {
+        self.auth_failures.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    pub fn auth_locked(&self) -> bool {
+        self.auth_failures.load(Ordering::Acquire) >= MAX_AUTH_FAILURES
+    }
+
+    pub fn uploads(&self) -> &UploadBudget {
+        &self.uploads
+    }
+}
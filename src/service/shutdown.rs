@@ -0,0 +1,83 @@
+use std::time::Duration;
+
+use tokio_util::{sync::CancellationToken, task::{TaskTracker, task_tracker::TaskTrackerToken}};
+use tracing::log::warn;
+
+// 关闭服务的句柄：触发后停止接受新的连接和请求，并可以等待处理中的请求完成
+#[derive(Debug, Clone, Default)]
+pub struct ShutdownHandle {
+    token: CancellationToken,
+    tracker: TaskTracker,
+}
+
+impl ShutdownHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn shutdown(&self) {
+        self.token.cancel();
+        self.tracker.close();
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.token.is_cancelled()
+    }
+
+    pub async fn wait(&self) {
+        self.token.cancelled().await
+    }
+
+    // 请求处理期间持有返回的 token，drop 时表示请求结束
+    pub fn enter(&self) -> TaskTrackerToken {
+        self.tracker.token()
+    }
+
+    // 等待处理中的请求全部完成，超过 deadline 时返回 false
+    pub async fn drain(&self, deadline: Duration) -> bool {
+        tokio::time::timeout(deadline, self.tracker.wait()).await.is_ok()
+    }
+}
+
+// 等待 SIGINT 或 SIGTERM
+pub async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut term) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {},
+                    _ = term.recv() => {},
+                }
+                return;
+            },
+            Err(e) => warn!("Failed to listen SIGTERM: {}", e),
+        }
+    }
+
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::ShutdownHandle;
+
+    #[tokio::test]
+    async fn shutdown_handle_should_work() {
+        let handle = ShutdownHandle::new();
+        assert!(!handle.is_shutdown());
+
+        let token = handle.enter();
+        handle.shutdown();
+        assert!(handle.is_shutdown());
+        handle.wait().await;
+
+        assert!(!handle.drain(Duration::from_millis(10)).await);
+        drop(token);
+        assert!(handle.drain(Duration::from_millis(10)).await);
+    }
+}
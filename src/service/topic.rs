@@ -4,7 +4,7 @@ use dashmap::{DashMap, DashSet};
 use tokio::sync::mpsc::{self, UnboundedSender, UnboundedReceiver};
 use tracing::log::warn;
 
-use crate::{CommandResponse};
+use crate::{CommandResponse, KvError, Result};
 
 // 不能使用 const
 static NEXT_ID: AtomicU32 = AtomicU32::new(1);
@@ -17,21 +17,40 @@ fn get_next_id() -> u32 {
 #[derive(Default, Debug, Clone)]
 pub struct Broadcaster {
     topics: DashMap<String, DashSet<u32>>,
-    sender: DashMap<u32, UnboundedSender<Arc<CommandResponse>>>
+    sender: DashMap<u32, UnboundedSender<Arc<CommandResponse>>>,
+    // 订阅 id 对应的 session id，只有建立订阅的 session 可以取消
+    owners: DashMap<u32, u64>,
 }
 
 
+impl Broadcaster {
+    // 关闭服务时通知所有订阅者退出
+    pub fn close_all(&self) {
+        self.topics.clear();
+        let ids = self.sender.iter().map(|x| *x.key()).collect::<Vec<_>>();
+        for id in ids {
+            self.owners.remove(&id);
+            if let Some((_, sender)) = self.sender.remove(&id) {
+                if let Err(e) = sender.send(Arc::new(CommandResponse::exit())) {
+                    warn!("Failed to send command: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
 pub trait Topic {
-    fn subscribe(&self, topic: impl Into<String>) -> UnboundedReceiver<Arc<CommandResponse>>;
+    fn subscribe(&self, topic: impl Into<String>, owner: u64) -> UnboundedReceiver<Arc<CommandResponse>>;
 
-    fn unsubscribe(&self, topic: &str, id: u32);
+    fn unsubscribe(&self, topic: &str, id: u32, owner: u64) -> Result<u32>;
 
     fn publish(self, topic: String, data: Arc<CommandResponse>);
 }
 
 impl Topic for Arc<Broadcaster> {
-    fn subscribe(&self, topic: impl Into<String>) -> UnboundedReceiver<Arc<CommandResponse>> {
+    fn subscribe(&self, topic: impl Into<String>, owner: u64) -> UnboundedReceiver<Arc<CommandResponse>> {
         let id = get_next_id();
+        self.owners.insert(id, owner);
         let set = self.topics.entry(topic.into()).or_default();
         set.insert(id);
 
@@ -50,37 +69,53 @@ impl Topic for Arc<Broadcaster> {
         rx
     }
 
-    fn unsubscribe(&self, topic: &str, id: u32) {
-        if let Some(set) = self.topics.get(topic) {
-            set.remove(&id);
-            if set.is_empty() {
-                drop(set);
-                self.topics.remove(topic);
-            }
+    // id 不存在或者不属于该 topic 时返回 404，不是 owner 建立的订阅返回 403
+    fn unsubscribe(&self, topic: &str, id: u32, owner: u64) -> Result<u32> {
+        let not_found = || KvError::NotFound(format!("topic {}", topic), format!("subscription {}", id));
+        let set = self.topics.get(topic).ok_or_else(not_found)?;
+        if !set.contains(&id) {
+            return Err(not_found());
         }
-
-        let (_, sender) = self.sender.remove(&id).clone().unwrap();
-        tokio::spawn(async move {
+        if self.owners.get(&id).is_some_and(|x| *x != owner) {
+            return Err(KvError::Forbidden(format!("subscription {} belongs to another session", id)));
+        }
+        set.remove(&id).ok_or_else(not_found)?;
+        drop(set);
+        self.owners.remove(&id);
+        // 与 publish 相同，在持有写锁时再检查一次，不会删掉并发订阅刚加入的 id
+        self.topics.remove_if(topic, |_, set| set.is_empty());
+
+        // 同步发送，可以在 runtime 之外取消订阅
+        if let Some((_, sender)) = self.sender.remove(&id) {
             if let Err(e) = sender.send(Arc::new(CommandResponse::exit())) {
                 warn!("Failed to send command: {:?}", e);
             }
-        });
+        }
+        Ok(id)
     }
 
+    // 接收端已经关闭（订阅者断开但没有取消订阅）的订阅在这里清理
     fn publish(self, topic: String, data: Arc<CommandResponse>) {
         tokio::spawn(async move {
-            if let Some(set) = self.topics.get(&topic) {
-                let set1 = set.clone();
-                set1
-                    .into_iter()
-                    .for_each(|id| {
-                        if let Some(sender) = self.sender.get(&id) {
-                            if let Err(e) = sender.send(data.clone()) {
-                                warn!("Failed to send command: {:?}", e);
-                            }
-                        }
-                    })
+            let Some(set) = self.topics.get(&topic) else {
+                return;
+            };
+            let dead = set
+                .clone()
+                .into_iter()
+                .filter(|id| match self.sender.get(id) {
+                    Some(sender) => sender.send(data.clone()).is_err(),
+                    None => false,
+                })
+                .collect::<Vec<_>>();
+            for id in dead {
+                warn!("Subscriber {} of {} is gone, removing it", id, topic);
+                set.remove(&id);
+                self.sender.remove(&id);
+                self.owners.remove(&id);
             }
+            drop(set);
+            self.topics.remove_if(&topic, |_, set| set.is_empty());
         });
     }
 }
@@ -90,16 +125,16 @@ impl Topic for Arc<Broadcaster> {
 mod tests {
     use std::sync::Arc;
 
-    use crate::CommandResponse;
+    use crate::{CommandResponse, KvError};
 
     use super::{Broadcaster, Topic, get_next_id};
 
     #[test]
     fn get_next_id_should_work() {
+        // NEXT_ID 是全局的，其他测试会并发分配 id，只检查递增
         let id = get_next_id();
-        assert_eq!(id, 1);
-        let id = get_next_id();
-        assert_eq!(id, 2);
+        let next = get_next_id();
+        assert!(next > id);
     }
 
 
@@ -107,13 +142,9 @@ mod tests {
     async fn topic_should_work() {
         let bc = Arc::new(Broadcaster::default());
 
-        let mut rx = bc.subscribe("topic");
-        
-        if let Some(cmd) = rx.recv().await {
-            let id = cmd.values[0].clone();
-            assert_eq!(id, 1.into());
-        }
-        let mut rx1 = bc.subscribe("topic");
+        let mut rx = bc.subscribe("topic", 1);
+        let id: i64 = (&rx.recv().await.unwrap().values[0]).try_into().unwrap();
+        let mut rx1 = bc.subscribe("topic", 1);
 
         let cmd = Arc::new(CommandResponse::ok());
         bc.clone().publish("topic".into(), cmd.clone());
@@ -124,7 +155,7 @@ mod tests {
         let res2 = rx1.recv().await.unwrap();
         assert_eq!(res1, res2);
 
-        bc.unsubscribe("topic", 1);
+        bc.unsubscribe("topic", id as _, 1).unwrap();
         bc.publish("topic".into(), cmd.clone());
 
         let res1 = rx.recv().await.unwrap();
@@ -133,4 +164,39 @@ mod tests {
         assert_eq!(res2, cmd);
     }
 
-}
\ No newline at end of file
+    #[tokio::test]
+    async fn unsubscribe_unknown_id_should_fail() {
+        let bc = Arc::new(Broadcaster::default());
+        let mut rx = bc.subscribe("topic", 1);
+        let id: i64 = (&rx.recv().await.unwrap().values[0]).try_into().unwrap();
+
+        assert!(matches!(bc.unsubscribe("topic", u32::MAX, 1), Err(KvError::NotFound(_, _))));
+        assert!(matches!(bc.unsubscribe("other", id as _, 1), Err(KvError::NotFound(_, _))));
+
+        // 其他 session 不能取消订阅
+        assert!(matches!(bc.unsubscribe("topic", id as _, 2), Err(KvError::Forbidden(_))));
+
+        // 失败的取消订阅不影响已有的订阅
+        bc.unsubscribe("topic", id as _, 1).unwrap();
+        assert_eq!(rx.recv().await.unwrap(), Arc::new(CommandResponse::exit()));
+        assert!(bc.unsubscribe("topic", id as _, 1).is_err());
+    }
+
+    #[tokio::test]
+    async fn publish_should_remove_dead_subscribers() {
+        let bc = Arc::new(Broadcaster::default());
+        let mut rx = bc.subscribe("topic", 1);
+        let id: i64 = (&rx.recv().await.unwrap().values[0]).try_into().unwrap();
+
+        drop(rx);
+        bc.clone().publish("topic".into(), Arc::new(CommandResponse::ok()));
+        for _ in 0..100 {
+            if bc.sender.is_empty() && bc.topics.is_empty() {
+                assert!(bc.unsubscribe("topic", id as _, 1).is_err());
+                return;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+        }
+        panic!("subscription {} is still alive", id);
+    }
+}
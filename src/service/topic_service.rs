@@ -5,32 +5,35 @@ use tokio_stream::{wrappers::UnboundedReceiverStream, once};
 
 use crate::{Subscribe, CommandResponse, Unsubscribe, Publish};
 
-use super::topic::{Topic};
+use super::{session::Session, topic::Topic};
 
 
 pub type StreamingResponse = Pin<Box<dyn Stream<Item = Arc<CommandResponse>> + Send>>;
 
 
 pub trait TopicService {
-    fn execute(self, topic: impl Topic) -> StreamingResponse;
+    fn execute(self, topic: impl Topic, session: &Session) -> StreamingResponse;
 }
 
 impl TopicService for Subscribe {
-    fn execute(self, topic: impl Topic) -> StreamingResponse {
-        let rx = topic.subscribe(&self.topic);
+    fn execute(self, topic: impl Topic, session: &Session) -> StreamingResponse {
+        let rx = topic.subscribe(&self.topic, session.id());
         Box::pin(UnboundedReceiverStream::new(rx))
     }
 }
 
 impl TopicService for Unsubscribe {
-    fn execute(self, topic: impl Topic) -> StreamingResponse {
-        topic.unsubscribe(&self.topic, self.id);
-        Box::pin(once(Arc::new(CommandResponse::exit())))
+    fn execute(self, topic: impl Topic, session: &Session) -> StreamingResponse {
+        let res = match topic.unsubscribe(&self.topic, self.id, session.id()) {
+            Ok(_) => CommandResponse::exit(),
+            Err(e) => e.into(),
+        };
+        Box::pin(once(Arc::new(res)))
     }
 }
 
 impl TopicService for Publish {
-    fn execute(self, topic: impl Topic) -> StreamingResponse {
+    fn execute(self, topic: impl Topic, _session: &Session) -> StreamingResponse {
         topic.publish(self.topic, Arc::new(self.data.into()));
         Box::pin(once(Arc::new(CommandResponse::exit())))
     }
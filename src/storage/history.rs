@@ -0,0 +1,125 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::pb::{Value, Version};
+
+// 每个 key 的历史版本按版本号从旧到新存放
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct VersionList {
+    #[prost(message, repeated, tag = "1")]
+    pub versions: Vec<Version>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct HistoryPolicy {
+    pub max_versions: u32,
+    pub retention_secs: u64,
+}
+
+impl HistoryPolicy {
+    pub fn new(max_versions: u32, retention_secs: u64) -> Self {
+        Self { max_versions, retention_secs }
+    }
+
+    // 追加一个新版本并清理超出数量或保留时间的旧版本，最新的版本总是保留
+    pub fn record(&self, versions: &mut Vec<Version>, version: u64, value: Option<Value>) {
+        let now = now_millis();
+        versions.push(Version {
+            version,
+            timestamp: now,
+            deleted: value.is_none(),
+            value,
+        });
+
+        let mut skip = 0;
+        if self.max_versions > 0 {
+            skip = versions.len().saturating_sub(self.max_versions as usize);
+        }
+        if self.retention_secs > 0 {
+            let deadline = now.saturating_sub(self.retention_secs * 1000);
+            let expired = versions.iter().take_while(|x| x.timestamp < deadline).count();
+            skip = skip.max(expired);
+        }
+        versions.drain(..skip.min(versions.len() - 1));
+    }
+
+    pub fn to_bytes(self) -> Vec<u8> {
+        let mut buf = self.max_versions.to_be_bytes().to_vec();
+        buf.extend_from_slice(&self.retention_secs.to_be_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> Option<Self> {
+        let max_versions = u32::from_be_bytes(buf.get(..4)?.try_into().ok()?);
+        let retention_secs = u64::from_be_bytes(buf.get(4..12)?.try_into().ok()?);
+        Some(Self::new(max_versions, retention_secs))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AsOf {
+    Version(u64),
+    Time(u64),
+}
+
+impl AsOf {
+    // 返回指定版本号或时间点上 key 的值，当时不存在或已被删除时返回 None
+    pub fn find(self, versions: &[Version]) -> Option<Value> {
+        versions
+            .iter()
+            .rev()
+            .find(|x| match self {
+                AsOf::Version(v) => x.version <= v,
+                AsOf::Time(t) => x.timestamp <= t,
+            })
+            .and_then(|x| x.value.clone())
+    }
+}
+
+pub fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::pb::Version;
+
+    use super::{AsOf, HistoryPolicy};
+
+    #[test]
+    fn history_policy_should_work() {
+        let policy = HistoryPolicy::new(2, 0);
+        let mut versions = vec![];
+        policy.record(&mut versions, 1, Some("v1".into()));
+        policy.record(&mut versions, 2, Some("v2".into()));
+        policy.record(&mut versions, 3, None);
+        assert_eq!(versions.iter().map(|x| x.version).collect::<Vec<_>>(), vec![2, 3]);
+        assert!(versions[1].deleted);
+
+        let policy = HistoryPolicy::new(0, 1);
+        let mut versions = vec![Version { version: 1, timestamp: 0, ..Default::default() }];
+        policy.record(&mut versions, 2, Some("v2".into()));
+        assert_eq!(versions.len(), 1);
+        assert_eq!(versions[0].version, 2);
+
+        assert_eq!(HistoryPolicy::from_bytes(&policy.to_bytes()), Some(policy));
+    }
+
+    #[test]
+    fn as_of_should_work() {
+        let versions = vec![
+            Version { version: 2, timestamp: 100, value: Some("v2".into()), deleted: false },
+            Version { version: 5, timestamp: 200, value: None, deleted: true },
+            Version { version: 7, timestamp: 300, value: Some("v7".into()), deleted: false },
+        ];
+        assert_eq!(AsOf::Version(1).find(&versions), None);
+        assert_eq!(AsOf::Version(4).find(&versions), Some("v2".into()));
+        assert_eq!(AsOf::Version(5).find(&versions), None);
+        assert_eq!(AsOf::Version(100).find(&versions), Some("v7".into()));
+        assert_eq!(AsOf::Time(250).find(&versions), None);
+        assert_eq!(AsOf::Time(300).find(&versions), Some("v7".into()));
+    }
+}
@@ -0,0 +1,223 @@
+use std::{collections::BTreeSet, ops::Bound, str::FromStr};
+
+use serde_json::Value as JsonValue;
+
+use crate::{pb::{value, Value}, KvError, Result};
+
+use super::json::{JsonPath, load_document};
+
+const TAG_BOOL: u8 = 1;
+const TAG_NUMBER: u8 = 2;
+const TAG_STRING: u8 = 3;
+const TAG_BINARY: u8 = 4;
+
+// 空路径对整个标量值建索引，否则对 json 文档中的字段建索引
+#[derive(Debug, Clone)]
+pub struct IndexField(Option<JsonPath>);
+
+impl FromStr for IndexField {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.trim().is_empty() {
+            Ok(Self(None))
+        } else {
+            Ok(Self(Some(s.parse()?)))
+        }
+    }
+}
+
+impl IndexField {
+    // 返回 value 对应的索引键，无法索引（类型不符、字段不存在）时返回 None
+    pub fn key(&self, value: &Value) -> Option<Vec<u8>> {
+        match &self.0 {
+            None => encode_value(value),
+            Some(path) => {
+                let doc = load_document(value.clone()).ok()?;
+                encode_json(path.get(&doc)?)
+            }
+        }
+    }
+
+    // 索引项可能落后于数据，查询时用当前的值重新判断是否在范围内
+    pub fn in_range(&self, value: &Value, range: &IndexRange) -> bool {
+        self.key(value).is_some_and(|x| range.contains(&x))
+    }
+}
+
+// 索引键保持值的顺序，且互不为前缀：
+// 数字统一按 f64 编码（|i| < 2^53 时精确），字符串和二进制转义 0x00 后以 0x00 0x00 结尾
+pub fn encode_value(value: &Value) -> Option<Vec<u8>> {
+    match value.value.as_ref()? {
+        value::Value::Bool(x) => Some(vec![TAG_BOOL, *x as u8]),
+        value::Value::Integer(x) => Some(encode_number(*x as f64)),
+        value::Value::Float(x) => Some(encode_number(*x)),
+        value::Value::String(x) => Some(encode_bytes(TAG_STRING, x.as_bytes())),
+        value::Value::Binary(x) => Some(encode_bytes(TAG_BINARY, x)),
+        value::Value::Json(_) => None,
+    }
+}
+
+fn encode_json(value: &JsonValue) -> Option<Vec<u8>> {
+    match value {
+        JsonValue::Bool(x) => Some(vec![TAG_BOOL, *x as u8]),
+        JsonValue::Number(x) => Some(encode_number(x.as_f64()?)),
+        JsonValue::String(x) => Some(encode_bytes(TAG_STRING, x.as_bytes())),
+        _ => None,
+    }
+}
+
+fn encode_number(x: f64) -> Vec<u8> {
+    let bits = x.to_bits();
+    let bits = if bits >> 63 == 1 { !bits } else { bits | 1 << 63 };
+
+    let mut buf = vec![TAG_NUMBER];
+    buf.extend_from_slice(&bits.to_be_bytes());
+    buf
+}
+
+fn encode_bytes(tag: u8, data: &[u8]) -> Vec<u8> {
+    let mut buf = Vec::with_capacity(data.len() + 3);
+    buf.push(tag);
+    for b in data {
+        buf.push(*b);
+        if *b == 0 {
+            buf.push(0xff);
+        }
+    }
+    buf.extend_from_slice(&[0, 0]);
+    buf
+}
+
+// 查询范围 [min, max]，两端都是闭区间，None 表示不限
+pub struct IndexRange {
+    pub min: Option<Vec<u8>>,
+    pub max: Option<Vec<u8>>,
+}
+
+impl IndexRange {
+    pub fn new(min: Option<&Value>, max: Option<&Value>) -> Result<Self> {
+        let encode = |x: Option<&Value>| {
+            x.map(|x| encode_value(x).ok_or_else(|| KvError::Invalid("index bound must be a scalar value".into())))
+                .transpose()
+        };
+        Ok(Self { min: encode(min)?, max: encode(max)? })
+    }
+
+    pub fn start(&self) -> Bound<&[u8]> {
+        match &self.min {
+            Some(x) => Bound::Included(x),
+            None => Bound::Unbounded,
+        }
+    }
+
+    pub fn below_max(&self, key: &[u8]) -> bool {
+        self.max.as_ref().is_none_or(|max| key <= max.as_slice())
+    }
+
+    pub fn contains(&self, key: &[u8]) -> bool {
+        self.min.as_ref().is_none_or(|min| key >= min.as_slice()) && self.below_max(key)
+    }
+}
+
+// MemoryDb 中单个索引的内容，(索引键, 主键) 有序存放
+#[derive(Debug)]
+pub struct TableIndex {
+    field: IndexField,
+    entries: BTreeSet<(Vec<u8>, String)>,
+}
+
+impl TableIndex {
+    pub fn new(field: IndexField) -> Self {
+        Self {
+            field,
+            entries: BTreeSet::new(),
+        }
+    }
+
+    pub fn field(&self) -> &IndexField {
+        &self.field
+    }
+
+    pub fn update(&mut self, key: &str, old: Option<&Value>, new: Option<&Value>) {
+        if let Some(x) = old.and_then(|x| self.field.key(x)) {
+            self.entries.remove(&(x, key.to_string()));
+        }
+        if let Some(x) = new.and_then(|x| self.field.key(x)) {
+            self.entries.insert((x, key.to_string()));
+        }
+    }
+
+    pub fn query(&self, range: &IndexRange) -> Vec<String> {
+        let start = match range.start() {
+            Bound::Included(x) => Bound::Included((x.to_vec(), String::new())),
+            _ => Bound::Unbounded,
+        };
+
+        self.entries
+            .range((start, Bound::Unbounded))
+            .take_while(|(x, _)| range.below_max(x))
+            .map(|(_, key)| key.clone())
+            .collect()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::pb::Value;
+
+    use super::{encode_value, IndexField, IndexRange, TableIndex};
+
+    #[test]
+    fn encode_value_should_keep_order() {
+        let values: Vec<Value> = vec![
+            false.into(),
+            true.into(),
+            (-10.5).into(),
+            (-1).into(),
+            0.into(),
+            1.into(),
+            1.5.into(),
+            1000.into(),
+            "".into(),
+            "a".into(),
+            "a\0b".into(),
+            "ab".into(),
+            "b".into(),
+            vec![0u8].into(),
+        ];
+        let keys = values.iter().map(|x| encode_value(x).unwrap()).collect::<Vec<_>>();
+        let mut sorted = keys.clone();
+        sorted.sort();
+        assert_eq!(keys, sorted);
+
+        assert_eq!(encode_value(&1.into()), encode_value(&1.0.into()));
+    }
+
+    #[test]
+    fn table_index_should_work() {
+        let field: IndexField = "$.age".parse().unwrap();
+        let mut index = TableIndex::new(field);
+
+        let doc = |age: i64| -> Value {
+            serde_json::json!({ "age": age }).into()
+        };
+
+        index.update("k1", None, Some(&doc(10)));
+        index.update("k2", None, Some(&doc(20)));
+        index.update("k3", None, Some(&doc(30)));
+        index.update("k4", None, Some(&"no json".into()));
+        index.update("k3", Some(&doc(30)), Some(&doc(20)));
+        index.update("k1", Some(&doc(10)), None);
+
+        let range = IndexRange::new(Some(&20.into()), Some(&20.into())).unwrap();
+        assert_eq!(index.query(&range), vec!["k2".to_string(), "k3".to_string()]);
+
+        let range = IndexRange::new(None, Some(&15.into())).unwrap();
+        assert!(index.query(&range).is_empty());
+
+        let range = IndexRange::new(Some(&15.into()), None).unwrap();
+        assert_eq!(index.query(&range).len(), 2);
+    }
+}
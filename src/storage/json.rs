@@ -0,0 +1,243 @@
+use std::str::FromStr;
+
+use serde_json::Value as JsonValue;
+
+use crate::{pb::{value, Value}, KvError, Result};
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Key(String),
+    Index(i64),
+}
+
+// 支持 $、$.a.b、$.a[0]、$["a"]、$.a[-1] 形式的路径，空字符串等同于 $
+#[derive(Debug, Clone, PartialEq)]
+pub struct JsonPath(Vec<Segment>);
+
+impl FromStr for JsonPath {
+    type Err = KvError;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let invalid = || KvError::Invalid(format!("invalid json path {}", s));
+
+        let rest = s.trim();
+        if rest.is_empty() {
+            return Ok(Self(vec![]));
+        }
+        let mut rest = rest.strip_prefix('$').ok_or_else(invalid)?;
+
+        let mut segments = vec![];
+        while let Some(c) = rest.chars().next() {
+            match c {
+                '.' => {
+                    let end = rest[1..].find(['.', '[']).map(|x| x + 1).unwrap_or(rest.len());
+                    let key = &rest[1..end];
+                    if key.is_empty() {
+                        return Err(invalid());
+                    }
+                    segments.push(Segment::Key(key.to_string()));
+                    rest = &rest[end..];
+                },
+                '[' => {
+                    let end = rest.find(']').ok_or_else(invalid)?;
+                    let inner = rest[1..end].trim();
+                    let quoted = inner.len() >= 2
+                        && ((inner.starts_with('"') && inner.ends_with('"'))
+                            || (inner.starts_with('\'') && inner.ends_with('\'')));
+                    if quoted {
+                        segments.push(Segment::Key(inner[1..inner.len() - 1].to_string()));
+                    } else {
+                        segments.push(Segment::Index(inner.parse().map_err(|_| invalid())?));
+                    }
+                    rest = &rest[end + 1..];
+                },
+                _ => return Err(invalid()),
+            }
+        }
+
+        Ok(Self(segments))
+    }
+}
+
+impl JsonPath {
+    pub fn is_root(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn get<'a>(&self, doc: &'a JsonValue) -> Option<&'a JsonValue> {
+        self.0.iter().try_fold(doc, |node, seg| seg.get(node))
+    }
+
+    pub fn get_mut<'a>(&self, doc: &'a mut JsonValue) -> Option<&'a mut JsonValue> {
+        self.0.iter().try_fold(doc, |node, seg| seg.get_mut(node))
+    }
+
+    // 父节点必须已存在；对象中不存在的 key 会被创建，数组只能替换已有下标
+    pub fn set(&self, doc: &mut JsonValue, value: JsonValue) -> Result<Option<JsonValue>> {
+        let Some((last, parent)) = self.split_last() else {
+            return Ok(Some(std::mem::replace(doc, value)));
+        };
+        let parent = parent.get_mut(doc).ok_or_else(|| self.not_found())?;
+
+        match (last, parent) {
+            (Segment::Key(key), JsonValue::Object(map)) => Ok(map.insert(key.clone(), value)),
+            (seg @ Segment::Index(_), node @ JsonValue::Array(_)) => {
+                let item = seg.get_mut(node).ok_or_else(|| self.not_found())?;
+                Ok(Some(std::mem::replace(item, value)))
+            },
+            _ => Err(self.mismatch()),
+        }
+    }
+
+    pub fn delete(&self, doc: &mut JsonValue) -> Result<Option<JsonValue>> {
+        let Some((last, parent)) = self.split_last() else {
+            return Ok(Some(doc.take()));
+        };
+        let Some(parent) = parent.get_mut(doc) else {
+            return Ok(None);
+        };
+
+        match (last, parent) {
+            (Segment::Key(key), JsonValue::Object(map)) => Ok(map.remove(key)),
+            (Segment::Index(i), JsonValue::Array(arr)) => {
+                Ok(resolve_index(*i, arr.len()).map(|i| arr.remove(i)))
+            },
+            _ => Ok(None),
+        }
+    }
+
+    fn split_last(&self) -> Option<(&Segment, JsonPath)> {
+        self.0
+            .split_last()
+            .map(|(last, parent)| (last, JsonPath(parent.to_vec())))
+    }
+
+    pub fn not_found(&self) -> KvError {
+        KvError::Invalid(format!("json path {} not found", self))
+    }
+
+    pub fn mismatch(&self) -> KvError {
+        KvError::Invalid(format!("json path {} has mismatched type", self))
+    }
+}
+
+impl std::fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "$")?;
+        for seg in &self.0 {
+            match seg {
+                Segment::Key(key) => write!(f, "[{:?}]", key)?,
+                Segment::Index(i) => write!(f, "[{}]", i)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Segment {
+    fn get<'a>(&self, node: &'a JsonValue) -> Option<&'a JsonValue> {
+        match (self, node) {
+            (Segment::Key(key), JsonValue::Object(map)) => map.get(key),
+            (Segment::Index(i), JsonValue::Array(arr)) => resolve_index(*i, arr.len()).map(|i| &arr[i]),
+            _ => None,
+        }
+    }
+
+    fn get_mut<'a>(&self, node: &'a mut JsonValue) -> Option<&'a mut JsonValue> {
+        match (self, node) {
+            (Segment::Key(key), JsonValue::Object(map)) => map.get_mut(key),
+            (Segment::Index(i), JsonValue::Array(arr)) => resolve_index(*i, arr.len()).map(|i| &mut arr[i]),
+            _ => None,
+        }
+    }
+}
+
+// 负数下标从数组末尾开始计算
+fn resolve_index(i: i64, len: usize) -> Option<usize> {
+    let i = if i < 0 { len as i64 + i } else { i };
+    (0..len as i64).contains(&i).then_some(i as usize)
+}
+
+// 存储中的 json 文档必须是 Value::Json
+pub fn load_document(value: Value) -> Result<JsonValue> {
+    match value.value {
+        Some(value::Value::Json(x)) => Ok(serde_json::from_str(&x)?),
+        _ => Err(KvError::Invalid("value is not a json document".into())),
+    }
+}
+
+pub fn incr_number(node: &mut JsonValue, by: &JsonValue) -> Option<JsonValue> {
+    let res: JsonValue = match (node.as_i64(), by.as_i64()) {
+        (Some(a), Some(b)) => a.checked_add(b)?.into(),
+        _ => serde_json::Number::from_f64(node.as_f64()? + by.as_f64()?)?.into(),
+    };
+    *node = res.clone();
+    Some(res)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::{JsonPath, Segment, incr_number};
+
+    #[test]
+    fn json_path_parse_should_work() {
+        let path: JsonPath = "$.a[0][\"b.c\"].d[-1]".parse().unwrap();
+        assert_eq!(path.0, vec![
+            Segment::Key("a".into()),
+            Segment::Index(0),
+            Segment::Key("b.c".into()),
+            Segment::Key("d".into()),
+            Segment::Index(-1),
+        ]);
+        assert!("$".parse::<JsonPath>().unwrap().is_root());
+        assert!("".parse::<JsonPath>().unwrap().is_root());
+
+        assert!("a.b".parse::<JsonPath>().is_err());
+        assert!("$.".parse::<JsonPath>().is_err());
+        assert!("$[x]".parse::<JsonPath>().is_err());
+        assert!("$[0".parse::<JsonPath>().is_err());
+    }
+
+    #[test]
+    fn json_path_get_set_delete_should_work() {
+        let mut doc = json!({"a": {"b": [1, 2, 3]}, "c": "x"});
+
+        let path: JsonPath = "$.a.b[-1]".parse().unwrap();
+        assert_eq!(path.get(&doc), Some(&json!(3)));
+
+        let old = path.set(&mut doc, json!(4)).unwrap();
+        assert_eq!(old, Some(json!(3)));
+
+        let path: JsonPath = "$.a.d".parse().unwrap();
+        assert_eq!(path.set(&mut doc, json!(true)).unwrap(), None);
+        assert_eq!(doc, json!({"a": {"b": [1, 2, 4], "d": true}, "c": "x"}));
+
+        let path: JsonPath = "$.x.y".parse().unwrap();
+        assert!(path.set(&mut doc, json!(1)).is_err());
+        let path: JsonPath = "$.c.y".parse().unwrap();
+        assert!(path.set(&mut doc, json!(1)).is_err());
+
+        let path: JsonPath = "$.a.b[0]".parse().unwrap();
+        assert_eq!(path.delete(&mut doc).unwrap(), Some(json!(1)));
+        let path: JsonPath = "$.c".parse().unwrap();
+        assert_eq!(path.delete(&mut doc).unwrap(), Some(json!("x")));
+        assert_eq!(path.delete(&mut doc).unwrap(), None);
+        assert_eq!(doc, json!({"a": {"b": [2, 4], "d": true}}));
+    }
+
+    #[test]
+    fn incr_number_should_work() {
+        let mut node = json!(1);
+        assert_eq!(incr_number(&mut node, &json!(2)), Some(json!(3)));
+        assert_eq!(incr_number(&mut node, &json!(0.5)), Some(json!(3.5)));
+        assert_eq!(node, json!(3.5));
+
+        let mut node = json!(i64::MAX);
+        assert_eq!(incr_number(&mut node, &json!(1)), None);
+        let mut node = json!("a");
+        assert_eq!(incr_number(&mut node, &json!(1)), None);
+    }
+}
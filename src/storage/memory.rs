@@ -1,29 +1,76 @@
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use dashmap::DashMap;
+use dashmap::mapref::entry::Entry;
 use dashmap::mapref::one::Ref;
 
-use crate::pb::{Value, KvPair};
+use crate::pb::{Value, KvPair, Version};
 use crate::{Result, KvError};
 use super::{Storage, StorageItem};
+use super::history::{AsOf, HistoryPolicy};
+use super::index::{IndexRange, TableIndex};
 
 
 
+// table 上的索引和历史记录，写入数据时需要同步维护
+#[derive(Default)]
+struct TableMeta {
+    indexes: BTreeMap<String, TableIndex>,
+    history: Option<(HistoryPolicy, HashMap<String, Vec<Version>>)>,
+}
+
+impl TableMeta {
+    fn on_write(&mut self, key: &str, old: Option<&Value>, new: Option<&Value>, version: &AtomicU64) {
+        self.indexes
+            .values_mut()
+            .for_each(|x| x.update(key, old, new));
+
+        if let Some((policy, history)) = self.history.as_mut() {
+            let versions = history.entry(key.into()).or_default();
+            policy.record(versions, version.fetch_add(1, Ordering::Relaxed), new.cloned());
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.indexes.is_empty() && self.history.is_none()
+    }
+}
+
+// 加锁顺序固定为 table -> meta -> table 内的 key，避免死锁
 #[derive(Clone)]
 pub struct MemoryDb {
-    table: Arc<DashMap<String, DashMap<String, Value>>>
+    table: Arc<DashMap<String, DashMap<String, Value>>>,
+    meta: Arc<DashMap<String, TableMeta>>,
+    version: Arc<AtomicU64>,
 }
 
 impl MemoryDb {
     pub fn new() -> Self {
         Self {
             table: Arc::new(DashMap::default()),
+            meta: Arc::new(DashMap::default()),
+            version: Arc::new(AtomicU64::new(1)),
         }
     }
 
     pub fn get_or_create_table(&self, table: impl Into<String>) -> Ref<String, DashMap<String, Value>> {
         self.table.entry(table.into()).or_insert(DashMap::new()).downgrade()
     }
+
+    fn remove_meta_if_empty(&self, table: &str) {
+        self.meta.remove_if(table, |_, x| x.is_empty());
+    }
+
+    // 写入数据时一直持有 table 在 meta 中的 entry，没有 meta 时持有的是所在 shard 的写锁，
+    // 这样写入和 create_index / enable_history 的回填互斥，回填时不会漏掉并发的写入
+    fn write_meta<R>(&self, table: &str, f: impl FnOnce(Option<&mut TableMeta>) -> R) -> R {
+        match self.meta.entry(table.into()) {
+            Entry::Occupied(mut e) => f(Some(e.get_mut())),
+            Entry::Vacant(_) => f(None),
+        }
+    }
 }
 
 impl Storage for MemoryDb {
@@ -37,8 +84,17 @@ impl Storage for MemoryDb {
     }
 
     fn set(&self, table: &str, key: impl Into<String>, value: Value) -> Result<Option<Value>> {
-        Ok(self.get_or_create_table(table)
-            .insert(key.into(), value))
+        let t = self.get_or_create_table(table);
+        let key = key.into();
+
+        Ok(self.write_meta(table, |meta| match meta {
+            None => t.insert(key, value),
+            Some(meta) => {
+                let old = t.insert(key.clone(), value.clone());
+                meta.on_write(&key, old.as_ref(), Some(&value), &self.version);
+                old
+            },
+        }))
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool> {
@@ -49,10 +105,43 @@ impl Storage for MemoryDb {
     }
 
     fn delete(&self, table: &str, key: &str) -> Result<Option<Value>> {
-        match self.table.get(table) {
-            Some(t) => Ok(t.remove(key).map(|x| x.1)),
-            None => Err(KvError::NotFound(table.into(), key.into()))
-        }
+        let Some(t) = self.table.get(table) else {
+            return Err(KvError::NotFound(table.into(), key.into()));
+        };
+
+        Ok(self.write_meta(table, |meta| {
+            let old = t.remove(key).map(|x| x.1);
+            if let (Some(meta), Some(_)) = (meta, old.as_ref()) {
+                meta.on_write(key, old.as_ref(), None, &self.version);
+            }
+            old
+        }))
+    }
+
+    fn update<F>(&self, table: &str, key: &str, mut f: F) -> Result<Value>
+    where
+        F: FnMut(Option<Value>) -> Result<Value>
+    {
+        let t = self.get_or_create_table(table);
+
+        self.write_meta(table, |meta| {
+            let (old, value) = match t.entry(key.into()) {
+                Entry::Occupied(mut e) => {
+                    let value = f(Some(e.get().clone()))?;
+                    (Some(e.insert(value.clone())), value)
+                },
+                Entry::Vacant(e) => {
+                    let value = f(None)?;
+                    e.insert(value.clone());
+                    (None, value)
+                }
+            };
+
+            if let Some(meta) = meta {
+                meta.on_write(key, old.as_ref(), Some(&value), &self.version);
+            }
+            Ok(value)
+        })
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<KvPair>> {
@@ -67,6 +156,94 @@ impl Storage for MemoryDb {
         } 
     }
 
+    fn create_index(&self, table: &str, index: &str, path: &str) -> Result<()> {
+        let t = self.get_or_create_table(table);
+        let mut meta = self.meta.entry(table.into()).or_default();
+        if meta.indexes.contains_key(index) {
+            return Err(KvError::Invalid(format!("index {} already exists on table {}", index, table)));
+        }
+
+        let mut idx = TableIndex::new(path.parse()?);
+        t.iter().for_each(|x| idx.update(x.key(), None, Some(x.value())));
+        meta.indexes.insert(index.into(), idx);
+        Ok(())
+    }
+
+    fn drop_index(&self, table: &str, index: &str) -> Result<bool> {
+        let Some(mut meta) = self.meta.get_mut(table) else {
+            return Ok(false);
+        };
+        let res = meta.indexes.remove(index).is_some();
+        drop(meta);
+        self.remove_meta_if_empty(table);
+        Ok(res)
+    }
+
+    fn query_index(&self, table: &str, index: &str, min: Option<&Value>, max: Option<&Value>) -> Result<Vec<KvPair>> {
+        let range = IndexRange::new(min, max)?;
+        let not_found = || KvError::IndexNotFound(table.into(), index.into());
+
+        let t = self.table.get(table).ok_or_else(not_found)?;
+        let meta = self.meta.get(table).ok_or_else(not_found)?;
+        let idx = meta.indexes.get(index).ok_or_else(not_found)?;
+
+        Ok(idx
+            .query(&range)
+            .into_iter()
+            .filter_map(|key| t.get(&key).map(|x| (key, x.value().clone())))
+            .filter(|(_, value)| idx.field().in_range(value, &range))
+            .map(|x| x.into())
+            .collect())
+    }
+
+    fn enable_history(&self, table: &str, policy: HistoryPolicy) -> Result<()> {
+        let t = self.get_or_create_table(table);
+        let mut meta = self.meta.entry(table.into()).or_default();
+
+        match meta.history.as_mut() {
+            Some((x, _)) => *x = policy,
+            None => {
+                // 已有的值作为各 key 的第一个版本
+                let history = t
+                    .iter()
+                    .map(|x| {
+                        let mut versions = vec![];
+                        policy.record(&mut versions, self.version.fetch_add(1, Ordering::Relaxed), Some(x.value().clone()));
+                        (x.key().clone(), versions)
+                    })
+                    .collect();
+                meta.history = Some((policy, history));
+            }
+        }
+        Ok(())
+    }
+
+    fn disable_history(&self, table: &str) -> Result<bool> {
+        let Some(mut meta) = self.meta.get_mut(table) else {
+            return Ok(false);
+        };
+        let res = meta.history.take().is_some();
+        drop(meta);
+        self.remove_meta_if_empty(table);
+        Ok(res)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>> {
+        let meta = self.meta.get(table);
+        match meta.as_ref().and_then(|x| x.history.as_ref()) {
+            Some((_, history)) => Ok(history.get(key).cloned().unwrap_or_default()),
+            None => Err(KvError::HistoryDisabled(table.into())),
+        }
+    }
+
+    fn get_as_of(&self, table: &str, key: &str, as_of: AsOf) -> Result<Option<Value>> {
+        Ok(as_of.find(&self.history(table, key)?))
+    }
+
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>> {
         match self.table.get(table) {
             Some(x) => Ok(Box::new(StorageItem::new(x.clone().into_iter()))),
@@ -78,7 +255,7 @@ impl Storage for MemoryDb {
 
 #[cfg(test)]
 mod tests {
-    use crate::storage::{memory::MemoryDb, Storage};
+    use crate::storage::{memory::MemoryDb, Storage, AsOf, HistoryPolicy};
 
     fn memory_db_init_and_set_initial_value() -> MemoryDb {
         let db = MemoryDb::new();
@@ -114,6 +291,126 @@ mod tests {
         assert_eq!(a, Some("v2".into()));
     }
 
+    #[test]
+    fn memory_db_update_should_work() {
+        let db = memory_db_init_and_set_initial_value();
+        let a = db.update("t1", "k2", |x| {
+            assert!(x.is_none());
+            Ok(1.into())
+        }).expect("db update error");
+        assert_eq!(a, 1.into());
+
+        let a = db.update("t1", "k2", |x| {
+            let x: i64 = (&x.unwrap()).try_into()?;
+            Ok((x + 1).into())
+        }).expect("db update error");
+        assert_eq!(a, 2.into());
+        assert_eq!(db.get("t1", "k2").unwrap(), Some(2.into()));
+    }
+
+    #[test]
+    fn memory_db_index_should_work() {
+        let db = memory_db_init_and_set_initial_value();
+        db.set("t1", "k2", "v2".into()).unwrap();
+        db.create_index("t1", "i1", "").unwrap();
+        assert!(db.create_index("t1", "i1", "").is_err());
+
+        db.set("t1", "k3", "v1".into()).unwrap();
+        let mut v = db.query_index("t1", "i1", Some(&"v1".into()), Some(&"v1".into())).unwrap();
+        v.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(v, vec![("k1", "v1".into()).into(), ("k3", "v1".into()).into()]);
+
+        db.delete("t1", "k1").unwrap();
+        db.update("t1", "k3", |_| Ok("v3".into())).unwrap();
+        let v = db.query_index("t1", "i1", Some(&"v2".into()), None).unwrap();
+        assert_eq!(v, vec![("k2", "v2".into()).into(), ("k3", "v3".into()).into()]);
+
+        assert!(db.drop_index("t1", "i1").unwrap());
+        assert!(!db.drop_index("t1", "i1").unwrap());
+        assert!(db.query_index("t1", "i1", None, None).is_err());
+    }
+
+    #[test]
+    fn memory_db_query_index_should_recheck_values() {
+        let db = memory_db_init_and_set_initial_value();
+        db.create_index("t1", "i1", "").unwrap();
+
+        // 绕过索引直接修改数据，模拟落后于数据的索引项
+        db.table.get("t1").unwrap().insert("k1".into(), "v9".into());
+        let v = db.query_index("t1", "i1", Some(&"v1".into()), Some(&"v1".into())).unwrap();
+        assert!(v.is_empty());
+    }
+
+    #[test]
+    fn memory_db_create_index_should_not_miss_concurrent_writes() {
+        let db = MemoryDb::new();
+        db.set("t1", "k0", 0.into()).unwrap();
+
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in 1..10000 {
+                    db.set("t1", format!("k{}", i), i.into()).unwrap();
+                }
+            })
+        };
+        db.create_index("t1", "i1", "").unwrap();
+        writer.join().unwrap();
+
+        assert_eq!(db.query_index("t1", "i1", None, None).unwrap().len(), 10000);
+    }
+
+    #[test]
+    fn memory_db_history_should_work() {
+        let db = memory_db_init_and_set_initial_value();
+        assert!(db.history("t1", "k1").is_err());
+
+        db.enable_history("t1", HistoryPolicy::new(3, 0)).unwrap();
+        db.set("t1", "k1", "v2".into()).unwrap();
+        db.delete("t1", "k1").unwrap();
+        db.update("t1", "k1", |_| Ok("v3".into())).unwrap();
+
+        let versions = db.history("t1", "k1").unwrap();
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions[0].value, Some("v2".into()));
+        assert!(versions[1].deleted);
+        assert!(versions[0].version < versions[1].version && versions[1].version < versions[2].version);
+
+        let v = versions[0].version;
+        assert_eq!(db.get_as_of("t1", "k1", AsOf::Version(v)).unwrap(), Some("v2".into()));
+        assert_eq!(db.get_as_of("t1", "k1", AsOf::Version(v + 1)).unwrap(), None);
+        assert_eq!(db.get_as_of("t1", "k1", AsOf::Time(u64::MAX)).unwrap(), Some("v3".into()));
+        // 最早的版本 v1 已经超出 max_versions 被清理
+        assert_eq!(db.get_as_of("t1", "k1", AsOf::Version(v - 1)).unwrap(), None);
+
+        assert!(db.disable_history("t1").unwrap());
+        assert!(db.get_as_of("t1", "k1", AsOf::Version(v)).is_err());
+    }
+
+    #[test]
+    fn memory_db_enable_history_should_not_miss_concurrent_writes() {
+        let db = MemoryDb::new();
+        db.set("t1", "k0", 0.into()).unwrap();
+
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in 1..10000 {
+                    db.set("t1", format!("k{}", i), i.into()).unwrap();
+                }
+            })
+        };
+        db.enable_history("t1", HistoryPolicy::new(3, 0)).unwrap();
+        writer.join().unwrap();
+
+        // 每个 key 最新的版本都是当前的值
+        for i in 0..10000 {
+            let key = format!("k{}", i);
+            let versions = db.history("t1", &key).unwrap();
+            assert_eq!(versions.last().and_then(|x| x.value.clone()), db.get("t1", &key).unwrap());
+        }
+    }
+
     #[test]
     fn memory_db_get_iter_should_work() {
         let db = memory_db_init_and_set_initial_value();
@@ -1,10 +1,15 @@
+mod history;
+mod index;
+mod json;
 mod memory;
 mod sleddb;
 
 pub use memory::MemoryDb;
 pub use sleddb::SledDb;
+pub(crate) use history::{AsOf, HistoryPolicy};
+pub(crate) use json::{JsonPath, load_document, incr_number};
 
-use crate::{Result, pb::{Value, KvPair}};
+use crate::{Result, pb::{Value, KvPair, Version}};
 
 // 由于后面要跨线程，需要添加该约束。(如果T实现了Send + Sync + 'static，则Arc<T>也实现了)
 // 当我们使用具体类型时，如果该类型T实现了 Send + Sync + 'static，就可以不加
@@ -18,9 +23,35 @@ pub trait Storage: Send + Sync + 'static {
 
     fn delete(&self, table: &str, key: &str) -> Result<Option<Value>>;
 
+    // 原子地读取-修改-写回一个 key，f 可能因并发冲突被多次调用，返回写入的新值
+    fn update<F>(&self, table: &str, key: &str, f: F) -> Result<Value>
+    where
+        F: FnMut(Option<Value>) -> Result<Value>;
+
     fn get_all(&self, table: &str) -> Result<Vec<KvPair>>;
 
+    // 在 table 上创建二级索引，path 为空时索引整个值，否则索引 json 文档中的字段
+    fn create_index(&self, table: &str, index: &str, path: &str) -> Result<()>;
+
+    fn drop_index(&self, table: &str, index: &str) -> Result<bool>;
+
+    // 返回索引值落在 [min, max] 中的 kv 对
+    fn query_index(&self, table: &str, index: &str, min: Option<&Value>, max: Option<&Value>) -> Result<Vec<KvPair>>;
+
+    // 开启后每次写入都会记录一个新版本，已开启时只更新保留策略
+    fn enable_history(&self, table: &str, policy: HistoryPolicy) -> Result<()>;
+
+    fn disable_history(&self, table: &str) -> Result<bool>;
+
+    // 按版本号从旧到新返回 key 的历史版本
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>>;
+
+    fn get_as_of(&self, table: &str, key: &str, as_of: AsOf) -> Result<Option<Value>>;
+
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>>;
+
+    // 把尚未落盘的数据写入磁盘，关闭服务前调用
+    fn flush(&self) -> Result<()>;
 }
 
 struct StorageItem<T> {
@@ -1,12 +1,39 @@
+use std::cell::RefCell;
+
+use prost::Message;
+
 use sled::Db;
 use sled::IVec;
+use sled::Tree;
+use sled::transaction::{ConflictableTransactionError, ConflictableTransactionResult, TransactionError, TransactionResult, Transactional, TransactionalTree};
 
+use crate::KvError;
 use crate::KvPair;
 use crate::Value;
+use crate::Version;
 use crate::Result;
 use crate::storage::StorageItem;
 
 use super::Storage;
+use super::history::{AsOf, HistoryPolicy, VersionList};
+use super::index::{IndexField, IndexRange};
+
+// table 和 key 之间的分隔符，与旧版本的存储格式一致；table 名中不允许出现，key 中可以出现任意字符
+const SEPARATOR: char = ':';
+// 索引定义存放在该 tree 中，key 为 table:index，value 为索引路径；
+// key 为 table 时 value 为该 table 索引定义的版本号，创建和删除索引时增加
+const INDEX_DEFINE_TREE: &str = "__indexes__";
+// 历史记录策略存放在该 tree 中，key 为 table
+const HISTORY_DEFINE_TREE: &str = "__histories__";
+
+// table 上需要和数据一起写入的索引和历史记录
+struct TableMeta {
+    // 读取时索引定义的版本号和历史记录策略，写入的事务中两者变化时需要重新读取
+    generation: Option<IVec>,
+    policy: Option<IVec>,
+    indexes: Vec<(IndexField, Tree)>,
+    history: Option<(HistoryPolicy, Tree)>,
+}
 
 #[derive(Debug, Clone)]
 pub struct SledDb (Db);
@@ -16,18 +43,161 @@ impl SledDb {
         Self(sled::open(path).expect("failed to create db"))
     }
 
-    fn get_full_name(&self, table: &str, key: &str) -> String {
-        format!("{}:{}", table, key)
+    fn get_full_name(&self, table: &str, key: &str) -> Result<String> {
+        Ok(format!("{}{}", self.get_prefix(table)?, key))
+    }
+
+    // table 名中出现分隔符时前缀会和其他 table 的数据重叠
+    fn get_prefix(&self, table: &str) -> Result<String> {
+        if table.contains(SEPARATOR) {
+            return Err(KvError::Invalid(format!("invalid table name {:?}", table)));
+        }
+        Ok(format!("{}{}", table, SEPARATOR))
+    }
+
+    fn get_index_tree_name(&self, table: &str, index: &str) -> Result<String> {
+        Ok(format!("__index__:{}", self.get_full_name(table, index)?))
+    }
+
+    fn get_index_tree(&self, table: &str, index: &str) -> Result<Tree> {
+        Ok(self.0.open_tree(self.get_index_tree_name(table, index)?)?)
+    }
+
+    fn get_history_tree(&self, table: &str) -> Result<Tree> {
+        Ok(self.0.open_tree(format!("__history__:{}", table))?)
+    }
+
+    fn get_history_policy(&self, table: &str) -> Result<Option<HistoryPolicy>> {
+        Ok(self.0.open_tree(HISTORY_DEFINE_TREE)?
+            .get(table)?
+            .and_then(|x| HistoryPolicy::from_bytes(&x)))
+    }
+
+    fn get_meta(&self, table: &str) -> Result<TableMeta> {
+        let prefix = self.get_prefix(table)?;
+        let defines = self.0.open_tree(INDEX_DEFINE_TREE)?;
+
+        // 先读版本号再读索引定义，两次读取之间索引有变化时写入的事务会发现版本号不一致
+        let generation = defines.get(table)?;
+        let policy = self.0.open_tree(HISTORY_DEFINE_TREE)?.get(table)?;
+
+        let indexes = defines
+            .scan_prefix(&prefix)
+            .map(|x| {
+                let (name, path) = x?;
+                let index = std::str::from_utf8(&name[prefix.len()..]).map_err(|_| KvError::ConvertError)?;
+                let path = std::str::from_utf8(&path).map_err(|_| KvError::ConvertError)?;
+                Ok((path.parse()?, self.get_index_tree(table, index)?))
+            })
+            .collect::<Result<_>>()?;
+
+        let history = match policy.as_deref().and_then(HistoryPolicy::from_bytes) {
+            Some(policy) => Some((policy, self.get_history_tree(table)?)),
+            None => None,
+        };
+
+        Ok(TableMeta { generation, policy, indexes, history })
+    }
+
+    // 在同一个事务中写入数据、所有索引以及历史版本，f 返回 None 表示删除
+    // 事务中确认 table 的索引定义和历史记录策略没有变化，否则重新读取后再写入，
+    // 这样写入不会漏掉并发创建的索引，也不会和 create_index / enable_history 的回填交错
+    fn write_with_meta<F>(&self, table: &str, key: &str, f: F) -> Result<(Option<Value>, Option<Value>)>
+    where
+        F: FnMut(Option<Value>) -> Result<Option<Value>>
+    {
+        let name = self.get_full_name(table, key)?;
+        let f = RefCell::new(f);
+
+        loop {
+            let meta = self.get_meta(table)?;
+            if let Some(res) = self.try_write_with_meta(table, key, &name, &meta, &f)? {
+                return Ok(res);
+            }
+        }
     }
 
-    fn get_prefix(&self, table: &str) -> String {
-        format!("{}", table)
+    fn try_write_with_meta<F>(&self, table: &str, key: &str, name: &str, meta: &TableMeta, f: &RefCell<F>) -> Result<Option<(Option<Value>, Option<Value>)>>
+    where
+        F: FnMut(Option<Value>) -> Result<Option<Value>>
+    {
+        let mut trees = vec![(*self.0).clone(), self.0.open_tree(INDEX_DEFINE_TREE)?, self.0.open_tree(HISTORY_DEFINE_TREE)?];
+        trees.extend(meta.indexes.iter().map(|(_, x)| x.clone()));
+        trees.extend(meta.history.iter().map(|(_, x)| x.clone()));
+
+        let res = trees.as_slice().transaction(|tx| {
+            if tx[1].get(table)? != meta.generation || tx[2].get(table)? != meta.policy {
+                return Ok(None);
+            }
+
+            let old = tx[0].get(name)?
+                .map(|x| x.as_ref().try_into())
+                .transpose()
+                .map_err(ConflictableTransactionError::Abort)?;
+            let new = (f.borrow_mut())(old.clone()).map_err(ConflictableTransactionError::Abort)?;
+
+            match new.clone() {
+                Some(value) => {
+                    let value: Vec<u8> = value.try_into().map_err(ConflictableTransactionError::Abort)?;
+                    tx[0].insert(name.as_bytes(), value)?;
+                },
+                None => {
+                    tx[0].remove(name.as_bytes())?;
+                }
+            }
+
+            for ((field, _), tree) in meta.indexes.iter().zip(&tx[3..]) {
+                if let Some(x) = old.as_ref().and_then(|x| field.key(x)) {
+                    tree.remove(index_entry(x, key))?;
+                }
+                if let Some(x) = new.as_ref().and_then(|x| field.key(x)) {
+                    tree.insert(index_entry(x, key), key.as_bytes())?;
+                }
+            }
+
+            // 删除不存在的 key 不记录版本
+            if let (Some((policy, _)), true) = (meta.history.as_ref(), old.is_some() || new.is_some()) {
+                let tree = &tx[tx.len() - 1];
+                let mut list = match tree.get(key)? {
+                    Some(x) => VersionList::decode(x.as_ref())
+                        .map_err(|e| ConflictableTransactionError::Abort(e.into()))?,
+                    None => VersionList::default(),
+                };
+                policy.record(&mut list.versions, tree.generate_id()?, new.clone());
+                tree.insert(key.as_bytes(), list.encode_to_vec())?;
+            }
+            Ok(Some((old, new)))
+        });
+
+        transaction_result(res)
     }
 }
 
+fn transaction_result<T>(res: TransactionResult<T, KvError>) -> Result<T> {
+    res.map_err(|e| match e {
+        TransactionError::Abort(e) => e,
+        TransactionError::Storage(e) => e.into(),
+    })
+}
+
+// 增加 table 索引定义的版本号，使用旧的索引定义开始的写入会重新读取
+fn bump_generation(tx: &TransactionalTree, table: &str) -> ConflictableTransactionResult<(), KvError> {
+    let generation = tx.get(table)?
+        .and_then(|x| x.as_ref().try_into().ok())
+        .map_or(0, u64::from_be_bytes);
+    tx.insert(table.as_bytes(), &(generation + 1).to_be_bytes())?;
+    Ok(())
+}
+
+// 索引 tree 中的 key 为 索引键 + 主键，value 为主键
+fn index_entry(mut index_key: Vec<u8>, key: &str) -> Vec<u8> {
+    index_key.extend_from_slice(key.as_bytes());
+    index_key
+}
+
 impl Storage for SledDb {
     fn get(&self, table: &str, key: &str) -> Result<Option<Value>> {
-        let name = self.get_full_name(table, key);
+        let name = self.get_full_name(table, key)?;
 
         self.0.get(name)?
             .map(|x| x.as_ref().try_into())
@@ -35,31 +205,31 @@ impl Storage for SledDb {
     }
 
     fn set(&self, table: &str, key: impl Into<String>, value: Value) -> Result<Option<Value>> {
-        let name = self.get_full_name(table, &key.into());
-
-        let value: Vec<u8> = value.try_into()?;
-
-        self.0.insert(name, value)?
-            .map(|x| x.as_ref().try_into())
-            .transpose()
+        let (old, _) = self.write_with_meta(table, &key.into(), |_| Ok(Some(value.clone())))?;
+        Ok(old)
     }
 
     fn contains(&self, table: &str, key: &str) -> Result<bool> {
-        let name = self.get_full_name(table, key);
+        let name = self.get_full_name(table, key)?;
         
         Ok(self.0.contains_key(&name)?)
     }
 
     fn delete(&self, table: &str, key: &str) -> Result<Option<Value>> {
-        let name = self.get_full_name(table, key);
+        let (old, _) = self.write_with_meta(table, key, |_| Ok(None))?;
+        Ok(old)
+    }
 
-        self.0.remove(name)?
-            .map(|x| x.as_ref().try_into())
-            .transpose()
+    fn update<F>(&self, table: &str, key: &str, mut f: F) -> Result<Value>
+    where
+        F: FnMut(Option<Value>) -> Result<Value>
+    {
+        let (_, value) = self.write_with_meta(table, key, |x| f(x).map(Some))?;
+        Ok(value.unwrap_or_default())
     }
 
     fn get_all(&self, table: &str) -> Result<Vec<KvPair>> {
-        let prefix = self.get_prefix(table);
+        let prefix = self.get_prefix(table)?;
 
         let value = self.0.scan_prefix(prefix)
             .map(|x| x.into())
@@ -68,8 +238,138 @@ impl Storage for SledDb {
         Ok(value)
     }
 
+    fn create_index(&self, table: &str, index: &str, path: &str) -> Result<()> {
+        let field: IndexField = path.parse()?;
+        let name = self.get_full_name(table, index)?;
+
+        // 先登记索引，之后的写入都会同时写索引
+        let created = transaction_result(self.0.open_tree(INDEX_DEFINE_TREE)?.transaction(|tx| {
+            if tx.get(&name)?.is_some() {
+                return Ok(false);
+            }
+            tx.insert(name.as_bytes(), path.as_bytes())?;
+            bump_generation(tx, table)?;
+            Ok(true)
+        }))?;
+        if !created {
+            return Err(KvError::Invalid(format!("index {} already exists on table {}", index, table)));
+        }
+
+        // 回填时每个 key 在事务中读取当前的值并写入索引，不会覆盖并发写入的结果
+        let tree = self.get_index_tree(table, index)?;
+        for pair in self.get_iter(table)? {
+            let name = self.get_full_name(table, &pair.key)?;
+            let res = [(*self.0).clone(), tree.clone()].as_slice().transaction(|tx| {
+                let value: Option<Value> = tx[0].get(&name)?
+                    .map(|x| x.as_ref().try_into())
+                    .transpose()
+                    .map_err(ConflictableTransactionError::Abort)?;
+                if let Some(x) = value.as_ref().and_then(|x| field.key(x)) {
+                    tx[1].insert(index_entry(x, &pair.key), pair.key.as_bytes())?;
+                }
+                Ok(())
+            });
+            transaction_result(res)?;
+        }
+        Ok(())
+    }
+
+    fn drop_index(&self, table: &str, index: &str) -> Result<bool> {
+        let name = self.get_full_name(table, index)?;
+
+        let res = transaction_result(self.0.open_tree(INDEX_DEFINE_TREE)?.transaction(|tx| {
+            let res = tx.remove(name.as_bytes())?.is_some();
+            if res {
+                bump_generation(tx, table)?;
+            }
+            Ok(res)
+        }))?;
+        self.0.drop_tree(self.get_index_tree_name(table, index)?)?;
+        Ok(res)
+    }
+
+    fn query_index(&self, table: &str, index: &str, min: Option<&Value>, max: Option<&Value>) -> Result<Vec<KvPair>> {
+        let range = IndexRange::new(min, max)?;
+        let name = self.get_full_name(table, index)?;
+        let field: IndexField = match self.0.open_tree(INDEX_DEFINE_TREE)?.get(name)? {
+            Some(path) => std::str::from_utf8(&path).map_err(|_| KvError::ConvertError)?.parse()?,
+            None => return Err(KvError::IndexNotFound(table.into(), index.into())),
+        };
+
+        let mut res = vec![];
+        for x in self.get_index_tree(table, index)?.range::<&[u8], _>((range.start(), std::ops::Bound::Unbounded)) {
+            let (entry, key) = x?;
+            if !range.below_max(&entry[..entry.len() - key.len()]) {
+                break;
+            }
+            let key = std::str::from_utf8(&key).map_err(|_| KvError::ConvertError)?;
+            // 读取索引和读取数据之间可能有并发的写入，索引项可能已经过期
+            match self.get(table, key)? {
+                Some(value) if field.in_range(&value, &range) => res.push((key, value).into()),
+                _ => (),
+            }
+        }
+        Ok(res)
+    }
+
+    fn enable_history(&self, table: &str, policy: HistoryPolicy) -> Result<()> {
+        let old = self.0.open_tree(HISTORY_DEFINE_TREE)?.insert(table, policy.to_bytes())?;
+        if old.is_some() {
+            return Ok(());
+        }
+
+        // 已有的值作为各 key 的第一个版本，在事务中读取当前的值；
+        // 登记策略之后的写入已经记录了版本时不再覆盖
+        let tree = self.get_history_tree(table)?;
+        for pair in self.get_iter(table)? {
+            let name = self.get_full_name(table, &pair.key)?;
+            let res = [(*self.0).clone(), tree.clone()].as_slice().transaction(|tx| {
+                if tx[1].get(&pair.key)?.is_some() {
+                    return Ok(());
+                }
+                let Some(value) = tx[0].get(&name)? else {
+                    return Ok(());
+                };
+                let value = value.as_ref().try_into().map_err(ConflictableTransactionError::Abort)?;
+
+                let mut list = VersionList::default();
+                policy.record(&mut list.versions, tx[0].generate_id()?, Some(value));
+                tx[1].insert(pair.key.as_bytes(), list.encode_to_vec())?;
+                Ok(())
+            });
+            transaction_result(res)?;
+        }
+        Ok(())
+    }
+
+    fn disable_history(&self, table: &str) -> Result<bool> {
+        let res = self.0.open_tree(HISTORY_DEFINE_TREE)?.remove(table)?.is_some();
+        self.0.drop_tree(format!("__history__:{}", table))?;
+        Ok(res)
+    }
+
+    fn history(&self, table: &str, key: &str) -> Result<Vec<Version>> {
+        if self.get_history_policy(table)?.is_none() {
+            return Err(KvError::HistoryDisabled(table.into()));
+        }
+
+        match self.get_history_tree(table)?.get(key)? {
+            Some(x) => Ok(VersionList::decode(x.as_ref())?.versions),
+            None => Ok(vec![]),
+        }
+    }
+
+    fn get_as_of(&self, table: &str, key: &str, as_of: AsOf) -> Result<Option<Value>> {
+        Ok(as_of.find(&self.history(table, key)?))
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.0.flush()?;
+        Ok(())
+    }
+
     fn get_iter(&self, table: &str) -> Result<Box<dyn Iterator<Item = KvPair>>> {
-        let prefix = self.get_prefix(table);
+        let prefix = self.get_prefix(table)?;
         
         let value = self.0.scan_prefix(prefix);
 
@@ -90,11 +390,10 @@ impl From<sled::Result<(IVec, IVec)>> for KvPair {
     }
 }
 
+// 去掉 table 前缀，key 中可能包含分隔符
 fn ivec_to_key(ivec: &[u8]) -> &str {
     let str = std::str::from_utf8(ivec).unwrap();
-    let mut s = str.split(":");
-    s.next();
-    s.next().unwrap()
+    str.split_once(SEPARATOR).map_or(str, |(_, key)| key)
 }
 
 
@@ -102,7 +401,7 @@ fn ivec_to_key(ivec: &[u8]) -> &str {
 mod tests {
     use tempfile::tempdir;
 
-    use crate::storage::Storage;
+    use crate::{storage::{Storage, AsOf, HistoryPolicy}, Value};
 
     use super::SledDb;
 
@@ -131,10 +430,182 @@ mod tests {
         res.sort_by(|a, b| a.key.cmp(&b.key));
         assert_eq!(res, vec![("k1", "v1".into()).into(), ("k2", "v2".into()).into()]);
 
+        let res = db.update("t1", "k3", |x| {
+            assert!(x.is_none());
+            Ok(1.into())
+        }).unwrap();
+        assert_eq!(res, 1.into());
+        let res = db.update("t1", "k3", |x| {
+            let x: i64 = (&x.unwrap()).try_into()?;
+            Ok((x + 1).into())
+        }).unwrap();
+        assert_eq!(res, 2.into());
+        db.delete("t1", "k3").unwrap();
+
         let res = db.delete("t1", "k1").unwrap();
         assert_eq!(res, Some("v1".into()));
         let res = db.delete("t1", "k2").unwrap();
         assert_eq!(res, Some("v2".into()));
         dir.close().unwrap();
     }
+
+    #[test]
+    fn sled_db_index_should_work() {
+        let dir = tempdir().unwrap();
+        let db = SledDb::new(dir.path());
+
+        let doc = |age: i64| -> Value {
+            serde_json::json!({ "age": age }).into()
+        };
+
+        db.set("t1", "k1", doc(10)).unwrap();
+        db.create_index("t1", "age", "$.age").unwrap();
+        assert!(db.create_index("t1", "age", "$.age").is_err());
+
+        db.set("t1", "k2", doc(20)).unwrap();
+        db.set("t1", "k3", doc(30)).unwrap();
+        db.update("t1", "k1", |_| Ok(doc(20))).unwrap();
+        db.delete("t1", "k3").unwrap();
+
+        let res = db.query_index("t1", "age", Some(&20.into()), Some(&20.into())).unwrap();
+        assert_eq!(res, vec![("k1", doc(20)).into(), ("k2", doc(20)).into()]);
+        let res = db.query_index("t1", "age", Some(&25.into()), None).unwrap();
+        assert!(res.is_empty());
+
+        // 绕过索引直接修改数据，模拟回填时并发写入留下的过期索引项
+        db.0.insert(db.get_full_name("t1", "k2").unwrap(), Vec::<u8>::try_from(doc(40)).unwrap()).unwrap();
+        let res = db.query_index("t1", "age", Some(&20.into()), Some(&20.into())).unwrap();
+        assert_eq!(res, vec![("k1", doc(20)).into()]);
+
+        assert!(db.drop_index("t1", "age").unwrap());
+        assert!(db.query_index("t1", "age", None, None).is_err());
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn sled_db_create_index_should_not_miss_concurrent_writes() {
+        let dir = tempdir().unwrap();
+        let db = SledDb::new(dir.path());
+        for i in 0..1000 {
+            db.set("t1", format!("k{}", i), 0.into()).unwrap();
+        }
+
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in 0..3000 {
+                    db.set("t1", format!("k{}", i), i.into()).unwrap();
+                }
+            })
+        };
+        db.create_index("t1", "i1", "").unwrap();
+        writer.join().unwrap();
+
+        // 每个 key 在索引中只有一项，并且与当前的值一致
+        let tree = db.get_index_tree("t1", "i1").unwrap();
+        assert_eq!(tree.len(), 3000);
+        let res = db.query_index("t1", "i1", Some(&1000.into()), None).unwrap();
+        assert_eq!(res.len(), 2000);
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn sled_db_enable_history_should_not_overwrite_concurrent_writes() {
+        let dir = tempdir().unwrap();
+        let db = SledDb::new(dir.path());
+        for i in 0..1000 {
+            db.set("t1", format!("k{}", i), 0.into()).unwrap();
+        }
+
+        let writer = {
+            let db = db.clone();
+            std::thread::spawn(move || {
+                for i in 0..3000 {
+                    db.set("t1", format!("k{}", i), i.into()).unwrap();
+                }
+            })
+        };
+        db.enable_history("t1", HistoryPolicy::new(0, 3600)).unwrap();
+        writer.join().unwrap();
+
+        // 每个 key 最新的版本与当前的值一致
+        for i in 0..3000 {
+            let key = format!("k{}", i);
+            let versions = db.history("t1", &key).unwrap();
+            assert_eq!(versions.last().and_then(|x| x.value.clone()), db.get("t1", &key).unwrap());
+        }
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn sled_db_tables_should_not_overlap() {
+        let dir = tempdir().unwrap();
+        let db = SledDb::new(dir.path());
+
+        db.set("a", "b:c", 1.into()).unwrap();
+        db.set("ab", "c", 3.into()).unwrap();
+        db.create_index("a", "i1", "").unwrap();
+        db.create_index("ab", "i2", "").unwrap();
+
+        assert_eq!(db.get_all("a").unwrap(), vec![("b:c", 1.into()).into()]);
+        assert_eq!(db.get_iter("ab").unwrap().collect::<Vec<_>>(), vec![("c", 3.into()).into()]);
+        assert_eq!(db.get_meta("a").unwrap().indexes.len(), 1);
+        assert_eq!(db.query_index("a", "i1", None, None).unwrap(), vec![("b:c", 1.into()).into()]);
+
+        // table 名中有分隔符时会与其他 table 重叠
+        assert!(db.set("a:b", "c", 4.into()).is_err());
+        assert!(db.get_all("a:b").is_err());
+        assert!(db.create_index("a:b", "i3", "").is_err());
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn sled_db_should_read_existing_data() {
+        let dir = tempdir().unwrap();
+
+        // 旧版本写入的数据，key 为 table:key
+        let db = sled::open(dir.path()).unwrap();
+        let value: Vec<u8> = Value::from("v1").try_into().unwrap();
+        db.insert("t1:k1", value.clone()).unwrap();
+        db.insert("t1:k2:x", value).unwrap();
+        db.flush().unwrap();
+        drop(db);
+
+        let db = SledDb::new(dir.path());
+        assert_eq!(db.get("t1", "k1").unwrap(), Some("v1".into()));
+        let mut res = db.get_all("t1").unwrap();
+        res.sort_by(|a, b| a.key.cmp(&b.key));
+        assert_eq!(res, vec![("k1", "v1".into()).into(), ("k2:x", "v1".into()).into()]);
+        dir.close().unwrap();
+    }
+
+    #[test]
+    fn sled_db_history_should_work() {
+        let dir = tempdir().unwrap();
+        let db = SledDb::new(dir.path());
+
+        db.set("t1", "k1", "v1".into()).unwrap();
+        assert!(db.history("t1", "k1").is_err());
+
+        db.enable_history("t1", HistoryPolicy::new(0, 3600)).unwrap();
+        db.set("t1", "k1", "v2".into()).unwrap();
+        db.delete("t1", "k1").unwrap();
+        db.update("t1", "k1", |_| Ok("v3".into())).unwrap();
+        db.delete("t1", "k2").unwrap();
+
+        let versions = db.history("t1", "k1").unwrap();
+        assert_eq!(versions.len(), 4);
+        assert!(versions[2].deleted);
+        assert!(db.history("t1", "k2").unwrap().is_empty());
+
+        let v = versions[1].version;
+        assert_eq!(db.get_as_of("t1", "k1", AsOf::Version(v)).unwrap(), Some("v2".into()));
+        assert_eq!(db.get_as_of("t1", "k1", AsOf::Version(v - 1)).unwrap(), Some("v1".into()));
+        assert_eq!(db.get_as_of("t1", "k1", AsOf::Version(versions[2].version)).unwrap(), None);
+        assert_eq!(db.get_as_of("t1", "k1", AsOf::Time(u64::MAX)).unwrap(), Some("v3".into()));
+
+        assert!(db.disable_history("t1").unwrap());
+        assert!(db.history("t1", "k1").is_err());
+        dir.close().unwrap();
+    }
 }
\ No newline at end of file